@@ -0,0 +1,409 @@
+// End-to-end smoke test over real PTYs: an emulated ADX on one pair, a
+// scripted game on the other, and the proxy binary in between. Exits
+// non-zero if anything the game gets back isn't what the ADX sent.
+//
+//     cargo build && cargo run --example e2e_pty --features e2e
+//
+// MAITOUCH_BIN overrides the proxy binary, which otherwise comes from the
+// same target directory as this example.
+//
+// The emulated ADX hands its bytes over no faster than a real line would,
+// E2E_BAUD baud (9600 by default, 0 for as fast as the PTY takes them) at
+// 10 bits a byte, E2E_BURST bytes at a time (1 by default; a USB adapter
+// batches up to its FIFO, e.g. 32) so frames straddle the proxy's reads.
+// The schedule it keeps has tests of its own:
+//
+//     cargo test --example e2e_pty --features e2e
+
+#[cfg(target_os = "linux")]
+fn main() {
+    if let Err(err) = e2e::run() {
+        eprintln!("e2e: FAILED: {:#}", err);
+        std::process::exit(1);
+    }
+    eprintln!("e2e: ok");
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("e2e: PTYs are only available on Linux");
+    std::process::exit(2);
+}
+
+#[cfg(target_os = "linux")]
+mod e2e {
+    use anyhow::{bail, ensure, Context, Result};
+    use serialport::{SerialPort, TTYPort};
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const FRAMES: usize = 500;
+    const FRAME_INTERVAL: Duration = Duration::from_millis(1);
+    // A1 is pressed for this many frames, then released for as many
+    const PRESS_FRAMES: usize = 50;
+    const CONFIG_COMMANDS: &[&[u8]] = &[b"{LAr2}", b"{RAr2}", b"{LAk5}", b"{RAk5}"];
+    const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+    const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    // Writes to the emulated ADX's end no faster than `baud`, `burst` bytes
+    // at a time
+    struct Line {
+        port: TTYPort,
+        schedule: Schedule,
+    }
+
+    // When a line at `byte_time` a byte carries each burst. Each burst is
+    // due when the line would have carried everything before it, so
+    // sleeping late doesn't add up.
+    struct Schedule {
+        byte_time: Duration,
+        burst: usize,
+        // When the line last came out of idle, and the bytes sent since
+        started: Instant,
+        sent: u32,
+    }
+
+    impl Schedule {
+        // The bursts `len` bytes written at `now` go out in, and when each
+        // is due
+        fn bursts(&mut self, now: Instant, len: usize) -> Vec<(usize, Instant)> {
+            // An idle line starts its schedule over
+            if now > self.started + self.byte_time * self.sent {
+                self.started = now;
+                self.sent = 0;
+            }
+            (0..len)
+                .step_by(self.burst)
+                .map(|at| {
+                    let burst = self.burst.min(len - at);
+                    self.sent += burst as u32;
+                    (burst, self.started + self.byte_time * self.sent)
+                })
+                .collect()
+        }
+    }
+
+    impl Line {
+        fn new(port: TTYPort) -> Result<Self> {
+            let setting = |name: &str, default: u32| -> Result<u32> {
+                match std::env::var(name) {
+                    Ok(value) => value
+                        .parse()
+                        .with_context(|| format!("{}={} isn't a number", name, value)),
+                    Err(_) => Ok(default),
+                }
+            };
+            let baud = setting("E2E_BAUD", 9600)?;
+            let burst = setting("E2E_BURST", 1)?.max(1) as usize;
+            let byte_time = match baud {
+                0 => Duration::ZERO,
+                baud => Duration::from_secs(10) / baud,
+            };
+            Ok(Line {
+                port,
+                schedule: Schedule {
+                    byte_time,
+                    burst,
+                    started: Instant::now(),
+                    sent: 0,
+                },
+            })
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> Result<()> {
+            if self.schedule.byte_time.is_zero() {
+                self.port.write_all(bytes)?;
+                return Ok(());
+            }
+            let mut rest = bytes;
+            for (burst, due) in self.schedule.bursts(Instant::now(), bytes.len()) {
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+                let (now, later) = rest.split_at(burst);
+                self.port.write_all(now)?;
+                rest = later;
+            }
+            Ok(())
+        }
+    }
+
+    // Kills the proxy however the run ends
+    struct Proxy(Child);
+
+    impl Drop for Proxy {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn frame(index: usize) -> [u8; 9] {
+        let pressed = (index / PRESS_FRAMES) % 2 == 1;
+        [b'(', pressed as u8, 0, 0, 0, 0, 0, 0, b')']
+    }
+
+    fn proxy_binary() -> Result<PathBuf> {
+        if let Some(bin) = std::env::var_os("MAITOUCH_BIN") {
+            return Ok(bin.into());
+        }
+        // target/<profile>/examples/e2e_pty -> target/<profile>/maitouch_rs
+        let exe = std::env::current_exe()?;
+        let bin = exe
+            .parent()
+            .and_then(|examples| examples.parent())
+            .map(|profile| profile.join("maitouch_rs"))
+            .context("locating the proxy binary")?;
+        ensure!(
+            bin.exists(),
+            "{} not found, run cargo build first",
+            bin.display()
+        );
+        Ok(bin)
+    }
+
+    // Answers config commands by echoing them in ADX delimiters and streams
+    // the scripted frames after {STAT}, like a real board
+    fn emulate_adx(mut port: TTYPort, streamed: Arc<AtomicBool>) -> Result<()> {
+        let mut line = Line::new(port.try_clone_native()?)?;
+        let mut pending = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = match port.read(&mut buf) {
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+                Err(err) => return Err(err.into()),
+            };
+            pending.extend_from_slice(&buf[..n]);
+            while let Some(end) = pending.iter().position(|&byte| byte == b'}') {
+                let packet: Vec<u8> = pending.drain(..=end).collect();
+                let Some(start) = packet.iter().rposition(|&byte| byte == b'{') else {
+                    continue;
+                };
+                match &packet[start..] {
+                    b"{STAT}" => {
+                        for index in 0..FRAMES {
+                            line.write(&frame(index))?;
+                            thread::sleep(FRAME_INTERVAL);
+                        }
+                        streamed.store(true, Ordering::Relaxed);
+                    }
+                    b"{HALT}" | b"{RSET}" => {}
+                    command => {
+                        let mut response = command.to_vec();
+                        response[0] = b'(';
+                        *response.last_mut().unwrap() = b')';
+                        line.write(&response)?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_for(port: &mut TTYPort, duration: Duration) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        let end = Instant::now() + duration;
+        while Instant::now() < end {
+            match port.read(&mut buf) {
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(out)
+    }
+
+    fn exchange(game: &mut TTYPort, command: &[u8]) -> Result<Vec<u8>> {
+        game.write_all(command)?;
+        read_for(game, RESPONSE_TIMEOUT)
+    }
+
+    // Collapses repeats, since the proxy resends the latest frame until a new one arrives
+    fn distinct_frames(stream: &[u8]) -> Result<Vec<[u8; 9]>> {
+        let start = stream
+            .iter()
+            .position(|&byte| byte == b'(')
+            .context("no frames")?;
+        let mut frames: Vec<[u8; 9]> = Vec::new();
+        for chunk in stream[start..].chunks_exact(9) {
+            let frame: [u8; 9] = chunk.try_into().unwrap();
+            ensure!(
+                frame[0] == b'(' && frame[8] == b')',
+                "misaligned frame {:?}",
+                frame
+            );
+            if frames.last() != Some(&frame) {
+                frames.push(frame);
+            }
+        }
+        Ok(frames)
+    }
+
+    pub fn run() -> Result<()> {
+        let (mut adx, adx_slave) = TTYPort::pair().context("creating ADX PTY")?;
+        let (mut game, game_slave) = TTYPort::pair().context("creating game PTY")?;
+        adx.set_timeout(Duration::from_millis(50))?;
+        game.set_timeout(Duration::from_millis(10))?;
+        let adx_path = adx_slave.name().context("ADX PTY has no name")?;
+        let alls_path = game_slave.name().context("game PTY has no name")?;
+
+        let streamed = Arc::new(AtomicBool::new(false));
+        {
+            let streamed = streamed.clone();
+            thread::spawn(move || {
+                if let Err(err) = emulate_adx(adx, streamed) {
+                    eprintln!("e2e: ADX emulator stopped: {}", err);
+                }
+            });
+        }
+        let _proxy = Proxy(
+            Command::new(proxy_binary()?)
+                .args([&alls_path, &adx_path])
+                .stdout(Stdio::null())
+                .spawn()
+                .context("starting the proxy")?,
+        );
+
+        // The proxy drains the ADX before it starts answering
+        let started = Instant::now();
+        game.write_all(b"{RSET}{HALT}")?;
+        loop {
+            let response = exchange(&mut game, CONFIG_COMMANDS[0])?;
+            if response.ends_with(b"(LAr2)") {
+                break;
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                bail!("proxy never answered the handshake");
+            }
+        }
+        // Every {LAr2} sent while the proxy drained gets its own answer, and
+        // on a paced line the last of them can still be on the way
+        read_for(&mut game, RESPONSE_TIMEOUT)?;
+        for command in CONFIG_COMMANDS {
+            let response = exchange(&mut game, command)?;
+            let mut expected = command.to_vec();
+            expected[0] = b'(';
+            *expected.last_mut().unwrap() = b')';
+            ensure!(
+                response == expected,
+                "{} answered with {:?}",
+                String::from_utf8_lossy(command),
+                String::from_utf8_lossy(&response)
+            );
+        }
+
+        game.write_all(b"{STAT}")?;
+        let mut stream = Vec::new();
+        while !streamed.load(Ordering::Relaxed) {
+            stream.extend(read_for(&mut game, Duration::from_millis(50))?);
+            ensure!(
+                started.elapsed() < STARTUP_TIMEOUT * 2,
+                "ADX never finished streaming"
+            );
+        }
+        stream.extend(read_for(&mut game, Duration::from_millis(50))?);
+        game.write_all(b"{HALT}")?;
+        // Whatever the writer sent before it saw HALT still has to be whole frames
+        stream.extend(read_for(&mut game, RESPONSE_TIMEOUT)?);
+
+        let mut expected: Vec<[u8; 9]> = Vec::new();
+        for index in 0..FRAMES {
+            if expected.last() != Some(&frame(index)) {
+                expected.push(frame(index));
+            }
+        }
+        // The stream ends on an all-clear frame, so the game isn't left
+        // holding the last touch
+        let all_clear = frame(0);
+        if expected.last() != Some(&all_clear) {
+            expected.push(all_clear);
+        }
+        let received = distinct_frames(&stream)?;
+        ensure!(
+            received == expected,
+            "forwarded {} distinct frames, expected {}",
+            received.len(),
+            expected.len()
+        );
+
+        let response = exchange(&mut game, b"{LAr3}")?;
+        ensure!(
+            response == b"(LAr3)",
+            "config after HALT answered with {:?}",
+            String::from_utf8_lossy(&response)
+        );
+        drop(game_slave);
+        drop(adx_slave);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // 9600 baud at 10 bits a byte
+        const BYTE_TIME: Duration = Duration::from_micros(1041);
+
+        fn schedule(burst: usize, started: Instant) -> Schedule {
+            Schedule {
+                byte_time: BYTE_TIME,
+                burst,
+                started,
+                sent: 0,
+            }
+        }
+
+        // When each burst is due, in byte times after `started`
+        fn due(bursts: &[(usize, Instant)], started: Instant) -> Vec<(usize, u32)> {
+            bursts
+                .iter()
+                .map(|&(burst, due)| (burst, ((due - started).as_micros() / 1041) as u32))
+                .collect()
+        }
+
+        #[test]
+        fn a_byte_at_a_time_goes_out_at_the_baud() {
+            let started = Instant::now();
+            let mut line = schedule(1, started);
+            let bursts = line.bursts(started, 9);
+            assert_eq!(
+                due(&bursts, started),
+                (1..=9).map(|n| (1, n)).collect::<Vec<_>>()
+            );
+            assert_eq!(bursts[8].1 - started, BYTE_TIME * 9);
+        }
+
+        #[test]
+        fn bursts_are_due_once_the_line_carried_them() {
+            let started = Instant::now();
+            let bursts = schedule(4, started).bursts(started, 9);
+            assert_eq!(due(&bursts, started), [(4, 4), (4, 8), (1, 9)]);
+        }
+
+        #[test]
+        fn a_write_to_a_busy_line_queues_behind_what_it_carries() {
+            let started = Instant::now();
+            let mut line = schedule(1, started);
+            line.bursts(started, 9);
+            // Written three byte times in, while six are still to go
+            let bursts = line.bursts(started + BYTE_TIME * 3, 2);
+            assert_eq!(due(&bursts, started), [(1, 10), (1, 11)]);
+        }
+
+        #[test]
+        fn an_idle_line_starts_over() {
+            let started = Instant::now();
+            let mut line = schedule(1, started);
+            line.bursts(started, 9);
+            let later = started + BYTE_TIME * 100;
+            let bursts = line.bursts(later, 2);
+            assert_eq!(due(&bursts, later), [(1, 1), (1, 2)]);
+        }
+    }
+}
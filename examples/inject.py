@@ -0,0 +1,22 @@
+#!/usr/bin/env python3
+# Sends touches to a proxy started with --inject-listen, e.g.
+#
+#     maitouch_rs COM3 COM4 --inject-listen 127.0.0.1:5800
+#     python3 examples/inject.py 127.0.0.1:5800 "A1 pulse 50" "B2 press"
+#
+# With no commands given, taps A1 to A8 around the ring.
+import socket
+import sys
+import time
+
+if len(sys.argv) < 2:
+    sys.exit("usage: inject.py <host:port> [command...]")
+host, port = sys.argv[1].rsplit(":", 1)
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+commands = sys.argv[2:]
+if commands:
+    sock.sendto("\n".join(commands).encode(), (host, int(port)))
+else:
+    for n in range(1, 9):
+        sock.sendto(f"A{n} pulse 80".encode(), (host, int(port)))
+        time.sleep(0.1)
@@ -0,0 +1,69 @@
+#!/usr/bin/env python3
+# Prints the touch state a proxy publishes with --shm, e.g.
+#
+#     maitouch_rs COM3 COM4 --shm maitouch-1p
+#     python3 examples/shm_reader.py maitouch-1p
+#
+# The segment is little-endian u64s: version (odd mid-update), monotonic
+# timestamp in microseconds, region bitmask (A1 = bit 0 through E8 =
+# bit 33) and frame number, then from layout 2 on the layout number and
+# the regions pressed and released since the previous frame. A proxy from
+# before layout 2 has a 32-byte segment with only the first four. See
+# src/shm.rs.
+import mmap
+import os
+import struct
+import sys
+import time
+
+SIZE_V1 = 32
+SIZE = 56
+# The C ring only has C1 and C2
+RINGS = [("A", 8), ("B", 8), ("C", 2), ("D", 8), ("E", 8)]
+REGIONS = [f"{ring}{n}" for ring, size in RINGS for n in range(1, size + 1)]
+
+if len(sys.argv) != 2:
+    sys.exit("usage: shm_reader.py <name>")
+name = sys.argv[1]
+if os.name == "nt":
+    try:
+        segment = mmap.mmap(-1, SIZE, tagname=f"Local\\{name}", access=mmap.ACCESS_READ)
+    except OSError:
+        segment = mmap.mmap(-1, SIZE_V1, tagname=f"Local\\{name}", access=mmap.ACCESS_READ)
+else:
+    with open(f"/dev/shm/{name}", "rb") as file:
+        size = min(os.fstat(file.fileno()).st_size, SIZE)
+        segment = mmap.mmap(file.fileno(), size, access=mmap.ACCESS_READ)
+layout = struct.unpack_from("<Q", segment, 32)[0] if len(segment) >= SIZE else 1
+
+
+def read():
+    # Retry until the version is even and unchanged across the copy, so
+    # the fields all come from the same update
+    while True:
+        (before,) = struct.unpack_from("<Q", segment, 0)
+        if before % 2:
+            continue
+        timestamp_us, regions, frame = struct.unpack_from("<QQQ", segment, 8)
+        pressed, released = struct.unpack_from("<QQ", segment, 40) if layout >= 2 else (0, 0)
+        (after,) = struct.unpack_from("<Q", segment, 0)
+        if after == before:
+            return before, timestamp_us, regions, frame, pressed, released
+
+
+def names(mask):
+    return [region for bit, region in enumerate(REGIONS) if mask >> bit & 1]
+
+
+last = None
+while True:
+    version, timestamp_us, regions, frame, pressed, released = read()
+    if version != last:
+        last = version
+        changes = [f"+{region}" for region in names(pressed)]
+        changes += [f"-{region}" for region in names(released)]
+        print(
+            f"{timestamp_us:>14}us  frame {frame:<8} {' '.join(names(regions)) or '-'}"
+            + (f"  ({' '.join(changes)})" if changes else "")
+        )
+    time.sleep(0.001)
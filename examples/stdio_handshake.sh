@@ -0,0 +1,20 @@
+#!/bin/sh
+# Drives a config-mode handshake and a second of streaming through the
+# proxy's stdin/stdout, then dumps what came back.
+#
+#     examples/stdio_handshake.sh /dev/ttyACM0
+set -e
+adx=${1:?usage: $0 <adx-port>}
+
+{
+    printf '{RSET}{HALT}'
+    sleep 1.5
+    for command in '{LAr2}' '{RAr2}' '{LAk5}' '{RAk5}'; do
+        printf '%s' "$command"
+        sleep 0.3
+    done
+    printf '{STAT}'
+    sleep 1
+    printf '{HALT}'
+    sleep 1.5
+} | cargo run --quiet -- stdio "$adx" | od -A d -c | sed -n '1,40p'
@@ -0,0 +1,89 @@
+use crate::framing::PacketDelimiter;
+
+// Commands the ALLS sends that change what the ADX does, rather than
+// configuring it
+pub const HALT: &str = "HALT";
+pub const STAT: &str = "STAT";
+pub const RSET: &str = "RSET";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandKind {
+    // Stop streaming touch frames
+    Halt,
+    // Start streaming touch frames
+    Stat,
+    // Back to power-on configuration
+    Reset,
+    // Anything else, answered with a response in config mode
+    Config,
+}
+
+pub fn classify(alls: &PacketDelimiter, packet: &[u8]) -> CommandKind {
+    if alls.wraps(packet, HALT.as_bytes()) {
+        CommandKind::Halt
+    } else if alls.wraps(packet, STAT.as_bytes()) {
+        CommandKind::Stat
+    } else if alls.wraps(packet, RSET.as_bytes()) {
+        CommandKind::Reset
+    } else {
+        CommandKind::Config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::maimai::{ADX, ALLS};
+
+    #[test]
+    fn classifies_the_mode_commands() {
+        assert_eq!(classify(&ALLS, b"{HALT}"), CommandKind::Halt);
+        assert_eq!(classify(&ALLS, b"{STAT}"), CommandKind::Stat);
+        assert_eq!(classify(&ALLS, b"{RSET}"), CommandKind::Reset);
+    }
+
+    #[test]
+    fn the_constants_are_the_payloads() {
+        let mut out = [0; 6];
+        for (name, kind) in [
+            (HALT, CommandKind::Halt),
+            (STAT, CommandKind::Stat),
+            (RSET, CommandKind::Reset),
+        ] {
+            let len = ALLS.wrap_into(name.as_bytes(), &mut out).unwrap();
+            assert_eq!(classify(&ALLS, &out[..len]), kind);
+        }
+    }
+
+    #[test]
+    fn config_commands_are_config() {
+        for packet in [&b"{LAr2}"[..], b"{RAk5}", b"{Lr2k}", b"{A}", b"{}"] {
+            assert_eq!(classify(&ALLS, packet), CommandKind::Config);
+        }
+    }
+
+    #[test]
+    fn unknown_bytes_are_config() {
+        for packet in [
+            &b""[..],
+            b"HALT",
+            b"{HALT",
+            b"HALT}",
+            b"(HALT)",
+            b"{halt}",
+            b"{HAL}",
+            b"{HALTS}",
+            b"{ STAT}",
+            b"{RSET}{STAT}",
+            b"\x00\xff\x7b\x7d",
+        ] {
+            assert_eq!(classify(&ALLS, packet), CommandKind::Config, "{:?}", packet);
+        }
+    }
+
+    #[test]
+    fn follows_the_given_delimiters() {
+        assert_eq!(classify(&ADX, b"(STAT)"), CommandKind::Stat);
+        assert_eq!(classify(&ADX, b"{STAT}"), CommandKind::Config);
+    }
+}
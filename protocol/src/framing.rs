@@ -0,0 +1,138 @@
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+// Bytes around every packet in one direction of the link
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketDelimiter {
+    pub open: char,
+    pub close: char,
+}
+
+impl PacketDelimiter {
+    // Whether `packet` is exactly `payload` between these delimiters
+    pub fn wraps(&self, packet: &[u8], payload: &[u8]) -> bool {
+        packet.len() == payload.len() + 2
+            && packet[0] == self.open as u8
+            && &packet[1..packet.len() - 1] == payload
+            && packet[packet.len() - 1] == self.close as u8
+    }
+
+    // Writes `payload` between the delimiters to the start of `out`,
+    // returning the packet length, or None if it doesn't fit
+    pub fn wrap_into(&self, payload: &[u8], out: &mut [u8]) -> Option<usize> {
+        let len = payload.len() + 2;
+        let packet = out.get_mut(..len)?;
+        packet[0] = self.open as u8;
+        packet[1..len - 1].copy_from_slice(payload);
+        packet[len - 1] = self.close as u8;
+        Some(len)
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn wrap(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = alloc::vec![0; payload.len() + 2];
+        self.wrap_into(payload, &mut packet);
+        packet
+    }
+}
+
+// The stock maimai link
+pub mod maimai {
+    use super::PacketDelimiter;
+    use crate::touch::PAYLOAD_LEN;
+
+    // Commands from the ALLS, e.g. {STAT}
+    pub const ALLS: PacketDelimiter = PacketDelimiter {
+        open: '{',
+        close: '}',
+    };
+    // Responses and touch frames from the ADX, e.g. (LAr2)
+    pub const ADX: PacketDelimiter = PacketDelimiter {
+        open: '(',
+        close: ')',
+    };
+    pub const TOUCH_FRAME_LEN: usize = PAYLOAD_LEN + 2;
+    pub const COMMAND_MAX_LEN: usize = 6;
+    // Sensitivity commands ({LAr2}, {RAk5}, ...) start with the side and are
+    // echoed back as a response of exactly this length, e.g. (LAr2)
+    pub const SIZED_RESPONSE_PREFIXES: &[&str] = &["L", "R"];
+    pub const SIZED_RESPONSE_LEN: usize = 6;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::maimai::{ADX, ALLS};
+
+    #[test]
+    fn wraps_only_the_exact_payload() {
+        assert!(ALLS.wraps(b"{STAT}", b"STAT"));
+        assert!(ADX.wraps(b"(LAr2)", b"LAr2"));
+        assert!(ALLS.wraps(b"{}", b""));
+        assert!(!ALLS.wraps(b"(STAT)", b"STAT"));
+        assert!(!ALLS.wraps(b"{STAT)", b"STAT"));
+        assert!(!ALLS.wraps(b"(STAT}", b"STAT"));
+        assert!(!ALLS.wraps(b"{STA}", b"STAT"));
+        assert!(!ALLS.wraps(b"{STATS}", b"STAT"));
+        assert!(!ALLS.wraps(b"{HALT}", b"STAT"));
+        assert!(!ALLS.wraps(b"STAT", b"STAT"));
+        assert!(!ALLS.wraps(b"", b""));
+        assert!(!ALLS.wraps(b"{", b""));
+    }
+
+    #[test]
+    fn wrap_into_fills_the_start_of_the_buffer() {
+        let mut out = [0xaa; 8];
+        assert_eq!(ALLS.wrap_into(b"HALT", &mut out), Some(6));
+        assert_eq!(&out, b"{HALT}\xaa\xaa");
+    }
+
+    #[test]
+    fn wrap_into_fits_exactly() {
+        let mut out = [0; 6];
+        assert_eq!(ADX.wrap_into(b"LAr2", &mut out), Some(6));
+        assert_eq!(&out, b"(LAr2)");
+        let mut out = [0; 2];
+        assert_eq!(ADX.wrap_into(b"", &mut out), Some(2));
+        assert_eq!(&out, b"()");
+    }
+
+    #[test]
+    fn wrap_into_leaves_a_short_buffer_alone() {
+        let mut out = [0; 5];
+        assert_eq!(ALLS.wrap_into(b"HALT", &mut out), None);
+        assert_eq!(out, [0; 5]);
+        assert_eq!(ALLS.wrap_into(b"", &mut []), None);
+    }
+
+    #[test]
+    fn wrap_into_agrees_with_wraps() {
+        let mut out = [0; 16];
+        for payload in [&b""[..], b"A", b"RSET", b"\x00\x7f\xff"] {
+            let len = ALLS.wrap_into(payload, &mut out).unwrap();
+            assert!(ALLS.wraps(&out[..len], payload));
+            assert!(!ADX.wraps(&out[..len], payload));
+        }
+    }
+
+    #[test]
+    fn maimai_sizes_fit_its_packets() {
+        use super::maimai::*;
+        assert_eq!(TOUCH_FRAME_LEN, 9);
+        for command in [&b"{HALT}"[..], b"{STAT}", b"{RSET}", b"{LAr2}"] {
+            assert!(command.len() <= COMMAND_MAX_LEN);
+        }
+        for response in [&b"(LAr2)"[..], b"(RAk5)"] {
+            assert_eq!(response.len(), SIZED_RESPONSE_LEN);
+            assert!(SIZED_RESPONSE_PREFIXES
+                .iter()
+                .any(|prefix| response[1..].starts_with(prefix.as_bytes())));
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn wrap_builds_the_packet() {
+        assert_eq!(ALLS.wrap(b"RSET"), b"{RSET}");
+        assert_eq!(ADX.wrap(b""), b"()");
+    }
+}
@@ -0,0 +1,13 @@
+// Byte-level definition of the maimai touch link: packet framing, the
+// command table, byte patterns for matching packets and the touch frame
+// bitmask. Shared with firmware, so it is no_std and only needs alloc for
+// the Vec-returning helpers.
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod command;
+pub mod framing;
+pub mod pattern;
+pub mod touch;
@@ -0,0 +1,121 @@
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+// Byte patterns for recognising packets and templating answers to them.
+// Each position is a literal byte, or None for any byte.
+
+pub fn matches(pattern: &[Option<u8>], packet: &[u8]) -> bool {
+    pattern.len() == packet.len()
+        && pattern
+            .iter()
+            .zip(packet)
+            .all(|(want, got)| want.is_none_or(|want| want == *got))
+}
+
+// How many positions the pattern leaves open
+pub fn open_count(pattern: &[Option<u8>]) -> usize {
+    pattern.iter().filter(|byte| byte.is_none()).count()
+}
+
+// The bytes of a matching packet at the pattern's open positions, in order
+pub fn captures<'a>(pattern: &'a [Option<u8>], packet: &'a [u8]) -> impl Iterator<Item = u8> + 'a {
+    pattern
+        .iter()
+        .zip(packet)
+        .filter(|(want, _)| want.is_none())
+        .map(|(_, &got)| got)
+}
+
+// Fills each open position of `template` with the next byte `pattern`
+// captured from `packet`. None if the packet doesn't match or there are
+// fewer captures than open positions.
+#[cfg(feature = "alloc")]
+pub fn fill(template: &[Option<u8>], pattern: &[Option<u8>], packet: &[u8]) -> Option<Vec<u8>> {
+    if !matches(pattern, packet) {
+        return None;
+    }
+    let mut captured = captures(pattern, packet);
+    template
+        .iter()
+        .map(|byte| byte.or_else(|| captured.next()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANY: Option<u8> = None;
+
+    #[cfg(feature = "alloc")]
+    fn lit(text: &[u8]) -> impl Iterator<Item = Option<u8>> + '_ {
+        text.iter().map(|&byte| Some(byte))
+    }
+
+    #[test]
+    fn matches_literals_and_open_positions() {
+        let pattern: [Option<u8>; 6] = [Some(b'{'), Some(b'L'), ANY, Some(b'r'), ANY, Some(b'}')];
+        assert!(matches(&pattern, b"{LAr2}"));
+        assert!(matches(&pattern, b"{LBrx}"));
+        assert!(!matches(&pattern, b"{RAr2}"));
+        assert!(!matches(&pattern, b"{LAr2"));
+        assert!(!matches(&pattern, b"{LAr2}}"));
+        assert!(matches(&[], b""));
+        assert!(!matches(&[], b"x"));
+    }
+
+    #[test]
+    fn counts_and_captures_open_positions() {
+        let pattern: [Option<u8>; 6] = [Some(b'{'), ANY, ANY, Some(b'r'), ANY, Some(b'}')];
+        assert_eq!(open_count(&pattern), 3);
+        assert!(captures(&pattern, b"{LAr2}").eq(*b"LA2"));
+        assert_eq!(open_count(&[Some(0); 4]), 0);
+        assert_eq!(captures(&[Some(0); 4], b"\0\0\0\0").count(), 0);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn fill_copies_captures_into_the_template() {
+        let pattern: alloc::vec::Vec<_> = lit(b"{L")
+            .chain([ANY])
+            .chain(lit(b"r"))
+            .chain([ANY])
+            .chain(lit(b"}"))
+            .collect();
+        let template: alloc::vec::Vec<_> = lit(b"(L")
+            .chain([ANY])
+            .chain(lit(b"r"))
+            .chain([ANY])
+            .chain(lit(b")"))
+            .collect();
+        assert_eq!(fill(&template, &pattern, b"{LAr2}").unwrap(), b"(LAr2)");
+        assert_eq!(fill(&template, &pattern, b"{LCr9}").unwrap(), b"(LCr9)");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn fill_takes_captures_in_order_and_may_drop_some() {
+        let pattern = [Some(b'<'), ANY, ANY, ANY, Some(b'>')];
+        let template = [ANY, Some(b'-'), ANY];
+        assert_eq!(fill(&template, &pattern, b"<abc>").unwrap(), b"a-b");
+        assert_eq!(
+            fill(
+                &lit(b"ok").collect::<alloc::vec::Vec<_>>(),
+                &pattern,
+                b"<abc>"
+            )
+            .unwrap(),
+            b"ok"
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn fill_refuses_a_mismatch_or_too_few_captures() {
+        let pattern = [Some(b'<'), ANY, Some(b'>')];
+        assert_eq!(fill(&[ANY], &pattern, b"[a]"), None);
+        assert_eq!(fill(&[ANY], &pattern, b"<ab>"), None);
+        assert_eq!(fill(&[ANY, ANY], &pattern, b"<a>"), None);
+        assert_eq!(fill(&[ANY], &[Some(b'x')], b"x"), None);
+    }
+}
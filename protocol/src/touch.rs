@@ -0,0 +1,682 @@
+use core::fmt;
+use core::str::FromStr;
+
+// The maimai touch frame carries 34 regions in 7 payload bytes, 5 bits per
+// byte, ordered A1-A8, B1-B8, C1-C2, D1-D8, E1-E8.
+pub const PAYLOAD_LEN: usize = 7;
+pub const REGION_COUNT: usize = 34;
+const BITS_PER_BYTE: usize = 5;
+const BYTE_MASK: u8 = 0x1f;
+
+const RINGS: &[(char, u8)] = &[('A', 8), ('B', 8), ('C', 2), ('D', 8), ('E', 8)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Region(u8);
+
+impl Region {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn all() -> impl Iterator<Item = Region> {
+        (0..REGION_COUNT as u8).map(Region)
+    }
+
+    pub fn ring(self) -> char {
+        self.ring_and_number().0
+    }
+
+    // 1-based position within the ring
+    pub fn number(self) -> u8 {
+        self.ring_and_number().1
+    }
+
+    pub fn from_ring(ring: char, number: u8) -> Option<Region> {
+        let mut base = 0;
+        for &(letter, size) in RINGS {
+            if letter == ring {
+                return (1..=size)
+                    .contains(&number)
+                    .then(|| Region(base + number - 1));
+            }
+            base += size;
+        }
+        None
+    }
+
+    // Where this region ends up when the screen is turned clockwise by
+    // `octants` eighths of a turn. The C halves only trade places once the
+    // turn is past a quarter either way.
+    pub fn rotated(self, octants: u8) -> Region {
+        let octants = octants % 8;
+        let (ring, number) = self.ring_and_number();
+        let number = match ring {
+            'C' if (3..=5).contains(&octants) => 3 - number,
+            'C' => number,
+            _ => (number - 1 + octants) % 8 + 1,
+        };
+        Region::from_ring(ring, number).unwrap()
+    }
+
+    // Left-right mirror image. A/B regions sit either side of the top, so
+    // A1 pairs with A8; D/E regions sit on the top and bottom, which stay put.
+    pub fn mirrored(self) -> Region {
+        let (ring, number) = self.ring_and_number();
+        let number = match ring {
+            'A' | 'B' => 9 - number,
+            'C' => 3 - number,
+            _ => (9 - number) % 8 + 1,
+        };
+        Region::from_ring(ring, number).unwrap()
+    }
+
+    fn ring_and_number(self) -> (char, u8) {
+        let mut base = 0;
+        for &(letter, size) in RINGS {
+            if self.0 < base + size {
+                return (letter, self.0 - base + 1);
+            }
+            base += size;
+        }
+        unreachable!("region index out of range")
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.ring(), self.number())
+    }
+}
+
+// A region name that isn't one of A1-A8, B1-B8, C1-C2, D1-D8 or E1-E8
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseRegionError;
+
+impl fmt::Display for ParseRegionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown region")
+    }
+}
+
+impl core::error::Error for ParseRegionError {}
+
+impl FromStr for Region {
+    type Err = ParseRegionError;
+
+    fn from_str(s: &str) -> Result<Self, ParseRegionError> {
+        let mut chars = s.chars();
+        let ring = chars
+            .next()
+            .map(|c| c.to_ascii_uppercase())
+            .ok_or(ParseRegionError)?;
+        chars
+            .as_str()
+            .parse()
+            .ok()
+            .and_then(|number| Region::from_ring(ring, number))
+            .ok_or(ParseRegionError)
+    }
+}
+
+// Decoded active regions of one touch frame. The spare 35th bit is kept
+// as-is so decode/encode round-trips exactly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TouchState(u64);
+
+impl TouchState {
+    // Decodes a payload in the stock layout
+    pub fn decode(payload: &[u8]) -> TouchState {
+        Packing::default().decode(payload)
+    }
+
+    pub fn encode_into(self, payload: &mut [u8]) {
+        Packing::default().encode_into(self, payload)
+    }
+
+    pub fn is_active(self, region: Region) -> bool {
+        self.0 & (1 << region.index()) != 0
+    }
+
+    pub fn set(&mut self, region: Region, active: bool) {
+        if active {
+            self.0 |= 1 << region.index();
+        } else {
+            self.0 &= !(1 << region.index());
+        }
+    }
+}
+
+// Order of the 5 region bits within each payload byte. Stock boards put
+// the lowest-numbered region of the byte in the lowest bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BitOrder {
+    #[default]
+    Lsb,
+    Msb,
+}
+
+impl BitOrder {
+    pub const NAMES: &'static [&'static str] = &["lsb", "msb"];
+}
+
+impl fmt::Display for BitOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+// Order of the payload bytes. Stock boards send the byte holding A1 first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    Normal,
+    Reversed,
+}
+
+impl ByteOrder {
+    pub const NAMES: &'static [&'static str] = &["normal", "reversed"];
+}
+
+impl fmt::Display for ByteOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOrderError;
+
+impl fmt::Display for ParseOrderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown order")
+    }
+}
+
+impl core::error::Error for ParseOrderError {}
+
+impl FromStr for BitOrder {
+    type Err = ParseOrderError;
+
+    fn from_str(s: &str) -> Result<Self, ParseOrderError> {
+        match s {
+            "lsb" => Ok(BitOrder::Lsb),
+            "msb" => Ok(BitOrder::Msb),
+            _ => Err(ParseOrderError),
+        }
+    }
+}
+
+impl FromStr for ByteOrder {
+    type Err = ParseOrderError;
+
+    fn from_str(s: &str) -> Result<Self, ParseOrderError> {
+        match s {
+            "normal" => Ok(ByteOrder::Normal),
+            "reversed" => Ok(ByteOrder::Reversed),
+            _ => Err(ParseOrderError),
+        }
+    }
+}
+
+// How a board lays the region bits out in the payload. Clone boards may
+// reverse the bits within each byte, the bytes within the frame, or both;
+// the default is the stock layout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Packing {
+    pub bit_order: BitOrder,
+    pub byte_order: ByteOrder,
+}
+
+impl Packing {
+    pub fn is_stock(self) -> bool {
+        self == Packing::default()
+    }
+
+    pub fn decode(self, payload: &[u8]) -> TouchState {
+        let mut mask = 0u64;
+        for group in 0..PAYLOAD_LEN {
+            if let Some(&byte) = payload.get(self.byte_index(group)) {
+                mask |= (self.region_bits(byte & BYTE_MASK) as u64) << (group * BITS_PER_BYTE);
+            }
+        }
+        TouchState(mask)
+    }
+
+    // Writes the region bits back, leaving any bits above the 5 region bits untouched
+    pub fn encode_into(self, state: TouchState, payload: &mut [u8]) {
+        for group in 0..PAYLOAD_LEN {
+            if let Some(byte) = payload.get_mut(self.byte_index(group)) {
+                let bits = self.region_bits((state.0 >> (group * BITS_PER_BYTE)) as u8 & BYTE_MASK);
+                *byte = (*byte & !BYTE_MASK) | bits;
+            }
+        }
+    }
+
+    // Payload byte holding the `group`th 5 regions
+    fn byte_index(self, group: usize) -> usize {
+        match self.byte_order {
+            ByteOrder::Normal => group,
+            ByteOrder::Reversed => PAYLOAD_LEN - 1 - group,
+        }
+    }
+
+    // Converts between stock and board bit order; either way round is the
+    // same swap
+    fn region_bits(self, bits: u8) -> u8 {
+        match self.bit_order {
+            BitOrder::Lsb => bits,
+            BitOrder::Msb => bits.reverse_bits() >> (u8::BITS as usize - BITS_PER_BYTE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKINGS: [Packing; 4] = [
+        Packing {
+            bit_order: BitOrder::Lsb,
+            byte_order: ByteOrder::Normal,
+        },
+        Packing {
+            bit_order: BitOrder::Msb,
+            byte_order: ByteOrder::Normal,
+        },
+        Packing {
+            bit_order: BitOrder::Lsb,
+            byte_order: ByteOrder::Reversed,
+        },
+        Packing {
+            bit_order: BitOrder::Msb,
+            byte_order: ByteOrder::Reversed,
+        },
+    ];
+
+    fn region(name: &str) -> Region {
+        name.parse().unwrap()
+    }
+
+    fn state(regions: &[&str]) -> TouchState {
+        let mut state = TouchState::default();
+        for name in regions {
+            state.set(region(name), true);
+        }
+        state
+    }
+
+    #[test]
+    fn regions_are_named_in_ring_order() {
+        let mut names = Region::all().map(|region| (region.ring(), region.number()));
+        for &(ring, size) in RINGS {
+            for number in 1..=size {
+                assert_eq!(names.next(), Some((ring, number)));
+            }
+        }
+        assert_eq!(names.next(), None);
+        assert_eq!(Region::all().count(), REGION_COUNT);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn region_names_round_trip() {
+        for region in Region::all() {
+            let name = alloc::format!("{}", region);
+            assert_eq!(name.parse::<Region>(), Ok(region));
+            assert_eq!(name.to_ascii_lowercase().parse::<Region>(), Ok(region));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_region_names() {
+        for name in ["", "A", "A0", "A9", "C3", "F1", "1A", "A1x", "AA", "E-1"] {
+            assert_eq!(name.parse::<Region>(), Err(ParseRegionError), "{}", name);
+        }
+        assert_eq!(Region::from_ring('B', 0), None);
+        assert_eq!(Region::from_ring('c', 1), None);
+    }
+
+    #[test]
+    fn rotation_turns_the_outer_rings_clockwise() {
+        assert_eq!(region("A1").rotated(1), region("A2"));
+        assert_eq!(region("A8").rotated(1), region("A1"));
+        assert_eq!(region("B3").rotated(6), region("B1"));
+        assert_eq!(region("D1").rotated(2), region("D3"));
+        assert_eq!(region("E7").rotated(3), region("E2"));
+    }
+
+    #[test]
+    fn rotation_swaps_the_c_halves_past_a_quarter_turn() {
+        for octants in 0..8 {
+            let swapped = (3..=5).contains(&octants);
+            let (c1, c2) = (region("C1").rotated(octants), region("C2").rotated(octants));
+            assert_eq!(c1 == region("C2"), swapped, "{}", octants);
+            assert_eq!(c2 == region("C1"), swapped, "{}", octants);
+        }
+    }
+
+    #[test]
+    fn rotation_is_a_permutation_within_each_ring() {
+        for octants in 0..16 {
+            let mut seen = [false; REGION_COUNT];
+            for region in Region::all() {
+                let rotated = region.rotated(octants);
+                assert_eq!(rotated.ring(), region.ring());
+                assert!(!seen[rotated.index()]);
+                seen[rotated.index()] = true;
+            }
+            for region in Region::all() {
+                assert_eq!(region.rotated(octants), region.rotated(octants % 8));
+            }
+        }
+        for region in Region::all() {
+            assert_eq!(region.rotated(0), region);
+            assert_eq!(region.rotated(8), region);
+        }
+    }
+
+    #[test]
+    fn rotations_compose_on_the_outer_rings() {
+        for region in Region::all().filter(|region| region.ring() != 'C') {
+            for a in 0..8 {
+                for b in 0..8 {
+                    assert_eq!(region.rotated(a).rotated(b), region.rotated(a + b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mirroring_swaps_left_and_right() {
+        for (a, b) in [
+            ("A1", "A8"),
+            ("A4", "A5"),
+            ("B2", "B7"),
+            ("C1", "C2"),
+            ("D2", "D8"),
+            ("D4", "D6"),
+            ("E3", "E7"),
+        ] {
+            assert_eq!(region(a).mirrored(), region(b));
+            assert_eq!(region(b).mirrored(), region(a));
+        }
+        for name in ["D1", "D5", "E1", "E5"] {
+            assert_eq!(region(name).mirrored(), region(name));
+        }
+    }
+
+    #[test]
+    fn mirroring_is_its_own_inverse() {
+        for region in Region::all() {
+            assert_eq!(region.mirrored().ring(), region.ring());
+            assert_eq!(region.mirrored().mirrored(), region);
+        }
+    }
+
+    #[test]
+    fn mirroring_reverses_rotation() {
+        for region in Region::all().filter(|region| region.ring() != 'C') {
+            for octants in 0..8 {
+                assert_eq!(
+                    region.rotated(octants).mirrored(),
+                    region.mirrored().rotated(8 - octants)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_the_stock_layout() {
+        assert_eq!(
+            TouchState::decode(&[0x01, 0, 0, 0, 0, 0, 0]),
+            state(&["A1"])
+        );
+        assert_eq!(
+            TouchState::decode(&[0x10, 0, 0, 0, 0, 0, 0]),
+            state(&["A5"])
+        );
+        assert_eq!(
+            TouchState::decode(&[0, 0x01, 0, 0, 0, 0, 0]),
+            state(&["A6"])
+        );
+        assert_eq!(
+            TouchState::decode(&[0, 0, 0, 0, 0, 0, 0x08]),
+            state(&["E8"])
+        );
+        assert_eq!(
+            TouchState::decode(&[0x1f; PAYLOAD_LEN]),
+            TouchState((1 << (PAYLOAD_LEN * BITS_PER_BYTE)) - 1)
+        );
+    }
+
+    #[test]
+    fn decodes_each_board_layout() {
+        let a1 = state(&["A1"]);
+        let e8 = state(&["E8"]);
+        for (packing, a1_payload, e8_payload) in [
+            (
+                PACKINGS[0],
+                [0x01, 0, 0, 0, 0, 0, 0],
+                [0, 0, 0, 0, 0, 0, 0x08],
+            ),
+            (
+                PACKINGS[1],
+                [0x10, 0, 0, 0, 0, 0, 0],
+                [0, 0, 0, 0, 0, 0, 0x02],
+            ),
+            (
+                PACKINGS[2],
+                [0, 0, 0, 0, 0, 0, 0x01],
+                [0x08, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                PACKINGS[3],
+                [0, 0, 0, 0, 0, 0, 0x10],
+                [0x02, 0, 0, 0, 0, 0, 0],
+            ),
+        ] {
+            assert_eq!(packing.decode(&a1_payload), a1, "{:?}", packing);
+            assert_eq!(packing.decode(&e8_payload), e8, "{:?}", packing);
+            let mut payload = [0; PAYLOAD_LEN];
+            packing.encode_into(a1, &mut payload);
+            assert_eq!(payload, a1_payload, "{:?}", packing);
+        }
+    }
+
+    #[test]
+    fn decode_ignores_bits_above_the_regions() {
+        for packing in PACKINGS {
+            assert_eq!(packing.decode(&[0xe0; PAYLOAD_LEN]), TouchState::default());
+        }
+    }
+
+    #[test]
+    fn decodes_a_short_payload_as_far_as_it_goes() {
+        assert_eq!(TouchState::decode(&[0x01]), state(&["A1"]));
+        assert_eq!(TouchState::decode(&[]), TouchState::default());
+        assert_eq!(PACKINGS[2].decode(&[0x01]), TouchState(1 << 30));
+    }
+
+    #[test]
+    fn encode_keeps_the_bits_above_the_regions() {
+        for packing in PACKINGS {
+            let mut payload = [0xe0; PAYLOAD_LEN];
+            packing.encode_into(state(&["A1", "C2", "E8"]), &mut payload);
+            assert!(payload.iter().all(|byte| byte & 0xe0 == 0xe0));
+            packing.encode_into(TouchState::default(), &mut payload);
+            assert_eq!(payload, [0xe0; PAYLOAD_LEN]);
+        }
+    }
+
+    #[test]
+    fn encode_leaves_a_short_payload_short() {
+        let mut payload = [0; 2];
+        TouchState(u64::MAX).encode_into(&mut payload);
+        assert_eq!(payload, [0x1f, 0x1f]);
+    }
+
+    #[test]
+    fn every_region_round_trips_alone_in_every_layout() {
+        for packing in PACKINGS {
+            for region in Region::all() {
+                let mut touched = TouchState::default();
+                touched.set(region, true);
+                let mut payload = [0; PAYLOAD_LEN];
+                packing.encode_into(touched, &mut payload);
+                assert_eq!(payload.iter().map(|byte| byte.count_ones()).sum::<u32>(), 1);
+                let decoded = packing.decode(&payload);
+                assert_eq!(decoded, touched, "{} {:?}", region, packing);
+                assert!(Region::all().all(|other| decoded.is_active(other) == (other == region)));
+            }
+        }
+    }
+
+    #[test]
+    fn every_byte_round_trips_in_every_layout() {
+        for packing in PACKINGS {
+            for group in 0..PAYLOAD_LEN {
+                for bits in 0..=BYTE_MASK {
+                    let mut payload = [0; PAYLOAD_LEN];
+                    payload[group] = bits;
+                    let mut encoded = [0; PAYLOAD_LEN];
+                    packing.encode_into(packing.decode(&payload), &mut encoded);
+                    assert_eq!(encoded, payload, "{:?}", packing);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_spare_bit() {
+        let spare = TouchState(1 << REGION_COUNT);
+        assert!(Region::all().all(|region| !spare.is_active(region)));
+        for packing in PACKINGS {
+            let mut payload = [0; PAYLOAD_LEN];
+            packing.encode_into(spare, &mut payload);
+            assert_ne!(payload, [0; PAYLOAD_LEN]);
+            assert_eq!(packing.decode(&payload), spare);
+        }
+    }
+
+    #[test]
+    fn set_and_clear_regions() {
+        let mut touched = state(&["B4", "D7"]);
+        assert!(touched.is_active(region("B4")));
+        assert!(!touched.is_active(region("B5")));
+        touched.set(region("B4"), false);
+        touched.set(region("B4"), false);
+        assert_eq!(touched, state(&["D7"]));
+    }
+
+    #[test]
+    fn the_stock_packing_is_the_default() {
+        assert!(PACKINGS[0].is_stock());
+        assert!(PACKINGS[1..].iter().all(|packing| !packing.is_stock()));
+    }
+
+    #[test]
+    fn order_names_round_trip() {
+        for name in BitOrder::NAMES {
+            let order: BitOrder = name.parse().unwrap();
+            assert_eq!(BitOrder::NAMES[order as usize], *name);
+        }
+        for name in ByteOrder::NAMES {
+            let order: ByteOrder = name.parse().unwrap();
+            assert_eq!(ByteOrder::NAMES[order as usize], *name);
+        }
+        assert_eq!("LSB".parse::<BitOrder>(), Err(ParseOrderError));
+        assert_eq!("".parse::<ByteOrder>(), Err(ParseOrderError));
+    }
+
+    // xorshift64, so the randomized round trips below are the same on
+    // every run and need no dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill(&mut self, bytes: &mut [u8]) {
+            for byte in bytes {
+                *byte = self.next() as u8;
+            }
+        }
+    }
+
+    const CASES: usize = 20_000;
+    // Every region plus the spare bit
+    const STATE_MASK: u64 = (1 << (PAYLOAD_LEN * BITS_PER_BYTE)) - 1;
+
+    #[test]
+    fn random_states_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for packing in PACKINGS {
+            for _ in 0..CASES {
+                let state = TouchState(rng.next() & STATE_MASK);
+                let mut payload = [0; PAYLOAD_LEN];
+                rng.fill(&mut payload);
+                let high: [u8; PAYLOAD_LEN] = payload.map(|byte| byte & !BYTE_MASK);
+                packing.encode_into(state, &mut payload);
+                assert_eq!(packing.decode(&payload), state, "{:?}", packing);
+                assert_eq!(payload.map(|byte| byte & !BYTE_MASK), high);
+            }
+        }
+    }
+
+    #[test]
+    fn random_payloads_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for packing in PACKINGS {
+            for _ in 0..CASES {
+                let mut payload = [0; PAYLOAD_LEN];
+                rng.fill(&mut payload);
+                let mut encoded = payload;
+                packing.encode_into(packing.decode(&payload), &mut encoded);
+                assert_eq!(encoded, payload, "{:?}", packing);
+                // Into a cleared frame only the region bits come back
+                let mut cleared = [0; PAYLOAD_LEN];
+                packing.encode_into(packing.decode(&payload), &mut cleared);
+                assert_eq!(cleared, payload.map(|byte| byte & BYTE_MASK));
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_agree_on_regions() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for _ in 0..CASES {
+            let state = TouchState(rng.next() & STATE_MASK);
+            for from in PACKINGS {
+                let mut payload = [0; PAYLOAD_LEN];
+                from.encode_into(state, &mut payload);
+                for to in PACKINGS {
+                    let mut converted = [0; PAYLOAD_LEN];
+                    to.encode_into(from.decode(&payload), &mut converted);
+                    assert_eq!(to.decode(&converted), state);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_input_never_panics() {
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+        let mut bytes = [0; 2 * PAYLOAD_LEN];
+        for packing in PACKINGS {
+            for _ in 0..CASES {
+                let len = rng.next() as usize % (bytes.len() + 1);
+                rng.fill(&mut bytes[..len]);
+                let state = packing.decode(&bytes[..len]);
+                assert_eq!(state.0 & !STATE_MASK, 0);
+                packing.encode_into(TouchState(rng.next()), &mut bytes[..len]);
+                for region in Region::all() {
+                    let _ = state.is_active(region);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The same event fires the hook at most this often, so a flapping board
+// doesn't fork a process per frame
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+// Events an operator may want to hear about, e.g. to light the service lamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Alert {
+    BoardDisconnected,
+    Stalled,
+    StallRecovered,
+    HandshakeFailed,
+    StreamCapped,
+    ShuttingDown,
+}
+
+impl Alert {
+    pub fn name(self) -> &'static str {
+        match self {
+            Alert::BoardDisconnected => "board-disconnected",
+            Alert::Stalled => "stalled",
+            Alert::StallRecovered => "stall-recovered",
+            Alert::HandshakeFailed => "handshake-failed",
+            Alert::StreamCapped => "stream-capped",
+            Alert::ShuttingDown => "shutting-down",
+        }
+    }
+}
+
+// Runs the --on-event command through the shell for each alert, with the
+// event in MAITOUCH_EVENT, a description in MAITOUCH_DETAIL and the
+// --player label in MAITOUCH_PLAYER. The command is started on the calling
+// thread but never waited on there, and failures are only logged.
+pub struct AlertHook {
+    command: Option<String>,
+    player: String,
+    last_fired: Mutex<HashMap<Alert, Instant>>,
+}
+
+impl AlertHook {
+    pub fn new(command: Option<String>, player: Option<String>) -> Self {
+        AlertHook {
+            command,
+            player: player.unwrap_or_default(),
+            last_fired: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn fire(&self, alert: Alert, detail: &str) {
+        let Some(command) = &self.command else {
+            return;
+        };
+        let now = Instant::now();
+        {
+            let mut last_fired = self.last_fired.lock().unwrap();
+            if let Some(last) = last_fired.get(&alert) {
+                if now - *last < MIN_INTERVAL {
+                    tracing::debug!("Not running event hook for {} again so soon", alert.name());
+                    return;
+                }
+            }
+            last_fired.insert(alert, now);
+        }
+        let child = shell(command)
+            .env("MAITOUCH_EVENT", alert.name())
+            .env("MAITOUCH_DETAIL", detail)
+            .env("MAITOUCH_PLAYER", &self.player)
+            .stdin(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                tracing::warn!("Couldn't run event hook for {}: {}", alert.name(), err);
+                return;
+            }
+        };
+        // Reaps the child and reports how it went
+        let spawned = thread::Builder::new()
+            .name("event hook".into())
+            .spawn(move || match child.wait() {
+                Ok(status) if !status.success() => {
+                    tracing::warn!("Event hook for {} exited with {}", alert.name(), status)
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("Event hook for {} failed: {}", alert.name(), err),
+            });
+        if let Err(err) = spawned {
+            tracing::warn!("Couldn't wait for event hook: {}", err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("maitouch-alert-{}-{}", name, std::process::id()))
+    }
+
+    // A hook script that appends a line per event to `log`
+    fn hook_script(name: &str, log: &Path) -> PathBuf {
+        let script = temp(&format!("{}.sh", name));
+        fs::write(
+            &script,
+            format!(
+                "echo \"$MAITOUCH_EVENT|$MAITOUCH_PLAYER|$MAITOUCH_DETAIL\" >> {}\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        script
+    }
+
+    // The lines in `log` once it has `count`, or a while has gone by
+    fn lines(log: &Path, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let text = fs::read_to_string(log).unwrap_or_default();
+            let lines: Vec<String> = text.lines().map(String::from).collect();
+            if lines.len() >= count || Instant::now() > deadline {
+                return lines;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn the_hook_hears_each_event_with_its_detail_and_player() {
+        let log = temp("events.log");
+        let script = hook_script("events", &log);
+        let hook = AlertHook::new(Some(format!("sh {}", script.display())), Some("P1".into()));
+        hook.fire(Alert::Stalled, "no frames for 2s");
+        // Too soon after the last one to fire again
+        hook.fire(Alert::Stalled, "no frames for 4s");
+        hook.fire(Alert::StallRecovered, "");
+        let mut lines = lines(&log, 2);
+        // The two hooks run side by side, so either can finish first
+        lines.sort();
+        thread::sleep(Duration::from_millis(100));
+        let late = fs::read_to_string(&log).unwrap().lines().count();
+        fs::remove_file(&log).unwrap();
+        fs::remove_file(&script).unwrap();
+        assert_eq!(
+            lines,
+            ["stall-recovered|P1|", "stalled|P1|no frames for 2s"]
+        );
+        assert_eq!(late, 2);
+    }
+
+    #[test]
+    fn without_a_command_nothing_runs() {
+        let hook = AlertHook::new(None, None);
+        hook.fire(Alert::ShuttingDown, "");
+        assert!(hook.last_fired.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+use crate::report::SessionReport;
+use serialport::SerialPort;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How often the probes are asked while streaming
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often a missing reader is warned about again while it stays missing
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+// Streaming this long without the game ever having sent anything means
+// there was most likely nothing there to send it. A resumed game is quiet
+// while streaming too, so this is well past a short pause.
+const NEVER_HEARD_AFTER: Duration = Duration::from_secs(60);
+
+// One way of telling whether anything has the other end of the ALLS port
+// open. A com0com pair in its default setup takes writes with nobody on
+// the other end and throws them away, so the writes themselves never say.
+// None is "can't tell", and any probe that sees a reader outvotes the
+// ones that don't.
+pub trait ReaderProbe: Send {
+    // Why the reader is taken for missing, for the warning
+    fn reason(&self) -> &'static str;
+    fn attached(&mut self) -> Option<bool>;
+}
+
+// com0com wires each end's DTR to the other end's DSR, and the game raises
+// DTR when it opens its end. A line that stays low while the game talks
+// isn't wired that way, so then the probe stops answering.
+#[cfg(windows)]
+pub struct DsrProbe {
+    port: Box<dyn SerialPort>,
+    report: Arc<SessionReport>,
+    wired: bool,
+}
+
+#[cfg(windows)]
+impl DsrProbe {
+    pub fn new(port: Box<dyn SerialPort>, report: Arc<SessionReport>) -> Self {
+        DsrProbe {
+            port,
+            report,
+            wired: false,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl ReaderProbe for DsrProbe {
+    fn reason(&self) -> &'static str {
+        "its DSR is low"
+    }
+
+    fn attached(&mut self) -> Option<bool> {
+        let dsr = self.port.read_data_set_ready().ok()?;
+        self.wired |= dsr;
+        if !dsr && !self.wired && self.report.game_ever_heard() {
+            return None;
+        }
+        Some(dsr)
+    }
+}
+
+// The game never having sent a single packet while the proxy streams to
+// it, as after resuming into a game that isn't running
+pub struct NeverHeardProbe {
+    report: Arc<SessionReport>,
+    since: Instant,
+}
+
+impl NeverHeardProbe {
+    pub fn new(report: Arc<SessionReport>) -> Self {
+        NeverHeardProbe {
+            report,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl ReaderProbe for NeverHeardProbe {
+    fn reason(&self) -> &'static str {
+        "the game hasn't sent anything since the proxy started"
+    }
+
+    fn attached(&mut self) -> Option<bool> {
+        if self.report.game_ever_heard() || self.since.elapsed() < NEVER_HEARD_AFTER {
+            return None;
+        }
+        Some(false)
+    }
+}
+
+// Asks the probes every CHECK_INTERVAL while streaming, warns at most once
+// a minute while the ALLS port seems to have no reader, and keeps the
+// report's no_reader state up to date
+pub struct ReaderWatch {
+    port: String,
+    probes: Vec<Box<dyn ReaderProbe>>,
+    report: Arc<SessionReport>,
+    last_check: Option<Instant>,
+    last_warned: Option<Instant>,
+}
+
+impl ReaderWatch {
+    pub fn new(port: &str, probes: Vec<Box<dyn ReaderProbe>>, report: Arc<SessionReport>) -> Self {
+        ReaderWatch {
+            port: port.to_string(),
+            probes,
+            report,
+            last_check: None,
+            last_warned: None,
+        }
+    }
+
+    // The probes this platform has. `modem` is a handle to the ALLS port's
+    // line status, when it's a serial port.
+    pub fn for_port(
+        port: &str,
+        modem: Option<Box<dyn SerialPort>>,
+        report: &Arc<SessionReport>,
+    ) -> Self {
+        #[allow(unused_mut)]
+        let mut probes: Vec<Box<dyn ReaderProbe>> =
+            vec![Box::new(NeverHeardProbe::new(report.clone()))];
+        #[cfg(windows)]
+        if let Some(modem) = modem {
+            probes.push(Box::new(DsrProbe::new(modem, report.clone())));
+        }
+        #[cfg(not(windows))]
+        let _ = modem;
+        Self::new(port, probes, report.clone())
+    }
+
+    // `now` is when the probes are asked, for the warning cadence and the
+    // time without a reader
+    pub fn check(&mut self, now: Instant) {
+        let mut missing = None;
+        let mut attached = false;
+        for probe in &mut self.probes {
+            match probe.attached() {
+                Some(true) => attached = true,
+                Some(false) => missing = missing.or(Some(probe.reason())),
+                None => {}
+            }
+        }
+        let missing = missing.filter(|_| !attached);
+        let was_missing = self
+            .report
+            .no_reader
+            .swap(missing.is_some(), Ordering::Relaxed);
+        let Some(reason) = missing else {
+            if was_missing {
+                tracing::info!("ALLS port {} has a reader again", self.port);
+            }
+            self.last_warned = None;
+            self.last_check = Some(now);
+            return;
+        };
+        if let Some(last_check) = self.last_check.filter(|_| was_missing) {
+            self.report
+                .no_reader_us
+                .fetch_add((now - last_check).as_micros() as u64, Ordering::Relaxed);
+        }
+        self.last_check = Some(now);
+        if self
+            .last_warned
+            .is_some_and(|warned| now - warned < WARN_INTERVAL)
+        {
+            return;
+        }
+        self.last_warned = Some(now);
+        tracing::warn!(
+            "!!! No reader attached to {} ({}), the frames sent to it go nowhere",
+            self.port,
+            reason
+        );
+    }
+
+    // A new stream starts counting from scratch
+    pub fn reset(&mut self) {
+        self.last_check = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logcapture::capturing;
+    use std::sync::Mutex;
+
+    // Answers whatever the test last set it to
+    #[derive(Clone)]
+    struct FakeProbe {
+        reason: &'static str,
+        answer: Arc<Mutex<Option<bool>>>,
+    }
+
+    impl FakeProbe {
+        fn new(reason: &'static str, answer: Option<bool>) -> Self {
+            FakeProbe {
+                reason,
+                answer: Arc::new(Mutex::new(answer)),
+            }
+        }
+
+        fn answer(&self, answer: Option<bool>) {
+            *self.answer.lock().unwrap() = answer;
+        }
+    }
+
+    impl ReaderProbe for FakeProbe {
+        fn reason(&self) -> &'static str {
+            self.reason
+        }
+
+        fn attached(&mut self) -> Option<bool> {
+            *self.answer.lock().unwrap()
+        }
+    }
+
+    fn watch(probes: &[&FakeProbe]) -> (ReaderWatch, Arc<SessionReport>) {
+        let report = Arc::new(SessionReport::new());
+        let probes = probes
+            .iter()
+            .map(|&probe| Box::new(probe.clone()) as Box<dyn ReaderProbe>)
+            .collect();
+        (ReaderWatch::new("COM4", probes, report.clone()), report)
+    }
+
+    fn no_reader(report: &SessionReport) -> (bool, Duration) {
+        (
+            report.no_reader.load(Ordering::Relaxed),
+            Duration::from_micros(report.no_reader_us.load(Ordering::Relaxed)),
+        )
+    }
+
+    #[test]
+    fn an_attached_probe_outvotes_a_missing_one() {
+        capturing(|log| {
+            let missing = FakeProbe::new("nobody", Some(false));
+            let attached = FakeProbe::new("unused", Some(true));
+            let unsure = FakeProbe::new("unused", None);
+            let (mut watch, report) = watch(&[&missing, &unsure, &attached]);
+            let start = Instant::now();
+            watch.check(start);
+            watch.check(start + CHECK_INTERVAL);
+            assert_eq!(no_reader(&report), (false, Duration::ZERO));
+            assert!(log.take().is_empty());
+        });
+    }
+
+    #[test]
+    fn no_answer_isnt_taken_for_missing() {
+        let unsure = FakeProbe::new("unused", None);
+        let (mut watch, report) = watch(&[&unsure]);
+        watch.check(Instant::now());
+        assert_eq!(no_reader(&report), (false, Duration::ZERO));
+    }
+
+    #[test]
+    fn a_missing_reader_is_warned_about_once_a_minute() {
+        capturing(|log| {
+            let missing = FakeProbe::new("nobody there", Some(false));
+            let (mut watch, report) = watch(&[&missing]);
+            let start = Instant::now();
+            let mut warnings = 0;
+            for second in 0..=150 {
+                watch.check(start + CHECK_INTERVAL * second);
+                for line in log.take() {
+                    assert_eq!(
+                        line,
+                        "!!! No reader attached to COM4 (nobody there), the frames sent to it go \
+                         nowhere"
+                    );
+                    // At the first check and every WARN_INTERVAL after
+                    assert_eq!(second % 60, 0, "warned at {}s", second);
+                    warnings += 1;
+                }
+            }
+            assert_eq!(warnings, 3);
+            // Missing from the first check to the last
+            assert_eq!(no_reader(&report), (true, Duration::from_secs(150)));
+        });
+    }
+
+    #[test]
+    fn a_reader_coming_back_is_logged_and_rearms_the_warning() {
+        capturing(|log| {
+            let probe = FakeProbe::new("nobody there", Some(false));
+            let (mut watch, report) = watch(&[&probe]);
+            let start = Instant::now();
+            watch.check(start);
+            watch.check(start + Duration::from_secs(5));
+            assert_eq!(log.take().len(), 1);
+
+            probe.answer(Some(true));
+            watch.check(start + Duration::from_secs(6));
+            assert_eq!(log.take(), ["ALLS port COM4 has a reader again"]);
+            assert_eq!(no_reader(&report), (false, Duration::from_secs(5)));
+            // Only the transition is logged
+            watch.check(start + Duration::from_secs(7));
+            assert!(log.take().is_empty());
+
+            // Gone again well within the minute, and warned about again
+            probe.answer(Some(false));
+            watch.check(start + Duration::from_secs(8));
+            assert_eq!(log.take().len(), 1);
+            watch.check(start + Duration::from_secs(10));
+            assert_eq!(no_reader(&report), (true, Duration::from_secs(7)));
+        });
+    }
+
+    #[test]
+    fn a_new_stream_doesnt_count_the_time_between() {
+        let missing = FakeProbe::new("nobody there", Some(false));
+        let (mut watch, report) = watch(&[&missing]);
+        let start = Instant::now();
+        watch.check(start);
+        watch.check(start + Duration::from_secs(2));
+        watch.reset();
+        watch.check(start + Duration::from_secs(100));
+        watch.check(start + Duration::from_secs(101));
+        assert_eq!(no_reader(&report), (true, Duration::from_secs(3)));
+    }
+}
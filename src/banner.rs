@@ -0,0 +1,134 @@
+use crate::handshake;
+use crate::io;
+use crate::retry::Retry;
+use anyhow::{bail, Context, Result};
+use maitouch_protocol::framing::PacketDelimiter;
+use maitouch_protocol::pattern;
+use std::io::{BufRead, ErrorKind};
+
+// --adx-banner: what the ADX prints when it powers on, written like a
+// handshake response (\xNN escapes, ? for any byte). Mid-stream the banner
+// comes out as stray bytes, malformed packets, or a packet left hanging as
+// the board goes quiet, so only those are searched; well-formed frames
+// never are. The tail of what was searched is kept, for a banner split
+// across reads.
+pub struct BannerWatch {
+    pattern: Vec<Option<u8>>,
+    recent: Vec<u8>,
+}
+
+impl BannerWatch {
+    pub fn parse(text: &str) -> Result<Self> {
+        let pattern = handshake::parse_pattern(text, true)
+            .with_context(|| format!("invalid ADX banner {}", text))?;
+        if pattern.is_empty() {
+            bail!("the ADX banner can't be empty");
+        }
+        Ok(BannerWatch {
+            pattern,
+            recent: Vec::new(),
+        })
+    }
+
+    // Whether the banner has turned up, once `bytes` are added to what was
+    // searched before
+    fn check(&mut self, bytes: &[u8]) -> bool {
+        self.recent.extend_from_slice(bytes);
+        let len = self.pattern.len();
+        if self
+            .recent
+            .windows(len)
+            .any(|window| pattern::matches(&self.pattern, window))
+        {
+            self.recent.clear();
+            return true;
+        }
+        let keep = self.recent.len().min(len - 1);
+        self.recent.drain(..self.recent.len() - keep);
+        false
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+
+    // As read_packet, searching what doesn't make a `frame_len` packet as it
+    // goes, including what is held on each timeout. None once the banner
+    // turned up, with the rest of the packet abandoned.
+    pub fn read_packet(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        reader: &mut dyn BufRead,
+        packet: &PacketDelimiter,
+        frame_len: usize,
+        retry: &Retry,
+    ) -> std::io::Result<Option<usize>> {
+        let timed_out =
+            |err: &std::io::Error| err.kind() == ErrorKind::TimedOut && retry.timed_out();
+        buffer.clear();
+        buffer.push(packet.open as u8);
+        // Only allocates once there are stray bytes
+        let mut stray = Vec::new();
+        // How much of each the banner was already looked for in
+        let (mut stray_seen, mut packet_seen) = (0, 0);
+        loop {
+            match io::skip_until(reader, packet.open as u8, Some(&mut stray)) {
+                Ok(_) => break,
+                Err(err) if timed_out(&err) => {
+                    if self.check(&stray[stray_seen..]) {
+                        return Ok(None);
+                    }
+                    stray_seen = stray.len();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        while let Err(err) = reader.read_until(packet.close as u8, buffer) {
+            if !timed_out(&err) {
+                return Err(err);
+            }
+            if self.check(&stray[stray_seen..]) || self.check(&buffer[packet_seen..]) {
+                return Ok(None);
+            }
+            stray_seen = stray.len();
+            packet_seen = buffer.len();
+        }
+        retry.succeeded();
+        let malformed = buffer.len() != frame_len;
+        if (stray.len() > stray_seen && self.check(&stray[stray_seen..]))
+            || (malformed && self.check(&buffer[packet_seen..]))
+        {
+            return Ok(None);
+        }
+        Ok(Some(stray.len()))
+    }
+}
+
+// The config commands the ADX was sent since the last RSET, to give a board
+// that power-cycled its settings back. A command replaces an earlier one
+// that only differs in its last byte, the value of a sensitivity command
+// like {LAr2}.
+#[derive(Default)]
+pub struct ConfigReplay {
+    commands: Vec<Vec<u8>>,
+}
+
+impl ConfigReplay {
+    pub fn record(&mut self, command: &[u8]) {
+        let setting = command.len().saturating_sub(2);
+        match self.commands.iter_mut().find(|sent| {
+            setting > 0 && sent.len() == command.len() && sent[..setting] == command[..setting]
+        }) {
+            Some(sent) => sent.copy_from_slice(command),
+            None => self.commands.push(command.to_vec()),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+
+    pub fn commands(&self) -> &[Vec<u8>] {
+        &self.commands
+    }
+}
@@ -0,0 +1,387 @@
+use crate::clock::MonotonicClock;
+use crate::handshake;
+use crate::ports::DEFAULT_BAUD;
+use crate::read_packet;
+use crate::retry::{Retry, RetryPolicy};
+use crate::wire::WireSpec;
+use anyhow::{Context, Result};
+use serialport::{ClearBuffer, SerialPort};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Consecutive well-formed frames needed before the new rate counts as working
+const VERIFY_FRAMES: usize = 16;
+// A board that ignored the switch, or garbage that never settles, fails within this
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+// Time for the board to answer the switch before its answer is thrown away
+const SWITCH_SETTLE: Duration = Duration::from_millis(50);
+
+// Vendor command that moves the ADX to another baud rate. The template is
+// written with handshake escapes and `{rate}` standing in for the rate, so
+// the same command can bring the board back down to 9600.
+pub struct BaudSwitch {
+    rate: u32,
+    upgrade: Vec<u8>,
+    restore: Vec<u8>,
+}
+
+impl BaudSwitch {
+    pub fn new(template: &str, rate: u32) -> Result<Self> {
+        let command = |rate: u32| {
+            handshake::parse_bytes(&template.replace("{rate}", &rate.to_string()))
+                .with_context(|| format!("invalid baud switch command {}", template))
+        };
+        Ok(BaudSwitch {
+            rate,
+            upgrade: command(rate)?,
+            restore: command(DEFAULT_BAUD)?,
+        })
+    }
+
+    // Called once the ADX is streaming. Returns false if frames didn't
+    // arrive at the new rate and the port was put back to 9600.
+    pub fn upgrade<R: Read>(
+        &self,
+        spec: &WireSpec,
+        adx_reader: &mut BufReader<R>,
+        adx_port: &mut dyn SerialPort,
+    ) -> Result<bool> {
+        tracing::info!("Switching ADX to {} baud", self.rate);
+        self.switch(adx_reader, adx_port, &self.upgrade, self.rate)?;
+
+        let deadline = Instant::now() + VERIFY_TIMEOUT;
+        let retry = Retry::until(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &MonotonicClock,
+            Some(deadline),
+        );
+        let mut frame = Vec::with_capacity(spec.touch_frame_len);
+        let mut good = 0;
+        // Frames that were on the wire during the switch come out garbled, so
+        // only a clean run after them counts
+        while good < VERIFY_FRAMES && Instant::now() < deadline {
+            match read_packet(&mut frame, adx_reader, &spec.adx, &retry) {
+                Ok(_) if frame.len() == spec.touch_frame_len => good += 1,
+                Ok(_) => good = 0,
+                Err(err) if err.kind() == ErrorKind::TimedOut => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if good >= VERIFY_FRAMES {
+            tracing::info!("ADX streaming at {} baud", self.rate);
+            return Ok(true);
+        }
+
+        tracing::warn!(
+            "!!! No clean frames from the ADX at {} baud, falling back to {}",
+            self.rate,
+            DEFAULT_BAUD
+        );
+        self.restore(adx_reader, adx_port)?;
+        Ok(false)
+    }
+
+    // Brings the ADX and the port back to 9600 for config mode
+    pub fn restore<R: Read>(
+        &self,
+        adx_reader: &mut BufReader<R>,
+        adx_port: &mut dyn SerialPort,
+    ) -> Result<()> {
+        self.switch(adx_reader, adx_port, &self.restore, DEFAULT_BAUD)
+    }
+
+    fn switch<R: Read>(
+        &self,
+        adx_reader: &mut BufReader<R>,
+        adx_port: &mut dyn SerialPort,
+        command: &[u8],
+        rate: u32,
+    ) -> Result<()> {
+        adx_port.write_all(command)?;
+        adx_port.flush()?;
+        adx_port.set_baud_rate(rate)?;
+        thread::sleep(SWITCH_SETTLE);
+        // Whatever was buffered arrived mid-switch, or is the board's answer to it
+        adx_port.clear(ClearBuffer::Input)?;
+        let buffered = adx_reader.buffer().len();
+        adx_reader.consume(buffered);
+        Ok(())
+    }
+}
+
+// Rates --auto-baud steps through when the stream looks mis-clocked
+pub const COMMON_RATES: &[u32] = &[9600, 19200, 38400, 57600, 115200];
+// How long into a session packets are collected before judging the line
+pub const DETECT_WINDOW: Duration = Duration::from_secs(3);
+// Too few packets to say anything, e.g. the ADX went quiet
+const DETECT_MIN_PACKETS: u64 = 50;
+// A wrong rate garbles most packets; a bad cable or ground only some
+const MISMATCH_MALFORMED: f64 = 0.5;
+const NOISY_MALFORMED: f64 = 0.02;
+// Touch payload bytes only use the low 5 bits. Sampling at the wrong rate
+// smears bits across byte boundaries, so high values turn up in bulk.
+const PAYLOAD_MAX: u8 = 0x1f;
+const MISMATCH_FOREIGN: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineVerdict {
+    Healthy,
+    // Some packets corrupted, but what arrives looks like touch data
+    Noisy,
+    // Mostly garbage made of values touch data never contains
+    Mismatch,
+}
+
+// Packets seen at the start of a streaming session, for judging whether
+// the ADX port is really running at the board's rate
+pub struct LineStats {
+    valid: u64,
+    malformed: u64,
+    // Occurrences of each byte value between the delimiters
+    bytes: [u64; 256],
+}
+
+impl LineStats {
+    pub fn new() -> Self {
+        LineStats {
+            valid: 0,
+            malformed: 0,
+            bytes: [0; 256],
+        }
+    }
+
+    // `packet` includes its delimiters
+    pub fn record(&mut self, packet: &[u8], valid: bool) {
+        if valid {
+            self.valid += 1;
+        } else {
+            self.malformed += 1;
+        }
+        let interior = packet.get(1..packet.len().saturating_sub(1)).unwrap_or(&[]);
+        for &byte in interior {
+            self.bytes[byte as usize] += 1;
+        }
+    }
+
+    pub fn malformed_ratio(&self) -> f64 {
+        self.malformed as f64 / (self.valid + self.malformed).max(1) as f64
+    }
+
+    // Share of payload bytes no touch frame could contain
+    fn foreign_ratio(&self) -> f64 {
+        let total: u64 = self.bytes.iter().sum();
+        let foreign: u64 = self.bytes[PAYLOAD_MAX as usize + 1..].iter().sum();
+        foreign as f64 / total.max(1) as f64
+    }
+
+    // None until enough packets have been seen
+    pub fn classify(&self) -> Option<LineVerdict> {
+        if self.valid + self.malformed < DETECT_MIN_PACKETS {
+            return None;
+        }
+        let malformed = self.malformed_ratio();
+        Some(
+            if malformed >= MISMATCH_MALFORMED && self.foreign_ratio() >= MISMATCH_FOREIGN {
+                LineVerdict::Mismatch
+            } else if malformed >= NOISY_MALFORMED {
+                LineVerdict::Noisy
+            } else {
+                LineVerdict::Healthy
+            },
+        )
+    }
+}
+
+// The rate --auto-baud tries after `rate` turned out wrong, wrapping around
+pub fn next_common_rate(rate: u32) -> u32 {
+    let next = COMMON_RATES
+        .iter()
+        .position(|&common| common == rate)
+        .map_or(0, |i| (i + 1) % COMMON_RATES.len());
+    COMMON_RATES[next]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maitouch_protocol::framing::maimai;
+    #[cfg(unix)]
+    use serialport::TTYPort;
+    #[cfg(unix)]
+    use std::io::Write;
+
+    #[cfg(unix)]
+    const TEMPLATE: &str = "{BR{rate}}";
+
+    // A board on the far end of a PTY: waits for the switch command, takes
+    // its time to change rate as a real board would and then streams
+    // `frames`. Returns everything it was sent.
+    #[cfg(unix)]
+    fn board(mut port: TTYPort, frames: Vec<Vec<u8>>) -> Vec<u8> {
+        port.set_timeout(Duration::from_millis(20)).unwrap();
+        let switch = BaudSwitch::new(TEMPLATE, 115_200).unwrap();
+        let mut got = Vec::new();
+        let mut buf = [0u8; 64];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !got.ends_with(&switch.upgrade) && Instant::now() < deadline {
+            if let Ok(n) = port.read(&mut buf) {
+                got.extend_from_slice(&buf[..n]);
+            }
+        }
+        thread::sleep(SWITCH_SETTLE * 2);
+        for frame in &frames {
+            port.write_all(frame).unwrap();
+            thread::sleep(Duration::from_millis(2));
+        }
+        // Whatever else the proxy sends once it has judged the stream
+        let deadline = Instant::now() + VERIFY_TIMEOUT;
+        while Instant::now() < deadline {
+            match port.read(&mut buf) {
+                Ok(n) => got.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                Err(_) => break,
+            }
+            if got.ends_with(&switch.restore) {
+                break;
+            }
+        }
+        // Still on the line while the proxy settles after the switch
+        thread::sleep(SWITCH_SETTLE * 2);
+        got
+    }
+
+    // Runs an upgrade against a board that streams `frames` once switched.
+    // Returns whether it held and what the board was sent.
+    #[cfg(unix)]
+    fn upgrade_against(frames: Vec<Vec<u8>>) -> (bool, Vec<u8>) {
+        let spec = WireSpec::maimai();
+        let (board_end, mut port) = TTYPort::pair().unwrap();
+        port.set_timeout(Duration::from_millis(50)).unwrap();
+        let mut reader = BufReader::new(port.try_clone_native().unwrap());
+        let switch = BaudSwitch::new(TEMPLATE, 115_200).unwrap();
+        thread::scope(|scope| {
+            let board = scope.spawn(|| board(board_end, frames));
+            let held = switch.upgrade(&spec, &mut reader, &mut port).unwrap();
+            (held, board.join().unwrap())
+        })
+    }
+
+    #[test]
+    fn the_template_gives_both_directions() {
+        let switch = BaudSwitch::new(r"\x02BR{rate}", 57_600).unwrap();
+        assert_eq!(switch.upgrade, b"\x02BR57600");
+        assert_eq!(switch.restore, b"\x02BR9600");
+        assert!(BaudSwitch::new(r"\xZZ{rate}", 57_600).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clean_frames_at_the_new_rate_keep_it() {
+        let frame = maimai::ADX.wrap(&[1, 0, 0, 0, 0, 0, 0]);
+        let (held, sent) = upgrade_against(vec![frame; VERIFY_FRAMES * 2]);
+        assert!(held);
+        assert_eq!(sent, b"{BR115200}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_that_ignored_the_switch_is_brought_back_down() {
+        // What a board still at 9600 looks like read at 115200
+        let garbage = vec![b"(\xff\xe0\x80)".to_vec(); VERIFY_FRAMES * 2];
+        let (held, sent) = upgrade_against(garbage);
+        assert!(!held);
+        assert_eq!(sent, b"{BR115200}{BR9600}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_torn_frame_starts_the_clean_run_over() {
+        let frame = maimai::ADX.wrap(&[1, 0, 0, 0, 0, 0, 0]);
+        let mut frames = vec![frame.clone(); VERIFY_FRAMES - 1];
+        frames.push(b"(\x01)".to_vec());
+        frames.extend(vec![frame; VERIFY_FRAMES - 1]);
+        let (held, sent) = upgrade_against(frames);
+        assert!(!held);
+        assert_eq!(sent, b"{BR115200}{BR9600}");
+    }
+
+    // `count` packets of `packet`, then `bad` of `garbage`
+    fn line(packet: &[u8], count: usize, garbage: &[u8], bad: usize) -> LineStats {
+        let mut stats = LineStats::new();
+        for _ in 0..count {
+            stats.record(packet, true);
+        }
+        for _ in 0..bad {
+            stats.record(garbage, false);
+        }
+        stats
+    }
+
+    #[test]
+    fn a_clean_line_is_healthy() {
+        let frame = maimai::ADX.wrap(&[1, 0, 0x1f, 0, 0, 0, 0]);
+        assert_eq!(
+            line(&frame, 100, b"", 0).classify(),
+            Some(LineVerdict::Healthy)
+        );
+        // One in a hundred torn is still just the odd glitch
+        let torn = &frame[..5];
+        assert_eq!(
+            line(&frame, 99, torn, 1).classify(),
+            Some(LineVerdict::Healthy)
+        );
+    }
+
+    #[test]
+    fn a_few_corrupted_packets_of_touch_data_are_noise() {
+        let frame = maimai::ADX.wrap(&[1, 0, 0, 0, 0, 0, 0]);
+        let torn = &frame[..5];
+        assert_eq!(
+            line(&frame, 98, torn, 2).classify(),
+            Some(LineVerdict::Noisy)
+        );
+        // Even mostly torn, bytes a frame could hold mean the rate is right
+        assert_eq!(
+            line(&frame, 20, torn, 80).classify(),
+            Some(LineVerdict::Noisy)
+        );
+    }
+
+    #[test]
+    fn mostly_foreign_garbage_is_a_rate_mismatch() {
+        let frame = maimai::ADX.wrap(&[1, 0, 0, 0, 0, 0, 0]);
+        let garbage = b"(\xff\xe0\x80\x3f)";
+        assert_eq!(
+            line(&frame, 40, garbage, 60).classify(),
+            Some(LineVerdict::Mismatch)
+        );
+        let stats = line(&frame, 40, garbage, 60);
+        assert!((stats.malformed_ratio() - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn too_few_packets_give_no_verdict() {
+        let garbage = b"(\xff\xff)";
+        assert_eq!(line(b"", 0, garbage, 49).classify(), None);
+        assert_eq!(
+            line(b"", 0, garbage, 50).classify(),
+            Some(LineVerdict::Mismatch)
+        );
+    }
+
+    #[test]
+    fn auto_baud_steps_through_the_common_rates_and_wraps() {
+        let mut rate = 9600;
+        let mut tried = Vec::new();
+        for _ in 0..COMMON_RATES.len() {
+            rate = next_common_rate(rate);
+            tried.push(rate);
+        }
+        assert_eq!(tried, [19200, 38400, 57600, 115200, 9600]);
+        // A rate off the list starts from the bottom
+        assert_eq!(next_common_rate(250_000), 9600);
+    }
+}
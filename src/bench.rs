@@ -0,0 +1,395 @@
+use crate::clock::MonotonicClock;
+use crate::wire::WireSpec;
+use crate::{stat_mode, Config, Pipeline};
+use anyhow::Result;
+use maitouch_protocol::command;
+use std::collections::HashSet;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Frames carry their sequence number in the low 5 bits of each payload
+// byte, the same bits the touch regions use
+const SEQ_BITS_PER_BYTE: usize = 5;
+
+pub struct BenchOptions {
+    pub duration: Duration,
+    pub rate: u32,
+    pub json: bool,
+}
+
+// Creation time of every generated frame, indexed by sequence number
+type Sent = Arc<Mutex<Vec<Instant>>>;
+
+// ADX side: emits numbered frames at the configured rate until the proxy
+// resets it, then behaves like a silent port
+struct FrameGenerator {
+    open: u8,
+    close: u8,
+    frame_len: usize,
+    interval: Duration,
+    next_frame: Instant,
+    pending: Vec<u8>,
+    sent: Sent,
+    reset: Arc<AtomicBool>,
+}
+
+impl Read for FrameGenerator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.reset.load(Ordering::Relaxed) {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        if self.pending.is_empty() {
+            let now = Instant::now();
+            if self.next_frame > now {
+                thread::sleep(self.next_frame - now);
+            }
+            self.next_frame = self.next_frame.max(now) + self.interval;
+
+            let seq = {
+                let mut sent = self.sent.lock().unwrap();
+                sent.push(Instant::now());
+                sent.len() as u64
+            };
+            self.pending.push(self.open);
+            for i in 0..self.frame_len - 2 {
+                self.pending
+                    .push((seq >> (i * SEQ_BITS_PER_BYTE)) as u8 & 0x1f);
+            }
+            self.pending.push(self.close);
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+// ADX side writes: only watched for the reset that ends the run
+struct ResetWatcher {
+    reset_command: Vec<u8>,
+    reset: Arc<AtomicBool>,
+}
+
+impl Write for ResetWatcher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf == self.reset_command.as_slice() {
+            self.reset.store(true, Ordering::Relaxed);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// ALLS side reads: quiet until the run is over, then a single HALT
+struct HaltAfter {
+    deadline: Instant,
+    halt: Option<Vec<u8>>,
+}
+
+impl Read for HaltAfter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let now = Instant::now();
+        if now < self.deadline {
+            thread::sleep((self.deadline - now).min(Duration::from_millis(100)));
+            return Err(ErrorKind::TimedOut.into());
+        }
+        match self.halt.take() {
+            Some(halt) => {
+                buf[..halt.len()].copy_from_slice(&halt);
+                Ok(halt.len())
+            }
+            None => {
+                thread::sleep(Duration::from_millis(100));
+                Err(ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+// ALLS side writes: records when each sequence number first reaches the game
+struct Sink {
+    frame_len: usize,
+    partial: Vec<u8>,
+    sent: Sent,
+    seen: HashSet<u64>,
+    latencies: Vec<Duration>,
+    writes: u64,
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let now = Instant::now();
+        self.partial.extend_from_slice(buf);
+        while self.partial.len() >= self.frame_len {
+            let frame: Vec<u8> = self.partial.drain(..self.frame_len).collect();
+            self.writes += 1;
+            let seq = frame[1..self.frame_len - 1]
+                .iter()
+                .enumerate()
+                .fold(0u64, |seq, (i, byte)| {
+                    seq | ((byte & 0x1f) as u64) << (i * SEQ_BITS_PER_BYTE)
+                });
+            if seq == 0 || !self.seen.insert(seq) {
+                continue;
+            }
+            if let Some(sent) = self.sent.lock().unwrap().get(seq as usize - 1) {
+                self.latencies.push(now - *sent);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Report {
+    wall: Duration,
+    cpu: Option<Duration>,
+    generated: usize,
+    forwarded: usize,
+    writes: u64,
+    percentiles: Vec<(&'static str, Duration)>,
+}
+
+pub fn run(options: &BenchOptions, config: &Config) -> Result<()> {
+    let report = measure(options, config)?;
+    if options.json {
+        println!("{}", json(&report));
+    } else {
+        print_text(&report);
+    }
+    Ok(())
+}
+
+// Streams through in-memory ports for the configured time
+fn measure(options: &BenchOptions, config: &Config) -> Result<Report> {
+    let spec = WireSpec::load(&config.wire_spec)?;
+    let mut pipeline = Pipeline::new(config, &spec)?;
+
+    let sent: Sent = Arc::default();
+    let reset = Arc::new(AtomicBool::new(false));
+    let interval = match options.rate {
+        0 => Duration::ZERO,
+        rate => Duration::from_secs(1) / rate,
+    };
+    let start = Instant::now();
+    let mut adx_reader = BufReader::new(FrameGenerator {
+        open: spec.adx.open as u8,
+        close: spec.adx.close as u8,
+        frame_len: spec.touch_frame_len,
+        interval,
+        next_frame: start,
+        pending: Vec::new(),
+        sent: sent.clone(),
+        reset: reset.clone(),
+    });
+    let mut adx_writer = ResetWatcher {
+        reset_command: spec.command(command::RSET),
+        reset,
+    };
+    let mut alls_reader = BufReader::new(HaltAfter {
+        deadline: start + options.duration,
+        halt: Some(spec.command(command::HALT)),
+    });
+    let mut alls_writer = Sink {
+        frame_len: spec.touch_frame_len,
+        partial: Vec::new(),
+        sent: sent.clone(),
+        seen: HashSet::new(),
+        latencies: Vec::new(),
+        writes: 0,
+    };
+
+    let cpu_start = cpu_time();
+    pipeline.stat_at = Instant::now();
+    stat_mode(
+        config,
+        &spec,
+        &mut pipeline,
+        &mut adx_reader,
+        &mut adx_writer,
+        &mut alls_reader,
+        &mut alls_writer,
+        &MonotonicClock,
+    )?;
+    let wall = start.elapsed();
+    let cpu = cpu_time().zip(cpu_start).map(|(end, start)| end - start);
+
+    let mut latencies = alls_writer.latencies;
+    latencies.sort();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+    let report = Report {
+        wall,
+        cpu,
+        generated: sent.lock().unwrap().len(),
+        forwarded: latencies.len(),
+        writes: alls_writer.writes,
+        percentiles: vec![
+            ("p50", percentile(50)),
+            ("p90", percentile(90)),
+            ("p99", percentile(99)),
+            ("max", latencies.last().copied().unwrap_or_default()),
+        ],
+    };
+    Ok(report)
+}
+
+fn print_text(report: &Report) {
+    let secs = report.wall.as_secs_f64();
+    println!("Duration:        {:.2}s", secs);
+    println!(
+        "Frames in:       {} ({:.0}/s)",
+        report.generated,
+        report.generated as f64 / secs
+    );
+    println!(
+        "Frames out:      {} distinct, {} writes ({:.0}/s)",
+        report.forwarded,
+        report.writes,
+        report.writes as f64 / secs
+    );
+    for (name, latency) in &report.percentiles {
+        println!("Latency {}:     {:?}", name, latency);
+    }
+    match report.cpu {
+        Some(cpu) => println!(
+            "CPU time:        {:?} ({:.0}% of one core)",
+            cpu,
+            cpu.as_secs_f64() / secs * 100.0
+        ),
+        None => println!("CPU time:        unavailable"),
+    }
+}
+
+fn json(report: &Report) -> String {
+    let percentiles: Vec<String> = report
+        .percentiles
+        .iter()
+        .map(|(name, latency)| format!("\"{}_us\":{}", name, latency.as_micros()))
+        .collect();
+    format!(
+        "{{\"duration_us\":{},\"frames_in\":{},\"frames_out\":{},\"writes\":{},\"latency\":{{{}}},\"cpu_us\":{}}}",
+        report.wall.as_micros(),
+        report.generated,
+        report.forwarded,
+        report.writes,
+        percentiles.join(","),
+        report
+            .cpu
+            .map_or("null".to_string(), |cpu| cpu.as_micros().to_string())
+    )
+}
+
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: getrusage only writes into the provided struct
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let micros = |tv: libc::timeval| tv.tv_sec as u64 * 1_000_000 + tv.tv_usec as u64;
+    Some(Duration::from_micros(
+        micros(usage.ru_utime) + micros(usage.ru_stime),
+    ))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn bench(rate: u32, proxy_args: &[&str]) -> Report {
+        let args = ["maitouch_rs", "alls-loopback", "adx-loopback"]
+            .iter()
+            .chain(proxy_args);
+        let config = Config::from_iter_safe(args).unwrap();
+        config.validate().unwrap();
+        let options = BenchOptions {
+            duration: Duration::from_millis(300),
+            rate,
+            json: false,
+        };
+        measure(&options, &config).unwrap()
+    }
+
+    fn assert_sane(report: &Report) {
+        assert!(
+            report.wall >= Duration::from_millis(300),
+            "{:?}",
+            report.wall
+        );
+        assert!(report.wall < Duration::from_secs(10), "{:?}", report.wall);
+        assert!(report.generated > 0);
+        assert!(report.forwarded > 0);
+        assert!(report.forwarded <= report.generated);
+        assert!(report.writes >= report.forwarded as u64);
+        let latencies: Vec<Duration> = report.percentiles.iter().map(|(_, at)| *at).collect();
+        assert!(latencies.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(latencies[0] > Duration::ZERO);
+        assert!(report.wall > *latencies.last().unwrap());
+        assert!(report.cpu.is_none_or(|cpu| cpu > Duration::ZERO));
+    }
+
+    #[test]
+    fn completes_with_sane_numbers() {
+        let report = bench(1000, &[]);
+        assert_sane(&report);
+        // Half the frames a 1kHz source makes in the time is plenty
+        assert!(report.generated >= 150, "{}", report.generated);
+        assert!(report.forwarded * 2 >= report.generated);
+    }
+
+    #[test]
+    fn completes_with_a_paced_writer() {
+        let report = bench(0, &["--frame-rate", "500"]);
+        assert_sane(&report);
+        assert!(report.writes < report.generated as u64);
+    }
+
+    #[test]
+    fn json_has_every_number() {
+        let report = bench(1000, &[]);
+        let json = json(&report);
+        for field in [
+            "\"duration_us\":",
+            "\"frames_in\":",
+            "\"frames_out\":",
+            "\"writes\":",
+            "\"p50_us\":",
+            "\"p99_us\":",
+            "\"max_us\":",
+            "\"cpu_us\":",
+        ] {
+            assert!(json.contains(field), "{} in {}", field, json);
+        }
+        assert!(json.starts_with('{') && json.ends_with('}'));
+    }
+
+    #[test]
+    fn json_matches_its_schema() {
+        let schema = crate::schema::document("bench").unwrap();
+        let mut report = bench(1000, &[]);
+        crate::schema::check(&schema, &json(&report)).unwrap();
+        report.cpu = None;
+        crate::schema::check(&schema, &json(&report)).unwrap();
+    }
+}
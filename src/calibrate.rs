@@ -0,0 +1,234 @@
+use crate::clock::{Clock, MonotonicClock};
+use anyhow::{bail, Context, Result};
+use maitouch_protocol::touch::Region;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Time before the first pulse, so setup doesn't delay it
+const LEAD_IN: Duration = Duration::from_millis(500);
+// Sleeping stops this long before a pulse is due and the rest is spun,
+// since long sleeps can overrun by a few milliseconds
+const SPIN_MARGIN: Duration = Duration::from_millis(5);
+// Pulses sent later than this are called out
+const LATE_WARN: Duration = Duration::from_millis(1);
+
+pub struct PulseTrain {
+    pub region: Region,
+    pub pulses: u32,
+    pub spacing: Duration,
+    pub width: Duration,
+    pub player: Option<String>,
+}
+
+// Sends the pulse train to a proxy's --inject-listen address, one pulse
+// command per datagram, and logs when each went out. The proxy merges the
+// pulses into the board's frames, so the presses show up in the game (and
+// in the proxy's --event-csv) alongside real touches.
+pub fn run(target: &str, train: &PulseTrain, csv: Option<&str>) -> Result<()> {
+    if train.width >= train.spacing {
+        bail!("pulses must be shorter than their spacing, or they run into one another");
+    }
+    let socket = UdpSocket::bind(if target.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })?;
+    socket
+        .connect(target)
+        .with_context(|| format!("connecting to {}", target))?;
+    let mut command = format!("{} pulse {}", train.region, train.width.as_millis());
+    if let Some(player) = &train.player {
+        command += &format!(" player={}", player);
+    }
+    let mut csv = csv
+        .map(|path| {
+            File::create(path)
+                .with_context(|| format!("creating calibration CSV {}", path))
+                .map(BufWriter::new)
+        })
+        .transpose()?;
+    if let Some(csv) = csv.as_mut() {
+        writeln!(csv, "pulse,timestamp_us,monotonic_us,late_us,region")?;
+    }
+
+    let worst = send_train(
+        &MonotonicClock,
+        train,
+        csv.as_mut().map(|csv| csv as &mut dyn Write),
+        || socket.send(command.as_bytes()).map(drop),
+    )?;
+    if let Some(csv) = csv.as_mut() {
+        csv.flush()?;
+    }
+    println!(
+        "Sent {} pulses of {} to {}, at most {:.1?} late",
+        train.pulses, train.region, target, worst
+    );
+    Ok(())
+}
+
+// Sends the train on `clock`, one `send` per pulse, and logs when each
+// went out. Returns how late the latest pulse was.
+fn send_train(
+    clock: &dyn Clock,
+    train: &PulseTrain,
+    mut csv: Option<&mut dyn Write>,
+    mut send: impl FnMut() -> std::io::Result<()>,
+) -> Result<Duration> {
+    let start = clock.now() + LEAD_IN;
+    // Unix time is derived from the monotonic clock, as in the event CSV
+    let start_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        + LEAD_IN;
+    let mut worst = Duration::ZERO;
+    for pulse in 0..train.pulses {
+        let due = start + train.spacing * pulse;
+        wait_until(clock, due);
+        send()?;
+        let sent = clock.now();
+        let late = sent - due;
+        worst = worst.max(late);
+        if late > LATE_WARN {
+            tracing::warn!("Pulse {} went out {:.1?} late", pulse, late);
+        }
+        let monotonic = sent - start;
+        println!(
+            "pulse {} at {}us",
+            pulse,
+            (start_unix + monotonic).as_micros()
+        );
+        if let Some(csv) = csv.as_mut() {
+            writeln!(
+                csv,
+                "{},{},{},{},{}",
+                pulse,
+                (start_unix + monotonic).as_micros(),
+                monotonic.as_micros(),
+                late.as_micros(),
+                train.region
+            )?;
+        }
+    }
+    Ok(worst)
+}
+
+fn wait_until(clock: &dyn Clock, deadline: Instant) {
+    if let Some(early) = deadline.checked_sub(SPIN_MARGIN) {
+        clock.sleep_until(early);
+    }
+    while clock.now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    // Time passing while the pulse loop spins, as each look at the clock
+    // takes a little
+    const TICK: Duration = Duration::from_micros(50);
+
+    // A mock clock run the way a busy OS runs the real one: sleeps wake up
+    // `overrun` late
+    struct Overrunning {
+        clock: MockClock,
+        overrun: Duration,
+    }
+
+    impl Clock for Overrunning {
+        fn now(&self) -> Instant {
+            self.clock.advance(TICK);
+            self.clock.now()
+        }
+
+        fn sleep_until(&self, deadline: Instant) {
+            self.clock.sleep_until(deadline);
+            self.clock.advance(self.overrun);
+        }
+    }
+
+    fn train(pulses: u32) -> PulseTrain {
+        PulseTrain {
+            region: "A1".parse().unwrap(),
+            pulses,
+            spacing: Duration::from_millis(250),
+            width: Duration::from_millis(50),
+            player: None,
+        }
+    }
+
+    // The pulse train on a clock whose sleeps overrun by `overrun`: when
+    // each pulse was sent, counted from the first one's due time, and its
+    // CSV rows
+    fn sent_on(overrun: Duration) -> (Vec<Duration>, Vec<String>) {
+        let clock = Overrunning {
+            clock: MockClock::new(),
+            overrun,
+        };
+        let first_due = clock.clock.now() + LEAD_IN;
+        let mut sent = Vec::new();
+        let mut csv = Vec::new();
+        send_train(&clock, &train(8), Some(&mut csv), || {
+            sent.push(clock.clock.now() - first_due);
+            Ok(())
+        })
+        .unwrap();
+        let rows = String::from_utf8(csv).unwrap();
+        (sent, rows.lines().map(String::from).collect())
+    }
+
+    #[test]
+    fn pulses_go_out_within_a_millisecond_of_their_schedule() {
+        // Sleeps overrunning by less than the spin margin are made up for
+        let (sent, rows) = sent_on(Duration::from_millis(4));
+        assert_eq!(sent.len(), 8);
+        for (pulse, sent) in sent.iter().enumerate() {
+            let due = train(8).spacing * pulse as u32;
+            let off = sent.abs_diff(due);
+            assert!(
+                off <= Duration::from_millis(1),
+                "pulse {}: {:?}",
+                pulse,
+                off
+            );
+        }
+        assert_eq!(rows.len(), 8);
+        for (pulse, row) in rows.iter().enumerate() {
+            let fields: Vec<&str> = row.split(',').collect();
+            assert_eq!(fields[0], pulse.to_string());
+            let monotonic: i64 = fields[2].parse().unwrap();
+            let due = 250_000 * pulse as i64;
+            assert!((monotonic - due).abs() <= 1000, "{}", row);
+            let late: u64 = fields[3].parse().unwrap();
+            assert!(late <= 1000, "{}", row);
+            assert_eq!(fields[4], "A1");
+        }
+    }
+
+    #[test]
+    fn a_late_pulse_is_called_out() {
+        // Past the spin margin, the overrun can't be made up for
+        crate::logcapture::capturing(|log| {
+            sent_on(Duration::from_millis(8));
+            let logged = log.take();
+            assert_eq!(logged.len(), 8);
+            assert!(logged[0].starts_with("Pulse 0 went out 3."), "{:?}", logged);
+        });
+    }
+
+    #[test]
+    fn pulses_must_be_shorter_than_their_spacing() {
+        let mut train = train(1);
+        train.width = train.spacing;
+        let err = run("127.0.0.1:9", &train, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pulses must be shorter than their spacing, or they run into one another"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use crate::failover::SilenceAction;
+use crate::features;
+use crate::ports::Transport;
+use crate::slider::AllsProtocol;
+use crate::wire::{self, WireSpec};
+use maitouch_protocol::command;
+use maitouch_protocol::framing::maimai;
+use maitouch_protocol::touch::{BitOrder, ByteOrder, Region, PAYLOAD_LEN, REGION_COUNT};
+
+// Bumped when a key is removed or changes meaning; new keys don't bump it
+const FORMAT_VERSION: u32 = 1;
+
+// Describes what this build supports as one line of JSON, for launchers
+// and installers to check before they start the proxy. Everything is read
+// from the lists the proxy itself parses its options against.
+pub fn json() -> String {
+    let transports: Vec<String> = Transport::ALL
+        .iter()
+        .map(|transport| {
+            format!(
+                "{{\"name\":{},\"form\":{},\"adx\":{},\"available\":{}}}",
+                quote(transport.name()),
+                quote(transport.form()),
+                transport.reaches_adx(),
+                transport.available()
+            )
+        })
+        .collect();
+    let spec = WireSpec::maimai();
+    let sized: Vec<String> = spec
+        .responses
+        .iter()
+        .map(|response| {
+            format!(
+                "{{\"prefix\":{},\"len\":{}}}",
+                quote(&response.prefix),
+                response.len
+            )
+        })
+        .collect();
+    let adx_features: Vec<String> = features::REGISTRY
+        .iter()
+        .map(|feature| {
+            format!(
+                "{{\"bit\":{},\"name\":{},\"option\":{}}}",
+                feature.bit,
+                quote(feature.name),
+                quote(feature.option)
+            )
+        })
+        .collect();
+    let regions: Vec<String> = Region::all().map(|region| region.to_string()).collect();
+    let regions: Vec<&str> = regions.iter().map(String::as_str).collect();
+    format!(
+        "{{\"format\":{},\"version\":{},\"os\":{},\"features\":{{\"shm\":{}}},\
+         \"tools\":{},\"transports\":[{}],\"wire_specs\":{},\"alls_protocols\":{},\
+         \"bit_orders\":{},\"byte_orders\":{},\"silence_actions\":{},\
+         \"protocol\":{{\"alls\":{},\"adx\":{},\"touch_frame_len\":{},\"payload_len\":{},\
+         \"command_max_len\":{},\"commands\":{},\"sized_responses\":[{}],\
+         \"region_count\":{},\"regions\":{},\"adx_features\":[{}]}}}}",
+        FORMAT_VERSION,
+        quote(env!("CARGO_PKG_VERSION")),
+        quote(std::env::consts::OS),
+        cfg!(feature = "shm"),
+        list(crate::TOOLS),
+        transports.join(","),
+        list(wire::PRESETS),
+        list(AllsProtocol::NAMES),
+        list(BitOrder::NAMES),
+        list(ByteOrder::NAMES),
+        list(SilenceAction::NAMES),
+        quote(&format!("{}{}", maimai::ALLS.open, maimai::ALLS.close)),
+        quote(&format!("{}{}", maimai::ADX.open, maimai::ADX.close)),
+        maimai::TOUCH_FRAME_LEN,
+        PAYLOAD_LEN,
+        maimai::COMMAND_MAX_LEN,
+        list(&[command::HALT, command::STAT, command::RSET]),
+        sized.join(","),
+        REGION_COUNT,
+        list(&regions),
+        adx_features.join(",")
+    )
+}
+
+fn list(items: &[&str]) -> String {
+    let quoted: Vec<String> = items.iter().map(|item| quote(item)).collect();
+    format!("[{}]", quoted.join(","))
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The raw value after the first `"key":`, up to where it ends
+    fn value<'a>(json: &'a str, key: &str) -> &'a str {
+        let start = json
+            .find(&format!("\"{}\":", key))
+            .unwrap_or_else(|| panic!("no {} in {}", key, json))
+            + key.len()
+            + 3;
+        let rest = &json[start..];
+        let (mut depth, mut in_string, mut escaped) = (0, false, false);
+        for (index, c) in rest.char_indices() {
+            if std::mem::take(&mut escaped) {
+                continue;
+            }
+            if in_string {
+                match c {
+                    '\\' => escaped = true,
+                    '"' if depth == 0 => return &rest[..=index],
+                    '"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => in_string = true,
+                '[' | '{' => depth += 1,
+                ',' | ']' | '}' if depth == 0 => return &rest[..index],
+                ']' | '}' if depth == 1 => return &rest[..=index],
+                ']' | '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        rest
+    }
+
+    #[test]
+    fn the_document_is_one_balanced_line() {
+        let json = json();
+        assert!(!json.contains('\n'));
+        let mut open = Vec::new();
+        let (mut in_string, mut escaped) = (false, false);
+        for c in json.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                _ if in_string => {}
+                '[' => open.push(']'),
+                '{' => open.push('}'),
+                ']' | '}' => assert_eq!(open.pop(), Some(c), "{}", json),
+                _ => {}
+            }
+        }
+        assert!(open.is_empty() && !in_string, "{}", json);
+        assert!(json.starts_with("{\"format\":1,"));
+    }
+
+    #[test]
+    fn the_build_is_described_as_compiled() {
+        let json = json();
+        assert_eq!(value(&json, "version"), quote(env!("CARGO_PKG_VERSION")));
+        assert_eq!(value(&json, "os"), quote(std::env::consts::OS));
+        assert_eq!(
+            value(&json, "features"),
+            format!("{{\"shm\":{}}}", cfg!(feature = "shm"))
+        );
+        assert_eq!(
+            value(&json, "transports"),
+            format!(
+                "[{{\"name\":\"serial\",\"form\":\"<port>\",\"adx\":true,\"available\":true}},\
+                 {{\"name\":\"pty\",\"form\":\"pty:[link]\",\"adx\":true,\"available\":{}}},\
+                 {{\"name\":\"stdio\",\"form\":\"stdio\",\"adx\":false,\"available\":true}},\
+                 {{\"name\":\"tcp-listen\",\"form\":\"tcp-listen://<addr:port>\",\"adx\":false,\
+                 \"available\":true}}]",
+                cfg!(unix)
+            )
+        );
+    }
+
+    #[test]
+    fn lists_are_the_ones_options_are_parsed_against() {
+        let json = json();
+        assert_eq!(value(&json, "tools"), list(crate::TOOLS));
+        assert_eq!(value(&json, "wire_specs"), "[\"maimai\"]");
+        assert_eq!(
+            value(&json, "alls_protocols"),
+            "[\"maimai\",\"chuni-slider\"]"
+        );
+        assert_eq!(value(&json, "bit_orders"), "[\"lsb\",\"msb\"]");
+        assert_eq!(value(&json, "byte_orders"), "[\"normal\",\"reversed\"]");
+        assert_eq!(value(&json, "silence_actions"), "[\"warn\",\"reconnect\"]");
+        let adx_features = value(&json, "adx_features");
+        for feature in features::REGISTRY {
+            assert!(
+                adx_features.contains(&format!(
+                    "{{\"bit\":{},\"name\":{},",
+                    feature.bit,
+                    quote(feature.name)
+                )),
+                "{}",
+                adx_features
+            );
+        }
+    }
+
+    #[test]
+    fn the_protocol_constants_are_the_maimai_ones() {
+        let json = json();
+        let json = value(&json, "protocol");
+        assert_eq!(value(json, "alls"), "\"{}\"");
+        assert_eq!(value(json, "adx"), "\"()\"");
+        assert_eq!(value(json, "touch_frame_len"), "9");
+        assert_eq!(value(json, "payload_len"), "7");
+        assert_eq!(value(json, "command_max_len"), "6");
+        assert_eq!(value(json, "commands"), "[\"HALT\",\"STAT\",\"RSET\"]");
+        assert_eq!(value(json, "region_count"), "34");
+        let regions = value(json, "regions");
+        assert!(regions.starts_with("[\"A1\",\"A2\","), "{}", regions);
+        assert!(regions.contains("\"C1\",\"C2\",\"D1\""), "{}", regions);
+        assert!(regions.ends_with("\"E8\"]"), "{}", regions);
+        assert!(value(json, "sized_responses").starts_with("[{\"prefix\":"));
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(quote("plain"), "\"plain\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(quote("tab\there\n"), "\"tab\\u0009here\\u000a\"");
+        assert_eq!(list(&[]), "[]");
+        assert_eq!(list(&["x", "y\""]), "[\"x\",\"y\\\"\"]");
+    }
+}
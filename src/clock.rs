@@ -0,0 +1,143 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep_until(&self, deadline: Instant);
+}
+
+// So a test can keep hold of a clock it hands to a component
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        (**self).sleep_until(deadline)
+    }
+}
+
+// And of one a component shares with threads of its own
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        (**self).sleep_until(deadline)
+    }
+}
+
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+}
+
+// A clock that only moves when told to. A sleeper wakes straight away with
+// the clock moved on to its deadline, so everything timed off it happens
+// at exactly the instant it asked for.
+#[cfg(test)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    // How far the clock has moved since `since`
+    pub fn since(&self, since: Instant) -> Duration {
+        self.now() - since
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_stands_still_until_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(clock.since(start), Duration::from_millis(5));
+        clock.advance(Duration::ZERO);
+        assert_eq!(clock.since(start), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn mock_sleep_wakes_at_the_deadline() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.sleep_until(start + Duration::from_micros(1500));
+        assert_eq!(clock.since(start), Duration::from_micros(1500));
+    }
+
+    #[test]
+    fn mock_sleep_never_goes_back() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(1));
+        clock.sleep_until(start);
+        clock.sleep_until(start + Duration::from_millis(10));
+        assert_eq!(clock.since(start), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_borrowed_clock_is_the_same_clock() {
+        let clock = MockClock::new();
+        let borrowed: &dyn Clock = &clock;
+        let start = borrowed.now();
+        clock.advance(Duration::from_millis(3));
+        assert_eq!(borrowed.now(), start + Duration::from_millis(3));
+        fn sleep<C: Clock>(clock: C, deadline: Instant) {
+            clock.sleep_until(deadline)
+        }
+        sleep(&clock, start + Duration::from_millis(7));
+        assert_eq!(clock.since(start), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn monotonic_sleep_reaches_the_deadline() {
+        let clock = MonotonicClock;
+        let deadline = clock.now() + Duration::from_millis(2);
+        clock.sleep_until(deadline);
+        assert!(clock.now() >= deadline);
+        // A deadline in the past doesn't sleep at all
+        clock.sleep_until(deadline - Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+// Lists com0com pairs with `setupc list`, creating one if none exists
+#[cfg(windows)]
+pub fn setup(setupc: &Path) -> Result<()> {
+    let mut pairs = list(setupc)?;
+    if pairs.is_empty() {
+        println!("No com0com pair found, creating one");
+        run(setupc, &["install", "PortName=COM#", "PortName=COM#"])?;
+        pairs = list(setupc)?;
+    }
+    if pairs.is_empty() {
+        bail!("setupc didn't create a port pair");
+    }
+    for (a, b) in pairs {
+        println!("Pair: {} <-> {}", a, b);
+    }
+    println!("Pass one port of a pair to the game and the other to --alls.");
+    Ok(())
+}
+
+fn run(setupc: &Path, args: &[&str]) -> Result<String> {
+    // setupc resolves its driver files relative to its own directory
+    let output = Command::new(setupc)
+        .arg("--silent")
+        .args(args)
+        .current_dir(setupc.parent().unwrap_or(Path::new(".")))
+        .output()
+        .with_context(|| format!("running {}", setupc.display()))?;
+    if !output.status.success() {
+        bail!(
+            "setupc {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The com0com pair `a` and `b` are the two ends of, if they are. What the
+// proxy wrote to one end it would read back from the other.
+pub fn same_pair(setupc: &Path, a: &str, b: &str) -> Result<Option<(String, String)>> {
+    Ok(pair_of(&list(setupc)?, a, b))
+}
+
+fn pair_of(pairs: &[(String, String)], a: &str, b: &str) -> Option<(String, String)> {
+    let (a, b) = (port_key(a), port_key(b));
+    pairs
+        .iter()
+        .find(|(one, other)| {
+            let (one, other) = (port_key(one), port_key(other));
+            (one == a && other == b) || (one == b && other == a)
+        })
+        .cloned()
+}
+
+// COM5, com5 and \\.\COM5 are one port
+fn port_key(name: &str) -> String {
+    name.trim_start_matches(r"\\.\").to_ascii_uppercase()
+}
+
+fn list(setupc: &Path) -> Result<Vec<(String, String)>> {
+    Ok(pairs(&run(setupc, &["list"])?))
+}
+
+// `setupc list` prints lines like `CNCA0 PortName=COM5`, pairs share the index
+fn pairs(output: &str) -> Vec<(String, String)> {
+    let mut halves: Vec<(String, String)> = Vec::new();
+    for line in output.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(device), Some(params)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let name = params
+            .split(',')
+            .find_map(|param| param.strip_prefix("PortName="))
+            .unwrap_or(device);
+        halves.push((device.to_string(), name.to_string()));
+    }
+    let mut pairs = Vec::new();
+    for (device, name) in &halves {
+        if let Some(index) = device.strip_prefix("CNCA") {
+            let other = format!("CNCB{}", index);
+            if let Some((_, peer)) = halves.iter().find(|(device, _)| *device == other) {
+                pairs.push((name.clone(), peer.clone()));
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What setupc prints with two pairs installed, one of them without
+    // port names of its own
+    const LIST: &str = "       CNCA0 PortName=COM5,EmuBR=yes\n       CNCB0 PortName=COM6\n       \
+                        CNCA1 PortName=-\n       CNCB1 PortName=COM#,RealPortName=COM8\n";
+
+    #[test]
+    fn pairs_are_matched_up_by_index() {
+        let pairs = pairs(LIST);
+        assert_eq!(
+            pairs,
+            [
+                ("COM5".to_string(), "COM6".to_string()),
+                ("-".to_string(), "COM#".to_string())
+            ]
+        );
+        // A half on its own isn't a pair
+        assert!(super::pairs("CNCA2 PortName=COM9\n").is_empty());
+        assert!(super::pairs("").is_empty());
+    }
+
+    #[test]
+    fn both_ends_of_one_pair_are_found_either_way_round() {
+        let pairs = pairs(LIST);
+        let pair = Some(("COM5".to_string(), "COM6".to_string()));
+        assert_eq!(pair_of(&pairs, "COM5", "COM6"), pair);
+        assert_eq!(pair_of(&pairs, "COM6", "COM5"), pair);
+        assert_eq!(pair_of(&pairs, r"\\.\com6", "com5"), pair);
+        // Ends of different pairs, or a port with no pair, are fine
+        assert_eq!(pair_of(&pairs, "COM5", "COM#"), None);
+        assert_eq!(pair_of(&pairs, "COM5", "COM3"), None);
+        assert_eq!(pair_of(&pairs, "COM5", "COM5"), None);
+    }
+
+    // setupc only exists on Windows, so a script stands in for it
+    #[cfg(unix)]
+    #[test]
+    fn pairs_are_listed_through_setupc() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let setupc = std::env::temp_dir().join(format!("maitouch-setupc-{}", std::process::id()));
+        std::fs::write(
+            &setupc,
+            format!(
+                "#!/bin/sh\n[ \"$*\" = \"--silent list\" ] && printf '{}'\n",
+                LIST
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&setupc, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let pair = same_pair(&setupc, "COM6", "COM5").unwrap();
+        let missing = same_pair(&setupc.with_extension("missing"), "COM6", "COM5");
+        std::fs::remove_file(&setupc).unwrap();
+        assert_eq!(pair, Some(("COM5".to_string(), "COM6".to_string())));
+        assert!(missing.is_err());
+    }
+}
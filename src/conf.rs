@@ -0,0 +1,145 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+// Parser for the flat subset of TOML used by spec and profile files:
+// `key = value` lines where a value is a string, integer or a single-line
+// array of those. Tables and multi-line values aren't supported.
+
+pub enum Value {
+    Str(String),
+    Int(i64),
+    List(Vec<Value>),
+}
+
+pub struct Entry {
+    pub line: usize,
+    pub key: String,
+    pub value: Value,
+}
+
+impl Entry {
+    pub fn unknown(&self) -> anyhow::Error {
+        anyhow!("line {}: unknown key {}", self.line, self.key)
+    }
+
+    fn context(&self) -> String {
+        format!("line {}: bad value for {}", self.line, self.key)
+    }
+
+    pub fn string(self) -> Result<String> {
+        let context = self.context();
+        self.value.string().context(context)
+    }
+
+    pub fn char(self) -> Result<char> {
+        let context = self.context();
+        self.value.char().context(context)
+    }
+
+    pub fn usize(self) -> Result<usize> {
+        let context = self.context();
+        self.value.usize().context(context)
+    }
+
+    pub fn list(self) -> Result<Vec<Value>> {
+        let context = self.context();
+        match self.value {
+            Value::List(items) => Ok(items),
+            _ => Err(anyhow!("expected an array")),
+        }
+        .context(context)
+    }
+
+    pub fn strings(self) -> Result<Vec<String>> {
+        let context = self.context();
+        match self.value {
+            Value::List(items) => items.into_iter().map(Value::string).collect(),
+            _ => Err(anyhow!("expected an array")),
+        }
+        .context(context)
+    }
+}
+
+impl Value {
+    pub fn string(self) -> Result<String> {
+        match self {
+            Value::Str(s) => Ok(s),
+            _ => bail!("expected a string"),
+        }
+    }
+
+    pub fn char(self) -> Result<char> {
+        let s = self.string()?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => Ok(c),
+            _ => bail!("expected a single ASCII character"),
+        }
+    }
+
+    pub fn usize(self) -> Result<usize> {
+        match self {
+            Value::Int(i) => Ok(usize::try_from(i)?),
+            _ => bail!("expected an integer"),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, raw) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected key = value", index + 1))?;
+        let (value, rest) = parse_value(raw.trim())
+            .with_context(|| format!("line {}: bad value for {}", index + 1, key.trim()))?;
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            bail!("line {}: unexpected trailing {}", index + 1, rest);
+        }
+        entries.push(Entry {
+            line: index + 1,
+            key: key.trim().to_string(),
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+// Parses one value off the front of `raw`, returning it and the remainder
+fn parse_value(raw: &str) -> Result<(Value, &str)> {
+    if let Some(rest) = raw.strip_prefix('"') {
+        let end = rest
+            .find('"')
+            .ok_or_else(|| anyhow!("unterminated string"))?;
+        return Ok((Value::Str(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = raw.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::List(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                bail!("expected , or ] in array");
+            }
+        }
+    }
+    let end = raw
+        .find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace())
+        .unwrap_or(raw.len());
+    let (token, rest) = raw.split_at(end);
+    let value = token
+        .parse()
+        .map_err(|_| anyhow!("expected a value, got {:?}", token))?;
+    Ok((Value::Int(value), rest))
+}
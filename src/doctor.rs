@@ -0,0 +1,548 @@
+use crate::clock::MonotonicClock;
+use crate::instance::InstanceLock;
+#[cfg(target_os = "linux")]
+use crate::latency;
+use crate::ports::{self, Transport};
+use crate::read_response;
+use crate::reads::{CountingPort, ReadStats};
+use crate::retry::{Retry, RetryPolicy};
+use crate::wire::WireSpec;
+use maitouch_protocol::command;
+use serialport::{SerialPortType, UsbPortInfo};
+use std::fmt;
+use std::io::{BufReader, ErrorKind, IsTerminal, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// FTDI adapters batch reads for this long by default, which shows up as
+// frames arriving in clumps. 1ms is what touch boards want.
+#[cfg(target_os = "linux")]
+const FTDI_GOOD_LATENCY_MS: u32 = 1;
+const FTDI_VID: u16 = 0x0403;
+// How long the self-test waits for the board to answer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+// How long the board is left streaming to see how its bytes arrive
+const STREAM_SAMPLE: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => " OK ",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Status::Ok => "\x1b[32m",
+            Status::Warn => "\x1b[33m",
+            Status::Fail => "\x1b[31m",
+        }
+    }
+}
+
+// Outcome of one diagnostic, with what to do about it when it isn't OK
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+struct Report<'a> {
+    checks: &'a [Check],
+    color: bool,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in self.checks {
+            let label = check.status.label();
+            if self.color {
+                write!(f, "{}[{}]\x1b[0m", check.status.color(), label)?;
+            } else {
+                write!(f, "[{}]", label)?;
+            }
+            writeln!(f, " {}: {}", check.name, check.detail)?;
+            if let Some(hint) = &check.hint {
+                writeln!(f, "       -> {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs every check, prints the report and returns whether nothing failed
+pub fn run(alls: &str, adx: &str, handshake: bool) -> bool {
+    let mut checks = Vec::new();
+    for (role, name) in [("ALLS", alls), ("ADX", adx)] {
+        checks.extend(check_port(role, name));
+    }
+    if handshake {
+        checks.push(check_handshake(adx));
+        if checks
+            .last()
+            .is_some_and(|check| check.status == Status::Ok)
+        {
+            checks.push(check_delivery(adx));
+        }
+    }
+    let stdout = std::io::stdout();
+    let report = Report {
+        checks: &checks,
+        color: stdout.is_terminal(),
+    };
+    let _ = write!(stdout.lock(), "{}", report);
+    checks.iter().all(|check| check.status != Status::Fail)
+}
+
+fn check_port(role: &str, name: &str) -> Vec<Check> {
+    let label = |what: &str| format!("{} {} {}", role, name, what);
+    if !Transport::of(name).is_device() {
+        return vec![Check::new(
+            label("port"),
+            Status::Ok,
+            "created by the proxy itself",
+        )];
+    }
+    let mut checks = vec![check_exists(&label("exists"), name)];
+    if checks[0].status == Status::Fail {
+        return checks;
+    }
+    checks.push(check_instance(&label("instance"), name));
+    #[cfg(target_os = "linux")]
+    checks.push(check_holders(&label("holders"), name));
+    checks.push(check_open(&label("open"), name));
+    let usb = usb_info(name);
+    checks.push(check_driver(&label("driver"), usb.as_ref()));
+    if usb.as_ref().is_some_and(|usb| usb.vid == FTDI_VID) {
+        checks.push(check_latency_timer(&label("latency timer"), name));
+    }
+    checks
+}
+
+fn check_exists(label: &str, name: &str) -> Check {
+    #[cfg(unix)]
+    let exists = std::path::Path::new(name).exists();
+    #[cfg(windows)]
+    let exists = serialport::available_ports()
+        .map(|found| {
+            found
+                .iter()
+                .any(|port| port.port_name.eq_ignore_ascii_case(name))
+        })
+        .unwrap_or(false);
+    if exists {
+        return Check::new(label, Status::Ok, "found");
+    }
+    let hint = if cfg!(windows) {
+        "check Device Manager for the port's COM number, or run setup-ports for a virtual pair"
+    } else {
+        "check the cable and dmesg, or run setup-ports for a virtual pair"
+    };
+    Check::new(label, Status::Fail, "no such port").hint(hint)
+}
+
+fn check_instance(label: &str, name: &str) -> Check {
+    match InstanceLock::acquire(&[name], false) {
+        Ok(_lock) => Check::new(label, Status::Ok, "no other maitouch instance"),
+        Err(err) => Check::new(label, Status::Fail, format!("{:#}", err))
+            .hint("stop the other instance, it will fight this one over the port"),
+    }
+}
+
+// Any process with the device open, found through /proc
+#[cfg(target_os = "linux")]
+fn check_holders(label: &str, name: &str) -> Check {
+    let Ok(device) = std::fs::canonicalize(name) else {
+        return Check::new(label, Status::Ok, "not checked");
+    };
+    let own = std::process::id().to_string();
+    let mut holders = Vec::new();
+    for process in std::fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let pid = process.file_name().to_string_lossy().into_owned();
+        if pid == own || !pid.bytes().all(|byte| byte.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let holds = fds
+            .flatten()
+            .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|target| target == device));
+        if holds {
+            let command = std::fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            holders.push(format!("{} ({})", pid, command.trim()));
+        }
+    }
+    if holders.is_empty() {
+        Check::new(label, Status::Ok, "nothing else has it open")
+    } else {
+        Check::new(
+            label,
+            Status::Warn,
+            format!("held open by {}", holders.join(", ")),
+        )
+        .hint("close the other program (ModemManager, a serial console) before starting the proxy")
+    }
+}
+
+fn check_open(label: &str, name: &str) -> Check {
+    match serialport::new(name, ports::DEFAULT_BAUD).open() {
+        Ok(_) => Check::new(label, Status::Ok, "opened"),
+        Err(err) => {
+            let check = Check::new(label, Status::Fail, err.to_string());
+            match err.kind() {
+                serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) if cfg!(unix) => {
+                    check.hint(
+                        "add yourself to the dialout group (sudo usermod -aG dialout $USER) and \
+                         log in again",
+                    )
+                }
+                serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => {
+                    check.hint("another program has the port open")
+                }
+                _ => check,
+            }
+        }
+    }
+}
+
+fn usb_info(name: &str) -> Option<UsbPortInfo> {
+    #[cfg(unix)]
+    let name = std::fs::canonicalize(name)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| name.to_string());
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|port| port.port_name.eq_ignore_ascii_case(&name))
+        .and_then(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) => Some(usb),
+            _ => None,
+        })
+}
+
+fn check_driver(label: &str, usb: Option<&UsbPortInfo>) -> Check {
+    let Some(usb) = usb else {
+        return Check::new(label, Status::Ok, "not a USB adapter");
+    };
+    let describe = |text: &Option<String>| text.clone().unwrap_or_else(|| "?".to_string());
+    Check::new(
+        label,
+        Status::Ok,
+        format!(
+            "USB {:04x}:{:04x} {} {}",
+            usb.vid,
+            usb.pid,
+            describe(&usb.manufacturer),
+            describe(&usb.product)
+        ),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn check_latency_timer(label: &str, name: &str) -> Check {
+    let Some(path) = latency::sysfs_path(name) else {
+        return Check::new(label, Status::Ok, "not readable");
+    };
+    let Some(latency) = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|text| text.trim().parse::<u32>().ok())
+    else {
+        return Check::new(label, Status::Ok, "not readable");
+    };
+    if latency <= FTDI_GOOD_LATENCY_MS {
+        Check::new(label, Status::Ok, format!("{}ms", latency))
+    } else {
+        Check::new(
+            label,
+            Status::Warn,
+            format!("{}ms, frames will arrive in bursts", latency),
+        )
+        .hint(format!(
+            "start the proxy with --set-latency-timer {0}, or echo {0} | sudo tee {1}",
+            FTDI_GOOD_LATENCY_MS,
+            path.display()
+        ))
+    }
+}
+
+// The proxy can't read or set the timer here, so this only says where it is
+#[cfg(not(target_os = "linux"))]
+fn check_latency_timer(label: &str, _name: &str) -> Check {
+    Check::new(
+        label,
+        Status::Warn,
+        "can't be read on this platform, FTDI adapters default to 16ms",
+    )
+    .hint(
+        "set it to 1 in Device Manager (the port's Properties > Port Settings > Advanced) \
+         and plug the adapter in again",
+    )
+}
+
+// Resets the board and sends one sensitivity command, expecting it echoed
+fn check_handshake(adx: &str) -> Check {
+    let label = format!("ADX {} handshake", adx);
+    let spec = WireSpec::maimai();
+    let mut port = match ports::open(adx) {
+        Ok(port) => port,
+        Err(err) => return Check::new(label, Status::Fail, format!("{:#}", err)),
+    };
+    let command = spec.command("LAr2");
+    let mut expected = command.clone();
+    expected[0] = spec.adx.open as u8;
+    *expected.last_mut().unwrap() = spec.adx.close as u8;
+    let result = (|| -> std::io::Result<Vec<u8>> {
+        let mut writer = port.port.try_clone()?;
+        writer.write_all(&spec.command(command::RSET))?;
+        writer.write_all(&spec.command(command::HALT))?;
+        std::thread::sleep(Duration::from_millis(100));
+        port.port.clear(serialport::ClearBuffer::Input)?;
+        writer.write_all(&command)?;
+        let retry = Retry::until(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &MonotonicClock,
+            Some(Instant::now() + HANDSHAKE_TIMEOUT),
+        );
+        let mut response = Vec::new();
+        read_response(
+            &mut response,
+            &mut BufReader::new(&mut port.port),
+            &spec,
+            &command,
+            &retry,
+        )?;
+        Ok(response)
+    })();
+    match result {
+        Ok(response) if response == expected => Check::new(label, Status::Ok, "board answered"),
+        Ok(response) => Check::new(
+            label,
+            Status::Warn,
+            format!("unexpected answer {}", String::from_utf8_lossy(&response)),
+        )
+        .hint("the board may be running at another baud rate"),
+        Err(err) => Check::new(label, Status::Fail, format!("no answer: {}", err))
+            .hint("check the board is powered and on this port"),
+    }
+}
+
+// Streams for a moment and looks at what each read on the port brought in
+fn check_delivery(adx: &str) -> Check {
+    let label = format!("ADX {} delivery", adx);
+    let spec = WireSpec::maimai();
+    let stats = Arc::new(ReadStats::default());
+    let mut port = match ports::open(adx) {
+        Ok(port) => CountingPort::new(port.port, stats.clone()),
+        Err(err) => return Check::new(label, Status::Fail, format!("{:#}", err)),
+    };
+    let result = (|| -> std::io::Result<()> {
+        port.write_all(&spec.command(command::STAT))?;
+        let end = Instant::now() + STREAM_SAMPLE;
+        let mut buf = [0u8; 256];
+        while Instant::now() < end {
+            match port.read(&mut buf) {
+                Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        port.write_all(&spec.command(command::HALT))
+    })();
+    let totals = stats.snapshot();
+    if let Err(err) = result {
+        return Check::new(label, Status::Fail, format!("streaming failed: {}", err));
+    }
+    if totals.bytes == 0 {
+        return Check::new(label, Status::Warn, "no frames after {STAT}")
+            .hint("the board answered its handshake but didn't stream");
+    }
+    if totals.trickling() {
+        return Check::new(
+            label,
+            Status::Warn,
+            format!("bytes trickle in one at a time: {}", totals),
+        )
+        .hint(
+            "a USB adapter's latency timer or driver is passing every byte on by itself; \
+             check its latency timer and try another driver or adapter",
+        );
+    }
+    Check::new(label, Status::Ok, totals.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_report_gives_each_check_a_line_and_its_hint_another() {
+        let checks = [
+            Check::new("ADX exists", Status::Ok, "found"),
+            Check::new("ADX open", Status::Fail, "denied").hint("join dialout"),
+        ];
+        let plain = Report {
+            checks: &checks,
+            color: false,
+        };
+        assert_eq!(
+            plain.to_string(),
+            "[ OK ] ADX exists: found\n[FAIL] ADX open: denied\n       -> join dialout\n"
+        );
+        let colored = Report {
+            checks: &checks[..1],
+            color: true,
+        };
+        assert_eq!(
+            colored.to_string(),
+            "\x1b[32m[ OK ]\x1b[0m ADX exists: found\n"
+        );
+    }
+
+    #[test]
+    fn a_port_the_proxy_creates_needs_no_checks() {
+        let checks = check_port("ALLS", "pty:");
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].status == Status::Ok);
+        assert_eq!(checks[0].name, "ALLS pty: port");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_missing_port_fails_and_stops_there() {
+        let checks = check_port("ADX", "/dev/maitouch-no-such-port");
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].status == Status::Fail);
+        assert_eq!(checks[0].detail, "no such port");
+        assert!(checks[0].hint.is_some());
+    }
+
+    #[test]
+    fn a_plain_port_isnt_reported_as_usb() {
+        let check = check_driver("ADX driver", None);
+        assert!(check.status == Status::Ok);
+        assert_eq!(check.detail, "not a USB adapter");
+    }
+
+    // A board on the far end of a PTY that answers {LAr2} with `answer` and
+    // streams its frames between {STAT} and {HALT}, for as long as the test
+    // takes. Each frame goes out in one write, or a byte at a time with
+    // `trickle`.
+    #[cfg(unix)]
+    fn with_board(answer: &'static [u8], trickle: bool, test: impl FnOnce(&str)) {
+        use serialport::{SerialPort, TTYPort};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (mut board, slave) = TTYPort::pair().unwrap();
+        board.set_timeout(Duration::from_millis(2)).unwrap();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut streaming = false;
+                let mut sent = 0;
+                let mut got = Vec::new();
+                let mut buf = [0u8; 64];
+                while !done.load(Ordering::Relaxed) {
+                    if let Ok(n) = board.read(&mut buf) {
+                        got.extend_from_slice(&buf[..n]);
+                    }
+                    while let Some(end) = got.iter().position(|&byte| byte == b'}') {
+                        let command: Vec<u8> = got.drain(..=end).collect();
+                        match command.as_slice() {
+                            b"{LAr2}" => board.write_all(answer).unwrap(),
+                            b"{STAT}" => streaming = true,
+                            b"{HALT}" => streaming = false,
+                            _ => {}
+                        }
+                    }
+                    if streaming {
+                        let frame = b"(\x01\x00\x00\x00\x00\x00\x00)";
+                        let _ = if trickle {
+                            board.write_all(&frame[sent % frame.len()..][..1])
+                        } else {
+                            board.write_all(frame)
+                        };
+                        sent += 1;
+                    }
+                }
+            });
+            test(&slave.name().unwrap());
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_that_echoes_passes_the_handshake_and_delivers_whole_frames() {
+        with_board(b"(LAr2)", false, |adx| {
+            let handshake = check_handshake(adx);
+            assert!(handshake.status == Status::Ok, "{}", handshake.detail);
+            let delivery = check_delivery(adx);
+            assert!(delivery.status == Status::Ok, "{}", delivery.detail);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_whose_bytes_trickle_in_is_called_out() {
+        with_board(b"(LAr2)", true, |adx| {
+            let check = check_delivery(adx);
+            assert!(check.status == Status::Warn, "{}", check.detail);
+            assert!(
+                check.detail.starts_with("bytes trickle in one at a time: "),
+                "{}",
+                check.detail
+            );
+            assert!(check.hint.unwrap().contains("latency timer"));
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_with_the_wrong_answer_is_warned_about() {
+        with_board(b"(LBr2)", false, |adx| {
+            let check = check_handshake(adx);
+            assert!(check.status == Status::Warn);
+            assert_eq!(check.detail, "unexpected answer (LBr2)");
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_silent_board_fails_the_handshake() {
+        with_board(b"", false, |adx| {
+            let check = check_handshake(adx);
+            assert!(check.status == Status::Fail);
+            assert!(check.detail.starts_with("no answer: "), "{}", check.detail);
+        });
+    }
+}
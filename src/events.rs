@@ -0,0 +1,246 @@
+use anyhow::{Context, Result};
+use maitouch_protocol::touch::{Region, TouchState};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CSV_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Press,
+    Release,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Press => "press",
+            Action::Release => "release",
+        }
+    }
+}
+
+// Region bitmask of `state`, A1 = bit 0 through E8 = bit 33
+pub fn mask(state: TouchState) -> u64 {
+    Region::all()
+        .filter(|&region| state.is_active(region))
+        .fold(0, |mask, region| mask | 1 << region.index())
+}
+
+// What changed from one touch state to the next, as region bitmasks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub pressed: u64,
+    pub released: u64,
+}
+
+impl Diff {
+    // Per-region events, in region order, A1 first
+    pub fn events(self) -> impl Iterator<Item = (Region, Action)> {
+        Region::all().filter_map(move |region| {
+            let bit = 1 << region.index();
+            if self.pressed & bit != 0 {
+                Some((region, Action::Press))
+            } else if self.released & bit != 0 {
+                Some((region, Action::Release))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// Turns successive touch states into what changed between them. This is
+// the one place the diff is worked out; the event CSV, the press counts
+// and the shared-memory diff all take it from here. The previous state is
+// the previous one given, so frames the low latency reader skipped are
+// folded into the next diff.
+#[derive(Default)]
+pub struct TransitionDetector {
+    last: u64,
+}
+
+impl TransitionDetector {
+    pub fn update(&mut self, state: TouchState) -> Diff {
+        let current = mask(state);
+        let last = std::mem::replace(&mut self.last, current);
+        Diff {
+            pressed: current & !last,
+            released: last & !current,
+        }
+    }
+}
+
+// Timeline of touch events for lining up with gameplay video
+pub struct EventCsv {
+    writer: BufWriter<File>,
+    session: String,
+    start: Instant,
+    start_unix: Duration,
+    last_flush: Instant,
+}
+
+impl EventCsv {
+    pub fn create(path: &str) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating event CSV {}", path))?;
+        let start = Instant::now();
+        let start_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let session = format!("{:x}-{:x}", start_unix.as_secs(), std::process::id());
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "session,timestamp_us,monotonic_us,region,action")?;
+        tracing::info!("Writing touch events to {} as session {}", path, session);
+        Ok(EventCsv {
+            writer,
+            session,
+            start,
+            start_unix,
+            last_flush: start,
+        })
+    }
+
+    // Unix time is derived from the monotonic clock so rows stay ordered
+    // even if the system clock is adjusted mid-session
+    pub fn record(&mut self, at: Instant, region: Region, action: Action) -> Result<()> {
+        let monotonic = at.saturating_duration_since(self.start);
+        writeln!(
+            self.writer,
+            "{},{},{},{},{}",
+            self.session,
+            (self.start_unix + monotonic).as_micros(),
+            monotonic.as_micros(),
+            region,
+            action.name()
+        )?;
+        if at.saturating_duration_since(self.last_flush) >= CSV_FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touching(regions: &[Region]) -> TouchState {
+        let mut state = TouchState::default();
+        for &region in regions {
+            state.set(region, true);
+        }
+        state
+    }
+
+    #[test]
+    fn the_mask_has_one_bit_per_region() {
+        let regions: Vec<Region> = Region::all().collect();
+        assert_eq!(mask(TouchState::default()), 0);
+        assert_eq!(mask(touching(&regions)), (1 << 34) - 1);
+        assert_eq!(mask(touching(&["A1".parse().unwrap()])), 1);
+        assert_eq!(mask(touching(&["E8".parse().unwrap()])), 1 << 33);
+    }
+
+    #[test]
+    fn every_region_goes_through_every_transition() {
+        for region in Region::all() {
+            let (on, off) = (touching(&[region]), TouchState::default());
+            let bit = 1 << region.index();
+            let mut detector = TransitionDetector::default();
+            // Off to off, off to on, on to on and on to off
+            assert_eq!(detector.update(off), Diff::default());
+            let pressed = detector.update(on);
+            assert_eq!(
+                pressed,
+                Diff {
+                    pressed: bit,
+                    released: 0
+                }
+            );
+            assert_eq!(
+                pressed.events().collect::<Vec<_>>(),
+                [(region, Action::Press)]
+            );
+            assert_eq!(detector.update(on), Diff::default());
+            let released = detector.update(off);
+            assert_eq!(
+                released,
+                Diff {
+                    pressed: 0,
+                    released: bit
+                }
+            );
+            assert_eq!(
+                released.events().collect::<Vec<_>>(),
+                [(region, Action::Release)]
+            );
+        }
+    }
+
+    #[test]
+    fn every_pair_of_regions_changes_independently() {
+        let regions: Vec<Region> = Region::all().collect();
+        for &held in &regions {
+            for &moved in regions.iter().filter(|&&region| region != held) {
+                let mut detector = TransitionDetector::default();
+                detector.update(touching(&[held]));
+                let diff = detector.update(touching(&[moved]));
+                assert_eq!(diff.pressed, 1 << moved.index());
+                assert_eq!(diff.released, 1 << held.index());
+                assert_eq!(
+                    detector.update(touching(&[held, moved])).pressed,
+                    1 << held.index()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn events_come_in_region_order() {
+        let regions: Vec<Region> = ["E8", "A1", "C2"]
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        let mut detector = TransitionDetector::default();
+        detector.update(touching(&regions[1..]));
+        let events: Vec<String> = detector
+            .update(touching(&regions[..1]))
+            .events()
+            .map(|(region, action)| format!("{} {}", region, action.name()))
+            .collect();
+        assert_eq!(events, ["A1 release", "C2 release", "E8 press"]);
+    }
+
+    #[test]
+    fn the_csv_stamps_each_event_from_the_session_start() {
+        let path = std::env::temp_dir().join(format!("maitouch-events-{}.csv", std::process::id()));
+        let mut csv = EventCsv::create(path.to_str().unwrap()).unwrap();
+        let start = csv.start;
+        csv.record(
+            start + Duration::from_millis(5),
+            "B3".parse().unwrap(),
+            Action::Press,
+        )
+        .unwrap();
+        csv.flush().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "session,timestamp_us,monotonic_us,region,action");
+        let row: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(row[0], csv.session);
+        let unix: u128 = row[1].parse().unwrap();
+        assert_eq!(
+            unix,
+            (csv.start_unix + Duration::from_millis(5)).as_micros()
+        );
+        assert_eq!(row[2..], ["5000", "B3", "press"]);
+    }
+}
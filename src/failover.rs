@@ -0,0 +1,673 @@
+use crate::clock::Clock;
+use crate::ports::{self, Endpoint};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often a dead primary is reopened
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(2);
+// How long a read waits while there is no port to read from
+const OUTAGE_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Side {
+    Primary,
+    Backup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SilenceAction {
+    Warn,
+    Reconnect,
+}
+
+impl SilenceAction {
+    pub const NAMES: &'static [&'static str] = &["warn", "reconnect"];
+}
+
+impl FromStr for SilenceAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(SilenceAction::Warn),
+            "reconnect" => Ok(SilenceAction::Reconnect),
+            _ => bail!("unknown silence action {}, expected warn or reconnect", s),
+        }
+    }
+}
+
+// A wedged virtual pair still takes writes but never delivers the game's
+// side, so the proxy hears nothing back. The game is legitimately quiet
+// while streaming too, so `after` has to outlast that.
+#[derive(Clone, Copy)]
+pub struct SilencePolicy {
+    pub after: Duration,
+    pub action: SilenceAction,
+}
+
+struct State {
+    active: Side,
+    reclaiming: bool,
+    // A reopened primary, waiting for each half to take its end
+    reclaimed_reader: Option<Endpoint>,
+    reclaimed_writer: Option<Box<dyn Write + Send>>,
+    // When the primary last sent anything, and whether its silence since
+    // has been reported
+    last_heard: Instant,
+    silence_reported: bool,
+}
+
+// Which ALLS endpoint is in use, shared by the reading and writing halves.
+// Either half that sees the primary fail switches both over; only the
+// active side is ever read or written, so frames aren't duplicated. With
+// no backup, the outage is waited out: frames are dropped and reads come
+// back empty until the primary is reopened.
+struct Failover<C> {
+    primary_name: String,
+    has_backup: bool,
+    silence: Option<SilencePolicy>,
+    // Opens the primary again once it has failed
+    reopen: Reopen,
+    clock: C,
+    state: Mutex<State>,
+}
+
+type Reopen = Box<dyn Fn(&str) -> Result<Endpoint> + Send + Sync>;
+
+impl<C: Clock + Send + Sync + 'static> Failover<C> {
+    fn active(&self) -> Side {
+        self.state.lock().unwrap().active
+    }
+
+    // Whether a failing primary is worked around rather than reported
+    fn recovers(&self) -> bool {
+        self.has_backup
+            || self
+                .silence
+                .is_some_and(|silence| silence.action == SilenceAction::Reconnect)
+    }
+
+    fn fail_over(self: &Arc<Self>, reason: &dyn fmt::Display) {
+        let mut state = self.state.lock().unwrap();
+        if state.active == Side::Backup {
+            return;
+        }
+        state.active = Side::Backup;
+        state.reclaimed_reader = None;
+        state.reclaimed_writer = None;
+        let next = if self.has_backup {
+            "switching to the backup"
+        } else {
+            "reopening it"
+        };
+        tracing::warn!(
+            "!!! ALLS port {} failed ({}), {}",
+            self.primary_name,
+            reason,
+            next
+        );
+        if !std::mem::replace(&mut state.reclaiming, true) {
+            let failover = self.clone();
+            thread::Builder::new()
+                .name("alls reclaim".into())
+                .spawn(move || failover.reclaim())
+                .expect("spawning the ALLS reclaim thread");
+        }
+    }
+
+    // Reopens the primary until it comes back, then switches to it again
+    fn reclaim(&self) {
+        loop {
+            self.clock.sleep_until(self.clock.now() + RECLAIM_INTERVAL);
+            match (self.reopen)(&self.primary_name) {
+                Ok(mut endpoint) => {
+                    let writer = std::mem::replace(&mut endpoint.writer, Box::new(io::sink()));
+                    let mut state = self.state.lock().unwrap();
+                    state.reclaimed_reader = Some(endpoint);
+                    state.reclaimed_writer = Some(writer);
+                    state.active = Side::Primary;
+                    state.reclaiming = false;
+                    state.last_heard = self.clock.now();
+                    state.silence_reported = false;
+                    tracing::info!("ALLS port {} is back, switching to it", self.primary_name);
+                    return;
+                }
+                Err(err) => tracing::debug!(
+                    "ALLS port {} still unavailable: {:#}",
+                    self.primary_name,
+                    err
+                ),
+            }
+        }
+    }
+
+    fn heard(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.last_heard = self.clock.now();
+        state.silence_reported = false;
+    }
+
+    // Called after a successful write to the primary
+    fn check_silence(self: &Arc<Self>) {
+        let Some(policy) = self.silence else {
+            return;
+        };
+        let silent_for = {
+            let mut state = self.state.lock().unwrap();
+            let silent_for = self.clock.now() - state.last_heard;
+            if state.silence_reported || silent_for < policy.after {
+                return;
+            }
+            state.silence_reported = true;
+            silent_for
+        };
+        tracing::warn!(
+            "!!! Nothing from the ALLS on {} for {:.0?} while it takes every frame. If it's a \
+             virtual pair (com0com), it may be wedged; recreating the pair fixes that.",
+            self.primary_name,
+            silent_for
+        );
+        if policy.action == SilenceAction::Reconnect {
+            self.fail_over(&format_args!("silent for {:.0?}", silent_for));
+        }
+    }
+}
+
+// Splits the ALLS endpoints into a reader and a writer that follow
+// whichever is active, starting with the primary
+pub fn split<C: Clock + Send + Sync + 'static>(
+    primary_name: &str,
+    primary: Endpoint,
+    backup: Option<Endpoint>,
+    silence: Option<SilencePolicy>,
+    clock: C,
+) -> (FailoverReader<C>, FailoverWriter<C>) {
+    let reopen = Box::new(ports::open_endpoint);
+    split_reopening(primary_name, primary, backup, silence, clock, reopen)
+}
+
+fn split_reopening<C: Clock + Send + Sync + 'static>(
+    primary_name: &str,
+    mut primary: Endpoint,
+    backup: Option<Endpoint>,
+    silence: Option<SilencePolicy>,
+    clock: C,
+    reopen: Reopen,
+) -> (FailoverReader<C>, FailoverWriter<C>) {
+    let last_heard = clock.now();
+    let failover = Arc::new(Failover {
+        primary_name: primary_name.to_string(),
+        has_backup: backup.is_some(),
+        silence,
+        reopen,
+        clock,
+        state: Mutex::new(State {
+            active: Side::Primary,
+            reclaiming: false,
+            reclaimed_reader: None,
+            reclaimed_writer: None,
+            last_heard,
+            silence_reported: false,
+        }),
+    });
+    let primary_writer = std::mem::replace(&mut primary.writer, Box::new(io::sink()));
+    let (backup, backup_writer) = match backup {
+        Some(mut backup) => {
+            let writer = std::mem::replace(&mut backup.writer, Box::new(io::sink()));
+            (Some(backup), Some(writer))
+        }
+        None => (None, None),
+    };
+    let reader = FailoverReader {
+        failover: failover.clone(),
+        primary: Some(primary),
+        backup,
+    };
+    let writer = FailoverWriter {
+        failover,
+        primary: Some(primary_writer),
+        backup: backup_writer,
+    };
+    (reader, writer)
+}
+
+// The reader keeps the whole endpoints, so a PTY backup stays open
+pub struct FailoverReader<C> {
+    failover: Arc<Failover<C>>,
+    primary: Option<Endpoint>,
+    backup: Option<Endpoint>,
+}
+
+impl<C> FailoverReader<C> {
+    fn read_backup(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.backup.as_mut() {
+            Some(backup) => backup.reader.read(buf),
+            None => {
+                thread::sleep(OUTAGE_POLL);
+                Err(ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+impl<C: Clock + Send + Sync + 'static> Read for FailoverReader<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failover.active() == Side::Backup {
+            // Let go of the dead port so it can be reopened
+            self.primary = None;
+            return self.read_backup(buf);
+        }
+        if self.primary.is_none() {
+            self.primary = self.failover.state.lock().unwrap().reclaimed_reader.take();
+        }
+        let Some(primary) = self.primary.as_mut() else {
+            return self.read_backup(buf);
+        };
+        let err = match primary.reader.read(buf) {
+            Ok(0) if !buf.is_empty() => ErrorKind::UnexpectedEof.into(),
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                return Err(err)
+            }
+            Err(err) => err,
+            Ok(read) => {
+                self.failover.heard();
+                return Ok(read);
+            }
+        };
+        if !self.failover.recovers() {
+            return Err(err);
+        }
+        self.primary = None;
+        self.failover.fail_over(&err);
+        // Looks like any other quiet spell to the caller, which reads again
+        Err(ErrorKind::TimedOut.into())
+    }
+}
+
+pub struct FailoverWriter<C> {
+    failover: Arc<Failover<C>>,
+    primary: Option<Box<dyn Write + Send>>,
+    backup: Option<Box<dyn Write + Send>>,
+}
+
+impl<C: Clock + Send + Sync + 'static> FailoverWriter<C> {
+    // The writer to use next, after catching up with any switchover. None
+    // while there is nothing to write to.
+    fn current(&mut self) -> (Side, Option<&mut (dyn Write + Send)>) {
+        let mut state = self.failover.state.lock().unwrap();
+        match state.active {
+            Side::Backup => self.primary = None,
+            Side::Primary if self.primary.is_none() => {
+                self.primary = state.reclaimed_writer.take();
+            }
+            Side::Primary => {}
+        }
+        match self.primary.as_mut() {
+            Some(primary) if state.active == Side::Primary => {
+                (Side::Primary, Some(primary.as_mut()))
+            }
+            _ => (
+                Side::Backup,
+                self.backup.as_mut().map(|backup| backup.as_mut() as _),
+            ),
+        }
+    }
+
+    // Runs a write on the active side. Returns `idle` without writing
+    // anything while neither side is available.
+    fn with_active<T>(
+        &mut self,
+        idle: T,
+        mut op: impl FnMut(&mut (dyn Write + Send)) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let (side, writer) = self.current();
+        let Some(writer) = writer else {
+            return Ok(idle);
+        };
+        match op(writer) {
+            Ok(written) if side == Side::Primary => {
+                self.failover.check_silence();
+                Ok(written)
+            }
+            Err(err)
+                if side == Side::Primary
+                    && err.kind() != ErrorKind::Interrupted
+                    && self.failover.recovers() =>
+            {
+                self.primary = None;
+                self.failover.fail_over(&err);
+                match self.backup.as_deref_mut() {
+                    Some(backup) => op(backup),
+                    None => Ok(idle),
+                }
+            }
+            result => result,
+        }
+    }
+}
+
+impl<C: Clock + Send + Sync + 'static> Write for FailoverWriter<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_active(buf.len(), |writer| writer.write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.with_active((), |writer| writer.write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_active((), |writer| writer.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::logcapture::capturing;
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{self, Sender};
+
+    // A scripted ALLS line: what the game has sent on it, what it got, and
+    // whether the line has died
+    #[derive(Clone, Default)]
+    struct Line(Arc<Mutex<LineState>>);
+
+    #[derive(Default)]
+    struct LineState {
+        incoming: VecDeque<u8>,
+        written: Vec<u8>,
+        dead: bool,
+    }
+
+    impl Line {
+        fn endpoint(&self) -> Endpoint {
+            Endpoint::new(Box::new(self.clone()), Box::new(self.clone()))
+        }
+
+        fn game_sends(&self, bytes: &[u8]) {
+            self.0.lock().unwrap().incoming.extend(bytes);
+        }
+
+        fn written(&self) -> Vec<u8> {
+            self.0.lock().unwrap().written.clone()
+        }
+
+        fn dies(&self) {
+            self.0.lock().unwrap().dead = true;
+        }
+    }
+
+    impl Read for Line {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut line = self.0.lock().unwrap();
+            if line.dead {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            if line.incoming.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let read = buf.len().min(line.incoming.len());
+            for (slot, byte) in buf.iter_mut().zip(line.incoming.drain(..read)) {
+                *slot = byte;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for Line {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut line = self.0.lock().unwrap();
+            if line.dead {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            line.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Halves = (
+        FailoverReader<Arc<MockClock>>,
+        FailoverWriter<Arc<MockClock>>,
+    );
+
+    // Splits the lines on `clock`. Each time the primary is reopened it's
+    // the next line sent on the channel handed back, and it never comes
+    // back once that's dropped.
+    fn split_lines(
+        primary: &Line,
+        backup: Option<&Line>,
+        silence: Option<SilencePolicy>,
+        clock: &Arc<MockClock>,
+    ) -> (Halves, Sender<Line>) {
+        let (reopened, lines) = mpsc::channel::<Line>();
+        let lines = Mutex::new(lines);
+        let reopen: Reopen = Box::new(move |_| match lines.lock().unwrap().recv() {
+            Ok(line) => Ok(line.endpoint()),
+            Err(_) => loop {
+                thread::park();
+            },
+        });
+        let halves = split_reopening(
+            "COM4",
+            primary.endpoint(),
+            backup.map(Line::endpoint),
+            silence,
+            clock.clone(),
+            reopen,
+        );
+        (halves, reopened)
+    }
+
+    // What the game has sent, up to the first read that times out
+    fn read_waiting(reader: &mut impl Read) -> Vec<u8> {
+        let mut got = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(read) => got.extend_from_slice(&buf[..read]),
+                Err(err) => {
+                    assert_eq!(err.kind(), ErrorKind::TimedOut);
+                    return got;
+                }
+            }
+        }
+    }
+
+    // The primary is reopened on a thread of its own
+    fn wait_until_on(side: Side, writer: &FailoverWriter<Arc<MockClock>>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while writer.failover.active() != side {
+            assert!(Instant::now() < deadline, "still not on the {:?}", side);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn only_the_primary_is_used_while_it_works() {
+        let (primary, backup) = (Line::default(), Line::default());
+        let clock = Arc::new(MockClock::new());
+        let ((mut reader, mut writer), _reopened) =
+            split_lines(&primary, Some(&backup), None, &clock);
+        writer.write_all(b"(1)").unwrap();
+        writer.write_all(b"(2)").unwrap();
+        primary.game_sends(b"{HALT}");
+        backup.game_sends(b"{STAT}");
+
+        assert_eq!(read_waiting(&mut reader), b"{HALT}");
+        assert_eq!(primary.written(), b"(1)(2)");
+        assert_eq!(backup.written(), b"");
+    }
+
+    #[test]
+    fn a_flapping_primary_is_switched_away_from_and_back_each_time() {
+        let (primary, backup) = (Line::default(), Line::default());
+        let clock = Arc::new(MockClock::new());
+        let ((mut reader, mut writer), reopened) =
+            split_lines(&primary, Some(&backup), None, &clock);
+        writer.write_all(b"(1)").unwrap();
+
+        // The writer notices first, and its frame goes to the backup
+        primary.dies();
+        capturing(|log| {
+            writer.write_all(b"(2)").unwrap();
+            assert_eq!(
+                log.take(),
+                ["!!! ALLS port COM4 failed (broken pipe), switching to the backup"]
+            );
+        });
+        backup.game_sends(b"{LAr2}");
+        assert_eq!(read_waiting(&mut reader), b"{LAr2}");
+
+        let reclaimed = Line::default();
+        reopened.send(reclaimed.clone()).unwrap();
+        wait_until_on(Side::Primary, &writer);
+        writer.write_all(b"(3)").unwrap();
+        reclaimed.game_sends(b"{STAT}");
+        backup.game_sends(b"{RSET}");
+        assert_eq!(read_waiting(&mut reader), b"{STAT}");
+
+        // This time the reader notices
+        reclaimed.dies();
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        writer.write_all(b"(4)").unwrap();
+        assert_eq!(read_waiting(&mut reader), b"{RSET}");
+
+        let again = Line::default();
+        reopened.send(again.clone()).unwrap();
+        wait_until_on(Side::Primary, &writer);
+        writer.write_all(b"(5)").unwrap();
+
+        // Each frame went out once, on whichever side was active
+        assert_eq!(primary.written(), b"(1)");
+        assert_eq!(backup.written(), b"(2)(4)");
+        assert_eq!(reclaimed.written(), b"(3)");
+        assert_eq!(again.written(), b"(5)");
+    }
+
+    #[test]
+    fn without_a_way_to_recover_a_failure_is_passed_on() {
+        let primary = Line::default();
+        let clock = Arc::new(MockClock::new());
+        let ((mut reader, mut writer), _reopened) = split_lines(&primary, None, None, &clock);
+        primary.dies();
+
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            writer.write_all(b"(1)").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+
+    const WEDGED: &str = "!!! Nothing from the ALLS on COM4 for 30s while it takes every frame. \
+                          If it's a virtual pair (com0com), it may be wedged; recreating the \
+                          pair fixes that.";
+
+    fn silence(action: SilenceAction) -> Option<SilencePolicy> {
+        Some(SilencePolicy {
+            after: Duration::from_secs(30),
+            action,
+        })
+    }
+
+    #[test]
+    fn a_game_quiet_for_less_than_the_threshold_isnt_warned_about() {
+        let primary = Line::default();
+        let clock = Arc::new(MockClock::new());
+        let ((mut reader, mut writer), _reopened) =
+            split_lines(&primary, None, silence(SilenceAction::Warn), &clock);
+        capturing(|log| {
+            // A song's worth of quiet, with a poll now and then
+            for _ in 0..10 {
+                clock.advance(Duration::from_secs(29));
+                writer.write_all(b"(1)").unwrap();
+                primary.game_sends(b"{LAr2}");
+                read_waiting(&mut reader);
+            }
+            assert!(log.take().is_empty());
+        });
+    }
+
+    #[test]
+    fn silence_past_the_threshold_is_warned_about_once() {
+        let primary = Line::default();
+        let clock = Arc::new(MockClock::new());
+        let ((mut reader, mut writer), _reopened) =
+            split_lines(&primary, None, silence(SilenceAction::Warn), &clock);
+        capturing(|log| {
+            clock.advance(Duration::from_secs(29));
+            writer.write_all(b"(1)").unwrap();
+            assert!(log.take().is_empty());
+            clock.advance(Duration::from_secs(1));
+            writer.write_all(b"(2)").unwrap();
+            assert_eq!(log.take(), [WEDGED]);
+            clock.advance(Duration::from_secs(60));
+            writer.write_all(b"(3)").unwrap();
+            assert!(log.take().is_empty());
+
+            // Hearing from the game starts it over
+            primary.game_sends(b"{LAr2}");
+            read_waiting(&mut reader);
+            clock.advance(Duration::from_secs(30));
+            writer.write_all(b"(4)").unwrap();
+            assert_eq!(log.take(), [WEDGED]);
+        });
+        // Warning is all it does
+        assert_eq!(writer.failover.active(), Side::Primary);
+        assert_eq!(primary.written(), b"(1)(2)(3)(4)");
+    }
+
+    #[test]
+    fn silence_past_the_threshold_reconnects_with_reconnect() {
+        let primary = Line::default();
+        let clock = Arc::new(MockClock::new());
+        let ((mut reader, mut writer), reopened) =
+            split_lines(&primary, None, silence(SilenceAction::Reconnect), &clock);
+        clock.advance(Duration::from_secs(30));
+        capturing(|log| {
+            writer.write_all(b"(1)").unwrap();
+            assert_eq!(
+                log.take(),
+                [
+                    WEDGED,
+                    "!!! ALLS port COM4 failed (silent for 30s), reopening it"
+                ]
+            );
+        });
+
+        // With no backup, the outage drops frames and reads come back empty
+        writer.write_all(b"(2)").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+
+        let reopened_line = Line::default();
+        reopened.send(reopened_line.clone()).unwrap();
+        wait_until_on(Side::Primary, &writer);
+        writer.write_all(b"(3)").unwrap();
+        reopened_line.game_sends(b"{LAr2}");
+        assert_eq!(read_waiting(&mut reader), b"{LAr2}");
+
+        assert_eq!(primary.written(), b"(1)");
+        assert_eq!(reopened_line.written(), b"(3)");
+    }
+}
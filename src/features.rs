@@ -0,0 +1,251 @@
+use crate::clock::MonotonicClock;
+use crate::read_packet;
+use crate::retry::{Retry, RetryPolicy};
+use crate::wire::WireSpec;
+use std::fmt;
+use std::io::{BufRead, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+// Firmware without the extension says nothing, so the probe gives up on it
+// after this long
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+// A u32 bitmap is at most this many hex digits
+const MAX_DIGITS: usize = 8;
+
+// An optional behaviour that forks of the firmware advertise with a bit of
+// the --feature-probe answer, and the option that relies on it
+pub struct Feature {
+    pub bit: u32,
+    pub name: &'static str,
+    pub option: &'static str,
+}
+
+pub const BAUD_SWITCH: Feature = Feature {
+    bit: 0,
+    name: "baud-switch",
+    option: "--upgrade-baud",
+};
+pub const KEEPALIVE: Feature = Feature {
+    bit: 1,
+    name: "keepalive",
+    option: "--adx-keepalive",
+};
+
+// Every bit the proxy knows, also listed by the capabilities tool
+pub const REGISTRY: &[Feature] = &[BAUD_SWITCH, KEEPALIVE];
+
+// The bitmap a board answered the probe with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub fn has(self, feature: &Feature) -> bool {
+        self.0 & (1 << feature.bit) != 0
+    }
+
+    // `response` is the probe's answer with its delimiters: the probe
+    // command's name in ADX delimiters with the bitmap in hex after it, so
+    // {FEAT} is answered like (FEAT3). None for anything else, which is what
+    // a board without the extension sends if it answers at all.
+    pub fn parse(spec: &WireSpec, probe: &[u8], response: &[u8]) -> Option<Self> {
+        let name = probe.get(1..probe.len().checked_sub(1)?)?;
+        let hex = response
+            .strip_prefix(&[spec.adx.open as u8])?
+            .strip_suffix(&[spec.adx.close as u8])?
+            .strip_prefix(name)?;
+        if hex.is_empty() || hex.len() > MAX_DIGITS {
+            return None;
+        }
+        let hex = std::str::from_utf8(hex).ok()?;
+        u32::from_str_radix(hex, 16).ok().map(Features)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<String> = REGISTRY
+            .iter()
+            .filter(|feature| self.has(feature))
+            .map(|feature| feature.name.to_string())
+            .collect();
+        let known = REGISTRY
+            .iter()
+            .fold(0, |known, feature| known | 1 << feature.bit);
+        names.extend(
+            (0..u32::BITS)
+                .filter(|bit| self.0 & !known & (1 << bit) != 0)
+                .map(|bit| format!("bit {}", bit)),
+        );
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+// Sends `probe` to the ADX in config mode and reads its answer. An answer
+// that isn't a bitmap, or none at all, counts as a board with no features,
+// so everything gated on them stays off.
+pub fn probe(
+    spec: &WireSpec,
+    adx_reader: &mut dyn BufRead,
+    adx_writer: &mut dyn Write,
+    probe: &[u8],
+) -> std::io::Result<Features> {
+    tracing::info!(
+        "Probing ADX features with {}",
+        String::from_utf8_lossy(probe)
+    );
+    adx_writer.write_all(probe)?;
+    adx_writer.flush()?;
+    let retry = Retry::until(
+        RetryPolicy {
+            backoff: Duration::ZERO,
+        },
+        &MonotonicClock,
+        Some(Instant::now() + PROBE_TIMEOUT),
+    );
+    let mut response = Vec::with_capacity(spec.command_max_len);
+    let features = match read_packet(&mut response, adx_reader, &spec.adx, &retry) {
+        Ok(_) => match Features::parse(spec, probe, &response) {
+            Some(features) => {
+                tracing::info!("ADX reports features: {} ({:#x})", features, features.0);
+                features
+            }
+            None => {
+                tracing::info!(
+                    "ADX answered the probe with {}, not a feature bitmap; taking it for a board \
+                     without optional features",
+                    String::from_utf8_lossy(&response)
+                );
+                Features::default()
+            }
+        },
+        Err(err) if err.kind() == ErrorKind::TimedOut => {
+            tracing::info!(
+                "No answer to the feature probe, taking the ADX for a board without optional \
+                 features"
+            );
+            Features::default()
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(features)
+}
+
+// Whether an option that relies on `feature` goes ahead. Without a probe
+// the option is taken at its word; with one, only a board that reported
+// the feature gets it.
+pub fn allows(features: Option<Features>, feature: &Feature) -> bool {
+    match features {
+        Some(features) if !features.has(feature) => {
+            tracing::warn!(
+                "!!! Ignoring {}: the ADX didn't report the {} feature",
+                feature.option,
+                feature.name
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, BufReader, Cursor, Read};
+
+    fn parse(response: &[u8]) -> Option<Features> {
+        Features::parse(&WireSpec::maimai(), b"{FEAT}", response)
+    }
+
+    // A board that never answers, as one without the extension
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(10));
+            Err(io::Error::new(ErrorKind::TimedOut, "no answer"))
+        }
+    }
+
+    #[test]
+    fn parses_the_bitmap_after_the_probes_name() {
+        assert_eq!(parse(b"(FEAT3)"), Some(Features(3)));
+        assert_eq!(parse(b"(FEAT0)"), Some(Features(0)));
+        assert_eq!(parse(b"(FEATffffffff)"), Some(Features(u32::MAX)));
+        assert_eq!(parse(b"(FEATa)"), Some(Features(10)));
+    }
+
+    #[test]
+    fn anything_but_a_bitmap_is_no_answer() {
+        for response in [
+            &b"(FEAT)"[..],
+            b"(FEAT100000000)",
+            b"(FEATzz)",
+            b"(LAr2)",
+            b"FEAT3",
+            b"(FEAT3",
+            b"{FEAT3}",
+        ] {
+            assert_eq!(
+                parse(response),
+                None,
+                "{:?}",
+                String::from_utf8_lossy(response)
+            );
+        }
+        assert_eq!(Features::parse(&WireSpec::maimai(), b"", b"()"), None);
+    }
+
+    #[test]
+    fn lists_known_features_by_name_and_the_rest_by_bit() {
+        assert_eq!(Features(0).to_string(), "none");
+        assert_eq!(Features(1).to_string(), "baud-switch");
+        assert_eq!(Features(3).to_string(), "baud-switch, keepalive");
+        assert_eq!(Features(0b1010).to_string(), "keepalive, bit 3");
+    }
+
+    #[test]
+    fn gates_an_option_only_when_probed() {
+        crate::logcapture::capturing(|log| {
+            assert!(allows(None, &KEEPALIVE));
+            assert!(allows(Some(Features(2)), &KEEPALIVE));
+            assert!(log.take().is_empty());
+            assert!(!allows(Some(Features(1)), &KEEPALIVE));
+            assert_eq!(
+                log.take(),
+                ["!!! Ignoring --adx-keepalive: the ADX didn't report the keepalive feature"]
+            );
+        });
+    }
+
+    #[test]
+    fn a_board_with_the_extension_reports_its_features() {
+        let mut sent = Vec::new();
+        let mut board = Cursor::new(b"(FEAT1)".to_vec());
+        let features = probe(&WireSpec::maimai(), &mut board, &mut sent, b"{FEAT}").unwrap();
+        assert_eq!(features, Features(1));
+        assert_eq!(sent, b"{FEAT}");
+    }
+
+    #[test]
+    fn a_board_without_it_reports_none() {
+        let spec = WireSpec::maimai();
+        // Some other answer to a command it doesn't know
+        let mut board = Cursor::new(b"(FE)".to_vec());
+        assert_eq!(
+            probe(&spec, &mut board, &mut Vec::new(), b"{FEAT}").unwrap(),
+            Features::default()
+        );
+        // Or none at all, given up on after the probe's own timeout
+        let started = Instant::now();
+        let mut board = BufReader::new(Silent);
+        assert_eq!(
+            probe(&spec, &mut board, &mut Vec::new(), b"{FEAT}").unwrap(),
+            Features::default()
+        );
+        assert!(started.elapsed() >= PROBE_TIMEOUT);
+    }
+}
@@ -0,0 +1,514 @@
+use crate::clock::Clock;
+use crate::conf;
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::touch::{Packing, Region, TouchState, PAYLOAD_LEN, REGION_COUNT};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// A stage applied to every touch frame before it is stored for the ALLS
+pub trait Filter: Send {
+    fn apply(&mut self, state: TouchState) -> TouchState;
+
+    // Forgets what earlier frames left behind, for a stream that starts over
+    fn reset(&mut self) {}
+}
+
+// Frames are decoded in the board's packing and written back in it, or in
+// the stock packing when the output is normalized
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter>>,
+    packing: Packing,
+    normalize: bool,
+}
+
+impl FilterChain {
+    pub fn new(packing: Packing, normalize: bool) -> Self {
+        FilterChain {
+            filters: Vec::new(),
+            packing,
+            normalize,
+        }
+    }
+
+    // Packing of the frames the chain hands on
+    pub fn output_packing(&self) -> Packing {
+        if self.normalize {
+            Packing::default()
+        } else {
+            self.packing
+        }
+    }
+
+    pub fn push(&mut self, filter: Box<dyn Filter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+    }
+
+    // Runs the chain over the payload bytes of a touch frame, in place
+    pub fn apply(&mut self, payload: &mut [u8]) {
+        let repack = self.output_packing() != self.packing;
+        if (self.filters.is_empty() && !repack) || payload.len() != PAYLOAD_LEN {
+            return;
+        }
+        let input = self.packing.decode(payload);
+        let output = self
+            .filters
+            .iter_mut()
+            .fold(input, |state, filter| filter.apply(state));
+        if output != input || repack {
+            self.output_packing().encode_into(output, payload);
+        }
+    }
+}
+
+// `A1+=B1`: whenever A1 is active, B1 is reported active too
+#[derive(Clone, Copy, Debug)]
+pub struct SpreadRule {
+    pub source: Region,
+    pub target: Region,
+}
+
+impl FromStr for SpreadRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (source, target) = s
+            .split_once("+=")
+            .ok_or_else(|| anyhow!("spread {} should look like A1+=B1", s))?;
+        let region = |name: &str| {
+            name.trim()
+                .parse()
+                .with_context(|| format!("in spread {}", s))
+        };
+        Ok(SpreadRule {
+            source: region(source)?,
+            target: region(target)?,
+        })
+    }
+}
+
+impl fmt::Display for SpreadRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+={}", self.source, self.target)
+    }
+}
+
+// Adds target regions for active sources. Rules only look at the input
+// state, so they don't chain into each other.
+pub struct Spread {
+    rules: Vec<SpreadRule>,
+}
+
+impl Spread {
+    pub fn new(rules: Vec<SpreadRule>) -> Self {
+        Spread { rules }
+    }
+}
+
+impl Filter for Spread {
+    fn apply(&mut self, state: TouchState) -> TouchState {
+        let mut output = state;
+        for rule in &self.rules {
+            if state.is_active(rule.source) {
+                output.set(rule.target, true);
+            }
+        }
+        output
+    }
+}
+
+// Moves every region to a fixed target, for touch assemblies mounted turned
+// or flipped relative to the screen
+pub struct Remap {
+    targets: [Region; REGION_COUNT],
+}
+
+impl Remap {
+    // Rotates by `octants` clockwise first, then mirrors
+    pub fn geometric(octants: u8, mirror: bool) -> Self {
+        let mut regions = Region::all();
+        Remap {
+            targets: std::array::from_fn(|_| {
+                let region = regions.next().unwrap().rotated(octants);
+                if mirror {
+                    region.mirrored()
+                } else {
+                    region
+                }
+            }),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        Region::all().all(|region| self.targets[region.index()] == region)
+    }
+}
+
+impl Filter for Remap {
+    fn apply(&mut self, state: TouchState) -> TouchState {
+        let mut output = state;
+        for region in Region::all() {
+            output.set(region, false);
+        }
+        for region in Region::all().filter(|&region| state.is_active(region)) {
+            output.set(self.targets[region.index()], true);
+        }
+        output
+    }
+}
+
+// `A*=+8,B3=2`: presses on ring A are held back 8ms, and on B3 2ms. Later
+// entries win, so one region can be singled out of its ring.
+#[derive(Clone, Debug)]
+pub struct RegionDelays([Duration; REGION_COUNT]);
+
+impl RegionDelays {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(Duration::is_zero)
+    }
+}
+
+impl FromStr for RegionDelays {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut delays = [Duration::ZERO; REGION_COUNT];
+        for entry in s.split(',') {
+            let (regions, ms) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("region delay {} should look like A*=+8", entry))?;
+            let ms = ms.trim();
+            if ms.starts_with('-') {
+                bail!(
+                    "region delay {} is negative; delay the other regions instead",
+                    entry
+                );
+            }
+            let ms: u64 = ms
+                .strip_prefix('+')
+                .unwrap_or(ms)
+                .parse()
+                .with_context(|| format!("bad delay in {}", entry))?;
+            let regions = regions.trim();
+            let selected: Vec<Region> = match regions.strip_suffix('*') {
+                Some(ring) => {
+                    let ring = ring.to_ascii_uppercase();
+                    let ring = match ring.as_bytes() {
+                        [letter] if Region::from_ring(*letter as char, 1).is_some() => {
+                            *letter as char
+                        }
+                        _ => bail!("no ring {} in region delay {}", ring, entry),
+                    };
+                    Region::all()
+                        .filter(|region| region.ring() == ring)
+                        .collect()
+                }
+                None => vec![regions
+                    .parse()
+                    .with_context(|| format!("in region delay {}", entry))?],
+            };
+            for region in selected {
+                delays[region.index()] = Duration::from_millis(ms);
+            }
+        }
+        Ok(RegionDelays(delays))
+    }
+}
+
+// Lists the delayed regions, a whole ring at once where it shares one delay
+impl fmt::Display for RegionDelays {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut entries = Vec::new();
+        let mut regions = Region::all().peekable();
+        while let Some(first) = regions.next() {
+            let mut ring = vec![first];
+            while let Some(region) = regions.next_if(|region| region.ring() == first.ring()) {
+                ring.push(region);
+            }
+            let delay = |region: &Region| self.0[region.index()];
+            if ring.iter().all(|region| delay(region) == delay(&first)) {
+                if !delay(&first).is_zero() {
+                    entries.push(format!("{}*=+{}", first.ring(), delay(&first).as_millis()));
+                }
+                continue;
+            }
+            for region in ring.iter().filter(|region| !delay(region).is_zero()) {
+                entries.push(format!("{}=+{}", region, delay(region).as_millis()));
+            }
+        }
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+// Holds presses (and releases too, if asked) back per region, for sensor
+// layers that react at different speeds. Each region's changes come due in
+// the order they happened, at most one per frame, so a tap shorter than
+// its delay still shows up for a frame.
+pub struct RegionDelay<C: Clock> {
+    clock: C,
+    delays: [Duration; REGION_COUNT],
+    delay_releases: bool,
+    input: TouchState,
+    output: TouchState,
+    queued: [VecDeque<(Instant, bool)>; REGION_COUNT],
+}
+
+impl<C: Clock> RegionDelay<C> {
+    pub fn new(clock: C, delays: &RegionDelays, delay_releases: bool) -> Self {
+        RegionDelay {
+            clock,
+            delays: delays.0,
+            delay_releases,
+            input: TouchState::default(),
+            output: TouchState::default(),
+            queued: std::array::from_fn(|_| VecDeque::new()),
+        }
+    }
+}
+
+impl<C: Clock + Send> Filter for RegionDelay<C> {
+    fn apply(&mut self, state: TouchState) -> TouchState {
+        let now = self.clock.now();
+        for region in Region::all() {
+            let queue = &mut self.queued[region.index()];
+            let active = state.is_active(region);
+            if active != self.input.is_active(region) {
+                let delay = if active || self.delay_releases {
+                    self.delays[region.index()]
+                } else {
+                    Duration::ZERO
+                };
+                // A release let through early still waits for its press
+                let due = queue
+                    .back()
+                    .map_or(now + delay, |&(last, _)| last.max(now + delay));
+                queue.push_back((due, active));
+            }
+            if let Some(&(due, active)) = queue.front() {
+                if due <= now {
+                    queue.pop_front();
+                    self.output.set(region, active);
+                }
+            }
+        }
+        self.input = state;
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.input = TouchState::default();
+        self.output = TouchState::default();
+        for queue in &mut self.queued {
+            queue.clear();
+        }
+    }
+}
+
+// Per-player filter settings loaded from a TOML profile
+#[derive(Default)]
+pub struct Profile {
+    pub spread: Vec<SpreadRule>,
+}
+
+impl Profile {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading profile {}", path))?;
+        Self::from_toml(&text).with_context(|| format!("invalid profile {}", path))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut profile = Profile::default();
+        for entry in conf::parse(text)? {
+            match entry.key.as_str() {
+                "spread" => {
+                    for rule in entry.strings()? {
+                        profile.spread.push(rule.parse()?);
+                    }
+                }
+                _ => return Err(entry.unknown()),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn region(name: &str) -> Region {
+        name.parse().unwrap()
+    }
+
+    fn touching(names: &[&str]) -> TouchState {
+        let mut state = TouchState::default();
+        for name in names {
+            state.set(region(name), true);
+        }
+        state
+    }
+
+    fn delay<'a>(clock: &'a MockClock, delays: &str, releases: bool) -> RegionDelay<&'a MockClock> {
+        RegionDelay::new(clock, &delays.parse().unwrap(), releases)
+    }
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn spread_rules_parse_and_print() {
+        let rule: SpreadRule = " a1 += B1 ".parse().unwrap();
+        assert_eq!((rule.source, rule.target), (region("A1"), region("B1")));
+        assert_eq!(rule.to_string(), "A1+=B1");
+        assert!("A1=B1".parse::<SpreadRule>().is_err());
+        assert!("A1+=Z9".parse::<SpreadRule>().is_err());
+        assert!("+=B1".parse::<SpreadRule>().is_err());
+    }
+
+    #[test]
+    fn spread_adds_targets_while_their_source_is_held() {
+        let rules = ["A1+=B1", "A1+=E1"].map(|rule| rule.parse().unwrap());
+        let mut spread = Spread::new(rules.to_vec());
+        assert_eq!(
+            spread.apply(touching(&["A1", "C1"])),
+            touching(&["A1", "B1", "C1", "E1"])
+        );
+        assert_eq!(spread.apply(touching(&["B1"])), touching(&["B1"]));
+        assert_eq!(spread.apply(TouchState::default()), TouchState::default());
+    }
+
+    #[test]
+    fn spread_rules_dont_chain() {
+        let rules = ["A1+=B1", "B1+=C1"].map(|rule| rule.parse().unwrap());
+        let mut spread = Spread::new(rules.to_vec());
+        assert_eq!(spread.apply(touching(&["A1"])), touching(&["A1", "B1"]));
+    }
+
+    #[test]
+    fn spread_rules_load_from_a_profile() {
+        let profile = Profile::from_toml("spread = [\"A1+=B1\", \"D2+=E2\"]\n").unwrap();
+        let rules: Vec<String> = profile.spread.iter().map(ToString::to_string).collect();
+        assert_eq!(rules, ["A1+=B1", "D2+=E2"]);
+        assert!(Profile::from_toml("spread = [\"A1\"]\n").is_err());
+        assert!(Profile::from_toml("rotate = 2\n").is_err());
+    }
+
+    #[test]
+    fn remap_moves_every_touched_region() {
+        let mut upside_down = Remap::geometric(4, false);
+        assert_eq!(
+            upside_down.apply(touching(&["A1", "B3", "C1"])),
+            touching(&["A5", "B7", "C2"])
+        );
+        let mut mirrored = Remap::geometric(0, true);
+        assert_eq!(
+            mirrored.apply(touching(&["A1", "D1", "E2"])),
+            touching(&["A8", "D1", "E8"])
+        );
+        // Turned first, then mirrored
+        let mut both = Remap::geometric(1, true);
+        assert_eq!(both.apply(touching(&["A1"])), touching(&["A7"]));
+        assert_eq!(both.apply(TouchState::default()), TouchState::default());
+    }
+
+    #[test]
+    fn a_full_turn_is_no_remap_at_all() {
+        assert!(Remap::geometric(0, false).is_identity());
+        assert!(Remap::geometric(8, false).is_identity());
+        assert!(!Remap::geometric(4, false).is_identity());
+        assert!(!Remap::geometric(0, true).is_identity());
+    }
+
+    #[test]
+    fn a_chain_only_rewrites_frames_its_filters_change() {
+        let rule = "A1+=B1".parse().unwrap();
+        let mut chain = FilterChain::new(Packing::default(), false);
+        chain.push(Box::new(Spread::new(vec![rule])));
+        let mut payload = [0u8; PAYLOAD_LEN];
+        Packing::default().encode_into(touching(&["C1"]), &mut payload);
+        let untouched = payload;
+        chain.apply(&mut payload);
+        assert_eq!(payload, untouched);
+        Packing::default().encode_into(touching(&["A1"]), &mut payload);
+        chain.apply(&mut payload);
+        assert_eq!(Packing::default().decode(&payload), touching(&["A1", "B1"]));
+    }
+
+    #[test]
+    fn presses_are_held_per_region() {
+        let clock = MockClock::new();
+        let mut filter = delay(&clock, "A*=+8,A3=2", false);
+        let pressed = touching(&["A1", "A3", "B1"]);
+        // B has no delay, A3 its own shorter one
+        assert_eq!(filter.apply(pressed), touching(&["B1"]));
+        clock.advance(MS * 2);
+        assert_eq!(filter.apply(pressed), touching(&["A3", "B1"]));
+        clock.advance(MS * 5);
+        assert_eq!(filter.apply(pressed), touching(&["A3", "B1"]));
+        clock.advance(MS);
+        assert_eq!(filter.apply(pressed), pressed);
+    }
+
+    #[test]
+    fn releases_pass_straight_through_unless_delayed() {
+        let clock = MockClock::new();
+        let mut filter = delay(&clock, "A1=+8", false);
+        filter.apply(touching(&["A1"]));
+        clock.advance(MS * 8);
+        assert_eq!(filter.apply(touching(&["A1"])), touching(&["A1"]));
+        clock.advance(MS * 20);
+        assert_eq!(filter.apply(TouchState::default()), TouchState::default());
+    }
+
+    #[test]
+    fn delay_releases_holds_releases_too() {
+        let clock = MockClock::new();
+        let mut filter = delay(&clock, "A1=+8", true);
+        filter.apply(touching(&["A1"]));
+        clock.advance(MS * 8);
+        assert_eq!(filter.apply(touching(&["A1"])), touching(&["A1"]));
+        clock.advance(MS * 20);
+        let released = TouchState::default();
+        assert_eq!(filter.apply(released), touching(&["A1"]));
+        clock.advance(MS * 7);
+        assert_eq!(filter.apply(released), touching(&["A1"]));
+        clock.advance(MS);
+        assert_eq!(filter.apply(released), released);
+    }
+
+    #[test]
+    fn a_tap_shorter_than_its_delay_still_shows_for_a_frame() {
+        let clock = MockClock::new();
+        let mut filter = delay(&clock, "A1=+8", false);
+        let (pressed, released) = (touching(&["A1"]), TouchState::default());
+        assert_eq!(filter.apply(pressed), released);
+        clock.advance(MS * 3);
+        // Released before the press came due: the release waits for it
+        assert_eq!(filter.apply(released), released);
+        clock.advance(MS * 5);
+        assert_eq!(filter.apply(released), pressed);
+        assert_eq!(filter.apply(released), released);
+    }
+
+    #[test]
+    fn reset_drops_what_is_queued() {
+        let clock = MockClock::new();
+        let mut filter = delay(&clock, "A1=+8", false);
+        filter.apply(touching(&["A1"]));
+        filter.reset();
+        clock.advance(MS * 8);
+        assert_eq!(filter.apply(TouchState::default()), TouchState::default());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::retry::Retry;
+use maitouch_protocol::framing::PacketDelimiter;
+use std::io::{BufRead, ErrorKind, Result};
+
+// Assembles frames from whatever the port has delivered and keeps only the
+// newest, so a backlog queued during a stall is skipped instead of being
+// replayed one frame at a time.
+pub struct LatestFrameReader {
+    open: u8,
+    close: u8,
+    frame_len: usize,
+    partial: Vec<u8>,
+    in_frame: bool,
+    pub skipped: u64,
+    pub malformed: u64,
+}
+
+impl LatestFrameReader {
+    pub fn new(packet: &PacketDelimiter, frame_len: usize) -> Self {
+        LatestFrameReader {
+            open: packet.open as u8,
+            close: packet.close as u8,
+            frame_len,
+            partial: Vec::with_capacity(frame_len),
+            in_frame: false,
+            skipped: 0,
+            malformed: 0,
+        }
+    }
+
+    // Blocks until at least one complete frame is available and copies the
+    // newest into `frame`. Returns how many older frames were skipped.
+    pub fn read_latest(
+        &mut self,
+        reader: &mut dyn BufRead,
+        frame: &mut Vec<u8>,
+        retry: &Retry,
+    ) -> Result<u64> {
+        let mut complete = 0u64;
+        loop {
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::TimedOut && retry.timed_out() => continue,
+                Err(err) => return Err(err),
+            };
+            if available.is_empty() {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            for &byte in available {
+                if byte == self.open {
+                    self.partial.clear();
+                    self.in_frame = true;
+                }
+                if !self.in_frame {
+                    continue;
+                }
+                self.partial.push(byte);
+                if byte == self.close {
+                    self.in_frame = false;
+                    if self.partial.len() == self.frame_len {
+                        frame.clear();
+                        frame.extend_from_slice(&self.partial);
+                        complete += 1;
+                    } else {
+                        self.malformed += 1;
+                    }
+                } else if self.partial.len() > self.frame_len {
+                    self.in_frame = false;
+                    self.malformed += 1;
+                }
+            }
+            let used = available.len();
+            reader.consume(used);
+            if complete > 0 {
+                retry.succeeded();
+                self.skipped += complete - 1;
+                return Ok(complete - 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::retry::RetryPolicy;
+    use maitouch_protocol::framing::maimai;
+    use std::collections::VecDeque;
+    use std::io::{BufReader, Read};
+    use std::time::Duration;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        backoff: Duration::from_millis(1),
+    };
+
+    // A port that delivers one chunk, or one error, per read and then
+    // hangs up
+    struct Chunks(VecDeque<Result<Vec<u8>>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                Some(Err(err)) => Err(err),
+                None => Ok(0),
+            }
+        }
+    }
+
+    // Told apart by `n`, kept clear of the delimiters
+    fn frame(n: u8) -> Vec<u8> {
+        maimai::ADX.wrap(&[n & 0x1f, n >> 5, 0, 0, 0, 0, 0])
+    }
+
+    fn reader() -> LatestFrameReader {
+        LatestFrameReader::new(&maimai::ADX, frame(0).len())
+    }
+
+    #[test]
+    fn a_queued_backlog_is_skipped_to_its_last_frame() {
+        let backlog: Vec<u8> = (1..=50).flat_map(frame).collect();
+        let mut port = BufReader::new(Chunks(VecDeque::from([Ok(backlog)])));
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        let mut reader = reader();
+        let mut latest = Vec::new();
+        assert_eq!(
+            reader.read_latest(&mut port, &mut latest, &retry).unwrap(),
+            49
+        );
+        assert_eq!(latest, frame(50));
+        assert_eq!((reader.skipped, reader.malformed), (49, 0));
+        // Nothing left over: the next read finds the port hung up
+        let err = reader.read_latest(&mut port, &mut latest, &retry);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_frame_split_across_reads_and_timeouts_is_put_together() {
+        let whole = frame(7);
+        let (head, tail) = whole.split_at(4);
+        let chunks = VecDeque::from([
+            Ok(head.to_vec()),
+            Err(ErrorKind::TimedOut.into()),
+            Ok(tail.to_vec()),
+        ]);
+        let mut port = BufReader::new(Chunks(chunks));
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        let mut latest = Vec::new();
+        assert_eq!(
+            reader()
+                .read_latest(&mut port, &mut latest, &retry)
+                .unwrap(),
+            0
+        );
+        assert_eq!(latest, whole);
+        // The timeout was retried, and the frame after it resets the count
+        assert_eq!(retry.consecutive(), 0);
+    }
+
+    #[test]
+    fn torn_and_oversized_frames_are_dropped() {
+        let mut line = frame(1);
+        // Lost its close delimiter and ran into the next frame
+        line.pop();
+        line.extend(frame(2));
+        // Garbage between frames, then one with too much payload
+        line.extend(b"xx(12345678)");
+        line.extend(frame(3));
+        let mut port = BufReader::new(Chunks(VecDeque::from([Ok(line)])));
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        let mut reader = reader();
+        let mut latest = Vec::new();
+        assert_eq!(
+            reader.read_latest(&mut port, &mut latest, &retry).unwrap(),
+            1
+        );
+        assert_eq!(latest, frame(3));
+        // The torn frame is simply started over by the next one; only the
+        // oversized one was read to its end
+        assert_eq!(reader.malformed, 1);
+    }
+
+    #[test]
+    fn a_read_error_is_handed_back() {
+        let chunks = VecDeque::from([Err(ErrorKind::BrokenPipe.into())]);
+        let mut port = BufReader::new(Chunks(chunks));
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        let err = reader().read_latest(&mut port, &mut Vec::new(), &retry);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::pattern;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+// Expected config-mode exchanges, one per line:
+//
+//     {LAr2} => (LAr2)
+//
+// `?` in a response matches any byte (for serial numbers and the like),
+// `\xNN` is a raw byte, and `\?` / `\\` are literal.
+pub struct Expectation {
+    exchanges: Vec<(Vec<u8>, Vec<Option<u8>>)>,
+}
+
+pub struct Mismatch {
+    expected: Vec<Option<u8>>,
+    actual: Vec<u8>,
+}
+
+impl Expectation {
+    pub fn load(path: &str) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading handshake {}", path))?;
+        Self::parse(&text).with_context(|| format!("invalid handshake file {}", path))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut exchanges = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (command, response) = line
+                .split_once("=>")
+                .ok_or_else(|| anyhow!("line {}: expected <command> => <response>", index + 1))?;
+            let command = parse_bytes(command.trim())
+                .with_context(|| format!("line {}: bad command", index + 1))?;
+            let response = parse_pattern(response.trim(), true)
+                .with_context(|| format!("line {}: bad response", index + 1))?;
+            exchanges.push((command, response));
+        }
+        Ok(Expectation { exchanges })
+    }
+
+    // None if the command isn't covered by the expectation
+    pub fn check(&self, command: &[u8], response: &[u8]) -> Option<Result<(), Mismatch>> {
+        let (_, expected) = self.exchanges.iter().find(|(cmd, _)| cmd == command)?;
+        Some(compare(expected, response))
+    }
+}
+
+// --require-board-id: the answer to the one config command the board
+// identifies itself in, as a line of a handshake file, e.g.
+// `{LAr2} => (LAr?)`. A cab with the wrong board plugged in stops there.
+#[derive(Clone, Debug)]
+pub struct BoardId {
+    command: Vec<u8>,
+    pattern: Vec<Option<u8>>,
+}
+
+impl FromStr for BoardId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (command, response) = s
+            .split_once("=>")
+            .ok_or_else(|| anyhow!("board id {} should look like {{LAr2}} => (LAr?)", s))?;
+        let command = parse_bytes(command.trim()).context("bad board id command")?;
+        let pattern = parse_pattern(response.trim(), true).context("bad board id pattern")?;
+        if command.is_empty() || pattern.is_empty() {
+            bail!("board id {} needs both a command and a pattern", s);
+        }
+        Ok(BoardId { command, pattern })
+    }
+}
+
+impl BoardId {
+    // None if `command` isn't the one the board identifies itself in
+    pub fn check(&self, command: &[u8], response: &[u8]) -> Option<Result<(), Mismatch>> {
+        (command == self.command).then(|| compare(&self.pattern, response))
+    }
+}
+
+fn compare(expected: &[Option<u8>], response: &[u8]) -> Result<(), Mismatch> {
+    if pattern::matches(expected, response) {
+        Ok(())
+    } else {
+        Err(Mismatch {
+            expected: expected.to_vec(),
+            actual: response.to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected ")?;
+        for byte in &self.expected {
+            match byte {
+                Some(byte) => write!(f, "{:02x} ", byte)?,
+                None => write!(f, "?? ")?,
+            }
+        }
+        write!(f, "got ")?;
+        for byte in &self.actual {
+            write!(f, "{:02x} ", byte)?;
+        }
+        let differing: Vec<String> = (0..self.expected.len().max(self.actual.len()))
+            .filter(|&i| match (self.expected.get(i), self.actual.get(i)) {
+                (Some(None), Some(_)) => false,
+                (Some(Some(want)), Some(got)) => want != got,
+                _ => true,
+            })
+            .map(|i| i.to_string())
+            .collect();
+        write!(f, "(differs at byte {})", differing.join(", "))
+    }
+}
+
+// Raw bytes written with the same escapes as a handshake command
+pub fn parse_bytes(text: &str) -> Result<Vec<u8>> {
+    Ok(parse_pattern(text, false)?.into_iter().flatten().collect())
+}
+
+pub fn parse_pattern(text: &str, wildcards: bool) -> Result<Vec<Option<u8>>> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '?' if wildcards => bytes.push(None),
+            '\\' => match chars.next() {
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    let byte = u8::from_str_radix(&hex, 16)
+                        .map_err(|_| anyhow!("bad escape \\x{}", hex))?;
+                    bytes.push(Some(byte));
+                }
+                Some(c @ ('?' | '\\')) => bytes.push(Some(c as u8)),
+                other => bail!(
+                    "bad escape \\{}",
+                    other.map(String::from).unwrap_or_default()
+                ),
+            },
+            c if c.is_ascii() => bytes.push(Some(c as u8)),
+            c => bail!("non-ASCII character {:?}, use \\xNN", c),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_expectation_checks_the_commands_it_covers() {
+        let expectation = Expectation::parse(
+            "# the stock board\n\
+             {LAr2} => (LAr2)\n\
+             \n\
+             {RSET} => (RSET??)\n",
+        )
+        .unwrap();
+        assert!(expectation.check(b"{LAr2}", b"(LAr2)").unwrap().is_ok());
+        assert!(expectation.check(b"{LAr2}", b"(LAr3)").unwrap().is_err());
+        assert!(expectation.check(b"{RSET}", b"(RSET01)").unwrap().is_ok());
+        assert!(expectation.check(b"{RSET}", b"(RSET0)").unwrap().is_err());
+        assert!(expectation.check(b"{HALT}", b"").is_none());
+    }
+
+    #[test]
+    fn a_bad_handshake_line_is_reported_by_number() {
+        let err = Expectation::parse("{LAr2} => (LAr2)\n{RAr2} (RAr2)\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 2: expected <command> => <response>");
+        let err = Expectation::parse("{LAr2} => (LA\\z)\n").err().unwrap();
+        assert_eq!(format!("{:#}", err), "line 1: bad response: bad escape \\z");
+    }
+
+    #[test]
+    fn patterns_take_wildcards_and_escapes() {
+        assert_eq!(
+            parse_pattern(r"a?\?\\\x7f", true).unwrap(),
+            [Some(b'a'), None, Some(b'?'), Some(b'\\'), Some(0x7f)]
+        );
+        // Commands have no wildcards, so a ? there is just a ?
+        assert_eq!(parse_bytes("{?}").unwrap(), b"{?}");
+        assert!(parse_pattern(r"\xZZ", true).is_err());
+        assert!(parse_pattern("é", true).is_err());
+    }
+
+    #[test]
+    fn a_mismatch_lists_every_differing_byte() {
+        let expected = parse_pattern("(A?C)", true).unwrap();
+        let mismatch = compare(&expected, b"(AXD").unwrap_err();
+        assert_eq!(
+            mismatch.to_string(),
+            "expected 28 41 ?? 43 29 got 28 41 58 44 (differs at byte 3, 4)"
+        );
+    }
+
+    #[test]
+    fn a_board_id_is_checked_against_its_command_only() {
+        let id: BoardId = "{LAr2} => (LAr?)".parse().unwrap();
+        assert!(id.check(b"{RAr2}", b"(XYZ)").is_none());
+        assert!(id.check(b"{LAr2}", b"(LAr2)").unwrap().is_ok());
+        assert!(id.check(b"{LAr2}", b"(LAr9)").unwrap().is_ok());
+        let mismatch = id.check(b"{LAr2}", b"(LBr2)").unwrap().unwrap_err();
+        assert_eq!(
+            mismatch.to_string(),
+            "expected 28 4c 41 72 ?? 29 got 28 4c 42 72 32 29 (differs at byte 2)"
+        );
+        // Longer or shorter isn't the same board either
+        assert!(id.check(b"{LAr2}", b"(LAr2)x").unwrap().is_err());
+        assert!(id.check(b"{LAr2}", b"(LA").unwrap().is_err());
+    }
+
+    #[test]
+    fn a_board_id_takes_handshake_escapes() {
+        let id: BoardId = r"{FW} => \x02v\?".parse().unwrap();
+        assert!(id.check(b"{FW}", b"\x02v?").unwrap().is_ok());
+        assert!(id.check(b"{FW}", b"\x02v1").unwrap().is_err());
+    }
+
+    #[test]
+    fn a_board_id_needs_a_command_and_a_pattern() {
+        assert!("{LAr2}".parse::<BoardId>().is_err());
+        assert!(" => (LAr2)".parse::<BoardId>().is_err());
+        assert!("{LAr2} => ".parse::<BoardId>().is_err());
+        assert!("{LAr2} => \\q".parse::<BoardId>().is_err());
+    }
+}
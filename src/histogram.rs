@@ -0,0 +1,119 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bound of the first bucket; each following bucket doubles it
+const FIRST_BUCKET: Duration = Duration::from_micros(250);
+// Buckets up to 128ms plus one for anything longer
+const BUCKETS: usize = 11;
+
+// Power-of-two histogram of durations, safe to record into from any thread
+pub struct Histogram {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    // A value exactly on a bound goes in the bucket that bound closes
+    pub fn record(&self, value: Duration) {
+        let bucket = (0..BUCKETS - 1)
+            .find(|&i| value <= Self::bound(i))
+            .unwrap_or(BUCKETS - 1);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bound(bucket: usize) -> Duration {
+        FIRST_BUCKET * (1 << bucket)
+    }
+
+    // (upper bound, count) per bucket, None for the open-ended last one
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, count)| {
+            let bound = (i < BUCKETS - 1).then(|| Self::bound(i));
+            (bound, count.load(Ordering::Relaxed))
+        })
+    }
+}
+
+// One line of `<=250us:12 <=500us:3 ... >128ms:0`, skipping empty buckets
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut last = FIRST_BUCKET;
+        let mut first = true;
+        for (bound, count) in self.buckets() {
+            if let Some(bound) = bound {
+                last = bound;
+            }
+            if count == 0 {
+                continue;
+            }
+            if !first {
+                write!(f, " ")?;
+            }
+            first = false;
+            match bound {
+                Some(bound) => write!(f, "<={:?}:{}", bound, count)?,
+                None => write!(f, ">{:?}:{}", last, count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(histogram: &Histogram) -> Vec<u64> {
+        histogram.buckets().map(|(_, count)| count).collect()
+    }
+
+    const US: Duration = Duration::from_micros(1);
+
+    #[test]
+    fn the_bounds_double_up_to_128ms() {
+        let bounds: Vec<Option<Duration>> =
+            Histogram::new().buckets().map(|(bound, _)| bound).collect();
+        let expected: Vec<Option<Duration>> = (0..10)
+            .map(|i| Some(Duration::from_micros(250 << i)))
+            .chain([None])
+            .collect();
+        assert_eq!(bounds, expected);
+        assert_eq!(expected[9], Some(Duration::from_millis(128)));
+    }
+
+    #[test]
+    fn a_value_on_a_bound_goes_in_the_bucket_it_closes() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::ZERO);
+        histogram.record(US * 250);
+        histogram.record(US * 251);
+        histogram.record(US * 500);
+        histogram.record(Duration::from_millis(128));
+        assert_eq!(counts(&histogram), [2, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn anything_past_128ms_goes_in_the_last_bucket() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::from_millis(128) + US);
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(counts(&histogram), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn the_line_skips_empty_buckets() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.to_string(), "");
+        histogram.record(US * 100);
+        histogram.record(US * 100);
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(1));
+        assert_eq!(histogram.to_string(), "<=250µs:2 <=4ms:1 >128ms:1");
+    }
+}
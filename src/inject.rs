@@ -0,0 +1,408 @@
+use crate::clock::MonotonicClock;
+use crate::filter::Filter;
+use crate::limit::{self, WarnLimiter};
+use crate::quarantine::{TransitionGuard, TransitionLimit};
+use crate::report::SessionReport;
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::touch::{Region, TouchState};
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest datagram read; anything past it is cut off and likely malformed
+const MAX_DATAGRAM: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InjectAction {
+    Press,
+    Release,
+    // Press now and release after the duration
+    Pulse(Duration),
+}
+
+// One line of an injection datagram:
+//
+//     <region> press|release|pulse <ms> [player=<id>]
+//
+// e.g. `A1 pulse 50 player=1P`. Several may be sent in one datagram, one
+// per line.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InjectCommand {
+    pub region: Region,
+    pub action: InjectAction,
+    pub player: Option<String>,
+}
+
+impl InjectCommand {
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let region = words
+            .next()
+            .ok_or_else(|| anyhow!("empty command"))?
+            .parse()?;
+        let action = match words.next() {
+            Some("press") => InjectAction::Press,
+            Some("release") => InjectAction::Release,
+            Some("pulse") => {
+                let ms: u64 = words
+                    .next()
+                    .ok_or_else(|| anyhow!("pulse needs a duration in ms"))?
+                    .parse()
+                    .context("bad pulse duration")?;
+                InjectAction::Pulse(Duration::from_millis(ms))
+            }
+            Some(action) => bail!("unknown action {}", action),
+            None => bail!("missing action"),
+        };
+        let mut player = None;
+        for word in words {
+            match word.strip_prefix("player=") {
+                Some(id) => player = Some(id.to_string()),
+                None => bail!("unexpected {}", word),
+            }
+        }
+        Ok(InjectCommand {
+            region,
+            action,
+            player,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Injected {
+    held: TouchState,
+    // Pulsed regions and when each is released
+    pulses: Vec<(Region, Instant)>,
+}
+
+// Touches injected over UDP, shared between the listener and the filter
+pub struct Injector {
+    injected: Mutex<Injected>,
+    report: Arc<SessionReport>,
+}
+
+impl Injector {
+    pub fn new(report: Arc<SessionReport>) -> Self {
+        Injector {
+            injected: Mutex::new(Injected::default()),
+            report,
+        }
+    }
+
+    pub fn apply(&self, command: &InjectCommand, now: Instant) {
+        let mut injected = self.injected.lock().unwrap();
+        let region = command.region;
+        match command.action {
+            InjectAction::Press => injected.held.set(region, true),
+            InjectAction::Release => {
+                injected.held.set(region, false);
+                injected.pulses.retain(|(pulsed, _)| *pulsed != region);
+            }
+            InjectAction::Pulse(duration) => {
+                let until = now + duration;
+                match injected
+                    .pulses
+                    .iter_mut()
+                    .find(|(pulsed, _)| *pulsed == region)
+                {
+                    Some((_, release)) => *release = (*release).max(until),
+                    None => injected.pulses.push((region, until)),
+                }
+            }
+        }
+    }
+
+    // How many regions `command` would press or release at `now`. A pulse
+    // is its press and its release, unless the region is already down.
+    fn transitions(&self, command: &InjectCommand, now: Instant) -> u32 {
+        let active = self.active(now).is_active(command.region);
+        match command.action {
+            InjectAction::Press => !active as u32,
+            InjectAction::Release => active as u32,
+            InjectAction::Pulse(_) if active => 0,
+            InjectAction::Pulse(_) => 2,
+        }
+    }
+
+    fn release_all(&self) {
+        *self.injected.lock().unwrap() = Injected::default();
+    }
+
+    // The injected regions at `now`, dropping pulses that have run out
+    pub fn active(&self, now: Instant) -> TouchState {
+        let mut injected = self.injected.lock().unwrap();
+        injected.pulses.retain(|(_, release)| *release > now);
+        let mut state = injected.held;
+        for (region, _) in &injected.pulses {
+            state.set(*region, true);
+        }
+        state
+    }
+
+    // Listens for commands on `addr` for the rest of the run. Commands
+    // naming a player other than `player` are ignored, and so is every
+    // command while the listener is quarantined for going past `limit`.
+    pub fn listen(
+        self: &Arc<Self>,
+        addr: &str,
+        player: Option<String>,
+        limit: Option<TransitionLimit>,
+    ) -> Result<()> {
+        let socket =
+            UdpSocket::bind(addr).with_context(|| format!("binding --inject-listen {}", addr))?;
+        tracing::info!("Listening for injected touches on {}", socket.local_addr()?);
+        let injector = self.clone();
+        thread::Builder::new()
+            .name("inject listener".into())
+            .spawn(move || injector.serve(socket, player, limit))?;
+        Ok(())
+    }
+
+    fn serve(&self, socket: UdpSocket, player: Option<String>, limit: Option<TransitionLimit>) {
+        let mut warnings = WarnLimiter::new(&MonotonicClock, limit::SUMMARY_WINDOW);
+        let mut guard = limit.map(|limit| {
+            TransitionGuard::new(
+                MonotonicClock,
+                "the injection listener",
+                limit,
+                self.report.clone(),
+            )
+        });
+        let mut buf = [0u8; MAX_DATAGRAM];
+        // Wakes up now and then to sum up warnings held back
+        if let Err(err) = socket.set_read_timeout(Some(limit::SUMMARY_WINDOW)) {
+            tracing::debug!("Couldn't set a timeout on the injection socket: {}", err);
+        }
+        loop {
+            warnings.tick();
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(err) => {
+                    tracing::warn!("Stopped listening for injected touches: {}", err);
+                    return;
+                }
+            };
+            let now = Instant::now();
+            let text = String::from_utf8_lossy(&buf[..len]);
+            for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                match InjectCommand::parse(line) {
+                    Ok(command) if command.player.is_some() && command.player != player => {
+                        tracing::debug!("Ignoring injected {} for another player", line)
+                    }
+                    Ok(command)
+                        if guard
+                            .as_mut()
+                            .is_some_and(|guard| !guard.admit(self.transitions(&command, now))) =>
+                    {
+                        self.release_all();
+                    }
+                    Ok(command) => {
+                        self.report.injected.fetch_add(1, Ordering::Relaxed);
+                        self.apply(&command, now);
+                    }
+                    Err(err) => {
+                        self.report
+                            .malformed_injections
+                            .fetch_add(1, Ordering::Relaxed);
+                        warnings.warn("malformed injections", || {
+                            format!("Ignoring injected {:?} from {}: {:#}", line, from, err)
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Adds the injected touches on top of whatever the board reports
+pub struct Inject(pub Arc<Injector>);
+
+impl Filter for Inject {
+    fn apply(&mut self, state: TouchState) -> TouchState {
+        let injected = self.0.active(Instant::now());
+        let mut output = state;
+        for region in Region::all().filter(|&region| injected.is_active(region)) {
+            output.set(region, true);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &str) -> Region {
+        name.parse().unwrap()
+    }
+
+    fn command(line: &str) -> InjectCommand {
+        InjectCommand::parse(line).unwrap()
+    }
+
+    fn regions(state: TouchState) -> Vec<Region> {
+        Region::all()
+            .filter(|&region| state.is_active(region))
+            .collect()
+    }
+
+    #[test]
+    fn parses_each_action() {
+        assert_eq!(
+            command("A1 press"),
+            InjectCommand {
+                region: region("A1"),
+                action: InjectAction::Press,
+                player: None,
+            }
+        );
+        assert_eq!(command("e8 release").action, InjectAction::Release);
+        assert_eq!(
+            command("  C1   pulse 50  player=1P "),
+            InjectCommand {
+                region: region("C1"),
+                action: InjectAction::Pulse(Duration::from_millis(50)),
+                player: Some("1P".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let error = |line| format!("{:#}", InjectCommand::parse(line).unwrap_err());
+        assert_eq!(error(""), "empty command");
+        assert_eq!(error("Z1 press"), "unknown region");
+        assert_eq!(error("A9 press"), "unknown region");
+        assert_eq!(error("C3 press"), "unknown region");
+        assert_eq!(error("A1"), "missing action");
+        assert_eq!(error("A1 tap"), "unknown action tap");
+        assert_eq!(error("A1 pulse"), "pulse needs a duration in ms");
+        assert_eq!(
+            error("A1 pulse -5"),
+            "bad pulse duration: invalid digit found in string"
+        );
+        assert_eq!(error("A1 press 1P"), "unexpected 1P");
+    }
+
+    #[test]
+    fn holds_until_released() {
+        let injector = Injector::new(Arc::new(SessionReport::new()));
+        let start = Instant::now();
+        injector.apply(&command("A1 press"), start);
+        injector.apply(&command("B2 press"), start);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            regions(injector.active(later)),
+            [region("A1"), region("B2")]
+        );
+        injector.apply(&command("A1 release"), later);
+        assert_eq!(regions(injector.active(later)), [region("B2")]);
+    }
+
+    #[test]
+    fn a_pulse_releases_on_schedule_with_nothing_else_sent() {
+        let injector = Injector::new(Arc::new(SessionReport::new()));
+        let start = Instant::now();
+        injector.apply(&command("A1 pulse 50"), start);
+        injector.apply(&command("D3 pulse 20"), start);
+        let at = |ms| regions(injector.active(start + Duration::from_millis(ms)));
+        assert_eq!(at(0), [region("A1"), region("D3")]);
+        assert_eq!(at(19), [region("A1"), region("D3")]);
+        assert_eq!(at(20), [region("A1")]);
+        assert_eq!(at(49), [region("A1")]);
+        assert_eq!(at(50), []);
+    }
+
+    #[test]
+    fn a_second_pulse_only_ever_extends_the_first() {
+        let injector = Injector::new(Arc::new(SessionReport::new()));
+        let start = Instant::now();
+        injector.apply(&command("A1 pulse 50"), start);
+        // Shorter than what's left of the first, so it changes nothing
+        injector.apply(&command("A1 pulse 10"), start + Duration::from_millis(10));
+        let at = |ms| {
+            injector
+                .active(start + Duration::from_millis(ms))
+                .is_active(region("A1"))
+        };
+        assert!(at(49));
+        injector.apply(&command("A1 pulse 30"), start + Duration::from_millis(40));
+        assert!(at(69));
+        assert!(!at(70));
+    }
+
+    #[test]
+    fn a_release_cancels_a_pulse() {
+        let injector = Injector::new(Arc::new(SessionReport::new()));
+        let start = Instant::now();
+        injector.apply(&command("A1 pulse 50"), start);
+        injector.apply(&command("A1 release"), start + Duration::from_millis(10));
+        assert_eq!(
+            regions(injector.active(start + Duration::from_millis(10))),
+            []
+        );
+    }
+
+    #[test]
+    fn counts_the_transitions_a_command_would_make() {
+        let injector = Injector::new(Arc::new(SessionReport::new()));
+        let now = Instant::now();
+        assert_eq!(injector.transitions(&command("A1 press"), now), 1);
+        assert_eq!(injector.transitions(&command("A1 release"), now), 0);
+        assert_eq!(injector.transitions(&command("A1 pulse 10"), now), 2);
+        injector.apply(&command("A1 press"), now);
+        assert_eq!(injector.transitions(&command("A1 press"), now), 0);
+        assert_eq!(injector.transitions(&command("A1 release"), now), 1);
+        assert_eq!(injector.transitions(&command("A1 pulse 10"), now), 0);
+    }
+
+    #[test]
+    fn the_filter_adds_injected_touches_to_the_boards() {
+        let injector = Arc::new(Injector::new(Arc::new(SessionReport::new())));
+        injector.apply(&command("B1 press"), Instant::now());
+        let mut board = TouchState::default();
+        board.set(region("A1"), true);
+        assert_eq!(
+            regions(Inject(injector).apply(board)),
+            [region("A1"), region("B1")]
+        );
+    }
+
+    #[test]
+    fn the_listener_applies_its_players_commands_and_counts_malformed_ones() {
+        let report = Arc::new(SessionReport::new());
+        let injector = Arc::new(Injector::new(report.clone()));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = injector.clone();
+        // Left listening once the test is done, as for the rest of a run
+        thread::spawn(move || listener.serve(socket, Some("1P".into()), None));
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender
+            .send_to(
+                b"A1 press\nbogus\n\nB2 press player=2P\nD3 press player=1P\n",
+                addr,
+            )
+            .unwrap();
+        sender.send_to(b"A1 tap", addr).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while report.malformed_injections.load(Ordering::Relaxed) < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(report.injected.load(Ordering::Relaxed), 2);
+        assert_eq!(report.malformed_injections.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            regions(injector.active(Instant::now())),
+            [region("A1"), region("D3")]
+        );
+    }
+}
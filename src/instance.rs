@@ -0,0 +1,261 @@
+use crate::ports::{self, Transport};
+use anyhow::{bail, Context, Result};
+#[cfg(not(windows))]
+use std::path::PathBuf;
+
+// Held for as long as the proxy runs so a second copy started on the same
+// ports fails up front instead of interleaving writes with this one. The OS
+// drops the locks when the process dies, so a crashed instance never leaves
+// a lock behind that has to be cleaned up by hand.
+pub struct InstanceLock {
+    _locks: Vec<PortLock>,
+}
+
+impl InstanceLock {
+    // With `force` a conflict is only logged and the port is used anyway
+    pub fn acquire(ports: &[&str], force: bool) -> Result<Self> {
+        let mut locks = Vec::new();
+        for name in ports {
+            let Some(key) = lock_key(name) else {
+                continue;
+            };
+            match PortLock::acquire(&key)? {
+                Ok(lock) => locks.push(lock),
+                Err(holder) => {
+                    let owner = holder.map_or(String::new(), |pid| format!(" (pid {})", pid));
+                    if !force {
+                        bail!(
+                            "another maitouch instance is already using {}{}, pass --force to \
+                             open it anyway",
+                            name,
+                            owner
+                        );
+                    }
+                    tracing::warn!(
+                        "Another maitouch instance is already using {}{}, carrying on because of \
+                         --force",
+                        name,
+                        owner
+                    );
+                }
+            }
+        }
+        Ok(InstanceLock { _locks: locks })
+    }
+}
+
+// The device two port names share, e.g. a symlink and its target, when
+// they do. Opening it as both ends has the proxy read its own writes.
+pub fn same_device(a: &str, b: &str) -> Option<String> {
+    lock_key(a).filter(|key| lock_key(b).as_ref() == Some(key))
+}
+
+// What identifies a port across instances: the resolved device path, or
+// the link path for a PTY the proxy creates itself. Stdio and anonymous
+// PTYs can't be shared, so they aren't locked, and a TCP listener's own
+// bind already fails if the address is taken.
+fn lock_key(name: &str) -> Option<String> {
+    if matches!(Transport::of(name), Transport::Stdio | Transport::TcpListen) {
+        return None;
+    }
+    if let Some(link) = name.strip_prefix(ports::PTY_PREFIX) {
+        return (!link.is_empty()).then(|| link.to_string());
+    }
+    // COM names don't canonicalize; COM5, com5 and \\.\COM5 are one port
+    #[cfg(windows)]
+    let key = name.trim_start_matches(r"\\.\").to_ascii_uppercase();
+    #[cfg(not(windows))]
+    let key = std::fs::canonicalize(name)
+        .unwrap_or_else(|_| PathBuf::from(name))
+        .to_string_lossy()
+        .into_owned();
+    Some(key)
+}
+
+// Every maitouch build has to agree on the name, whatever Rust it was
+// built with, so this is a fixed hash (64-bit FNV-1a) and not the std one
+fn lock_name(key: &str) -> String {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("maitouch-{:016x}", hash)
+}
+
+// flock() on a file in the temp dir that also records the holder's pid.
+// A file left by a dead instance is simply locked again and overwritten.
+#[cfg(unix)]
+struct PortLock {
+    _file: std::fs::File,
+}
+
+#[cfg(unix)]
+impl PortLock {
+    // Err(pid) when another live process holds the lock
+    fn acquire(key: &str) -> Result<std::result::Result<Self, Option<u32>>> {
+        use std::io::{Read, Seek, Write};
+        use std::os::unix::io::AsRawFd;
+
+        let path = std::env::temp_dir().join(format!("{}.lock", lock_name(key)));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("opening lock file {}", path.display()))?;
+        // SAFETY: the descriptor belongs to `file`, which outlives the call
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err).with_context(|| format!("locking {}", path.display()));
+            }
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            return Ok(Err(pid.trim().parse().ok()));
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        tracing::debug!("Locked {} through {}", key, path.display());
+        Ok(Ok(PortLock { _file: file }))
+    }
+}
+
+#[cfg(windows)]
+mod kernel32 {
+    pub type Handle = *mut std::ffi::c_void;
+    pub const ERROR_ALREADY_EXISTS: i32 = 183;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn CreateMutexW(
+            attributes: *mut std::ffi::c_void,
+            owned: i32,
+            name: *const u16,
+        ) -> Handle;
+        pub fn CloseHandle(handle: Handle) -> i32;
+    }
+}
+
+// A named mutex; Windows destroys it once the last handle to it closes,
+// including when its owner dies. It can't say who holds it.
+#[cfg(windows)]
+struct PortLock {
+    handle: kernel32::Handle,
+}
+
+#[cfg(windows)]
+impl PortLock {
+    fn acquire(key: &str) -> Result<std::result::Result<Self, Option<u32>>> {
+        let name: Vec<u16> = format!("Local\\{}", lock_name(key))
+            .encode_utf16()
+            .chain(Some(0))
+            .collect();
+        // SAFETY: name is NUL terminated and outlives the call
+        let handle = unsafe { kernel32::CreateMutexW(std::ptr::null_mut(), 0, name.as_ptr()) };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("creating instance mutex for {}", key));
+        }
+        if std::io::Error::last_os_error().raw_os_error() == Some(kernel32::ERROR_ALREADY_EXISTS) {
+            // SAFETY: handle was just returned by CreateMutexW
+            unsafe { kernel32::CloseHandle(handle) };
+            return Ok(Err(None));
+        }
+        Ok(Ok(PortLock { handle }))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for PortLock {
+    fn drop(&mut self) {
+        // SAFETY: the handle is owned by this lock and closed once
+        unsafe { kernel32::CloseHandle(self.handle) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // A PTY link name no other test or instance locks
+    fn unique_port() -> String {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        format!(
+            "{}/tmp/maitouch-instance-test-{}-{}",
+            ports::PTY_PREFIX,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    #[test]
+    fn lock_names_are_fixed() {
+        // The published FNV-1a test values
+        assert_eq!(lock_name(""), "maitouch-cbf29ce484222325");
+        assert_eq!(lock_name("a"), "maitouch-af63dc4c8601ec8c");
+        assert_eq!(lock_name("foobar"), "maitouch-85944171f73967e8");
+        assert_ne!(lock_name("/dev/ttyUSB0"), lock_name("/dev/ttyUSB1"));
+    }
+
+    #[test]
+    fn unlockable_ports_have_no_key() {
+        assert_eq!(lock_key(ports::STDIO), None);
+        assert_eq!(lock_key(ports::PTY_PREFIX), None);
+        assert_eq!(lock_key("pty:/tmp/link").as_deref(), Some("/tmp/link"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_held_port_conflicts_until_released() {
+        let port = unique_port();
+        let lock = InstanceLock::acquire(&[&port], false).unwrap();
+        let err = InstanceLock::acquire(&[&port], false).err().unwrap();
+        let message = err.to_string();
+        assert!(message.contains("already using"), "{}", message);
+        assert!(
+            message.contains(&format!("(pid {})", std::process::id())),
+            "{}",
+            message
+        );
+        // --force carries on without the lock
+        InstanceLock::acquire(&[&port], true).unwrap();
+        drop(lock);
+        InstanceLock::acquire(&[&port], false).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stale_lock_file_is_taken_over() {
+        let port = unique_port();
+        let key = lock_key(&port).unwrap();
+        let path = std::env::temp_dir().join(format!("{}.lock", lock_name(&key)));
+        // What a crashed instance leaves: the file and its pid, no flock
+        std::fs::write(&path, "4194304").unwrap();
+        let lock = InstanceLock::acquire(&[&port], false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(lock);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn one_device_under_two_names() {
+        let dir = std::env::temp_dir().join(format!("maitouch-same-device-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let device = dir.join("device");
+        let link = dir.join("link");
+        std::fs::write(&device, "").unwrap();
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&device, &link).unwrap();
+        let (device, link) = (device.to_str().unwrap(), link.to_str().unwrap());
+        assert!(same_device(device, link).is_some());
+        assert_eq!(same_device(device, "/dev/null"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,12 @@
-use memchr;
 use std::io::{BufRead, ErrorKind, Result};
 
-// Copy-paste of BufRead::skip_until so that we can build on stable rust
-pub fn skip_until<R: BufRead + ?Sized>(r: &mut R, delim: u8) -> Result<usize> {
+// Copy-paste of BufRead::skip_until so that we can build on stable rust,
+// which also appends the bytes it skipped before the delimiter to `kept`
+pub fn skip_until<R: BufRead + ?Sized>(
+    r: &mut R,
+    delim: u8,
+    mut kept: Option<&mut Vec<u8>>,
+) -> Result<usize> {
     let mut read = 0;
     loop {
         let (done, used) = {
@@ -11,10 +15,14 @@ pub fn skip_until<R: BufRead + ?Sized>(r: &mut R, delim: u8) -> Result<usize> {
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => return Err(e),
             };
-            match memchr::memchr(delim, available) {
-                Some(i) => (true, i + 1),
-                None => (false, available.len()),
+            let (done, used, skipped) = match memchr::memchr(delim, available) {
+                Some(i) => (true, i + 1, i),
+                None => (false, available.len(), available.len()),
+            };
+            if let Some(kept) = kept.as_mut() {
+                kept.extend_from_slice(&available[..skipped]);
             }
+            (done, used)
         };
         r.consume(used);
         read += used;
@@ -23,3 +31,22 @@ pub fn skip_until<R: BufRead + ?Sized>(r: &mut R, delim: u8) -> Result<usize> {
         }
     }
 }
+
+// Appends to `buf` until it holds `len` bytes. As with read_until, bytes
+// read before an error stay in `buf`, so a timed out call can be retried.
+pub fn fill_to<R: BufRead + ?Sized>(r: &mut R, buf: &mut Vec<u8>, len: usize) -> Result<()> {
+    while buf.len() < len {
+        let available = match r.fill_buf() {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let used = available.len().min(len - buf.len());
+        buf.extend_from_slice(&available[..used]);
+        r.consume(used);
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use crate::report::SessionReport;
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// Failed keep-alives in a row before the board is taken for gone
+const MAX_FAILURES: u32 = 3;
+
+// `7b4b417d:2000`: bytes written to the ADX every interval while it
+// streams, for firmware with a watchdog that stops streaming without them
+#[derive(Clone, Debug)]
+pub struct Keepalive {
+    pub bytes: Vec<u8>,
+    pub interval: Duration,
+}
+
+impl FromStr for Keepalive {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (hex, ms) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("keep-alive {} should look like 7b4b417d:2000", s))?;
+        let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+        if hex.is_empty() || !hex.len().is_multiple_of(2) {
+            bail!("keep-alive bytes {} should be whole hex bytes", hex);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .with_context(|| format!("bad hex in keep-alive {}", hex))?;
+        let ms: u64 = ms
+            .trim()
+            .parse()
+            .with_context(|| format!("bad interval in keep-alive {}", s))?;
+        if ms == 0 {
+            bail!("keep-alive interval must be above 0ms");
+        }
+        Ok(Keepalive {
+            bytes,
+            interval: Duration::from_millis(ms),
+        })
+    }
+}
+
+impl Keepalive {
+    // Writes the keep-alive every interval until `writing` is cleared,
+    // counting into the report. The writer is only shared with the stream
+    // reader, for restarting a board that power-cycled. Gives up with the
+    // error once MAX_FAILURES writes in a row have failed.
+    pub fn run<W: Write>(
+        &self,
+        writer: &Mutex<&mut W>,
+        writing: &AtomicBool,
+        report: &SessionReport,
+    ) -> Result<()> {
+        let mut failures = 0;
+        while writing.load(Ordering::Relaxed) {
+            thread::park_timeout(self.interval);
+            if !writing.load(Ordering::Relaxed) {
+                break;
+            }
+            let mut writer = writer.lock().unwrap();
+            match writer.write_all(&self.bytes).and_then(|()| writer.flush()) {
+                Ok(()) => {
+                    failures = 0;
+                    report.keepalives.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    failures += 1;
+                    report.keepalive_failures.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        "Couldn't send the ADX its keep-alive ({} of {}): {}",
+                        failures,
+                        MAX_FAILURES,
+                        err
+                    );
+                    if failures >= MAX_FAILURES {
+                        return Err(err).context("ADX keep-alives keep failing");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    // Fails the writes it's told to, in order, and keeps what it's sent
+    struct Flaky {
+        fails: Vec<bool>,
+        sent: Vec<u8>,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.fails.is_empty() && self.fails.remove(0) {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+            }
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn keepalive(s: &str) -> Keepalive {
+        s.parse().unwrap()
+    }
+
+    fn error(s: &str) -> String {
+        format!("{:#}", s.parse::<Keepalive>().unwrap_err())
+    }
+
+    #[test]
+    fn parses_hex_bytes_and_an_interval() {
+        let parsed = keepalive("7b4b417d:2000");
+        assert_eq!(parsed.bytes, b"{KA}");
+        assert_eq!(parsed.interval, Duration::from_secs(2));
+        // Whitespace between the bytes is let through
+        assert_eq!(keepalive("7b 4b 41 7d : 50").bytes, b"{KA}");
+    }
+
+    #[test]
+    fn bad_keepalives_say_what_is_wrong() {
+        assert_eq!(
+            error("7b4b417d"),
+            "keep-alive 7b4b417d should look like 7b4b417d:2000"
+        );
+        assert_eq!(
+            error("7b4:20"),
+            "keep-alive bytes 7b4 should be whole hex bytes"
+        );
+        assert_eq!(error(":20"), "keep-alive bytes  should be whole hex bytes");
+        assert!(error("7bzz:20").starts_with("bad hex in keep-alive 7bzz"));
+        assert!(error("7b:soon").starts_with("bad interval in keep-alive 7b:soon"));
+        assert_eq!(error("7b:0"), "keep-alive interval must be above 0ms");
+    }
+
+    #[test]
+    fn writes_every_interval_until_told_to_stop() {
+        let keepalive = keepalive("7b4b417d:10");
+        let mut board = Flaky {
+            fails: Vec::new(),
+            sent: Vec::new(),
+        };
+        let report = SessionReport::new();
+        let writing = AtomicBool::new(true);
+        {
+            let writer = Mutex::new(&mut board);
+            thread::scope(|scope| {
+                let run = scope.spawn(|| keepalive.run(&writer, &writing, &report));
+                thread::sleep(Duration::from_millis(100));
+                writing.store(false, Ordering::Relaxed);
+                run.thread().unpark();
+                run.join().unwrap().unwrap();
+            });
+        }
+        let sent = report.keepalives.load(Ordering::Relaxed);
+        assert!(sent >= 3, "only {} keep-alives in 100ms", sent);
+        assert_eq!(board.sent, b"{KA}".repeat(sent as usize));
+        assert_eq!(report.keepalive_failures.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn gives_up_after_failures_in_a_row() {
+        // A success in between starts the count over, so it's the last
+        // three that end it
+        let keepalive = keepalive("00:1");
+        let mut board = Flaky {
+            fails: vec![true, true, false, true, true, true],
+            sent: Vec::new(),
+        };
+        let report = SessionReport::new();
+        let err = keepalive
+            .run(&Mutex::new(&mut board), &AtomicBool::new(true), &report)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "ADX keep-alives keep failing");
+        assert_eq!(board.sent, [0]);
+        assert_eq!(report.keepalives.load(Ordering::Relaxed), 1);
+        assert_eq!(report.keepalive_failures.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn writes_nothing_once_stopped() {
+        let mut board = Flaky {
+            fails: Vec::new(),
+            sent: Vec::new(),
+        };
+        let report = SessionReport::new();
+        keepalive("00:1")
+            .run(&Mutex::new(&mut board), &AtomicBool::new(false), &report)
+            .unwrap();
+        assert!(board.sent.is_empty());
+    }
+}
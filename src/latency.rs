@@ -0,0 +1,243 @@
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::{bail, Result};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// What an FTDI latency timer can be set to, in ms
+pub const RANGE: std::ops::RangeInclusive<u32> = 1..=255;
+
+// Where the usb-serial drivers list their ports on Linux. Only ftdi_sio
+// gives a port a latency_timer attribute, so finding one is how an FTDI
+// adapter is told apart from the others.
+#[cfg(target_os = "linux")]
+const USB_SERIAL_DEVICES: &str = "/sys/bus/usb-serial/devices";
+
+// The latency_timer attribute of the FTDI adapter behind `port`, following
+// symlinks like /dev/serial/by-id to the ttyUSB they point at
+#[cfg(target_os = "linux")]
+pub fn sysfs_path(port: &str) -> Option<PathBuf> {
+    sysfs_path_under(Path::new(USB_SERIAL_DEVICES), port)
+}
+
+#[cfg(target_os = "linux")]
+fn sysfs_path_under(devices: &Path, port: &str) -> Option<PathBuf> {
+    let device = std::fs::canonicalize(port).unwrap_or_else(|_| port.into());
+    let tty = device.file_name()?;
+    Some(devices.join(tty).join("latency_timer"))
+}
+
+#[cfg(target_os = "linux")]
+fn read(path: &PathBuf) -> Result<u32> {
+    let text = std::fs::read_to_string(path)?;
+    text.trim()
+        .parse()
+        .with_context(|| format!("{} holds {:?}", path.display(), text.trim()))
+}
+
+// An FTDI adapter's latency timer, set for the session and put back to what
+// it was by restore(). The restore also runs on drop, but a signal exits
+// without unwinding, so the shutdown handler has to call it itself.
+pub struct LatencyTimer {
+    path: PathBuf,
+    original: u32,
+    restored: AtomicBool,
+}
+
+impl LatencyTimer {
+    // Sets the latency timer of the adapter behind `port` to `ms`. Meant
+    // for before the port is opened, so the first read already gets it.
+    #[cfg(target_os = "linux")]
+    pub fn set(port: &str, ms: u32) -> Result<Self> {
+        Self::set_under(Path::new(USB_SERIAL_DEVICES), port, ms)
+    }
+
+    // set(), with the usb-serial devices listed under `devices`
+    #[cfg(target_os = "linux")]
+    fn set_under(devices: &Path, port: &str, ms: u32) -> Result<Self> {
+        let Some(path) = sysfs_path_under(devices, port).filter(|path| path.exists()) else {
+            bail!(
+                "{} isn't an FTDI adapter, it has no latency timer to set",
+                port
+            );
+        };
+        let original = read(&path)?;
+        if original != ms {
+            std::fs::write(&path, ms.to_string()).with_context(|| {
+                format!(
+                    "setting the latency timer of {} (as root: echo {} | sudo tee {})",
+                    port,
+                    ms,
+                    path.display()
+                )
+            })?;
+            // The driver rounds or refuses some values without failing the write
+            let set = read(&path)?;
+            if set != ms {
+                bail!("{} took a latency timer of {}ms, not {}ms", port, set, ms);
+            }
+        }
+        tracing::info!(
+            "Latency timer of {} set to {}ms (was {}ms)",
+            port,
+            ms,
+            original
+        );
+        Ok(LatencyTimer {
+            path,
+            original,
+            restored: AtomicBool::new(original == ms),
+        })
+    }
+
+    // The FTDI driver on Windows keeps the timer in the registry, where a
+    // change only takes once the adapter is plugged in again
+    #[cfg(not(target_os = "linux"))]
+    pub fn set(port: &str, ms: u32) -> Result<Self> {
+        let _ = ms;
+        bail!(
+            "setting the latency timer of {} isn't supported on this platform; \
+             set it in Device Manager (the port's Properties > Port Settings > Advanced)",
+            port
+        )
+    }
+
+    pub fn restore(&self) {
+        if self.restored.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        // An adapter that was unplugged comes back with its default anyway
+        match std::fs::write(&self.path, self.original.to_string()) {
+            Ok(()) => tracing::info!("Latency timer put back to {}ms", self.original),
+            Err(err) => tracing::warn!(
+                "Couldn't put the latency timer back to {}ms: {}",
+                self.original,
+                err
+            ),
+        }
+    }
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+// The timer is only set through sysfs
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    // A sysfs tree with an FTDI ttyUSB0 at `latency` and a ttyACM0 from
+    // another driver, and a /dev with a by-id link to the ttyUSB0. `test`
+    // gets the devices directory and the /dev directory.
+    fn fake_sysfs(name: &str, latency: &str, test: impl FnOnce(&Path, &Path)) {
+        let root =
+            std::env::temp_dir().join(format!("maitouch-sysfs-{}-{}", name, std::process::id()));
+        let devices = root.join("sys/bus/usb-serial/devices");
+        let dev = root.join("dev");
+        std::fs::create_dir_all(devices.join("ttyUSB0")).unwrap();
+        std::fs::create_dir_all(devices.join("ttyACM0")).unwrap();
+        std::fs::create_dir_all(dev.join("serial/by-id")).unwrap();
+        std::fs::write(devices.join("ttyUSB0/latency_timer"), latency).unwrap();
+        std::fs::write(dev.join("ttyUSB0"), "").unwrap();
+        std::fs::write(dev.join("ttyACM0"), "").unwrap();
+        std::os::unix::fs::symlink(
+            "../../ttyUSB0",
+            dev.join("serial/by-id/usb-FTDI_FT232R_USB_UART-if00-port0"),
+        )
+        .unwrap();
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| test(&devices, &dev)));
+        std::fs::remove_dir_all(&root).unwrap();
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    fn timer(devices: &Path) -> String {
+        std::fs::read_to_string(devices.join("ttyUSB0/latency_timer")).unwrap()
+    }
+
+    fn port(dev: &Path, name: &str) -> String {
+        dev.join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn sets_the_timer_and_puts_it_back() {
+        fake_sysfs("set", "16\n", |devices, dev| {
+            let latency = LatencyTimer::set_under(devices, &port(dev, "ttyUSB0"), 1).unwrap();
+            assert_eq!(timer(devices), "1");
+            latency.restore();
+            assert_eq!(timer(devices), "16");
+            // Only once, so the drop leaves alone what came after
+            std::fs::write(devices.join("ttyUSB0/latency_timer"), "2").unwrap();
+            drop(latency);
+            assert_eq!(timer(devices), "2");
+        });
+    }
+
+    #[test]
+    fn a_drop_puts_the_timer_back() {
+        fake_sysfs("drop", "16", |devices, dev| {
+            drop(LatencyTimer::set_under(devices, &port(dev, "ttyUSB0"), 4).unwrap());
+            assert_eq!(timer(devices), "16");
+        });
+    }
+
+    #[test]
+    fn follows_a_by_id_link_to_the_tty() {
+        fake_sysfs("by-id", "16", |devices, dev| {
+            let by_id = port(dev, "serial/by-id/usb-FTDI_FT232R_USB_UART-if00-port0");
+            assert_eq!(
+                sysfs_path_under(devices, &by_id).unwrap(),
+                devices.join("ttyUSB0/latency_timer")
+            );
+            let _latency = LatencyTimer::set_under(devices, &by_id, 1).unwrap();
+            assert_eq!(timer(devices), "1");
+        });
+    }
+
+    #[test]
+    fn a_timer_already_there_is_left_alone() {
+        fake_sysfs("same", "1", |devices, dev| {
+            let latency = LatencyTimer::set_under(devices, &port(dev, "ttyUSB0"), 1).unwrap();
+            std::fs::write(devices.join("ttyUSB0/latency_timer"), "2").unwrap();
+            drop(latency);
+            assert_eq!(timer(devices), "2");
+        });
+    }
+
+    #[test]
+    fn an_adapter_without_a_timer_isnt_ftdi() {
+        fake_sysfs("acm", "16", |devices, dev| {
+            let acm = port(dev, "ttyACM0");
+            let err = LatencyTimer::set_under(devices, &acm, 1).err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "{} isn't an FTDI adapter, it has no latency timer to set",
+                    acm
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn a_timer_that_isnt_a_number_is_an_error() {
+        fake_sysfs("garbage", "soon\n", |devices, dev| {
+            let err = LatencyTimer::set_under(devices, &port(dev, "ttyUSB0"), 1)
+                .err()
+                .unwrap();
+            let path = devices.join("ttyUSB0/latency_timer");
+            assert_eq!(
+                format!("{}", err),
+                format!("{} holds \"soon\"", path.display())
+            );
+            assert_eq!(timer(devices), "soon\n");
+        });
+    }
+}
@@ -0,0 +1,344 @@
+use crate::clock::Clock;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+pub const SUMMARY_WINDOW: Duration = Duration::from_secs(10);
+
+struct Site {
+    window_start: Instant,
+    suppressed: u64,
+}
+
+// Rate limits warnings from hot paths. The first occurrence of a key is
+// logged straight away; further ones within the window are only counted.
+// When a window closes with some held back, their count is logged and a
+// new window starts, so a flood gets one line every window however long
+// it lasts. A window that closes with nothing held back ends the key's
+// suppression, and its next occurrence is logged in full again. Windows
+// are closed on every warn() and tick(), so a flood that stops is still
+// summed up once its window is over.
+pub struct WarnLimiter<'a, K> {
+    clock: &'a dyn Clock,
+    window: Duration,
+    sites: HashMap<K, Site>,
+    // When the earliest window closes, so tick() costs a comparison
+    next_close: Option<Instant>,
+}
+
+impl<'a, K: Hash + Eq + Display> WarnLimiter<'a, K> {
+    pub fn new(clock: &'a dyn Clock, window: Duration) -> Self {
+        WarnLimiter {
+            clock,
+            window,
+            sites: HashMap::new(),
+            next_close: None,
+        }
+    }
+
+    pub fn warn(&mut self, key: K, message: impl FnOnce() -> String) {
+        let now = self.clock.now();
+        self.close_windows(now);
+        match self.sites.get_mut(&key) {
+            Some(site) => site.suppressed += 1,
+            None => {
+                tracing::warn!("{}", message());
+                self.sites.insert(
+                    key,
+                    Site {
+                        window_start: now,
+                        suppressed: 0,
+                    },
+                );
+                let close = now + self.window;
+                self.next_close = Some(self.next_close.map_or(close, |next| next.min(close)));
+            }
+        }
+    }
+
+    // Sums up the windows that have closed since the last call. Call it
+    // often from the loop the warnings come from.
+    pub fn tick(&mut self) {
+        if self.next_close.is_some() {
+            self.close_windows(self.clock.now());
+        }
+    }
+
+    fn close_windows(&mut self, now: Instant) {
+        if self.next_close.is_none_or(|next| now < next) {
+            return;
+        }
+        let window = self.window;
+        self.sites.retain(|key, site| {
+            if now.saturating_duration_since(site.window_start) < window {
+                return true;
+            }
+            if site.suppressed == 0 {
+                return false;
+            }
+            tracing::warn!(
+                "...and {} more {} in the last {}s",
+                site.suppressed,
+                key,
+                window.as_secs()
+            );
+            site.window_start = now;
+            site.suppressed = 0;
+            true
+        });
+        self.next_close = self
+            .sites
+            .values()
+            .map(|site| site.window_start + window)
+            .min();
+    }
+
+    // Reports whatever is still being held back, e.g. when leaving streaming mode
+    pub fn finish(&mut self) {
+        for (key, site) in self.sites.drain() {
+            if site.suppressed > 0 {
+                tracing::warn!("...and {} more {}", site.suppressed, key);
+            }
+        }
+        self.next_close = None;
+    }
+}
+
+// Collapses runs of identical config-mode exchanges, e.g. a test menu
+// polling the same command, into one line with a repeat count
+pub struct RepeatCollapser<'a> {
+    clock: &'a dyn Clock,
+    window: Duration,
+    current: Option<(String, String)>,
+    repeats: u64,
+    last_report: Instant,
+}
+
+impl<'a> RepeatCollapser<'a> {
+    pub fn new(clock: &'a dyn Clock, window: Duration) -> Self {
+        RepeatCollapser {
+            clock,
+            window,
+            current: None,
+            repeats: 0,
+            last_report: clock.now(),
+        }
+    }
+
+    // Returns true if this exchange differs from the previous one and should
+    // be logged in full. Long runs are reported every window.
+    pub fn observe(&mut self, command: &str, response: &str) -> bool {
+        let now = self.clock.now();
+        if let Some((cmd, resp)) = &self.current {
+            if cmd == command && resp == response {
+                self.repeats += 1;
+                if now.saturating_duration_since(self.last_report) >= self.window {
+                    self.flush();
+                }
+                return false;
+            }
+        }
+        self.flush();
+        self.current = Some((command.to_string(), response.to_string()));
+        true
+    }
+
+    pub fn flush(&mut self) {
+        if let Some((command, response)) = &self.current {
+            if self.repeats > 0 {
+                if response.is_empty() {
+                    tracing::info!("{} repeated {} more times", command, self.repeats);
+                } else {
+                    tracing::info!(
+                        "{} -> {} repeated {} more times",
+                        command,
+                        response,
+                        self.repeats
+                    );
+                }
+            }
+        }
+        self.repeats = 0;
+        self.last_report = self.clock.now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::logcapture::capturing;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn flood(warnings: &mut WarnLimiter<&str>, key: &'static str, count: usize) {
+        for n in 0..count {
+            warnings.warn(key, || format!("{} {}", key, n));
+        }
+    }
+
+    #[test]
+    fn logs_the_first_warning_and_holds_back_the_rest() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut warnings = WarnLimiter::new(&clock, WINDOW);
+            flood(&mut warnings, "short packets", 100);
+            assert_eq!(log.take(), ["short packets 0"]);
+            clock.advance(WINDOW - Duration::from_millis(1));
+            warnings.tick();
+            assert!(log.take().is_empty());
+        });
+    }
+
+    #[test]
+    fn sums_up_a_flood_that_stops_inside_the_window() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut warnings = WarnLimiter::new(&clock, WINDOW);
+            flood(&mut warnings, "short packets", 5);
+            clock.advance(Duration::from_secs(1));
+            warnings.tick();
+            assert_eq!(log.take(), ["short packets 0"]);
+            clock.advance(WINDOW);
+            warnings.tick();
+            assert_eq!(log.take(), ["...and 4 more short packets in the last 10s"]);
+            warnings.finish();
+            assert!(log.take().is_empty());
+        });
+    }
+
+    #[test]
+    fn sums_up_a_lasting_flood_once_a_window() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut warnings = WarnLimiter::new(&clock, WINDOW);
+            let mut summaries = Vec::new();
+            // 1000 warnings a second for 35s
+            for _ in 0..35_000 {
+                warnings.warn("frame gaps", || "frame gap".to_string());
+                clock.advance(Duration::from_millis(1));
+                summaries.extend(log.take());
+            }
+            assert_eq!(
+                summaries,
+                [
+                    "frame gap",
+                    "...and 9999 more frame gaps in the last 10s",
+                    "...and 10000 more frame gaps in the last 10s",
+                    "...and 10000 more frame gaps in the last 10s",
+                ]
+            );
+            warnings.finish();
+            assert_eq!(log.take(), ["...and 5000 more frame gaps"]);
+        });
+    }
+
+    #[test]
+    fn a_quiet_window_logs_the_next_warning_in_full() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut warnings = WarnLimiter::new(&clock, WINDOW);
+            flood(&mut warnings, "short packets", 1);
+            clock.advance(WINDOW);
+            warnings.tick();
+            flood(&mut warnings, "short packets", 1);
+            assert_eq!(log.take(), ["short packets 0", "short packets 0"]);
+        });
+    }
+
+    #[test]
+    fn any_warning_closes_the_windows_due() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut warnings = WarnLimiter::new(&clock, WINDOW);
+            flood(&mut warnings, "short packets", 3);
+            clock.advance(Duration::from_secs(4));
+            flood(&mut warnings, "frame gaps", 2);
+            clock.advance(Duration::from_secs(6));
+            flood(&mut warnings, "frame gaps", 1);
+            assert_eq!(
+                log.take(),
+                [
+                    "short packets 0",
+                    "frame gaps 0",
+                    "...and 2 more short packets in the last 10s",
+                ]
+            );
+            clock.advance(Duration::from_secs(4));
+            warnings.tick();
+            assert_eq!(log.take(), ["...and 2 more frame gaps in the last 10s"]);
+        });
+    }
+
+    #[test]
+    fn finish_reports_what_is_held_back() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut warnings = WarnLimiter::new(&clock, WINDOW);
+            flood(&mut warnings, "short packets", 3);
+            flood(&mut warnings, "frame gaps", 1);
+            log.take();
+            warnings.finish();
+            assert_eq!(log.take(), ["...and 2 more short packets"]);
+            flood(&mut warnings, "short packets", 1);
+            assert_eq!(log.take(), ["short packets 0"]);
+        });
+    }
+
+    #[test]
+    fn repeats_are_collapsed_only_while_they_run_back_to_back() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut repeats = RepeatCollapser::new(&clock, WINDOW);
+            let shown: Vec<bool> = [
+                ("{A}", "(A)"),
+                ("{A}", "(A)"),
+                ("{B}", "(B)"),
+                ("{A}", "(A)"),
+            ]
+            .iter()
+            .map(|(command, response)| repeats.observe(command, response))
+            .collect();
+            // A A B A: the second A is a repeat, the last one isn't, as B
+            // came between them
+            assert_eq!(shown, [true, false, true, true]);
+            assert_eq!(log.take(), ["{A} -> (A) repeated 1 more times"]);
+            repeats.flush();
+            assert!(log.take().is_empty());
+        });
+    }
+
+    #[test]
+    fn a_lasting_run_of_repeats_is_reported_once_a_window() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut repeats = RepeatCollapser::new(&clock, WINDOW);
+            assert!(repeats.observe("{HALT}", ""));
+            for _ in 0..25 {
+                clock.advance(Duration::from_secs(1));
+                assert!(!repeats.observe("{HALT}", ""));
+            }
+            assert_eq!(
+                log.take(),
+                [
+                    "{HALT} repeated 10 more times",
+                    "{HALT} repeated 10 more times"
+                ]
+            );
+            repeats.flush();
+            assert_eq!(log.take(), ["{HALT} repeated 5 more times"]);
+        });
+    }
+
+    #[test]
+    fn the_same_command_with_another_response_isnt_a_repeat() {
+        capturing(|log| {
+            let clock = MockClock::new();
+            let mut repeats = RepeatCollapser::new(&clock, WINDOW);
+            assert!(repeats.observe("{LAr2}", "(LAr2)"));
+            assert!(repeats.observe("{LAr2}", "(LAr3)"));
+            assert!(log.take().is_empty());
+        });
+    }
+}
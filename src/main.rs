@@ -1,169 +1,1245 @@
-use anyhow::Result;
-use std::io::{BufRead, BufReader, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::RwLock;
-use std::thread;
-use std::time::Duration;
-use structopt::StructOpt;
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, ScopedJoinHandle};
+use std::time::{Duration, Instant};
+use structopt::clap::{AppSettings, ErrorKind, Shell};
+use structopt::{StructOpt, StructOptInternal};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::reload;
 
+mod alert;
+mod attach;
+mod banner;
+mod baud;
+mod bench;
+mod calibrate;
+mod capabilities;
+mod clock;
+mod com0com;
+mod conf;
+mod doctor;
+mod events;
+mod failover;
+mod features;
+mod filter;
+mod framed;
+mod handshake;
+mod histogram;
+mod inject;
+mod instance;
 mod io;
+mod keepalive;
+mod latency;
+mod limit;
+#[cfg(test)]
+mod logcapture;
+mod mirror;
+mod oslog;
+mod pacing;
+mod pending;
+mod ports;
+#[cfg(unix)]
+mod pty;
+mod quarantine;
+mod rate;
+mod reads;
+mod report;
+mod resume;
+mod retry;
+mod sched;
+mod schema;
+#[cfg(all(test, unix))]
+mod script;
+mod shell;
+mod shm;
+mod shutdown;
+mod slider;
+mod state;
+mod strict;
+mod synth;
+mod tcp;
+mod timeouts;
+mod vectors;
+mod verbosity;
+mod warmup;
+mod wire;
 
-const MAX_MESSAGE_SIZE: usize = 6;
-const TOUCH_PACKET_SIZE: usize = 9;
-const HALT_COMMAND: &[u8] = "{HALT}".as_bytes();
-const RESET_COMMAND: &[u8] = "{RSET}".as_bytes();
-const STAT_COMMAND: &[u8] = "{STAT}".as_bytes();
-
-#[derive(PartialEq)]
-enum CommandType {
-    Halt,
-    Stat,
-    Reset,
-    Config,
-}
-
-struct PacketDelimiter {
-    pub open: char,
-    pub close: char,
-}
-
-const ALLS_PACKET: PacketDelimiter = PacketDelimiter {
-    open: '{',
-    close: '}',
-};
-const ADX_PACKET: PacketDelimiter = PacketDelimiter {
-    open: '(',
-    close: ')',
-};
+use alert::{Alert, AlertHook};
+use attach::ReaderWatch;
+use banner::{BannerWatch, ConfigReplay};
+use baud::{BaudSwitch, LineStats, LineVerdict};
+use clock::{Clock, MonotonicClock};
+use events::{Action, Diff, EventCsv, TransitionDetector};
+use failover::{SilenceAction, SilencePolicy};
+use features::Features;
+use filter::{FilterChain, Profile, RegionDelay, RegionDelays, Remap, Spread, SpreadRule};
+use framed::LatestFrameReader;
+use handshake::{BoardId, Expectation};
+use inject::{Inject, Injector};
+use instance::InstanceLock;
+use keepalive::Keepalive;
+use latency::LatencyTimer;
+use limit::{RepeatCollapser, WarnLimiter};
+use maitouch_protocol::command::{self, CommandKind};
+use maitouch_protocol::framing::{maimai, PacketDelimiter};
+use maitouch_protocol::touch::{BitOrder, ByteOrder, Packing, Region, TouchState};
+use mirror::Mirror;
+use oslog::{LogTarget, OsLogLayer};
+use pacing::{Coalescer, Decimator, Finalizer, PacedWriter};
+use pending::PendingCommands;
+use ports::Transport;
+use quarantine::{AdxGuard, TransitionLimit};
+use rate::RateMonitor;
+use reads::CountingPort;
+use report::SessionReport;
+use resume::ResumeState;
+use retry::{Retry, RetryPolicy};
+use sched::ThreadTuning;
+use shm::ShmState;
+use slider::{AllsProtocol, SliderMap};
+use state::SharedTouchState;
+use strict::{Direction, Strict, Violation};
+use synth::Synthesizer;
+use tcp::AllsListener;
+use timeouts::{LatencyLearner, TimeoutProfile};
+use verbosity::Verbosity;
+use warmup::Warmup;
+use wire::WireSpec;
 
+// Reads one packet into `buffer`, returning how many stray bytes came
+// before its open delimiter
 fn read_packet(
     buffer: &mut Vec<u8>,
     reader: &mut dyn BufRead,
     packet: &PacketDelimiter,
-) -> std::io::Result<()> {
+    retry: &Retry,
+) -> std::io::Result<usize> {
+    let expired =
+        |err: &std::io::Error| err.kind() != std::io::ErrorKind::TimedOut || !retry.timed_out();
     buffer.clear();
     buffer.push(packet.open as u8);
     tracing::trace!("skip_until");
-    loop {
-        match io::skip_until(reader, packet.open as u8) {
-            Err(err) => {
-                if err.kind() != std::io::ErrorKind::TimedOut {
-                    return Err(err);
-                }
-            }
-            Ok(_) => break,
+    let stray = skip_to_open(reader, packet, &expired)?;
+    tracing::trace!("read_until");
+    while let Err(err) = reader.read_until(packet.close as u8, buffer) {
+        if expired(&err) {
+            return Err(err);
         }
     }
-    tracing::trace!("read_until");
+    retry.succeeded();
+    Ok(stray)
+}
+
+fn skip_to_open(
+    reader: &mut dyn BufRead,
+    packet: &PacketDelimiter,
+    expired: &dyn Fn(&std::io::Error) -> bool,
+) -> std::io::Result<usize> {
     loop {
-        match reader.read_until(packet.close as u8, buffer) {
-            Err(err) => {
-                if err.kind() != std::io::ErrorKind::TimedOut {
-                    return Err(err);
-                }
-            }
-            Ok(_) => break,
+        match io::skip_until(reader, packet.open as u8, None) {
+            Ok(skipped) => return Ok(skipped.saturating_sub(1)),
+            Err(err) if expired(&err) => return Err(err),
+            Err(_) => {}
         }
     }
-    Ok(())
 }
 
-fn get_command_type(buffer: &Vec<u8>) -> CommandType {
-    match buffer.as_slice() {
-        HALT_COMMAND => CommandType::Halt,
-        STAT_COMMAND => CommandType::Stat,
-        RESET_COMMAND => CommandType::Reset,
-        _ => CommandType::Config,
+// Reads the ADX's answer to a config command. When the spec knows how long
+// it is, exactly that many bytes are taken so a close delimiter inside the
+// payload doesn't cut it short; otherwise it ends at the first one.
+fn read_response(
+    buffer: &mut Vec<u8>,
+    reader: &mut dyn BufRead,
+    spec: &WireSpec,
+    command: &[u8],
+    retry: &Retry,
+) -> std::io::Result<usize> {
+    let Some(len) = spec.response_len(command) else {
+        return read_packet(buffer, reader, &spec.adx, retry);
+    };
+    let expired =
+        |err: &std::io::Error| err.kind() != std::io::ErrorKind::TimedOut || !retry.timed_out();
+    buffer.clear();
+    buffer.push(spec.adx.open as u8);
+    let stray = skip_to_open(reader, &spec.adx, &expired)?;
+    while let Err(err) = io::fill_to(reader, buffer, len) {
+        if expired(&err) {
+            return Err(err);
+        }
     }
+    retry.succeeded();
+    if buffer.last() != Some(&(spec.adx.close as u8)) {
+        tracing::warn!(
+            "Response {} doesn't end where the wire spec says it should",
+            String::from_utf8_lossy(buffer)
+        );
+    }
+    Ok(stray)
 }
 
-fn stat_mode(
-    adx_reader: &mut (dyn BufRead + Send),
-    adx_writer: &mut dyn Write,
-    alls_reader: &mut (dyn BufRead + Send),
-    alls_writer: &mut (dyn Write + Send),
-) -> Result<()> {
-    tracing::info!("Streaming mode");
-    let run_flag = AtomicBool::new(true);
-    let state_buffer = RwLock::new([0u8; TOUCH_PACKET_SIZE]);
+// Reads one command from the ALLS. A game that closes and reopens its port
+// can leave half a command behind, which would run into the first command
+// it sends next; a packet too long for any command that holds another open
+// delimiter is taken from that delimiter on, and what came before counts
+// as stray.
+fn read_command(
+    buffer: &mut Vec<u8>,
+    reader: &mut dyn BufRead,
+    spec: &WireSpec,
+    retry: &Retry,
+) -> std::io::Result<usize> {
+    let stray = read_packet(buffer, reader, &spec.alls, retry)?;
+    if buffer.len() <= spec.command_max_len {
+        return Ok(stray);
+    }
+    let Some(start) = memchr::memrchr(spec.alls.open as u8, &buffer[1..]).map(|at| at + 1) else {
+        return Ok(stray);
+    };
+    tracing::warn!(
+        "Dropped {:?} from the ALLS, cut off by {:?}",
+        String::from_utf8_lossy(&buffer[..start]),
+        String::from_utf8_lossy(&buffer[start..])
+    );
+    buffer.drain(..start);
+    Ok(stray + start)
+}
 
-    thread::scope(|scope| {
-        // Read the latest touch update
-        scope.spawn(|| {
-            let mut local_buf = Vec::<u8>::with_capacity(TOUCH_PACKET_SIZE);
-            while run_flag.load(Ordering::Relaxed) {
-                read_packet(&mut local_buf, adx_reader, &ADX_PACKET).unwrap();
-                if local_buf.len() != TOUCH_PACKET_SIZE {
+// Per-frame processing state that lives across streaming sessions
+struct Pipeline {
+    filters: FilterChain,
+    events: Option<EventCsv>,
+    report: Arc<SessionReport>,
+    alerts: Arc<AlertHook>,
+    // Rate the ADX port ran at this session, and how the line looked
+    adx_baud: u32,
+    line_verdict: Option<LineVerdict>,
+    strict: Option<Arc<Strict>>,
+    resume: Option<ResumeState>,
+    shm: Option<ShmState>,
+    // Whether the current ALLS connection has anything on the other end
+    reader_watch: Option<ReaderWatch>,
+    // When the {STAT} that started the current stream was forwarded
+    stat_at: Instant,
+    mirror: Option<Arc<Mirror>>,
+    // What the ADX answered --feature-probe with, and the keep-alive left
+    // once that is taken into account
+    features: Option<Features>,
+    keepalive: Option<Keepalive>,
+    // For an ADX that power-cycles mid-stream: its banner, and the config
+    // to give it back
+    banner: Option<BannerWatch>,
+    config_sent: ConfigReplay,
+}
+
+impl Pipeline {
+    fn new(config: &Config, spec: &WireSpec) -> Result<Self> {
+        let report = Arc::new(SessionReport::new());
+        Ok(Pipeline {
+            filters: build_filters(config, spec, &report)?,
+            events: match &config.event_csv {
+                Some(path) => {
+                    require_touch_layout(spec, "touch event exports")?;
+                    Some(EventCsv::create(path)?)
+                }
+                None => None,
+            },
+            report,
+            alerts: Arc::new(AlertHook::new(
+                config.on_event.clone(),
+                config.player.clone(),
+            )),
+            adx_baud: ports::DEFAULT_BAUD,
+            line_verdict: None,
+            strict: config
+                .strict
+                .then(|| Arc::new(Strict::new(config.strict_history))),
+            resume: config
+                .resume_state
+                .as_deref()
+                .map(|path| ResumeState::new(path, config.player.as_deref())),
+            shm: match &config.shm {
+                Some(name) => {
+                    require_touch_layout(spec, "shared-memory exports")?;
+                    Some(ShmState::create(name)?)
+                }
+                None => None,
+            },
+            reader_watch: None,
+            stat_at: Instant::now(),
+            mirror: None,
+            features: None,
+            keepalive: config.adx_keepalive.clone(),
+            banner: config
+                .adx_banner
+                .as_deref()
+                .map(BannerWatch::parse)
+                .transpose()?,
+            config_sent: ConfigReplay::default(),
+        })
+    }
+}
+
+// What one stream's threads share, from its {STAT} to its teardown
+struct Session<'a, W> {
+    // Cleared in order on teardown: first the writer, then the reader
+    writing: AtomicBool,
+    run_flag: AtomicBool,
+    state_buffer: SharedTouchState,
+    // Everything the stream times goes by this
+    clock: &'a (dyn Clock + Sync),
+    stream_start: Instant,
+    // When the {STAT} that started the stream was forwarded
+    stat_at: Instant,
+    // Microseconds since stream_start at which the last touch packet arrived
+    last_frame_us: AtomicU64,
+    adx_retry: Retry<'a>,
+    alls_retry: Retry<'a>,
+    // The first --strict violation; the thread that finds it stops the
+    // writer and the halt watcher
+    violation: Mutex<Option<anyhow::Error>>,
+    // The keep-alive writes to the ADX while it streams, and so does the
+    // reader when the board power-cycles
+    adx_writer: Mutex<&'a mut W>,
+    report: Arc<SessionReport>,
+    alerts: Arc<AlertHook>,
+    strict: Option<Arc<Strict>>,
+}
+
+impl<W> Session<'_, W> {
+    // How long the stream has been going
+    fn elapsed(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.stream_start)
+    }
+
+    // Ends the stream under the game: stops the writer, and the halt
+    // watcher with it
+    fn stop(&self) {
+        self.writing.store(false, Ordering::Relaxed);
+        self.alls_retry.cancel();
+    }
+
+    fn abort(&self, err: anyhow::Error) {
+        self.violation.lock().unwrap().get_or_insert(err);
+        self.stop();
+    }
+}
+
+// What config mode lends a stream, handed back once it's over
+struct Lent<'a, R, W> {
+    pipeline: &'a mut Pipeline,
+    adx_reader: &'a mut R,
+    adx_writer: &'a mut W,
+    alls_writer: &'a mut (dyn Write + Send),
+}
+
+// What the reader is handed when its stream starts, and hands back
+type ReaderLoan<'a, R> = (&'a mut Pipeline, &'a mut R);
+type ReaderStart<'a, R, W> = (Arc<Session<'a, W>>, ReaderLoan<'a, R>);
+type WriterStart<'a, W> = (Arc<Session<'a, W>>, &'a mut (dyn Write + Send));
+
+// A stream's reader and writer threads, spawned ahead of its {STAT} and
+// waiting for it with their buffers allocated. Neither touches a port
+// until the stream starts: the board isn't streaming yet, and config mode
+// still reads its answers.
+struct Armed<'scope, R, W> {
+    start_reader: mpsc::Sender<ReaderStart<'scope, R, W>>,
+    start_writer: mpsc::Sender<WriterStart<'scope, W>>,
+    reader: ScopedJoinHandle<'scope, Option<(Result<()>, ReaderLoan<'scope, R>)>>,
+    writer: ScopedJoinHandle<'scope, Option<(Result<()>, &'scope mut (dyn Write + Send))>>,
+    clock: &'scope (dyn Clock + Sync),
+}
+
+impl<'scope, R, W> Armed<'scope, R, W>
+where
+    R: BufRead + Send + 'scope,
+    W: Write + Send + 'scope,
+{
+    fn new(
+        scope: &'scope thread::Scope<'scope, '_>,
+        config: &'scope Config,
+        spec: &'scope WireSpec,
+        clock: &'scope (dyn Clock + Sync),
+    ) -> Self {
+        let tuning = ThreadTuning {
+            realtime: config.realtime,
+            pin_cpu: config.pin_cpu,
+        };
+        let touch_layout = has_touch_layout(spec);
+        let stream_policy = stream_policy(config);
+        let adx_timeout = Duration::from_millis(config.adx_timeout_ms);
+        let warmup = Duration::from_millis(config.stream_warmup_ms);
+        let game_loss = config.halt_on_game_loss_secs.map(Duration::from_secs);
+        let stream_cap = config
+            .max_stream_minutes
+            .map(|minutes| Duration::from_secs(minutes * 60));
+        let gap_warn = config.gap_warn_ms.map(Duration::from_millis);
+
+        // Read the latest touch update. A read that fails ends the stream,
+        // and the error is handed back through the join.
+        let (start_reader, started) = mpsc::channel::<ReaderStart<R, W>>();
+        let reader = scope.spawn(move || {
+            tuning.apply("Reader");
+            // Packets collected until the line is judged, DETECT_WINDOW in
+            let mut line_stats = touch_layout.then(LineStats::new);
+            let mut local_buf = Vec::<u8>::with_capacity(spec.touch_frame_len);
+            let mut transitions = TransitionDetector::default();
+            let mut warnings = WarnLimiter::new(clock, limit::SUMMARY_WINDOW);
+            let mut latest = config
+                .low_latency
+                .then(|| LatestFrameReader::new(&spec.adx, spec.touch_frame_len));
+            // Paused here until the stream starts
+            let (session, (pipeline, adx_reader)) = started.recv().ok()?;
+            let Session {
+                ref run_flag,
+                ref state_buffer,
+                stream_start,
+                ref last_frame_us,
+                ref adx_retry,
+                ref adx_writer,
+                ref report,
+                ref alerts,
+                ref strict,
+                ..
+            } = *session;
+            let stop = || session.stop();
+            let abort = |err| session.abort(err);
+            let elapsed = || session.elapsed();
+            let read = 'read: {
+                let Pipeline {
+                    filters,
+                    events,
+                    shm,
+                    adx_baud,
+                    line_verdict,
+                    banner,
+                    config_sent,
+                    ..
+                } = &mut *pipeline;
+                *line_verdict = None;
+                if let Some(banner) = banner.as_mut() {
+                    banner.reset();
+                }
+                // Frames discarded while the ADX settles, from the start of the
+                // stream or the board's last power cycle
+                let mut warming = Warmup::new(warmup, stream_start);
+                // When the previous frame was accepted, and how many have been this session
+                let mut last_accepted: Option<Instant> = None;
+                let mut accepted = 0u64;
+                let mut latest_malformed = 0;
+                let mut rate =
+                    RateMonitor::new(config.expected_rate, *adx_baud, spec.touch_frame_len);
+                while run_flag.load(Ordering::Relaxed) {
+                    // Frames the low latency reader passed over to get to this one
+                    let mut backlog = 0;
+                    let mut power_cycled = false;
+                    let read = match latest.as_mut() {
+                        Some(latest) => latest
+                            .read_latest(adx_reader, &mut local_buf, adx_retry)
+                            .map(|skipped| {
+                                if skipped > 0 {
+                                    tracing::debug!("Skipped {} backlog frames", skipped);
+                                }
+                                report.skipped.fetch_add(skipped, Ordering::Relaxed);
+                                backlog = skipped;
+                                0
+                            }),
+                        None => match banner.as_mut() {
+                            Some(banner) => banner
+                                .read_packet(
+                                    &mut local_buf,
+                                    adx_reader,
+                                    &spec.adx,
+                                    spec.touch_frame_len,
+                                    adx_retry,
+                                )
+                                .map(|read| {
+                                    power_cycled = read.is_none();
+                                    read.unwrap_or(0)
+                                }),
+                            None => read_packet(&mut local_buf, adx_reader, &spec.adx, adx_retry),
+                        },
+                    };
+                    let stray = match read {
+                        // The teardown cancelled the retry
+                        Err(_) if !run_flag.load(Ordering::Relaxed) => break,
+                        Err(err) => {
+                            alerts.fire(
+                                Alert::BoardDisconnected,
+                                &format!("ADX read failed: {}", err),
+                            );
+                            stop();
+                            break 'read Err(err).context("ADX read failed while streaming");
+                        }
+                        Ok(stray) => stray,
+                    };
+                    warnings.tick();
+                    if let Some(strict) = &strict {
+                        strict.record(Direction::FromAdx, &local_buf);
+                    }
+                    if power_cycled {
+                        report.adx_power_cycles.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!(
+                            "!!! ADX power-cycled, sending it {} config commands and {{STAT}} again",
+                            config_sent.commands().len()
+                        );
+                        let restarted = restart_stream(
+                            spec,
+                            adx_reader,
+                            adx_writer,
+                            config_sent.commands(),
+                            stream_policy,
+                            clock,
+                        );
+                        if let Err(err) = restarted {
+                            alerts.fire(
+                                Alert::BoardDisconnected,
+                                &format!("ADX restart failed: {:#}", err),
+                            );
+                            stop();
+                            break 'read Err(
+                                err.context("couldn't restart the ADX after it power-cycled")
+                            );
+                        }
+                        // Nothing from before the power cycle carries over
+                        filters.reset();
+                        if touch_layout {
+                            let released = record_transitions(
+                                report,
+                                events.as_mut(),
+                                &mut transitions,
+                                clock.now(),
+                                TouchState::default(),
+                            );
+                            if let Some(shm) = shm.as_mut() {
+                                shm.publish(TouchState::default(), released);
+                            }
+                        }
+                        state_buffer.store(&all_clear_frame(spec));
+                        last_accepted = None;
+                        warming.restart(clock.now());
+                        continue;
+                    }
+                    let well_formed = local_buf.len() == spec.touch_frame_len;
+                    if !warming.is_over() && warming.discard(clock.now(), well_formed) {
+                        if well_formed {
+                            last_frame_us.store(elapsed().as_micros() as u64, Ordering::Relaxed);
+                        }
+                        continue;
+                    }
+                    if let Some(strict) = &strict {
+                        let dropped = latest.as_ref().map_or(0, |latest| latest.malformed);
+                        let found = if stray > 0 {
+                            Some(strict.violation(
+                                Violation::UnexpectedPacket,
+                                format!("{} stray bytes from the ADX before a frame", stray),
+                            ))
+                        } else if dropped > latest_malformed {
+                            Some(strict.violation(
+                                Violation::MalformedFrame,
+                                "the low latency reader dropped a malformed frame",
+                            ))
+                        } else if local_buf.len() != spec.touch_frame_len {
+                            Some(strict.violation(
+                                Violation::MalformedFrame,
+                                format!(
+                                    "touch packet of {} bytes, expected {}",
+                                    local_buf.len(),
+                                    spec.touch_frame_len
+                                ),
+                            ))
+                        } else {
+                            None
+                        };
+                        latest_malformed = dropped;
+                        if let Some(err) = found {
+                            abort(err);
+                            break;
+                        }
+                    }
+                    if let Some(stats) = line_stats.as_mut() {
+                        stats.record(&local_buf, local_buf.len() == spec.touch_frame_len);
+                        if elapsed() >= baud::DETECT_WINDOW {
+                            *line_verdict = judge_line(stats, *adx_baud);
+                            line_stats = None;
+                        }
+                    }
+                    if local_buf.len() != spec.touch_frame_len {
+                        report.malformed.fetch_add(1, Ordering::Relaxed);
+                        warnings.warn("short touch packets", || {
+                            format!(
+                                "Couldn't forward touch packet, buf was {} expected {}",
+                                local_buf.len(),
+                                spec.touch_frame_len
+                            )
+                        });
+                        continue;
+                    }
+                    let now = clock.now();
+                    if let Some(previous) = last_accepted.replace(now) {
+                        let gap = now - previous;
+                        report.frame_gaps.record(gap);
+                        if gap_warn.is_some_and(|limit| gap > limit) {
+                            warnings.warn("frame gaps", || {
+                                format!("{:.1?} gap in touch frames after frame {}", gap, accepted)
+                            });
+                        }
+                    }
+                    accepted += 1;
+                    for _ in 0..=backlog {
+                        rate.record(now);
+                    }
+                    let len = local_buf.len();
+                    filters.apply(&mut local_buf[1..len - 1]);
+                    let state = touch_layout
+                        .then(|| filters.output_packing().decode(&local_buf[1..len - 1]));
+                    let diff = state.map(|state| {
+                        record_transitions(report, events.as_mut(), &mut transitions, now, state)
+                    });
+                    state_buffer.store(&local_buf);
+                    if let (Some(shm), Some(state), Some(diff)) = (shm.as_mut(), state, diff) {
+                        shm.publish(state, diff);
+                    }
+                    last_frame_us.store(elapsed().as_micros() as u64, Ordering::Relaxed);
+                    report.frames.fetch_add(1, Ordering::Relaxed);
+                }
+                warming.finish();
+                if let Some(stats) = line_stats {
+                    *line_verdict = judge_line(&stats, *adx_baud);
+                }
+                report
+                    .rate_deviations
+                    .fetch_add(rate.deviations, Ordering::Relaxed);
+                warnings.finish();
+                // Close out anything still held so the timeline doesn't end
+                // mid-press: nothing is touched once the stream stops, whatever
+                // the last frame said
+                let released = record_transitions(
+                    report,
+                    events.as_mut(),
+                    &mut transitions,
+                    clock.now(),
+                    TouchState::default(),
+                );
+                if let Some(events) = events {
+                    if let Err(err) = events.flush() {
+                        tracing::warn!("Couldn't flush event CSV: {}", err);
+                    }
+                }
+                if let Some(shm) = shm {
+                    shm.publish(TouchState::default(), released);
+                }
+                if let Some(latest) = latest {
                     tracing::info!(
-                        "Couldn't forward touch packet, buf was {} expected {}",
-                        local_buf.len(),
-                        TOUCH_PACKET_SIZE
+                        "Low latency reader skipped {} backlog frames, {} malformed",
+                        latest.skipped,
+                        latest.malformed
                     );
-                    continue;
-                }
-                {
-                    let mut locked_buf = state_buffer.write().unwrap();
-                    locked_buf.as_mut().copy_from_slice(local_buf.as_slice());
+                    report
+                        .malformed
+                        .fetch_add(latest.malformed, Ordering::Relaxed);
                 }
-            }
+                Ok(())
+            };
+            Some((read, (pipeline, adx_reader)))
         });
 
         // Write the latest touch update
-        scope.spawn(|| {
-            while run_flag.load(Ordering::Relaxed) {
-                {
-                    let locked_buf = state_buffer.read().unwrap();
-                    alls_writer.write_all(locked_buf.as_ref()).unwrap();
+        let (start_writer, started) = mpsc::channel::<WriterStart<W>>();
+        let writer = scope.spawn(move || {
+            tuning.apply("Writer");
+            let mut paced = config
+                .frame_rate
+                .filter(|hz| *hz > 0)
+                .map(|hz| PacedWriter::new(clock, Duration::from_secs(1) / hz));
+            let mut decimator = config.decimate.filter(|n| *n > 1).map(Decimator::new);
+            let coalesce = Duration::from_micros(config.coalesce_us);
+            let mut coalescer = (!coalesce.is_zero() && !Transport::of(&config.alls).is_device())
+                .then(|| Coalescer::new(clock, coalesce));
+            let mut frame = all_clear_frame(spec);
+            let all_clear = all_clear_frame(spec);
+            // Paused here until the stream starts
+            let (session, alls_writer) = started.recv().ok()?;
+            let Session {
+                ref writing,
+                ref state_buffer,
+                stream_start,
+                stat_at,
+                ref last_frame_us,
+                ref adx_retry,
+                ref report,
+                ref alerts,
+                ref strict,
+                ..
+            } = *session;
+            let stop = || session.stop();
+            let abort = |err| session.abort(err);
+            let elapsed = || session.elapsed();
+            let mut version = 0;
+            let mut output = Finalizer::new(alls_writer, all_clear, &report.final_clears);
+            let mut stalled = false;
+            let mut first_frame = true;
+            // The ALLS write that failed, if one did; it ends the stream
+            let mut written = Ok(());
+            while writing.load(Ordering::Relaxed) {
+                if game_loss.is_some_and(|limit| report.game_silence(clock.now()) >= limit) {
+                    lose_game(report, clock.now());
+                    // Stops the halt watcher as well; the ADX is reset once it has
+                    stop();
+                    break;
                 }
-                alls_writer.flush().unwrap();
-            }
-        });
-
-        // Watch for halt
-        scope.spawn(|| {
-            let mut command_buffer = Vec::<u8>::with_capacity(MAX_MESSAGE_SIZE);
-            loop {
-                match read_packet(&mut command_buffer, alls_reader, &ALLS_PACKET) {
-                    Err(err) => {
-                        if err.kind() != std::io::ErrorKind::TimedOut {
-                            return;
+                // The clock is monotonic, so a change of the system time can't trip this
+                if let Some(cap) = stream_cap.filter(|cap| elapsed() >= *cap) {
+                    report.stream_caps.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        "!!! Streaming for over {:?} without a HALT, taking the game for wedged: \
+                         clearing the ALLS, resetting the ADX and waiting for a fresh handshake",
+                        cap
+                    );
+                    alerts.fire(
+                        Alert::StreamCapped,
+                        &format!("streaming for over {:?} without a HALT", cap),
+                    );
+                    // The all-clear goes out as the output is dropped
+                    stop();
+                    break;
+                }
+                if let Some(strict) = &strict {
+                    let last_frame = Duration::from_micros(last_frame_us.load(Ordering::Relaxed));
+                    let silence = elapsed().saturating_sub(last_frame);
+                    if silence > adx_timeout {
+                        abort(strict.violation(
+                            Violation::StaleFrame,
+                            format!(
+                                "no frame from the ADX for {:.1?}, the ALLS would keep getting the last one",
+                                silence
+                            ),
+                        ));
+                        break;
+                    }
+                }
+                if config.strict_passthrough {
+                    let last_frame = Duration::from_micros(last_frame_us.load(Ordering::Relaxed));
+                    let silent = elapsed().saturating_sub(last_frame) > adx_timeout;
+                    if silent != stalled {
+                        stalled = silent;
+                        if stalled {
+                            report.stalls.fetch_add(1, Ordering::Relaxed);
+                            tracing::warn!(
+                                "ADX stalled ({} consecutive timeouts), withholding frames from ALLS",
+                                adx_retry.consecutive()
+                            );
+                            alerts.fire(Alert::Stalled, "ADX stopped sending frames");
+                        } else {
+                            tracing::info!("ADX resumed, forwarding frames");
+                            alerts.fire(Alert::StallRecovered, "ADX resumed sending frames");
                         }
                     }
-                    Ok(_) => {
-                        if get_command_type(&command_buffer) == CommandType::Halt {
-                            tracing::info!("HALT command in streaming mode");
-                            run_flag.store(false, Ordering::Relaxed);
+                    if stalled {
+                        thread::sleep(Duration::from_millis(1));
+                        continue;
+                    }
+                }
+                let stored = state_buffer.load_if_newer(&mut version, &mut frame);
+                // Both count frames the reader stored, not passes through this loop
+                if (decimator.is_some() || coalescer.is_some()) && !stored {
+                    let mut idle = Duration::from_micros(100);
+                    if let Some(coalescer) = coalescer.as_mut() {
+                        written = coalescer.poll(|held| output.write(held));
+                        if written.is_err() {
                             break;
                         }
+                        idle = idle.min(coalescer.remaining());
+                    }
+                    thread::sleep(idle);
+                    continue;
+                }
+                if let Some(decimator) = decimator.as_mut() {
+                    if !decimator.admit(&frame) {
+                        continue;
                     }
                 }
+                written = match (paced.as_mut(), coalescer.as_mut()) {
+                    (Some(paced), _) => paced.write_frame(|| output.write(&frame)),
+                    (None, Some(coalescer)) => coalescer.push(&frame, |held| output.write(held)),
+                    (None, None) => output.write(&frame),
+                };
+                if written.is_err() {
+                    break;
+                }
+                // Version 0 is the all-clear frame the stream starts from
+                if first_frame && version > 0 {
+                    first_frame = false;
+                    let read_at = Duration::from_micros(last_frame_us.load(Ordering::Relaxed));
+                    tracing::info!(
+                        "First ADX frame reached the ALLS {:.1?} after {{STAT}} (streaming started \
+                         after {:.1?}, the frame was read {:.1?} into it)",
+                        clock.now().saturating_duration_since(stat_at),
+                        stream_start.saturating_duration_since(stat_at),
+                        read_at
+                    );
+                }
+            }
+            // Frames still held go out before config mode resumes, and the
+            // ALLS isn't left holding half a frame
+            if let Some(coalescer) = coalescer.as_mut().filter(|_| written.is_ok()) {
+                written = coalescer.flush(|held| output.write(held));
+            }
+            if written.is_ok() {
+                written = output.finish();
+            }
+            let written = written.context("ALLS write failed while streaming");
+            if written.is_err() {
+                stop();
             }
+            report
+                .torn_frames
+                .fetch_add(output.cursor().completed, Ordering::Relaxed);
+            report
+                .resyncs
+                .fetch_add(output.cursor().resynced, Ordering::Relaxed);
+            if let Some(paced) = paced {
+                tracing::info!("ALLS write cost average {:?}", paced.write_cost());
+            }
+            if let Some(coalescer) = coalescer {
+                tracing::info!(
+                    "Coalesced {} frames into {} writes, holding one {:?} at most",
+                    coalescer.frames,
+                    coalescer.writes,
+                    coalescer.longest_hold
+                );
+            }
+            if let Some(decimator) = decimator {
+                tracing::info!(
+                    "Decimation forwarded {} frames, skipped {}",
+                    decimator.forwarded,
+                    decimator.skipped
+                );
+            }
+            // However the stream ended, the all-clear goes out as `output` is
+            // dropped; a panic here sends one anyway
+            drop(output);
+            Some((written, alls_writer))
         });
-    });
 
-    drain_and_reset(adx_reader, adx_writer)?;
+        Armed {
+            start_reader,
+            start_writer,
+            reader,
+            writer,
+            clock,
+        }
+    }
+
+    // Streams from the ADX, which has been sent its {STAT}, until the game
+    // halts it or something fails, then drains and resets the ADX
+    fn stream(
+        self,
+        config: &Config,
+        spec: &WireSpec,
+        lent: Lent<'scope, R, W>,
+        alls_reader: &mut (dyn BufRead + Send),
+    ) -> Result<Lent<'scope, R, W>> {
+        let clock = self.clock;
+        let Lent {
+            pipeline,
+            adx_reader,
+            adx_writer,
+            alls_writer,
+        } = lent;
+        if let Some(mirror) = &pipeline.mirror {
+            mirror.set_streaming(true);
+        }
+        let session = Arc::new(Session {
+            writing: AtomicBool::new(true),
+            run_flag: AtomicBool::new(true),
+            state_buffer: SharedTouchState::new(&all_clear_frame(spec)),
+            clock,
+            stream_start: clock.now(),
+            stat_at: pipeline.stat_at,
+            last_frame_us: AtomicU64::new(0),
+            adx_retry: Retry::new(stream_policy(config), clock),
+            alls_retry: Retry::new(stream_policy(config), clock),
+            violation: Mutex::new(None),
+            adx_writer: Mutex::new(adx_writer),
+            report: pipeline.report.clone(),
+            alerts: pipeline.alerts.clone(),
+            strict: pipeline.strict.clone(),
+        });
+        let resume = pipeline.resume.clone();
+        let mut reader_watch = pipeline.reader_watch.take();
+        let keepalive = pipeline.keepalive.clone();
+        // Nothing but this stands between the {STAT} and the first frame.
+        // A thread that is gone panicked getting ready, which its join
+        // passes on.
+        self.start_reader
+            .send((session.clone(), (pipeline, adx_reader)))
+            .ok();
+        // Let the reader get to the ADX first: on one core a writer let go
+        // first spins on the all-clear frame for a whole timeslice
+        thread::yield_now();
+        self.start_writer.send((session.clone(), alls_writer)).ok();
+        tracing::info!("Streaming mode");
+
+        let Session {
+            ref writing,
+            ref run_flag,
+            ref adx_retry,
+            ref alls_retry,
+            ref adx_writer,
+            ref report,
+            ref alerts,
+            ref strict,
+            ..
+        } = *session;
+        let abort = |err| session.abort(err);
+        let (teardown_start, streamed, (pipeline, adx_reader), alls_writer) =
+            thread::scope(|scope| {
+                // Keeps the resume state fresh so a restart mid-stream picks it up
+                let keeper = resume.as_ref().map(|resume| {
+                    scope.spawn(move || {
+                        while writing.load(Ordering::Relaxed) {
+                            thread::park_timeout(resume::REFRESH_INTERVAL);
+                            resume.mark_streaming(report.no_reader.load(Ordering::Relaxed));
+                        }
+                    })
+                });
+
+                // Keeps a watchdog firmware streaming; nothing else writes to the
+                // ADX until the stream is over
+                let keepalive = keepalive.as_ref().map(|keepalive| {
+                    scope.spawn(move || {
+                        if let Err(err) = keepalive.run(adx_writer, writing, report) {
+                            alerts.fire(Alert::BoardDisconnected, &format!("{:#}", err));
+                            abort(err);
+                        }
+                    })
+                });
+
+                // Looks out for an ALLS port nothing reads from
+                let watcher = reader_watch.as_mut().map(|watch| {
+                    watch.reset();
+                    scope.spawn(move || {
+                        while writing.load(Ordering::Relaxed) {
+                            thread::park_timeout(attach::CHECK_INTERVAL);
+                            watch.check(clock.now());
+                        }
+                    })
+                });
+
+                // Watch for halt
+                let mut command_buffer = Vec::<u8>::with_capacity(spec.command_max_len);
+                loop {
+                    match read_command(&mut command_buffer, alls_reader, spec, alls_retry) {
+                        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            tracing::info!("ALLS closed in streaming mode");
+                            break;
+                        }
+                        // Timeouts only get this far once a --strict violation or the
+                        // game going quiet cancels the retry
+                        Err(_) => break,
+                        Ok(stray) => {
+                            report.game_heard(clock.now());
+                            // A game that restarted under us starts its handshake over
+                            let halt = matches!(
+                                command::classify(&spec.alls, &command_buffer),
+                                CommandKind::Halt | CommandKind::Reset
+                            );
+                            if let Some(strict) = &strict {
+                                let checked = check_command(strict, spec, &command_buffer, stray)
+                                    .and_then(|()| {
+                                        if halt {
+                                            return Ok(());
+                                        }
+                                        Err(strict.violation(
+                                            Violation::UnexpectedPacket,
+                                            format!(
+                                                "ALLS sent {} while streaming",
+                                                String::from_utf8_lossy(&command_buffer)
+                                            ),
+                                        ))
+                                    });
+                                if let Err(err) = checked {
+                                    abort(err);
+                                    break;
+                                }
+                            }
+                            if halt {
+                                tracing::info!(
+                                    "{} command in streaming mode{}",
+                                    String::from_utf8_lossy(&command_buffer),
+                                    match command::classify(&spec.alls, &command_buffer) {
+                                        CommandKind::Reset => ", the ALLS session restarted",
+                                        _ => "",
+                                    }
+                                );
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                // Stop the writer first so no stale frame reaches the ALLS
+                // after HALT, then cut the reader's wait short
+                let teardown_start = clock.now();
+                writing.store(false, Ordering::Relaxed);
+                for idle in keeper.iter().chain(&watcher).chain(&keepalive) {
+                    idle.thread().unpark();
+                }
+                let written = self.writer.join();
+                run_flag.store(false, Ordering::Relaxed);
+                adx_retry.cancel();
+                let read = self.reader.join();
+                // Both threads are done either way, so a panic can go on up
+                let (written, alls_writer) = written
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                    .expect("the writer was started");
+                let (read, lent) = read
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                    .expect("the reader was started");
+                (teardown_start, read.and(written), lent, alls_writer)
+            });
+        pipeline.reader_watch = reader_watch;
+        if let Some(mirror) = &pipeline.mirror {
+            mirror.set_streaming(false);
+        }
+        report
+            .streaming_us
+            .fetch_add(session.elapsed().as_micros() as u64, Ordering::Relaxed);
+        report.sessions.fetch_add(1, Ordering::Relaxed);
+
+        let Some(Session {
+            violation,
+            adx_writer,
+            ..
+        }) = Arc::into_inner(session)
+        else {
+            unreachable!("the stream's threads are done with it");
+        };
+        let adx_writer = adx_writer.into_inner().unwrap();
+        let drained = drain_and_reset(spec, adx_reader, adx_writer, DRAIN_QUIET);
+        pipeline.config_sent.clear();
+        tracing::info!(
+            "Streaming teardown took {:.1?}",
+            clock.now().saturating_duration_since(teardown_start)
+        );
+
+        // What ended the stream comes first; a board that is gone can't be
+        // drained either
+        match violation.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => streamed,
+        }?;
+        drained?;
+        Ok(Lent {
+            pipeline,
+            adx_reader,
+            adx_writer,
+            alls_writer,
+        })
+    }
+}
+
+fn stream_policy(config: &Config) -> RetryPolicy {
+    RetryPolicy {
+        backoff: Duration::from_millis(config.stream_retry_backoff_ms),
+    }
+}
+
+// Streams on a {STAT} with no stream armed for it, spawning the threads
+#[allow(clippy::too_many_arguments)]
+fn stat_mode(
+    config: &Config,
+    spec: &WireSpec,
+    pipeline: &mut Pipeline,
+    mut adx_reader: &mut (dyn BufRead + Send),
+    mut adx_writer: &mut (dyn Write + Send),
+    alls_reader: &mut (dyn BufRead + Send),
+    alls_writer: &mut (dyn Write + Send),
+    clock: &(dyn Clock + Sync),
+) -> Result<()> {
+    thread::scope(|scope| {
+        let lent = Lent {
+            pipeline,
+            adx_reader: &mut adx_reader,
+            adx_writer: &mut adx_writer,
+            alls_writer,
+        };
+        Armed::new(scope, config, spec, clock)
+            .stream(config, spec, lent, alls_reader)
+            .map(drop)
+    })
+}
+
+// Under --strict, records a command from the ALLS and rejects it if it
+// came after stray bytes or is longer than the wire spec allows
+fn check_command(strict: &Strict, spec: &WireSpec, command: &[u8], stray: usize) -> Result<()> {
+    strict.record(Direction::FromAlls, command);
+    let command_str = String::from_utf8_lossy(command);
+    if stray > 0 {
+        return Err(strict.violation(
+            Violation::UnexpectedPacket,
+            format!("{} stray bytes from the ALLS before {}", stray, command_str),
+        ));
+    }
+    if command.len() > spec.command_max_len {
+        return Err(strict.violation(
+            Violation::CommandTooLong,
+            format!(
+                "{} is {} bytes, the wire spec allows {}",
+                command_str,
+                command.len(),
+                spec.command_max_len
+            ),
+        ));
+    }
+    Ok(())
+}
 
+// Under --strict, records a config response and rejects it if it came
+// after stray bytes, was cut short or doesn't answer `command`
+fn check_response(
+    strict: &Strict,
+    spec: &WireSpec,
+    command: &[u8],
+    response: &[u8],
+    stray: usize,
+) -> Result<()> {
+    strict.record(Direction::FromAdx, response);
+    let command_str = String::from_utf8_lossy(command);
+    let response_str = String::from_utf8_lossy(response);
+    if stray > 0 {
+        return Err(strict.violation(
+            Violation::UnexpectedPacket,
+            format!(
+                "{} stray bytes from the ADX before the response to {}",
+                stray, command_str
+            ),
+        ));
+    }
+    if response.len() < 2 || response.last() != Some(&(spec.adx.close as u8)) {
+        return Err(strict.violation(
+            Violation::ShortRead,
+            format!("response {} to {} was cut short", response_str, command_str),
+        ));
+    }
+    if !strict::answers(command, response) {
+        return Err(strict.violation(
+            Violation::MismatchedResponse,
+            format!("{} doesn't answer {}", response_str, command_str),
+        ));
+    }
     Ok(())
 }
 
+// Reports the game as gone under --halt-on-game-loss-secs
+fn lose_game(report: &SessionReport, now: Instant) {
+    report.game_losses.fetch_add(1, Ordering::Relaxed);
+    tracing::warn!(
+        "Nothing from the game for {:.0?}, taking it for gone: resetting the ADX and waiting \
+         for it to come back",
+        report.game_silence(now)
+    );
+}
+
+fn all_clear_frame(spec: &WireSpec) -> Vec<u8> {
+    let mut frame = vec![0u8; spec.touch_frame_len];
+    frame[0] = spec.adx.open as u8;
+    frame[spec.touch_frame_len - 1] = spec.adx.close as u8;
+    frame
+}
+
+// Warns about a line that looks mis-clocked or noisy
+fn judge_line(stats: &LineStats, baud: u32) -> Option<LineVerdict> {
+    let verdict = stats.classify();
+    let malformed = stats.malformed_ratio() * 100.0;
+    match verdict {
+        Some(LineVerdict::Mismatch) => tracing::warn!(
+            "!!! Possible baud rate mismatch, ADX may not be running at {} baud ({:.0}% of packets malformed)",
+            baud,
+            malformed
+        ),
+        Some(LineVerdict::Noisy) => tracing::warn!(
+            "{:.1}% of touch packets malformed, check the ADX cable and grounding",
+            malformed
+        ),
+        Some(LineVerdict::Healthy) => tracing::debug!("ADX line looks healthy at {} baud", baud),
+        None => {}
+    }
+    verdict
+}
+
+// Counts presses for the session report and exports every event if asked to
+fn record_transitions(
+    report: &SessionReport,
+    mut events: Option<&mut EventCsv>,
+    transitions: &mut TransitionDetector,
+    now: Instant,
+    state: TouchState,
+) -> Diff {
+    let diff = transitions.update(state);
+    for (region, action) in diff.events() {
+        if action == Action::Press {
+            report.presses[region.index()].fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(events) = events.as_mut() {
+            if let Err(err) = events.record(now, region, action) {
+                tracing::warn!("Couldn't write event CSV: {}", err);
+            }
+        }
+    }
+    diff
+}
+
+// ADX silence that ends the drain after streaming. A frame takes ~10ms at
+// 9600 baud, so this outlasts anything the ADX sent before it saw HALT.
+const DRAIN_QUIET: Duration = Duration::from_millis(25);
+
+// Reads until the ADX has been silent for `quiet`, at least one port
+// timeout. While streaming the port timeout is short, so this is what keeps
+// frames still in flight from being taken for config responses.
 fn drain_and_reset(
-    adx_read: &mut (dyn BufRead),
-    adx_write: &mut (dyn Write),
+    spec: &WireSpec,
+    adx_read: &mut dyn BufRead,
+    adx_write: &mut dyn Write,
+    quiet: Duration,
+) -> std::io::Result<()> {
+    adx_write.write_all(&spec.command(command::RSET))?;
+    halt_and_drain(spec, adx_read, adx_write, quiet)
+}
+
+// As drain_and_reset, but leaves the ADX's config alone
+fn halt_and_drain(
+    spec: &WireSpec,
+    adx_read: &mut dyn BufRead,
+    adx_write: &mut dyn Write,
+    quiet: Duration,
 ) -> std::io::Result<()> {
     tracing::info!("Halting and clearing ADX read buffer");
 
-    adx_write.write_all(RESET_COMMAND)?;
-    adx_write.write_all(HALT_COMMAND)?;
-    let mut buf = Vec::<u8>::with_capacity(TOUCH_PACKET_SIZE);
+    adx_write.write_all(&spec.command(command::HALT))?;
+    let mut buf = Vec::<u8>::with_capacity(spec.touch_frame_len);
+    let mut last_data = Instant::now();
 
     loop {
-        match adx_read.read_until(ADX_PACKET.close as u8, &mut buf) {
+        match adx_read.read_until(spec.adx.close as u8, &mut buf) {
             Ok(bytes) => {
                 tracing::info!("read {}", bytes);
                 buf.clear();
+                last_data = Instant::now();
             }
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::TimedOut {
+                    if last_data.elapsed() < quiet {
+                        continue;
+                    }
                     tracing::info!("timeout");
                     return Ok(());
                 }
@@ -173,68 +1249,3192 @@ fn drain_and_reset(
     }
 }
 
+// Gives an ADX that power-cycled mid-stream back the config the game sent
+// it, then starts it streaming again. Its answers are dropped, the game has
+// had them already.
+fn restart_stream<W: Write>(
+    spec: &WireSpec,
+    adx_reader: &mut dyn BufRead,
+    adx_writer: &Mutex<&mut W>,
+    commands: &[Vec<u8>],
+    policy: RetryPolicy,
+    clock: &(dyn Clock + Sync),
+) -> Result<()> {
+    for command in commands {
+        adx_writer.lock().unwrap().write_all(command)?;
+        if spec.expects_response(command) {
+            discard_reply(spec, adx_reader, command, policy, clock)?;
+        }
+    }
+    let mut adx_writer = adx_writer.lock().unwrap();
+    adx_writer.write_all(&spec.command(command::STAT))?;
+    adx_writer.flush()?;
+    Ok(())
+}
+
+fn build_filters(
+    config: &Config,
+    spec: &WireSpec,
+    report: &Arc<SessionReport>,
+) -> Result<FilterChain> {
+    let profile = match &config.profile {
+        Some(path) => Profile::load(path)?,
+        None => Profile::default(),
+    };
+    let packing = Packing {
+        bit_order: config.bit_order,
+        byte_order: config.byte_order,
+    };
+    if !packing.is_stock() {
+        tracing::info!(
+            "Decoding frames with {} bit order and {} byte order{}",
+            config.bit_order,
+            config.byte_order,
+            if config.normalize_output {
+                ", forwarding them in the stock order"
+            } else {
+                ""
+            }
+        );
+    }
+    let mut filters = FilterChain::new(packing, config.normalize_output);
+    let limit = config
+        .max_transitions_per_sec
+        .map(|per_sec| TransitionLimit {
+            per_sec,
+            cooldown: Duration::from_secs(config.quarantine_secs),
+        });
+
+    // The board's own touches, as it reports them
+    if let Some(limit) = limit.filter(|_| config.limit_adx_transitions) {
+        tracing::info!(
+            "Holding the ADX to {} touch transitions a second",
+            limit.per_sec
+        );
+        filters.push(Box::new(AdxGuard::new(limit, report.clone())));
+    }
+
+    // Undo how the assembly is mounted before anything works with region names
+    let remap = Remap::geometric(config.rotate, config.mirror);
+    if !remap.is_identity() {
+        tracing::info!(
+            "Remapping regions for a touch assembly rotated {} octants{}",
+            config.rotate % 8,
+            if config.mirror { " and mirrored" } else { "" }
+        );
+        filters.push(Box::new(remap));
+    }
+
+    if let Some(delays) = config
+        .region_delay
+        .as_ref()
+        .filter(|delays| !delays.is_zero())
+    {
+        tracing::info!(
+            "Delaying presses{} on {}",
+            if config.delay_releases {
+                " and releases"
+            } else {
+                ""
+            },
+            delays
+        );
+        filters.push(Box::new(RegionDelay::new(
+            MonotonicClock,
+            delays,
+            config.delay_releases,
+        )));
+    }
+
+    let spread: Vec<SpreadRule> = profile
+        .spread
+        .iter()
+        .chain(&config.spread)
+        .copied()
+        .collect();
+    if !spread.is_empty() {
+        let rules: Vec<String> = spread.iter().map(SpreadRule::to_string).collect();
+        tracing::info!("Spreading {}", rules.join(" "));
+        filters.push(Box::new(Spread::new(spread)));
+    }
+
+    // Injected touches go on top of the board's, in screen terms
+    if let Some(addr) = &config.inject_listen {
+        let injector = Arc::new(Injector::new(report.clone()));
+        injector.listen(addr, config.player.clone(), limit)?;
+        filters.push(Box::new(Inject(injector)));
+    }
+
+    if !filters.is_empty() || !packing.is_stock() {
+        require_touch_layout(spec, "touch filters")?;
+    }
+    Ok(filters)
+}
+
+// Features that decode regions only understand the maimai frame layout
+fn has_touch_layout(spec: &WireSpec) -> bool {
+    spec.touch_frame_len == maimai::TOUCH_FRAME_LEN
+}
+
+fn require_touch_layout(spec: &WireSpec, feature: &str) -> Result<()> {
+    if !has_touch_layout(spec) {
+        bail!(
+            "{} need the maimai frame layout, wire spec {} doesn't have it",
+            feature,
+            spec.name
+        );
+    }
+    Ok(())
+}
+
 fn run_touch_proxy(config: &Config) -> Result<()> {
-    let mut alls_reader_port = serialport::new(&config.alls, 9600)
-        .timeout(Duration::from_secs(1))
-        .open()?;
-    let mut alls_writer = alls_reader_port.try_clone()?;
-    let mut alls_reader = BufReader::new(&mut alls_reader_port);
+    let spec = WireSpec::load(&config.wire_spec)?;
+    tracing::info!("Wire spec {}", spec.name);
+    let mut pipeline = Pipeline::new(config, &spec)?;
+    if config.coalesce_us > 0 && Transport::of(&config.alls).is_device() {
+        tracing::info!(
+            "Not coalescing writes to {}, a serial port sends each byte as it comes anyway",
+            config.alls
+        );
+    }
+    let mut alls = vec![("ALLS", config.alls.as_str())];
+    alls.extend(
+        config
+            .alls_backup
+            .as_deref()
+            .map(|name| ("ALLS backup", name)),
+    );
+    alls.extend(
+        config
+            .alls_mirror
+            .as_deref()
+            .map(|name| ("ALLS mirror", name)),
+    );
+    for (role, name) in alls {
+        if let Some(device) = instance::same_device(name, &config.adx) {
+            bail!(
+                "{} {} and ADX {} resolve to the same device {}, the proxy would read its own \
+                 writes",
+                role,
+                name,
+                config.adx,
+                device
+            );
+        }
+        // Without com0com there is no pair to be on
+        if config.com0com_setupc.exists() {
+            match com0com::same_pair(&config.com0com_setupc, name, &config.adx) {
+                Ok(Some((one, other))) => bail!(
+                    "{} {} and ADX {} are the two ends of com0com pair {} <-> {}, the proxy \
+                     would read its own writes",
+                    role,
+                    name,
+                    config.adx,
+                    one,
+                    other
+                ),
+                Ok(None) => {}
+                Err(err) => tracing::debug!("Couldn't list the com0com pairs: {:#}", err),
+            }
+        }
+    }
+    let mut locked = vec![config.alls.as_str(), config.adx.as_str()];
+    locked.extend(config.alls_backup.as_deref());
+    locked.extend(config.alls_mirror.as_deref());
+    let _lock = InstanceLock::acquire(&locked, config.force)?;
+    let latency =
+        config
+            .set_latency_timer
+            .and_then(|ms| match LatencyTimer::set(&config.adx, ms) {
+                Ok(latency) => Some(Arc::new(latency)),
+                Err(err) => {
+                    tracing::warn!(
+                        "!!! Latency timer left as it was: {:#}. doctor shows what it is set to",
+                        err
+                    );
+                    None
+                }
+            });
+
+    // The summary is written however the proxy goes down: signals, errors and panics
+    let report = pipeline.report.clone();
+    let alerts = pipeline.alerts.clone();
+    let summary_file = config.summary_file.clone();
+    let restore = latency.clone();
+    shutdown::on_terminate(move || {
+        alerts.fire(Alert::ShuttingDown, "caught signal");
+        report.finish(summary_file.as_deref());
+        if let Some(latency) = restore {
+            latency.restore();
+        }
+    })?;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        proxy_loop(config, &spec, &mut pipeline, &MonotonicClock)
+    }));
+    let detail = match &result {
+        Ok(Ok(())) => "ALLS closed".to_string(),
+        Ok(Err(err)) => format!("error: {:#}", err),
+        Err(_) => "panicked".to_string(),
+    };
+    pipeline.alerts.fire(Alert::ShuttingDown, &detail);
+    pipeline.report.finish(config.summary_file.as_deref());
+    if let Some(latency) = &latency {
+        latency.restore();
+    }
+    match result {
+        Ok(result) => result,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+// The ALLS endpoint as a reader and writer, translated to the slider
+// protocol and wrapped for failover when those are asked for
+fn alls_halves(
+    config: &Config,
+    spec: &WireSpec,
+    pipeline: &Pipeline,
+    mut alls: ports::Endpoint,
+) -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    if config.alls_protocol == AllsProtocol::ChuniSlider {
+        require_touch_layout(spec, "chuni-slider output")?;
+        let map = match &config.slider_map {
+            Some(path) => SliderMap::load(path)?,
+            None => SliderMap::default(),
+        };
+        tracing::info!("Speaking the chuni slider protocol to the ALLS");
+        slider::translate(&mut alls, spec, map, pipeline.filters.output_packing());
+    }
+    if config.alls_backup.is_none() && config.alls_silence_warn_secs.is_none() {
+        // The reader keeps the whole endpoint, so a PTY stays open
+        let writer = std::mem::replace(&mut alls.writer, Box::new(std::io::sink()));
+        return Ok((Box::new(alls), writer));
+    }
+    let backup = config
+        .alls_backup
+        .as_deref()
+        .map(ports::open_endpoint)
+        .transpose()?;
+    let silence = config.alls_silence_warn_secs.map(|secs| SilencePolicy {
+        after: Duration::from_secs(secs),
+        action: config.alls_silence_action,
+    });
+    let (reader, writer) = failover::split(&config.alls, alls, backup, silence, MonotonicClock);
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+fn proxy_loop(
+    config: &Config,
+    spec: &WireSpec,
+    pipeline: &mut Pipeline,
+    clock: &(dyn Clock + Sync),
+) -> Result<()> {
+    let listener = (Transport::of(&config.alls) == Transport::TcpListen)
+        .then(|| {
+            let idle = config.alls_idle_timeout_secs.map(Duration::from_secs);
+            AllsListener::bind(&config.alls, idle)
+        })
+        .transpose()?;
+    // A port is opened before the ADX, as the game may already be waiting
+    // on it, but TCP clients are only taken once the ADX is ready for them
+    let mut alls = match &listener {
+        Some(_) => None,
+        None => Some(ports::open_endpoint(&config.alls)?),
+    };
 
-    let mut adx_reader_port = serialport::new(&config.adx, 9600)
-        .timeout(Duration::from_secs(1))
-        .open()?;
-    let mut adx_writer = adx_reader_port.try_clone()?;
-    let mut adx_reader = BufReader::new(&mut adx_reader_port);
+    pipeline.mirror = config
+        .alls_mirror
+        .as_deref()
+        .map(|name| {
+            Mirror::open(
+                name,
+                config.alls_mirror_stream_only,
+                pipeline.report.clone(),
+            )
+        })
+        .transpose()?;
 
-    drain_and_reset(&mut adx_reader, &mut adx_writer)?;
+    let mut adx = ports::open(&config.adx)?;
+    adx.port = Box::new(CountingPort::new(
+        adx.port,
+        pipeline.report.adx_reads.clone(),
+    ));
+    let mut adx_writer;
+    (adx.port, adx_writer) = ports::duplex(&config.adx, adx.port);
+    let mut adx_reader = BufReader::new(&mut adx.port);
+
+    // Resuming keeps the config the game already sent, so the ADX is only halted
+    let mut resuming = pipeline
+        .resume
+        .as_ref()
+        .is_some_and(ResumeState::interrupted);
+    if resuming {
+        halt_and_drain(spec, &mut adx_reader, &mut adx_writer, Duration::ZERO)?;
+    } else {
+        drain_and_reset(spec, &mut adx_reader, &mut adx_writer, Duration::ZERO)?;
+    }
 
     tracing::info!("Ports opened");
 
-    let mut command_buffer = Vec::<u8>::with_capacity(MAX_MESSAGE_SIZE);
+    if let Some(probe) = &config.feature_probe {
+        let probe = handshake::parse_bytes(probe)?;
+        let features = features::probe(spec, &mut adx_reader, &mut adx_writer, &probe)?;
+        pipeline.report.adx_features.set(features.0).ok();
+        pipeline.features = Some(features);
+    }
+    if pipeline.keepalive.is_some() && !features::allows(pipeline.features, &features::KEEPALIVE) {
+        pipeline.keepalive = None;
+    }
+
+    let mut command_buffer = Vec::<u8>::with_capacity(spec.command_max_len);
+    let mut response_buffer = Vec::<u8>::with_capacity(spec.touch_frame_len);
+    let expectation = config
+        .expect_handshake
+        .as_deref()
+        .map(Expectation::load)
+        .transpose()?;
+    let synthesizer = config
+        .synthesize_responses
+        .as_deref()
+        .map(Synthesizer::load)
+        .transpose()?;
+    let config_policy = RetryPolicy {
+        backoff: Duration::from_millis(config.retry_backoff_ms),
+    };
+    let game_loss = config.halt_on_game_loss_secs.map(Duration::from_secs);
+    let mut timeouts = config
+        .timeouts
+        .as_deref()
+        .map(TimeoutProfile::load)
+        .transpose()?;
+    let mut learner = config.learn_timeouts.as_deref().map(LatencyLearner::new);
+    let baud_switch = config
+        .upgrade_baud
+        .zip(config.upgrade_baud_command.as_deref())
+        .filter(|_| features::allows(pipeline.features, &features::BAUD_SWITCH))
+        .map(|(rate, command)| BaudSwitch::new(command, rate))
+        .transpose()?;
 
-    // At startup, the ADX is in config mode.
-    // ALLS will send message to it, ADX will responds until streaming is enabled.
-    tracing::info!("Read loop started");
+    // One pass per ALLS connection; only tcp-listen ever has more than one
     loop {
-        read_packet(&mut command_buffer, &mut alls_reader, &ALLS_PACKET)?;
-        adx_writer.write_all(&command_buffer)?;
-
-        let cmd_str = String::from_utf8_lossy(&command_buffer);
-        tracing::info!("From ALLS: {}", cmd_str);
-
-        match get_command_type(&command_buffer) {
-            CommandType::Config => {
-                read_packet(&mut command_buffer, &mut adx_reader, &ADX_PACKET)?;
-                let resp_str = String::from_utf8_lossy(&command_buffer);
-                tracing::info!("From ADX: {}", resp_str);
-                alls_writer.write_all(&command_buffer)?;
-                alls_writer.flush()?;
-            }
-            CommandType::Stat => {
-                stat_mode(
-                    &mut adx_reader,
-                    &mut adx_writer,
-                    &mut alls_reader,
-                    &mut alls_writer,
-                )?;
-            }
-            _ => (),
+        let alls = match (alls.take(), &listener) {
+            (Some(alls), _) => alls,
+            (None, Some(listener)) => listener.accept()?,
+            (None, None) => return Ok(()),
         };
-    }
-}
+        let mut alls = alls;
+        pipeline.reader_watch = Some(ReaderWatch::for_port(
+            &config.alls,
+            alls.modem.take(),
+            &pipeline.report,
+        ));
+        let (alls_reader, mut alls_writer) = alls_halves(config, spec, pipeline, alls)?;
+        if let Some(mirror) = &pipeline.mirror {
+            alls_writer = mirror.wrap(alls_writer);
+        }
+        let mut alls_reader = BufReader::new(alls_reader);
+        let mut repeats = config
+            .collapse_repeats
+            .then(|| RepeatCollapser::new(clock, limit::SUMMARY_WINDOW));
 
-#[derive(Debug, StructOpt)]
-struct Config {
-    pub alls: String,
-    pub adx: String,
-}
+        thread::scope(|scope| -> Result<()> {
+            // Config mode lends the ports and the pipeline to each stream,
+            // and gets them back when it's over
+            let mut pipeline = &mut *pipeline;
+            let mut adx_reader = &mut adx_reader;
+            let mut adx_writer = &mut adx_writer;
+            let mut alls_writer: &mut (dyn Write + Send) = &mut *alls_writer;
+            // With --prearm, the next stream's threads wait through config mode
+            let mut prearmed = config
+                .prearm
+                .then(|| Armed::new(scope, config, spec, clock));
+            // At startup, the ADX is in config mode.
+            // ALLS will send message to it, ADX will responds until streaming is enabled.
+            tracing::info!("Read loop started");
+            let mut pending = PendingCommands::new();
+            // Commands left unanswered since the last answer, by the wire spec or
+            // a timeout, in case the ADX answers one late
+            let mut unanswered = Vec::<Vec<u8>>::new();
+            // The game can't be lost before it has turned up
+            let mut game_seen = false;
+            // A config command got its answer since the last RSET, so the next
+            // RSET means the game started over
+            let mut configured = false;
+            loop {
+                let stray = if std::mem::take(&mut resuming) {
+                    tracing::info!(
+                        "Restarted while the game was streaming, sending {{STAT}} for it"
+                    );
+                    command_buffer = spec.command(command::STAT);
+                    0
+                } else if let Some(command) = pending.pop() {
+                    command_buffer = command;
+                    0
+                } else {
+                    // Until the game is given up on, waiting for it has a deadline
+                    let silence = pipeline.report.game_silence(clock.now());
+                    let lost = game_loss.is_some_and(|limit| silence >= limit);
+                    let deadline = game_loss
+                        .filter(|_| game_seen && !lost)
+                        .map(|limit| clock.now() + (limit - silence));
+                    let alls_retry = Retry::until(config_policy, clock, deadline);
+                    let stray = match read_command(
+                        &mut command_buffer,
+                        &mut alls_reader,
+                        spec,
+                        &alls_retry,
+                    ) {
+                        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            tracing::info!("ALLS closed");
+                            break;
+                        }
+                        Err(err)
+                            if err.kind() == std::io::ErrorKind::TimedOut && deadline.is_some() =>
+                        {
+                            lose_game(&pipeline.report, clock.now());
+                            drain_and_reset(
+                                spec,
+                                &mut adx_reader,
+                                &mut adx_writer,
+                                Duration::ZERO,
+                            )?;
+                            pipeline.config_sent.clear();
+                            continue;
+                        }
+                        result => result?,
+                    };
+                    if lost && game_seen {
+                        tracing::info!("The game is back");
+                    }
+                    game_seen = true;
+                    pipeline.report.game_heard(clock.now());
+                    stray
+                };
+                if let Some(strict) = &pipeline.strict {
+                    check_command(strict, spec, &command_buffer, stray)?;
+                }
+                let synthesized = synthesizer
+                    .as_ref()
+                    .and_then(|synthesizer| synthesizer.answer(&spec.alls, &command_buffer));
+                if synthesized.as_ref().is_none_or(|answer| answer.forward) {
+                    adx_writer.write_all(&command_buffer)?;
+                    match command::classify(&spec.alls, &command_buffer) {
+                        CommandKind::Config => pipeline.config_sent.record(&command_buffer),
+                        CommandKind::Reset => pipeline.config_sent.clear(),
+                        _ => {}
+                    }
+                }
+                let forwarded_at = clock.now();
 
-fn main() {
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-    let config = Config::from_args();
-    tracing::info!("ALLS {} ADX {}", config.alls, config.adx);
-    run_touch_proxy(&config).unwrap();
+                let cmd_str = String::from_utf8_lossy(&command_buffer).into_owned();
+                if repeats.is_none() {
+                    tracing::info!("From ALLS: {}", cmd_str);
+                }
+
+                if let Some(answer) = synthesized {
+                    if answer.forward {
+                        discard_reply(
+                            spec,
+                            &mut adx_reader,
+                            &command_buffer,
+                            config_policy,
+                            clock,
+                        )?;
+                    }
+                    tracing::info!(
+                        "Synthesized, not from the ADX: {}",
+                        String::from_utf8_lossy(&answer.response)
+                    );
+                    alls_writer.write_all(&answer.response)?;
+                    alls_writer.flush()?;
+                    continue;
+                }
+
+                let kind = command::classify(&spec.alls, &command_buffer);
+                if kind == CommandKind::Reset && std::mem::take(&mut configured) {
+                    tracing::info!("ALLS session restarted");
+                    // Late answers still owed are to a game that's gone
+                    unanswered.clear();
+                    if let Some(repeats) = repeats.as_mut() {
+                        repeats.flush();
+                    }
+                }
+                match kind {
+                    CommandKind::Config if !spec.expects_response(&command_buffer) => {
+                        log_exchange(&mut repeats, &cmd_str, None);
+                        unanswered.push(command_buffer.clone());
+                    }
+                    CommandKind::Config => {
+                        // A game that doesn't wait for the answer may have sent more already
+                        pending.take_buffered(&mut alls_reader, &spec.alls, &command_buffer);
+                        // Timeouts are retried forever unless a learned profile or strict
+                        // passthrough sets a deadline
+                        let learned = timeouts
+                            .as_ref()
+                            .and_then(|timeouts| timeouts.timeout(&command_buffer));
+                        let deadline = match learned {
+                            Some(timeout) => Some(forwarded_at + timeout),
+                            None => config.strict_passthrough.then(|| {
+                                clock.now() + Duration::from_millis(config.adx_timeout_ms)
+                            }),
+                        };
+                        let adx_retry = Retry::until(config_policy, clock, deadline);
+                        // Deadlines are only checked between reads, so a learned one
+                        // shorter than the port timeout needs shorter reads
+                        if let Some(timeout) =
+                            learned.filter(|timeout| *timeout < ports::PORT_TIMEOUT)
+                        {
+                            adx_reader.get_mut().set_timeout(timeout)?;
+                        }
+                        let read = loop {
+                            let stray = match read_response(
+                                &mut response_buffer,
+                                &mut adx_reader,
+                                spec,
+                                &command_buffer,
+                                &adx_retry,
+                            ) {
+                                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                                    tracing::warn!(
+                                        "No response from ADX after {} timeouts, leaving {} unanswered",
+                                        adx_retry.consecutive(),
+                                        cmd_str
+                                    );
+                                    pipeline.alerts.fire(
+                                        Alert::HandshakeFailed,
+                                        &format!("no response to {}", cmd_str),
+                                    );
+                                    if let Some(timeouts) = timeouts.as_mut() {
+                                        timeouts.missed(&command_buffer);
+                                    }
+                                    // Its answer may still turn up behind the next command's
+                                    unanswered.push(command_buffer.clone());
+                                    break None;
+                                }
+                                Err(err) => {
+                                    pipeline.alerts.fire(
+                                        Alert::BoardDisconnected,
+                                        &format!("ADX read failed: {}", err),
+                                    );
+                                    return Err(err.into());
+                                }
+                                Ok(stray) => stray,
+                            };
+                            // A late answer to a command the spec calls unanswered
+                            // would otherwise go back in place of this one's
+                            let late = unanswered.iter().position(|late| {
+                                strict::answers(late, &response_buffer)
+                                    && !strict::answers(&command_buffer, &response_buffer)
+                            });
+                            let Some(late) = late else {
+                                break Some(stray);
+                            };
+                            let late = unanswered.remove(late);
+                            tracing::warn!(
+                                "ADX answered {} late, dropped {} rather than pass it off as the \
+                                 answer to {}{}",
+                                String::from_utf8_lossy(&late),
+                                String::from_utf8_lossy(&response_buffer),
+                                cmd_str,
+                                if spec.expects_response(&late) {
+                                    ""
+                                } else {
+                                    "; the wire spec shouldn't list it as unanswered"
+                                }
+                            );
+                        };
+                        if learned.is_some() {
+                            adx_reader.get_mut().set_timeout(ports::PORT_TIMEOUT)?;
+                        }
+                        let Some(stray) = read else {
+                            continue;
+                        };
+                        unanswered.clear();
+                        configured = true;
+                        if let Some(timeouts) = timeouts.as_mut() {
+                            timeouts.answered(&command_buffer);
+                        }
+                        if let Some(learner) = learner.as_mut() {
+                            learner.record(
+                                &command_buffer,
+                                clock.now().saturating_duration_since(forwarded_at),
+                            );
+                        }
+                        if let Some(strict) = &pipeline.strict {
+                            check_response(strict, spec, &command_buffer, &response_buffer, stray)?;
+                        }
+                        let resp_str = String::from_utf8_lossy(&response_buffer);
+                        log_exchange(&mut repeats, &cmd_str, Some(&resp_str));
+                        if let Some(expectation) = &expectation {
+                            match expectation.check(&command_buffer, &response_buffer) {
+                                Some(Err(mismatch)) => {
+                                    tracing::warn!(
+                                        "!!! ADX response to {} doesn't match the expected handshake: {}",
+                                        cmd_str,
+                                        mismatch
+                                    );
+                                    pipeline.alerts.fire(
+                                        Alert::HandshakeFailed,
+                                        &format!("{}: {}", cmd_str, mismatch),
+                                    );
+                                    if config.expect_strict {
+                                        bail!("handshake mismatch for {}: {}", cmd_str, mismatch);
+                                    }
+                                }
+                                Some(Ok(())) => {
+                                    tracing::debug!("{} matches expected handshake", cmd_str)
+                                }
+                                None => {
+                                    tracing::debug!("{} isn't in the expected handshake", cmd_str)
+                                }
+                            }
+                        }
+                        if let Some(board_id) = &config.require_board_id {
+                            match board_id.check(&command_buffer, &response_buffer) {
+                                Some(Err(mismatch)) => {
+                                    pipeline.alerts.fire(
+                                        Alert::HandshakeFailed,
+                                        &format!("wrong board, {} answered {}", cmd_str, resp_str),
+                                    );
+                                    bail!(
+                                        "the ADX isn't the required board, it answered {} with {}: {}",
+                                        cmd_str,
+                                        resp_str,
+                                        mismatch
+                                    );
+                                }
+                                Some(Ok(())) => tracing::info!("ADX board: {}", resp_str),
+                                None => {}
+                            }
+                        }
+                        // The game restarted while this was in flight, and the
+                        // fresh one would take the answer for its own
+                        if let Some(dropped) = pending.restart(&spec.alls) {
+                            tracing::info!(
+                                "ALLS session restarted, dropped the answer to {} and {} commands \
+                                 queued before the restart",
+                                cmd_str,
+                                dropped
+                            );
+                            configured = false;
+                            unanswered.clear();
+                            continue;
+                        }
+                        alls_writer.write_all(&response_buffer)?;
+                        alls_writer.flush()?;
+                    }
+                    CommandKind::Stat => {
+                        log_exchange(&mut repeats, &cmd_str, None);
+                        if let Some(repeats) = repeats.as_mut() {
+                            repeats.flush();
+                        }
+                        let upgraded = match &baud_switch {
+                            Some(switch) => {
+                                switch.upgrade(spec, adx_reader, adx_writer.as_mut())?
+                            }
+                            None => false,
+                        };
+                        pipeline.adx_baud = adx_writer.baud_rate()?;
+                        // A short timeout lets the reader notice HALT promptly
+                        adx_reader.get_mut().set_timeout(ports::STREAM_TIMEOUT)?;
+                        if let Some(resume) = &pipeline.resume {
+                            resume.mark_streaming(false);
+                        }
+                        pipeline.stat_at = forwarded_at;
+                        let armed = prearmed
+                            .take()
+                            .unwrap_or_else(|| Armed::new(scope, config, spec, clock));
+                        let lent = Lent {
+                            pipeline,
+                            adx_reader,
+                            adx_writer,
+                            alls_writer,
+                        };
+                        Lent {
+                            pipeline,
+                            adx_reader,
+                            adx_writer,
+                            alls_writer,
+                        } = armed.stream(config, spec, lent, &mut alls_reader)?;
+                        if let Some(resume) = &pipeline.resume {
+                            resume.clear();
+                        }
+                        adx_reader.get_mut().set_timeout(ports::PORT_TIMEOUT)?;
+                        if let Some(switch) = baud_switch.as_ref().filter(|_| upgraded) {
+                            switch.restore(adx_reader, adx_writer.as_mut())?;
+                        }
+                        if config.auto_baud && pipeline.line_verdict == Some(LineVerdict::Mismatch)
+                        {
+                            let rate = baud::next_common_rate(pipeline.adx_baud);
+                            tracing::warn!(
+                                "Trying {} baud on the ADX port from the next command",
+                                rate
+                            );
+                            adx_writer.set_baud_rate(rate)?;
+                        }
+                        if config.prearm {
+                            prearmed = Some(Armed::new(scope, config, spec, clock));
+                        }
+                    }
+                    _ => log_exchange(&mut repeats, &cmd_str, None),
+                };
+            }
+            Ok(())
+        })?;
+
+        if listener.is_some() {
+            // The next client starts its handshake from scratch, so the ADX
+            // does too, whatever state the last one left it in
+            tracing::info!("Game disconnected, resetting the ADX for the next one");
+            drain_and_reset(spec, &mut adx_reader, &mut adx_writer, Duration::ZERO)?;
+            pipeline.config_sent.clear();
+        }
+    }
+}
+
+// How long a forwarded command with a synthesized response waits for the
+// ADX's own answer. Config responses come back within a couple of frames.
+const FORWARDED_REPLY_WAIT: Duration = Duration::from_millis(50);
+
+// Waits a moment for the ADX to answer a forwarded command whose response
+// was synthesized, so a late reply isn't taken for the next command's
+fn discard_reply(
+    spec: &WireSpec,
+    adx_reader: &mut dyn BufRead,
+    command: &[u8],
+    policy: RetryPolicy,
+    clock: &(dyn Clock + Sync),
+) -> Result<()> {
+    let retry = Retry::until(policy, clock, Some(clock.now() + FORWARDED_REPLY_WAIT));
+    let mut reply = Vec::new();
+    match read_response(&mut reply, adx_reader, spec, command, &retry) {
+        Ok(_) => tracing::debug!(
+            "Dropped the ADX's own answer {}",
+            String::from_utf8_lossy(&reply)
+        ),
+        Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+// Logs a config-mode exchange. Without --collapse-repeats the command has
+// already been logged as it arrived.
+fn log_exchange(repeats: &mut Option<RepeatCollapser>, command: &str, response: Option<&str>) {
+    if let Some(repeats) = repeats {
+        if !repeats.observe(command, response.unwrap_or_default()) {
+            return;
+        }
+        tracing::info!("From ALLS: {}", command);
+    }
+    if let Some(response) = response {
+        tracing::info!("From ADX: {}", response);
+    }
+}
+
+// Options are listed in help in the order they're declared here, area by area
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting = AppSettings::DeriveDisplayOrder,
+    setting = AppSettings::UnifiedHelpMessage,
+    after_help = "TOOLS:
+    setup-ports       Create a virtual port pair for the ALLS side
+    bench-loopback    Measure proxy overhead without any serial ports
+    doctor            Check the ports, drivers and permissions before a session
+    shell             Send commands to the ADX by hand and see its answers
+    wait-touch        Wait for a touch on the ADX alone and print which region it was
+    calibrate-latency Send timed synthetic presses for measuring end-to-end latency
+    capabilities      Print what this build supports, as JSON
+    schema            Print the JSON Schema of a document the proxy writes
+    completions       Print a shell completion script"
+)]
+struct Config {
+    // Ports
+    /// ALLS-side port, pty:[link] to create a PTY pair and give its other end to the game,
+    /// stdio to talk over stdin/stdout (logs then go to stderr), or tcp-listen://<addr:port> to
+    /// take game clients over TCP one at a time, resetting the ADX between them
+    pub alls: String,
+    pub adx: String,
+    /// Second ALLS-side port to switch to if the ALLS port starts failing. The ALLS port is
+    /// reopened in the background and used again once it comes back.
+    #[structopt(long)]
+    pub alls_backup: Option<String>,
+    /// Second ALLS-side port that gets a copy of everything sent to the ALLS port, for a second
+    /// game instance. Nothing is read from it, and a mirror that falls behind only loses its
+    /// copy; without --frame-rate the proxy writes as fast as the ALLS port takes, so a mirror
+    /// slower than that drops frames.
+    #[structopt(long)]
+    pub alls_mirror: Option<String>,
+    /// Only mirror streaming frames, not the config responses to commands the mirror never sent
+    #[structopt(long, requires = "alls-mirror")]
+    pub alls_mirror_stream_only: bool,
+    /// Drop a tcp-listen game client once nothing has gone either way for this many seconds,
+    /// so a client that vanished without closing doesn't hold the ADX
+    #[structopt(long)]
+    pub alls_idle_timeout_secs: Option<u64>,
+    /// Warn when the ALLS port has sent nothing for this many seconds while it takes every
+    /// frame, as a wedged com0com pair does after sleep/resume. The game can be quiet for a
+    /// whole song, so this has to be longer than one.
+    #[structopt(long)]
+    pub alls_silence_warn_secs: Option<u64>,
+    /// What to do about a silent ALLS port: warn, or reconnect to close and reopen it (or switch
+    /// to --alls-backup until it's back)
+    #[structopt(
+        long,
+        default_value = "warn",
+        possible_values = SilenceAction::NAMES
+    )]
+    pub alls_silence_action: SilenceAction,
+    /// Take the game for gone once it has sent nothing for this many seconds: stop streaming,
+    /// reset the ADX and wait quietly for the game to come back and start its handshake over.
+    /// The game is quiet while streaming too, so this has to be longer than a song.
+    #[structopt(long)]
+    pub halt_on_game_loss_secs: Option<u64>,
+    /// End a stream that has run this many minutes without a HALT, taking the game for wedged:
+    /// the ALLS gets an all-clear frame, the ADX is reset and the proxy waits for a fresh
+    /// handshake. Make it longer than the longest session, credits included.
+    #[structopt(long)]
+    pub max_stream_minutes: Option<u64>,
+    /// Packet framing: a built-in preset (maimai) or the path to a TOML spec file. The maimai
+    /// preset takes the answers to the L and R sensitivity commands as exactly 6 bytes, as a
+    /// stock ADX sends them; for a board that answers them otherwise, use a spec file with
+    /// `responses = []`, which reads every answer up to its close delimiter
+    #[structopt(long, default_value = "maimai")]
+    pub wire_spec: String,
+    /// Protocol spoken to the ALLS: maimai, or chuni-slider to drive a Chunithm slider input
+    /// (use with --frame-rate to keep the report rate sane)
+    #[structopt(
+        long,
+        default_value = "maimai",
+        possible_values = AllsProtocol::NAMES
+    )]
+    pub alls_protocol: AllsProtocol,
+    /// Map touch regions onto the 32 chuni slider cells with a TOML file instead of unrolling
+    /// the A and B rings
+    #[structopt(long)]
+    pub slider_map: Option<String>,
+    /// Open the ports even if another maitouch instance holds them
+    #[structopt(long)]
+    pub force: bool,
+    /// Path to com0com's setupc.exe, to check the ALLS and ADX aren't two ends of one pair
+    /// (Windows)
+    #[structopt(long, default_value = "C:\\Program Files (x86)\\com0com\\setupc.exe")]
+    pub com0com_setupc: PathBuf,
+    /// Switch the ADX to this baud rate while streaming, falling back to 9600 if frames don't
+    /// come through cleanly
+    #[structopt(long, requires = "upgrade-baud-command")]
+    pub upgrade_baud: Option<u32>,
+    /// Vendor command that switches the ADX baud rate, with \xNN escapes and {rate} standing
+    /// in for the rate, e.g. "{BR{rate}}"
+    #[structopt(long, requires = "upgrade-baud")]
+    pub upgrade_baud_command: Option<String>,
+    /// When a stream looks like it was received at the wrong baud rate, move the ADX port on
+    /// to the next common rate (9600 to 115200) after it ends
+    #[structopt(long, conflicts_with = "upgrade-baud")]
+    pub auto_baud: bool,
+
+    // Config mode
+    /// Before the game's handshake, send the ADX this command (with \xNN escapes), which
+    /// firmware forks answer with a feature bitmap, e.g. "{FEAT}" answered by (FEAT3).
+    /// --upgrade-baud and --adx-keepalive then only take effect if the board reports them; a
+    /// board that doesn't answer gets neither.
+    #[structopt(long)]
+    pub feature_probe: Option<String>,
+    /// Compare config-mode ADX responses against a known-good handshake file
+    #[structopt(long)]
+    pub expect_handshake: Option<String>,
+    /// Abort instead of warning when a response doesn't match --expect-handshake
+    #[structopt(long, requires = "expect-handshake")]
+    pub expect_strict: bool,
+    /// Stop unless the ADX answers this config command as the board the cab should have, e.g.
+    /// "{LAr2} => (LAr?)" in the --expect-handshake syntax. Checked whenever the game sends it.
+    #[structopt(long)]
+    pub require_board_id: Option<BoardId>,
+    /// Answer the config commands listed in this TOML file with canned responses instead of
+    /// waiting on the ADX, for vendor commands older firmware doesn't know
+    #[structopt(long)]
+    pub synthesize_responses: Option<String>,
+    /// Never retry or paper over a silent ADX: leave config commands unanswered and stop
+    /// streaming frames, so the game's own error handling takes over
+    #[structopt(long)]
+    pub strict_passthrough: bool,
+    /// How long the ADX may stay silent before --strict-passthrough gives up on it
+    #[structopt(long, default_value = "1000")]
+    pub adx_timeout_ms: u64,
+    /// Record how long the ADX takes to answer each config command to this file, for use
+    /// with --timeouts on later runs
+    #[structopt(long)]
+    pub learn_timeouts: Option<String>,
+    /// Give each config command its own response deadline, twice its slowest answers in a
+    /// file from --learn-timeouts. Other commands keep the --strict-passthrough rule.
+    #[structopt(long)]
+    pub timeouts: Option<String>,
+    /// Sleep after a serial read timeout before retrying, doubling while the line stays quiet
+    #[structopt(long, default_value = "5")]
+    pub retry_backoff_ms: u64,
+    /// Abort on anything the proxy would otherwise tolerate (malformed frames, stray bytes,
+    /// over-long commands, short or mismatched responses, stale frames), logging the recent
+    /// traffic as a hex transcript
+    #[structopt(long)]
+    pub strict: bool,
+    /// Packets kept for the --strict transcript
+    #[structopt(long, default_value = "64")]
+    pub strict_history: usize,
+    /// Remember in this file while the game has the ADX streaming, and if the proxy restarts
+    /// mid-stream, start streaming again straight away instead of waiting for a {STAT} the
+    /// game won't resend
+    #[structopt(long)]
+    pub resume_state: Option<String>,
+
+    // Streaming
+    /// While streaming, parse everything the ADX has queued and forward only the newest frame
+    #[structopt(long)]
+    pub low_latency: bool,
+    /// Spawn the streaming threads while still in config mode and keep them waiting, so on
+    /// {STAT} all that is left is forwarding it and letting them go
+    #[structopt(long)]
+    pub prearm: bool,
+    /// Pace touch frames to the ALLS at this rate instead of writing as fast as possible
+    #[structopt(long)]
+    pub frame_rate: Option<u32>,
+    /// Forward only every nth frame to the ALLS, though a frame with a touch change is always
+    /// sent straight away
+    #[structopt(long)]
+    pub decimate: Option<u32>,
+    /// Join the frames that come in within this many microseconds (up to 1000) into one write,
+    /// for a tcp-listen, pty or stdio ALLS where each write is a packet or a syscall. Frames then
+    /// go out as the ADX sends them rather than repeated in between. No effect on a serial port.
+    #[structopt(long, default_value = "0")]
+    pub coalesce_us: u64,
+    /// Send all-clear frames for this long after {STAT} while the ADX's scan settles, discarding
+    /// what it reports
+    #[structopt(long, default_value = "0")]
+    pub stream_warmup_ms: u64,
+    /// Write these bytes to the ADX every so many milliseconds while it streams, for firmware
+    /// that stops streaming without a keep-alive, e.g. 7b4b417d:2000 (hex bytes:interval)
+    #[structopt(long)]
+    pub adx_keepalive: Option<Keepalive>,
+    /// Bytes the ADX sends when it powers on, with \xNN escapes and ? for any byte. When they turn
+    /// up mid-stream, the board is given the game's config and {STAT} again and the filters
+    /// start over, instead of the banner only showing up as malformed frames.
+    #[structopt(long, conflicts_with = "low-latency")]
+    pub adx_banner: Option<String>,
+    /// Set the latency timer of the FTDI adapter behind the ADX port to this many milliseconds
+    /// (1 to 255, and 1 is what touch boards want) before opening it, and put the old value
+    /// back on a clean exit. Linux only, as root or with write access to its sysfs attribute.
+    #[structopt(long)]
+    pub set_latency_timer: Option<u32>,
+    /// Retry backoff used while streaming, where waking late costs latency
+    #[structopt(long, default_value = "0")]
+    pub stream_retry_backoff_ms: u64,
+    /// Run the streaming reader and writer threads at realtime priority, if the OS allows it
+    #[structopt(long)]
+    pub realtime: bool,
+    /// Pin the streaming reader and writer threads to this CPU
+    #[structopt(long)]
+    pub pin_cpu: Option<usize>,
+
+    // Filters
+    /// Load filter settings from a TOML profile
+    #[structopt(long)]
+    pub profile: Option<String>,
+    /// Also report a region while another is held, e.g. A1+=B1 (repeatable)
+    #[structopt(long, number_of_values = 1)]
+    pub spread: Vec<SpreadRule>,
+    /// Hold presses back this many milliseconds per region, for sensor layers that react at
+    /// different speeds, e.g. A*=+8,B3=2 (a ring letter and * for the whole ring)
+    #[structopt(long)]
+    pub region_delay: Option<RegionDelays>,
+    /// Hold releases back by the --region-delay too, rather than passing them straight on
+    #[structopt(long)]
+    pub delay_releases: bool,
+    /// Take extra touches from UDP datagrams on this address, one command per line:
+    /// "<region> press|release|pulse <ms> [player=<id>]", e.g. "A1 pulse 50"
+    #[structopt(long)]
+    pub inject_listen: Option<String>,
+    /// Quarantine a touch source that makes more presses and releases than this a second: its
+    /// touches are released and it's ignored for --quarantine-secs. Applies to --inject-listen,
+    /// and to the ADX with --limit-adx-transitions.
+    #[structopt(long)]
+    pub max_transitions_per_sec: Option<u32>,
+    /// How long a quarantined touch source is ignored for
+    #[structopt(long, default_value = "5")]
+    pub quarantine_secs: u64,
+    /// Hold the ADX to --max-transitions-per-sec too; the game then sees nothing touched while
+    /// it's quarantined
+    #[structopt(long, requires = "max-transitions-per-sec")]
+    pub limit_adx_transitions: bool,
+    /// Touch assembly is turned clockwise by this many eighths of a turn (4 for upside down);
+    /// applied before --mirror
+    #[structopt(long, default_value = "0")]
+    pub rotate: u8,
+    /// Touch assembly is mirrored left to right
+    #[structopt(long)]
+    pub mirror: bool,
+    /// Order of the region bits within each payload byte as the board sends them: lsb (stock,
+    /// lowest region in the lowest bit) or msb
+    #[structopt(long, default_value = "lsb", possible_values = BitOrder::NAMES)]
+    pub bit_order: BitOrder,
+    /// Order of the payload bytes as the board sends them: normal (stock, A ring first) or
+    /// reversed
+    #[structopt(long, default_value = "normal", possible_values = ByteOrder::NAMES)]
+    pub byte_order: ByteOrder,
+    /// Forward frames re-encoded in the stock bit and byte order instead of as the board sent
+    /// them
+    #[structopt(long)]
+    pub normalize_output: bool,
+
+    // Logging and observability
+    /// Log runs of identical config-mode exchanges once with a repeat count
+    #[structopt(long)]
+    pub collapse_repeats: bool,
+    /// Write per-region press/release events from streaming mode to this CSV file
+    #[structopt(long)]
+    pub event_csv: Option<String>,
+    /// Also write the end-of-run session summary to this file as JSON
+    #[structopt(long)]
+    pub summary_file: Option<String>,
+    /// Where the logs go, repeatable: stdout, stderr, file:<path>, journald (Linux) or eventlog
+    /// (Windows). journald and eventlog only get warnings and errors, and the rest still goes to
+    /// stdout unless another target takes it.
+    #[structopt(long, number_of_values = 1)]
+    pub log_target: Vec<LogTarget>,
+    /// Publish the decoded touch state to a shared-memory segment of this name for local
+    /// readers (see examples/shm_reader.py); needs the shm feature
+    #[structopt(long)]
+    pub shm: Option<String>,
+    /// Frame rate the ADX firmware streams at, warned about if it's more than 20% off. By
+    /// default any of 250, 500 or 1000Hz or the line's full speed is accepted
+    #[structopt(long)]
+    pub expected_rate: Option<u32>,
+    /// Warn about any gap between consecutive touch frames longer than this
+    #[structopt(long)]
+    pub gap_warn_ms: Option<u64>,
+    /// Run this shell command on critical events (board disconnected, stalled, stall recovered,
+    /// handshake failed, stream capped, shutting down), with MAITOUCH_EVENT, MAITOUCH_DETAIL and
+    /// MAITOUCH_PLAYER set
+    #[structopt(long)]
+    pub on_event: Option<String>,
+    /// Label for this proxy passed to --on-event as MAITOUCH_PLAYER, e.g. 1P
+    #[structopt(long)]
+    pub player: Option<String>,
+}
+
+impl Config {
+    // Conflicts that depend on values, which clap can't express
+    fn validate(&self) -> structopt::clap::Result<()> {
+        let conflict = |message: &str| {
+            Err(structopt::clap::Error::with_description(
+                message,
+                ErrorKind::ArgumentConflict,
+            ))
+        };
+        if !Transport::of(&self.adx).reaches_adx() {
+            return conflict(&format!(
+                "{} is only supported as the ALLS port",
+                Transport::of(&self.adx).form()
+            ));
+        }
+        if self.alls == self.adx && self.alls != ports::PTY_PREFIX {
+            return conflict("the ALLS and ADX ports must be different");
+        }
+        if self.slider_map.is_some() && self.alls_protocol != AllsProtocol::ChuniSlider {
+            return conflict("--slider-map only applies with --alls-protocol chuni-slider");
+        }
+        if self.shm.is_some() && !cfg!(feature = "shm") {
+            return conflict(
+                "--shm needs a build with the shm feature (cargo build --features shm)",
+            );
+        }
+        if self.delay_releases && self.region_delay.is_none() {
+            return conflict("--delay-releases needs --region-delay");
+        }
+        let reconnect = self.alls_silence_action == SilenceAction::Reconnect;
+        if reconnect && self.alls_silence_warn_secs.is_none() {
+            return conflict("--alls-silence-action reconnect needs --alls-silence-warn-secs");
+        }
+        let reopened = if self.alls_backup.is_some() {
+            Some("--alls-backup")
+        } else {
+            reconnect.then_some("--alls-silence-action reconnect")
+        };
+        let listening = Transport::of(&self.alls) == Transport::TcpListen;
+        if self.alls_idle_timeout_secs.is_some() && !listening {
+            return conflict("--alls-idle-timeout-secs only applies to a tcp-listen:// ALLS");
+        }
+        if let Some(option) = reopened {
+            if !Transport::of(&self.alls).is_device() {
+                return conflict(&format!("{} needs a serial port as the ALLS port", option));
+            }
+            if self.alls_protocol != AllsProtocol::Maimai {
+                return conflict(&format!(
+                    "{} only applies with --alls-protocol maimai",
+                    option
+                ));
+            }
+        }
+        for target in &self.log_target {
+            let available = match target {
+                LogTarget::Journald => cfg!(target_os = "linux"),
+                LogTarget::EventLog => cfg!(windows),
+                LogTarget::Stdout => self.alls != ports::STDIO,
+                _ => true,
+            };
+            if !available {
+                return conflict(&format!(
+                    "--log-target {} isn't available {}",
+                    target,
+                    if *target == LogTarget::Stdout {
+                        "while stdout is the ALLS port"
+                    } else {
+                        "on this platform"
+                    }
+                ));
+            }
+        }
+        if let Some(per_sec) = self.max_transitions_per_sec {
+            if per_sec == 0 {
+                return conflict("--max-transitions-per-sec must be above 0");
+            }
+            if self.inject_listen.is_none() && !self.limit_adx_transitions {
+                return conflict(
+                    "--max-transitions-per-sec needs --inject-listen or --limit-adx-transitions",
+                );
+            }
+        }
+        if let Some(ms) = self.set_latency_timer {
+            if !latency::RANGE.contains(&ms) {
+                return conflict("--set-latency-timer takes 1 to 255ms");
+            }
+            if !Transport::of(&self.adx).is_device() {
+                return conflict("--set-latency-timer needs a serial port as the ADX port");
+            }
+        }
+        if self.max_stream_minutes == Some(0) {
+            return conflict("--max-stream-minutes must be above 0");
+        }
+        if self.expected_rate == Some(0) {
+            return conflict("--expected-rate must be above 0");
+        }
+        // Not a clap conflict: --coalesce-us always has its default value,
+        // which would rule out --frame-rate altogether
+        if self.coalesce_us > 0 && self.frame_rate.is_some() {
+            return conflict("--coalesce-us and --frame-rate can't be used together");
+        }
+        if self.coalesce_us > pacing::MAX_COALESCE_US {
+            return conflict(&format!(
+                "--coalesce-us takes at most {}us",
+                pacing::MAX_COALESCE_US
+            ));
+        }
+        if let Some(mirror) = &self.alls_mirror {
+            if mirror == ports::STDIO {
+                return conflict("stdio can't be the ALLS mirror");
+            }
+            if Transport::of(mirror) == Transport::TcpListen {
+                return conflict(
+                    "the ALLS mirror can't be tcp-listen://, it has no client to wait for",
+                );
+            }
+            if self.alls_protocol != AllsProtocol::Maimai {
+                return conflict("--alls-mirror only applies with --alls-protocol maimai");
+            }
+            if [Some(&self.alls), Some(&self.adx), self.alls_backup.as_ref()]
+                .contains(&Some(mirror))
+            {
+                return conflict("the ALLS mirror must be a port of its own");
+            }
+        }
+        if let Some(backup) = &self.alls_backup {
+            if backup == ports::STDIO {
+                return conflict("stdio can't be the ALLS backup");
+            }
+            if *backup == self.alls || *backup == self.adx {
+                return conflict("the ALLS backup must be a port of its own");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Tool {
+    /// Create a virtual port pair for the ALLS side: two bridged PTYs on Unix, a com0com pair
+    /// on Windows
+    SetupPorts {
+        /// Symlink the game's end of the pair to this path (Unix)
+        #[structopt(long)]
+        game_link: Option<PathBuf>,
+        /// Symlink the proxy's end of the pair to this path (Unix)
+        #[structopt(long)]
+        proxy_link: Option<PathBuf>,
+        /// Path to com0com's setupc.exe (Windows)
+        #[structopt(long, default_value = "C:\\Program Files (x86)\\com0com\\setupc.exe")]
+        setupc: PathBuf,
+    },
+    /// Measure the proxy's own overhead by streaming through in-memory ports
+    BenchLoopback {
+        #[structopt(long, default_value = "5")]
+        duration_secs: u64,
+        /// Frames per second produced by the simulated ADX, 0 for as fast as possible
+        #[structopt(long, default_value = "1000")]
+        rate: u32,
+        #[structopt(long)]
+        json: bool,
+        /// Proxy options to benchmark with, after --
+        #[structopt(last = true)]
+        proxy_args: Vec<String>,
+    },
+    /// Print what this build supports as one line of JSON: tools, port kinds, wire spec
+    /// presets, option values, compiled-in features and the protocol constants
+    Capabilities,
+    /// Print the JSON Schema of one of the JSON documents: the --summary-file summary, the
+    /// capabilities description or bench-loopback --json
+    Schema {
+        #[structopt(possible_values = schema::DOCUMENTS)]
+        document: String,
+    },
+    /// Run the protocol conformance vectors, then the board's part of them against a live ADX,
+    /// for firmware authors to check a build against. Exits non-zero if any vector fails.
+    #[structopt(setting = AppSettings::Hidden)]
+    CheckVectors {
+        adx: String,
+        /// Vector manifest to run instead of the built-in maimai one
+        #[structopt(long)]
+        manifest: Option<String>,
+    },
+    /// Print a completion script covering the proxy's options and the tools
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    /// Check that the ports exist and can be opened, that nothing else holds them and that the
+    /// adapter is set up for low latency. Exits non-zero if any check fails.
+    Doctor {
+        alls: String,
+        adx: String,
+        /// Also reset the ADX, check it answers a config command, and stream briefly to see how
+        /// its bytes arrive
+        #[structopt(long)]
+        handshake: bool,
+    },
+    /// Talk to the ADX by hand: send commands, raw bytes or stream for a while, and see what
+    /// it answers. Reads commands from stdin; type help for the list.
+    Shell { adx: String },
+    /// Wait for a touch on the ADX alone, without a game, and print the region that was touched.
+    /// Exits with 1 if nothing is touched in time. The board is left halted either way.
+    WaitTouch {
+        adx: String,
+        /// Only wait for this region (repeatable); any region by default
+        #[structopt(long = "region", number_of_values = 1)]
+        regions: Vec<Region>,
+        #[structopt(long, default_value = "30")]
+        timeout_secs: u64,
+    },
+    /// Send timed synthetic presses to a proxy running with --inject-listen and log when each
+    /// went out, to line up with an external capture of the screen or audio
+    CalibrateLatency {
+        /// The proxy's --inject-listen address
+        target: String,
+        #[structopt(long, default_value = "A1")]
+        region: Region,
+        #[structopt(long, default_value = "10")]
+        pulses: u32,
+        #[structopt(long, default_value = "1000")]
+        spacing_ms: u64,
+        /// How long each press is held
+        #[structopt(long, default_value = "50")]
+        pulse_ms: u64,
+        /// Only reach a proxy started with this --player
+        #[structopt(long)]
+        player: Option<String>,
+        /// Write the send time of every pulse to this CSV
+        #[structopt(long)]
+        csv: Option<String>,
+    },
+}
+
+const TOOLS: &[&str] = &[
+    "setup-ports",
+    "bench-loopback",
+    "doctor",
+    "shell",
+    "wait-touch",
+    "calibrate-latency",
+    "capabilities",
+    "schema",
+    "completions",
+    "check-vectors",
+];
+
+fn run_tool(tool: Tool) -> Result<()> {
+    match tool {
+        Tool::SetupPorts {
+            game_link,
+            proxy_link,
+            setupc,
+        } => {
+            #[cfg(unix)]
+            {
+                let _ = setupc;
+                pty::bridge(game_link.as_deref(), proxy_link.as_deref())
+            }
+            #[cfg(windows)]
+            {
+                let _ = (game_link, proxy_link);
+                com0com::setup(&setupc)
+            }
+        }
+        Tool::BenchLoopback {
+            duration_secs,
+            rate,
+            json,
+            proxy_args,
+        } => {
+            let args = ["maitouch_rs", "alls-loopback", "adx-loopback"]
+                .into_iter()
+                .map(String::from)
+                .chain(proxy_args);
+            let config = Config::from_iter_safe(args)?;
+            config.validate()?;
+            let options = bench::BenchOptions {
+                duration: Duration::from_secs(duration_secs),
+                rate,
+                json,
+            };
+            bench::run(&options, &config)
+        }
+        Tool::Doctor {
+            alls,
+            adx,
+            handshake,
+        } => {
+            if !doctor::run(&alls, &adx, handshake) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Tool::Shell { adx } => shell::run(&adx),
+        Tool::WaitTouch {
+            adx,
+            regions,
+            timeout_secs,
+        } => {
+            if !shell::wait_touch(&adx, &regions, Duration::from_secs(timeout_secs))? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Tool::CalibrateLatency {
+            target,
+            region,
+            pulses,
+            spacing_ms,
+            pulse_ms,
+            player,
+            csv,
+        } => {
+            let train = calibrate::PulseTrain {
+                region,
+                pulses,
+                spacing: Duration::from_millis(spacing_ms),
+                width: Duration::from_millis(pulse_ms),
+                player,
+            };
+            calibrate::run(&target, &train, csv.as_deref())
+        }
+        Tool::Capabilities => {
+            println!("{}", capabilities::json());
+            Ok(())
+        }
+        Tool::Schema { document } => {
+            println!("{}", schema::json_schema(&document).unwrap());
+            Ok(())
+        }
+        Tool::CheckVectors { adx, manifest } => {
+            if !vectors::run(&adx, manifest.as_deref())? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Tool::Completions { shell } => {
+            // Tools are dispatched by hand, so graft them onto the proxy's own parser
+            let mut app = <Tool as StructOptInternal>::augment_clap(Config::clap());
+            app.gen_completions_to(env!("CARGO_BIN_NAME"), shell, &mut std::io::stdout());
+            Ok(())
+        }
+    }
+}
+
+// Logs go to every one of `targets`, and to stdout (stderr when stdout
+// carries the protocol) when none of them takes everything
+fn init_logging(targets: &[LogTarget], to_stderr: bool) -> Result<Verbosity> {
+    let (filter, handle) = reload::Layer::new(LevelFilter::INFO);
+    let console = if to_stderr {
+        LogTarget::Stderr
+    } else {
+        LogTarget::Stdout
+    };
+    let fallback = targets.iter().all(LogTarget::is_os_log).then_some(&console);
+    let mut layers = Vec::new();
+    for target in targets.iter().chain(fallback) {
+        let layer: Box<dyn Layer<_> + Send + Sync> = match target {
+            LogTarget::Stdout => Box::new(
+                tracing_subscriber::fmt::layer().with_writer(BoxMakeWriter::new(std::io::stdout)),
+            ),
+            LogTarget::Stderr => Box::new(
+                tracing_subscriber::fmt::layer().with_writer(BoxMakeWriter::new(std::io::stderr)),
+            ),
+            LogTarget::File(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening log file {}", path.display()))?;
+                Box::new(
+                    tracing_subscriber::fmt::layer()
+                        .with_ansi(false)
+                        .with_writer(Mutex::new(file)),
+                )
+            }
+            target => Box::new(OsLogLayer::open(target)?),
+        };
+        layers.push(layer);
+    }
+    let subscriber = tracing_subscriber::registry().with(filter).with(layers);
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    Ok(Verbosity::new(handle))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|arg| TOOLS.contains(&arg.as_str())) {
+        // Tools report on stdout, keep the logs out of the way
+        init_logging(&[], true).unwrap();
+        run_tool(Tool::from_iter(&args)).unwrap();
+        return;
+    }
+    let config = Config::from_args();
+    config.validate().unwrap_or_else(|err| err.exit());
+    // Logs mustn't end up in the protocol stream
+    let verbosity = init_logging(&config.log_target, config.alls == ports::STDIO).unwrap();
+    #[cfg(unix)]
+    verbosity::on_signals(verbosity).unwrap();
+    #[cfg(not(unix))]
+    let _ = verbosity;
+    tracing::info!("ALLS {} ADX {}", config.alls, config.adx);
+    let result = run_touch_proxy(&config);
+    // The panic below doesn't go through tracing, so an OS log would miss it
+    if let Err(err) = &result {
+        tracing::error!("Stopping: {:#}", err);
+    }
+    result.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::MockClock;
+    #[cfg(unix)]
+    use script::{Expect, SessionScript};
+
+    fn parse(options: &[&str]) -> structopt::clap::Result<Config> {
+        let config =
+            Config::from_iter_safe(["maitouch_rs", "pty:", "/dev/null"].iter().chain(options))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn frame_rate_takes_no_coalescing() {
+        assert!(parse(&["--frame-rate", "500"]).is_ok());
+        assert!(parse(&["--coalesce-us", "200"]).is_ok());
+        assert!(parse(&["--frame-rate", "500", "--coalesce-us", "0"]).is_ok());
+        assert!(parse(&["--frame-rate", "500", "--coalesce-us", "200"]).is_err());
+    }
+
+    // Reads the answer to `command` from `line`, and what is left after it
+    fn response_to(command: &[u8], line: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let retry = Retry::new(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &MonotonicClock,
+        );
+        let mut reader = line;
+        let mut response = Vec::new();
+        read_response(
+            &mut response,
+            &mut reader,
+            &WireSpec::maimai(),
+            command,
+            &retry,
+        )
+        .unwrap();
+        (response, reader.to_vec())
+    }
+
+    #[test]
+    fn a_sized_response_keeps_the_close_delimiter_inside_it() {
+        // What the board answers a sensitivity command of ")" with, followed
+        // by the next answer
+        let (response, rest) = response_to(b"{L)r2}", b"(L)r2)(LAr2)");
+        assert_eq!(response, b"(L)r2)");
+        assert_eq!(rest, b"(LAr2)");
+        let (response, _) = response_to(b"{RA)2}", b"junk(RA)2)");
+        assert_eq!(response, b"(RA)2)");
+    }
+
+    #[test]
+    fn an_unsized_response_ends_at_its_first_close_delimiter() {
+        let (response, rest) = response_to(b"{ABCD}", b"(AB)CD)");
+        assert_eq!(response, b"(AB)");
+        assert_eq!(rest, b"CD)");
+    }
+
+    #[test]
+    fn a_sized_response_cut_short_by_the_line_is_an_error() {
+        let retry = Retry::new(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &MonotonicClock,
+        );
+        let mut reader: &[u8] = b"(L)r";
+        let err = read_response(
+            &mut Vec::new(),
+            &mut reader,
+            &WireSpec::maimai(),
+            b"{L)r2}",
+            &retry,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rotate_4_maps_a1_to_a5() {
+        let config = parse(&["--rotate", "4"]).unwrap();
+        let report = Arc::new(SessionReport::new());
+        let mut filters = build_filters(&config, &WireSpec::maimai(), &report).unwrap();
+        let mut state = TouchState::default();
+        state.set("A1".parse().unwrap(), true);
+        let mut payload = [0u8; maitouch_protocol::touch::PAYLOAD_LEN];
+        state.encode_into(&mut payload);
+        filters.apply(&mut payload);
+        let mut rotated = TouchState::default();
+        rotated.set("A5".parse().unwrap(), true);
+        assert_eq!(TouchState::decode(&payload), rotated);
+    }
+
+    // The ports a proxy is started with, checked as they are on startup
+    fn ports(alls: &str, adx: &str) -> structopt::clap::Result<Config> {
+        let config = Config::from_iter_safe(["maitouch_rs", alls, adx])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn the_two_ports_must_differ_and_the_adx_cant_be_stdio() {
+        assert!(ports("pty:", "/dev/ttyUSB0").is_ok());
+        assert!(ports("stdio", "/dev/ttyUSB0").is_ok());
+        let err = ports("/dev/ttyUSB0", "/dev/ttyUSB0").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ArgumentConflict);
+        assert_eq!(
+            err.message,
+            "error: the ALLS and ADX ports must be different"
+        );
+        assert_eq!(
+            ports("/dev/ttyUSB0", "stdio").unwrap_err().kind,
+            ErrorKind::ArgumentConflict
+        );
+        // Each pty: is a pair of its own
+        assert!(ports("pty:", "pty:").is_ok());
+    }
+
+    #[test]
+    fn slider_map_needs_chuni_slider() {
+        let map = ["--slider-map", "map.toml"];
+        assert_eq!(
+            parse(&map).unwrap_err().message,
+            "error: --slider-map only applies with --alls-protocol chuni-slider"
+        );
+        assert!(parse(&["--alls-protocol", "chuni-slider", map[0], map[1]]).is_ok());
+    }
+
+    #[test]
+    fn the_alls_protocol_takes_only_its_listed_values() {
+        for name in AllsProtocol::NAMES {
+            let config = parse(&["--alls-protocol", name]).unwrap();
+            assert_eq!(config.alls_protocol, name.parse().unwrap());
+        }
+        let err = parse(&["--alls-protocol", "wacca"]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn the_upgrade_command_needs_upgrade_baud() {
+        let command = ["--upgrade-baud-command", "{BR{rate}}"];
+        assert_eq!(
+            parse(&command).unwrap_err().kind,
+            ErrorKind::MissingRequiredArgument
+        );
+        assert!(parse(&["--upgrade-baud", "115200", command[0], command[1]]).is_ok());
+    }
+
+    #[test]
+    fn completions_cover_the_options_and_the_tools() {
+        let mut app = <Tool as StructOptInternal>::augment_clap(Config::clap());
+        let mut script = Vec::new();
+        app.gen_completions_to("maitouch_rs", Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        for word in [
+            "--rotate",
+            "--alls-protocol",
+            "wait-touch",
+            "bench-loopback",
+        ] {
+            assert!(script.contains(word), "{} missing", word);
+        }
+    }
+
+    #[test]
+    fn expected_rate_must_be_above_0() {
+        assert!(parse(&["--expected-rate", "1000"]).is_ok());
+        assert!(parse(&["--expected-rate", "0"]).is_err());
+    }
+
+    // Runs a session under `spec`: the game sends each command in `answers`
+    // and must get its answer back, then streams and must see the frames in
+    // order
+    #[cfg(unix)]
+    fn proxy_under(spec_option: &str, spec: WireSpec, answers: &[(&str, &str)]) {
+        let mut script = SessionScript::new()
+            .wire_spec(spec_option, spec)
+            .adx_streams(script::frames(20));
+        // The proxy reads the ALLS once it has drained the ADX; until then
+        // the commands wait in the PTY
+        for (command, answer) in answers {
+            script = script
+                .adx_answers(command, answer)
+                .alls_sends(command)
+                .alls_expects(Expect::Reply(answer.as_bytes().to_vec()));
+        }
+        script
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::HALT)
+            .alls_goes_quiet(Duration::from_millis(100))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn proxies_under_the_maimai_spec() {
+        proxy_under(
+            "maimai",
+            WireSpec::maimai(),
+            &[
+                ("{LAr2}", "(LAr2)"),
+                // Sensitivity answers are read by length, so a close
+                // delimiter inside one doesn't cut it short
+                ("{RA)2}", "(RA)2)"),
+                // Anything else is read up to its close delimiter
+                ("{ABCD}", "(AB)"),
+            ],
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn proxies_under_a_custom_spec() {
+        let text = "name = \"waccaish\"\n\
+                    alls_open = \"[\"\nalls_close = \"]\"\n\
+                    adx_open = \"<\"\nadx_close = \">\"\n\
+                    touch_frame_len = 11\ncommand_max_len = 8\n\
+                    responses = []\n";
+        let path = std::env::temp_dir().join(format!("maitouch-spec-{}.toml", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let spec = WireSpec::from_toml(text).unwrap();
+        proxy_under(
+            path.to_str().unwrap(),
+            spec,
+            &[
+                // Without sized responses, an answer of any length is
+                // taken up to the close delimiter
+                ("[LAr2]", "<LAr22>"),
+                ("[ABCDEF]", "<ok>"),
+            ],
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // A spec file for a board that doesn't answer commands starting with
+    // any of `unanswered`
+    #[cfg(unix)]
+    fn unanswered_spec(name: &str, unanswered: &str) -> (std::path::PathBuf, WireSpec) {
+        let text = format!("unanswered = {}\n", unanswered);
+        let path = std::env::temp_dir().join(format!(
+            "maitouch-spec-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, &text).unwrap();
+        (path, WireSpec::from_toml(&text).unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn led_commands_go_through_between_config_without_holding_it_up() {
+        let (path, spec) = unanswered_spec("led", "[\"B\"]");
+        let session = SessionScript::new()
+            .wire_spec(path.to_str().unwrap(), spec)
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_answers("{LBr3}", "(LBr3)")
+            .adx_answers("{RAr1}", "(RAr1)")
+            // The first answer waits on the proxy opening the ports
+            .alls_sends("{RAr1}")
+            .alls_expects(Expect::Reply(b"(RAr1)".to_vec()))
+            // The board never answers {BL..}
+            .alls_sends("{LAr2}")
+            .alls_sends("{BL01}")
+            .alls_sends("{LBr3}")
+            .alls_sends("{BL02}")
+            .alls_sends("{BL03}")
+            .alls_sends("{RAr1}")
+            .alls_expects(Expect::Reply(b"(LAr2)(LBr3)(RAr1)".to_vec()))
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            .run();
+        std::fs::remove_file(&path).unwrap();
+
+        // Waiting on an LED command's answer would have taken a port timeout
+        let waited = session.got[1].waited;
+        assert!(waited < ports::PORT_TIMEOUT / 2, "{:?}", waited);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_answer_to_a_command_listed_as_unanswered_is_dropped() {
+        // LA is wrongly listed, and the board answers it after all
+        let (path, spec) = unanswered_spec("misclassified", "[\"B\", \"LA\"]");
+        SessionScript::new()
+            .wire_spec(path.to_str().unwrap(), spec)
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_answers("{LBr3}", "(LBr3)")
+            .alls_sends("{LAr2}")
+            .alls_sends("{BL01}")
+            .alls_sends("{LBr3}")
+            // (LAr2) comes in ahead of (LBr3), and is no answer to it
+            .alls_expects(Expect::Reply(b"(LBr3)".to_vec()))
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            .run();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_halt_read_along_with_the_stat_ends_the_stream() {
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_streams(script::frames(1))
+            // The board takes a moment to start streaming, so a HALT that
+            // got lost would let its frames through, and one that wasn't
+            // has them drained
+            .adx_lead(Duration::from_millis(10))
+            // In one write, so the proxy takes both in one read
+            .alls_sends("{STAT}{HALT}")
+            .alls_goes_quiet(Duration::from_millis(200))
+            // Only answered once the stream is over
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::ClearThen(b"(LAr2)".to_vec()))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_whole_session_goes_through_config_streaming_and_back() {
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_answers("{RAr2}", "(RAr2)")
+            .adx_streams(script::frames(5))
+            .alls_sends_command(command::RSET)
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends("{RAr2}")
+            .alls_expects(Expect::Reply(b"(RAr2)".to_vec()))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::HALT)
+            .alls_expects(Expect::Cleared(Duration::from_millis(200)))
+            .report_shows("one stream", |report| {
+                report.sessions.load(Ordering::Relaxed) == 1
+            })
+            // Config mode again, with nothing left over from the stream
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::HALT)
+            .alls_expects(Expect::Cleared(Duration::from_millis(200)))
+            .report_shows("a second stream", |report| {
+                report.sessions.load(Ordering::Relaxed) == 2
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_rset_while_streaming_ends_the_stream_and_starts_over() {
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_streams(script::frames(5))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::RSET)
+            .alls_expects(Expect::Cleared(Duration::from_millis(200)))
+            .report_shows("the stream ended", |report| {
+                report.sessions.load(Ordering::Relaxed) == 1
+            })
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_game_restarted_in_config_mode_gets_a_fresh_session() {
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_streams(script::frames(5))
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            // The new game starts over, and goes on to stream
+            .alls_sends_command(command::RSET)
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_command_cut_off_by_a_restart_doesnt_swallow_the_rset() {
+        // The old game got half a command out before it closed the port;
+        // the new one's {RSET} runs straight into it
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_answers("{RAr2}", "(RAr2)")
+            .adx_streams(script::frames(5))
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends("{LA")
+            .alls_goes_quiet(Duration::from_millis(100))
+            .alls_sends("{RSET}{RAr2}")
+            .alls_expects(Expect::Reply(b"(RAr2)".to_vec()))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_game_restarted_mid_stream_streams_again() {
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_streams(script::frames(5))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::RSET)
+            .alls_expects(Expect::Cleared(Duration::from_millis(200)))
+            // The new game's handshake, the way the first went
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .report_shows("the first game's stream over", |report| {
+                report.sessions.load(Ordering::Relaxed) == 1
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_that_power_cycles_mid_stream_streams_again_by_itself() {
+        // The game only ever sends the one {STAT}, so only the proxy's
+        // restart gets the rest of the stream going
+        SessionScript::new()
+            .options(&["--adx-banner", "BOOT"])
+            .adx_power_cycles(10, b"BOOT")
+            .adx_streams(script::frames(20))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .report_shows("one power cycle, in one stream", |report| {
+                report.adx_power_cycles.load(Ordering::Relaxed) == 1
+                    && report.sessions.load(Ordering::Relaxed) == 0
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stat_while_streaming_is_ignored() {
+        SessionScript::new()
+            .adx_streams(script::frames(5))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::STAT)
+            .alls_goes_quiet(Duration::from_millis(200))
+            .report_shows("the stream still going", |report| {
+                report.sessions.load(Ordering::Relaxed) == 0
+            })
+            .alls_sends_command(command::HALT)
+            .alls_expects(Expect::Cleared(Duration::from_millis(200)))
+            .report_shows("one stream", |report| {
+                report.sessions.load(Ordering::Relaxed) == 1
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_halt_in_config_mode_gets_no_answer() {
+        SessionScript::new()
+            .adx_answers("{LAr2}", "(LAr2)")
+            .alls_sends_command(command::HALT)
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .report_shows("no stream", |report| {
+                report.sessions.load(Ordering::Relaxed) == 0
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_game_gone_mid_stream_ends_the_stream_and_the_loop() {
+        // The game hangs up without a HALT once it has its frames
+        let session = SessionScript::new()
+            .adx_streams(script::frames(5))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .run();
+        assert_eq!(session.report.sessions.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_reporting_keep_alives_gets_them() {
+        SessionScript::new()
+            .options(&[
+                "--feature-probe",
+                "{FEAT}",
+                "--adx-keepalive",
+                "7b4b417d:30",
+            ])
+            .adx_answers("{FEAT}", "(FEAT2)")
+            .adx_needs_keepalive(b"{KA}", Duration::from_millis(100))
+            .adx_streams(script::frames(150))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .report_shows("the features and keep-alives", |report| {
+                report.adx_features.get() == Some(&2)
+                    && report.keepalives.load(Ordering::Relaxed) >= 3
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_without_the_extension_gets_no_keep_alives() {
+        // It doesn't know {FEAT}, so it doesn't answer
+        SessionScript::new()
+            .options(&[
+                "--feature-probe",
+                "{FEAT}",
+                "--adx-keepalive",
+                "7b4b417d:30",
+            ])
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_streams(script::frames(5))
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_goes_quiet(Duration::from_millis(200))
+            .report_shows("no features and no keep-alives", |report| {
+                report.adx_features.get() == Some(&0)
+                    && report.keepalives.load(Ordering::Relaxed) == 0
+            })
+            .run();
+    }
+
+    // Runs `session` with an --alls-mirror on a PTY, handing back what the
+    // mirror got
+    #[cfg(unix)]
+    fn with_mirror(session: impl FnOnce(&str) -> script::Session) -> (script::Session, Vec<u8>) {
+        use serialport::{SerialPort, TTYPort};
+
+        let (mut far, near) = TTYPort::pair().unwrap();
+        far.set_timeout(Duration::from_millis(20)).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let done = &done;
+            let copy = scope.spawn(move || {
+                let mut copy = Vec::new();
+                let mut buf = [0u8; 256];
+                while !done.load(Ordering::Relaxed) {
+                    if let Ok(n) = far.read(&mut buf) {
+                        copy.extend_from_slice(&buf[..n]);
+                    }
+                }
+                copy
+            });
+            let session = session(&near.name().unwrap());
+            // For the mirror thread to catch up
+            thread::sleep(Duration::from_millis(200));
+            done.store(true, Ordering::Relaxed);
+            (session, copy.join().unwrap())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_mirror_gets_what_the_game_gets() {
+        let (session, copy) = with_mirror(|mirror| {
+            SessionScript::new()
+                // Each of the board's frames is a touch change, so decimating
+                // sends each once rather than repeating the latest faster
+                // than a mirror is expected to keep up with
+                .options(&["--alls-mirror", mirror, "--decimate", "2"])
+                .adx_answers("{LAr2}", "(LAr2)")
+                .adx_streams(script::frames(30))
+                .alls_sends("{LAr2}")
+                .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+                .alls_sends_command(command::STAT)
+                .alls_expects(Expect::Stream)
+                .run()
+        });
+        let got: Vec<u8> = session
+            .got
+            .iter()
+            .flat_map(|got| got.bytes.clone())
+            .collect();
+        assert_eq!(copy, got);
+        assert_eq!(session.report.mirror_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stream_only_mirror_gets_only_the_stream() {
+        let (session, copy) = with_mirror(|mirror| {
+            SessionScript::new()
+                .options(&[
+                    "--alls-mirror",
+                    mirror,
+                    "--alls-mirror-stream-only",
+                    "--decimate",
+                    "2",
+                ])
+                .adx_answers("{LAr2}", "(LAr2)")
+                .adx_streams(script::frames(30))
+                .alls_sends("{LAr2}")
+                .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+                .alls_sends_command(command::STAT)
+                .alls_expects(Expect::Stream)
+                .run()
+        });
+        assert_eq!(copy, session.got[1].bytes);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_with_a_watchdog_streams_on_with_keep_alives() {
+        // The board gives up 100ms without {KA}, long before its stream of
+        // about 300ms is done
+        SessionScript::new()
+            .options(&["--adx-keepalive", "7b4b417d:30"])
+            .adx_needs_keepalive(b"{KA}", Duration::from_millis(100))
+            .adx_streams(script::frames(150))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .report_shows("keep-alives sent", |report| {
+                report.keepalives.load(Ordering::Relaxed) >= 3
+                    && report.keepalive_failures.load(Ordering::Relaxed) == 0
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_with_a_watchdog_stops_streaming_without_keep_alives() {
+        // The same board without the option, so the test above can't pass
+        // on a board that never holds its watchdog to it
+        SessionScript::new()
+            .adx_needs_keepalive(b"{KA}", Duration::from_millis(100))
+            .adx_streams(script::frames(150))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Touched)
+            .alls_goes_quiet(Duration::from_millis(500))
+            .report_shows("the stream cut short", |report| {
+                let frames = report.frames.load(Ordering::Relaxed);
+                frames > 0 && frames < 150
+            })
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_unanswered_command_gets_no_answer_under_strict_passthrough() {
+        SessionScript::new()
+            .options(&["--strict-passthrough", "--adx-timeout-ms", "50"])
+            // The board never answers {LAr2}
+            .adx_answers("{RAr2}", "(RAr2)")
+            .alls_sends("{LAr2}")
+            // Past the deadline and the port timeout it is checked after
+            .alls_goes_quiet(ports::PORT_TIMEOUT + Duration::from_millis(500))
+            // The proxy has moved on, and answers the next command. Nothing
+            // went back for {LAr2}, so the game's own timeout is left to
+            // deal with it.
+            .alls_sends("{RAr2}")
+            .alls_expects(Expect::Reply(b"(RAr2)".to_vec()))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_late_answer_isnt_passed_off_as_the_next_one() {
+        SessionScript::new()
+            .options(&["--strict-passthrough", "--adx-timeout-ms", "50"])
+            .adx_answers_late("{LAr2}", "(LAr2)", Duration::from_secs(2))
+            .adx_answers("{RAr2}", "(RAr2)")
+            .alls_sends("{LAr2}")
+            .alls_goes_quiet(ports::PORT_TIMEOUT + Duration::from_millis(300))
+            // The board sends (LAr2) only after this has gone out, so it is
+            // the first thing the proxy reads for {RAr2}
+            .alls_sends("{RAr2}")
+            .alls_expects(Expect::Reply(b"(RAr2)".to_vec()))
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_sent_before_an_answer_are_answered_in_order() {
+        SessionScript::new()
+            // Slow enough that the others are all in before it comes
+            .adx_answers_late("{LAr2}", "(LAr2)", Duration::from_millis(300))
+            .adx_answers("{RAr2}", "(RAr2)")
+            .adx_answers("{LBr2}", "(LBr2)")
+            .alls_sends("{LAr2}")
+            .alls_sends("{RAr2}{LBr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)(RAr2)(LBr2)".to_vec()))
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_game_restarted_before_its_answer_doesnt_get_it() {
+        SessionScript::new()
+            .adx_answers_late("{LAr2}", "(LAr2)", Duration::from_millis(300))
+            .adx_answers("{RAr2}", "(RAr2)")
+            .alls_sends("{LAr2}")
+            .alls_sends("{RSET}")
+            // The fresh game would take (LAr2) for the answer to whatever
+            // it sends first
+            .alls_expects(Expect::Nothing(Duration::from_millis(600)))
+            .alls_sends("{RAr2}")
+            .alls_expects(Expect::Reply(b"(RAr2)".to_vec()))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_profile_far_off_the_board_falls_back_to_waiting() {
+        let path =
+            std::env::temp_dir().join(format!("maitouch-profile-{}.toml", std::process::id()));
+        // Learned on a board that answered in a millisecond
+        std::fs::write(&path, "latency = [\"{LAr2}\", 1000, 50]\n").unwrap();
+        let mut script = SessionScript::new()
+            .options(&["--timeouts", path.to_str().unwrap()])
+            .adx_answers_late("{LAr2}", "(LAr2)", Duration::from_millis(100))
+            .adx_answers("{RAr2}", "(RAr2)");
+        for _ in 0..3 {
+            // Given up on, and its late answer isn't taken for (RAr2)
+            script = script
+                .alls_sends("{LAr2}")
+                .alls_goes_quiet(Duration::from_millis(300))
+                .alls_sends("{RAr2}")
+                .alls_expects(Expect::Reply(b"(RAr2)".to_vec()));
+        }
+        // Dropped from the profile, it's waited for as usual
+        script
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .run();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_wrong_board_stops_the_proxy() {
+        let session = SessionScript::new()
+            .options(&["--require-board-id", "{LAr2} => (LAr?)"])
+            .adx_answers("{RAr2}", "(RAr2)")
+            .adx_answers("{LAr2}", "(LBr2)")
+            // Other commands aren't checked
+            .alls_sends("{RAr2}")
+            .alls_expects(Expect::Reply(b"(RAr2)".to_vec()))
+            // The wrong board's answer never reaches the game
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Nothing(Duration::from_millis(300)))
+            .run();
+
+        let err = format!("{:#}", session.result.unwrap_err());
+        assert!(
+            err.starts_with("the ADX isn't the required board, it answered {LAr2} with (LBr2)"),
+            "{}",
+            err
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_synthesized_answer_stands_in_for_the_boards() {
+        let path =
+            std::env::temp_dir().join(format!("maitouch-synthesize-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "respond = [\"{LBv?}\", \"(LBv?10)\"]\n\
+             respond_and_forward = [\"{XVER}\", \"(XVER0100)\"]\n",
+        )
+        .unwrap();
+        SessionScript::new()
+            .options(&["--synthesize-responses", path.to_str().unwrap()])
+            // Had {LBv1} reached the board, the rest would go unanswered
+            .adx_hangs_up_on("{LBv1}")
+            .adx_answers("{XVER}", "(XVER9999)")
+            .adx_answers("{LAr2}", "(LAr2)")
+            .alls_sends("{LBv1}")
+            .alls_expects(Expect::Reply(b"(LBv110)".to_vec()))
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            // The board's own answer to {XVER} is thrown away, and doesn't
+            // turn up as the answer to the next command
+            .alls_sends("{XVER}")
+            .alls_expects(Expect::Reply(b"(XVER0100)".to_vec()))
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_expects(Expect::Nothing(Duration::from_millis(200)))
+            .run();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_that_hangs_up_on_a_command_ends_the_loop() {
+        let session = SessionScript::new()
+            .adx_hangs_up_on("{LAr2}")
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Nothing(ports::PORT_TIMEOUT))
+            .run();
+
+        assert!(session.result.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_torn_frame_is_dropped_and_the_stream_carries_on() {
+        let session = SessionScript::new()
+            .adx_streams(script::frames(20).torn_at(5))
+            .alls_sends("{STAT}")
+            .alls_expects(Expect::Stream)
+            .alls_sends("{HALT}")
+            .alls_goes_quiet(Duration::from_millis(100))
+            .run();
+
+        assert!(session.report.malformed.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_that_drops_off_mid_stream_ends_the_loop() {
+        let session = SessionScript::new()
+            .adx_streams(script::frames(5).then_hangup())
+            .alls_sends("{STAT}")
+            .alls_expects(Expect::Touched)
+            // The game is left with its touches cleared
+            .alls_expects(Expect::Cleared(Duration::from_millis(200)))
+            .run();
+
+        assert!(session.result.is_err());
+    }
+
+    // Where a session keeps its resume state
+    #[cfg(unix)]
+    fn resume_state(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("maitouch-resume-{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streaming_is_remembered_until_the_game_halts() {
+        let path = resume_state("session");
+        let written = path.clone();
+        SessionScript::new()
+            .options(&["--resume-state", &path])
+            .adx_streams(script::frames(20))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .report_shows("the resume state written", move |_| {
+                std::fs::read_to_string(&written)
+                    .is_ok_and(|state| state.starts_with("mode=streaming\n"))
+            })
+            .alls_sends_command(command::HALT)
+            .alls_goes_quiet(Duration::from_millis(100))
+            .run();
+
+        // A clean halt removes it
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_proxy_restarted_mid_stream_streams_again_without_a_stat() {
+        let path = resume_state("restarted");
+        ResumeState::new(&path, None).mark_streaming(false);
+        SessionScript::new()
+            .options(&["--resume-state", &path])
+            .adx_streams(script::frames(20))
+            // The game sends nothing, as it thinks the stream never stopped
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::HALT)
+            .alls_goes_quiet(Duration::from_millis(100))
+            .run();
+
+        // A clean halt removes it
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stale_resume_state_waits_for_the_game() {
+        let path = resume_state("stale");
+        ResumeState::new(&path, None).mark_streaming(false);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        SessionScript::new()
+            .options(&["--resume-state", &path])
+            .adx_streams(script::frames(20))
+            .alls_expects(Expect::Nothing(Duration::from_millis(300)))
+            .alls_sends_command(command::STAT)
+            .alls_expects(Expect::Stream)
+            .alls_sends_command(command::HALT)
+            .alls_goes_quiet(Duration::from_millis(100))
+            .run();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn each_tcp_client_gets_a_freshly_reset_adx() {
+        use serialport::{SerialPort, TTYPort};
+        use std::net::{TcpListener, TcpStream};
+
+        let (mut board, adx) = TTYPort::pair().unwrap();
+        board.set_timeout(Duration::from_millis(2)).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let alls = format!("tcp-listen://127.0.0.1:{}", port);
+        let config = Config::from_iter_safe(["maitouch_rs", &alls, &adx.name().unwrap()]).unwrap();
+        let spec = WireSpec::maimai();
+        let mut pipeline = Pipeline::new(&config, &spec).unwrap();
+        let commands = Mutex::new(Vec::<Vec<u8>>::new());
+        let done = AtomicBool::new(false);
+        let wait_for = |what: &str, happened: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !happened() {
+                assert!(Instant::now() < deadline, "no {}", what);
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        // A game client that configures, streams until it's touched, and
+        // leaves
+        let play = || {
+            let mut game = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            game.set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            game.write_all(b"{LAr2}").unwrap();
+            let mut answer = [0u8; 6];
+            game.read_exact(&mut answer).unwrap();
+            assert_eq!(&answer, b"(LAr2)");
+            game.write_all(b"{STAT}").unwrap();
+            let mut frame = [0u8; 9];
+            loop {
+                game.read_exact(&mut frame).unwrap();
+                if frame != all_clear_frame(&spec)[..] {
+                    break;
+                }
+            }
+            assert_eq!(&frame, b"(\x01\0\0\0\0\0\0)");
+        };
+
+        let result = thread::scope(|scope| {
+            let proxy = scope.spawn(|| proxy_loop(&config, &spec, &mut pipeline, &MonotonicClock));
+            let (commands, done) = (&commands, &done);
+            scope.spawn(move || {
+                let mut streaming = false;
+                let mut got = Vec::new();
+                let mut buf = [0u8; 64];
+                while !done.load(Ordering::Relaxed) {
+                    if let Ok(n) = board.read(&mut buf) {
+                        got.extend_from_slice(&buf[..n]);
+                    }
+                    while let Some(end) = got.iter().position(|&byte| byte == b'}') {
+                        let command: Vec<u8> = got.drain(..=end).collect();
+                        match command.as_slice() {
+                            b"{LAr2}" => board.write_all(b"(LAr2)").unwrap(),
+                            b"{STAT}" => streaming = true,
+                            b"{HALT}" | b"{RSET}" => streaming = false,
+                            _ => {}
+                        }
+                        commands.lock().unwrap().push(command);
+                    }
+                    if streaming {
+                        board.write_all(b"(\x01\0\0\0\0\0\0)").unwrap();
+                    }
+                }
+                // Dropping the board's end is what stops the proxy
+            });
+
+            let played = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                play();
+                // The next client waits in the backlog until the ADX is
+                // reset for it
+                play();
+                wait_for("reset after the second client", &|| {
+                    commands.lock().unwrap().len() == 14
+                });
+            }));
+            // Lets the board go, even if the game failed
+            done.store(true, Ordering::Relaxed);
+            played.unwrap_or_else(|panic| panic::resume_unwind(panic));
+            proxy.join().unwrap()
+        });
+
+        assert!(result.is_err());
+        // The stream's own teardown resets the ADX, and then it's reset
+        // again for whoever connects next
+        let reset: &[&[u8]] = &[b"{RSET}", b"{HALT}"];
+        let session: &[&[u8]] = &[b"{LAr2}", b"{STAT}", b"{RSET}", b"{HALT}"];
+        let expected = [reset, session, reset, session, reset].concat();
+        assert_eq!(*commands.lock().unwrap(), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_lost_game_is_answered_again_once_it_comes_back() {
+        let session = SessionScript::new()
+            .on_mock_clock()
+            .options(&["--halt-on-game-loss-secs", "5"])
+            .adx_answers("{LAr2}", "(LAr2)")
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            // Without the clock moving on, the game is never given up on
+            .alls_goes_quiet(Duration::from_millis(100))
+            .report_shows("the game still there", |report| {
+                report.game_losses.load(Ordering::Relaxed) == 0
+            })
+            // It is once the port times out past the limit
+            .advance_clock(Duration::from_secs(5))
+            .until(|report| report.game_losses.load(Ordering::Relaxed) == 1)
+            // However long it then stays away, it is waited for
+            .advance_clock(Duration::from_secs(60))
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .run();
+
+        assert_eq!(session.report.game_losses.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_capped_stream_waits_for_a_fresh_handshake() {
+        let session = SessionScript::new()
+            .on_mock_clock()
+            .options(&["--max-stream-minutes", "1"])
+            .adx_answers("{LAr2}", "(LAr2)")
+            .adx_streams(script::frames(1))
+            .alls_sends("{STAT}")
+            .alls_expects(Expect::Touched)
+            // The game never halts; the clock running past the cap ends
+            // the stream for it
+            .advance_clock(Duration::from_secs(60))
+            .until(|report| report.sessions.load(Ordering::Relaxed) == 1)
+            // What the stream left behind, down to its all-clear
+            .alls_expects(Expect::Cleared(DRAIN_QUIET * 3))
+            // Back in config mode, the game is answered and can stream again
+            .alls_sends("{LAr2}")
+            .alls_expects(Expect::Reply(b"(LAr2)".to_vec()))
+            .alls_sends("{STAT}")
+            .alls_expects(Expect::Touched)
+            .alls_sends("{HALT}")
+            .alls_goes_quiet(Duration::from_millis(100))
+            .run();
+
+        assert_eq!(session.report.stream_caps.load(Ordering::Relaxed), 1);
+    }
+
+    // Has the game start and halt `streams` streams, timing each from the
+    // {STAT} it sends to the first ADX frame it gets back
+    #[cfg(unix)]
+    fn stat_to_first_frame(options: &[&str], streams: usize) -> Vec<Duration> {
+        let mut script = SessionScript::new()
+            .options(options)
+            .adx_streams(script::frames(1));
+        for _ in 0..=streams {
+            // The proxy drains and resets the ADX, and the game throws away
+            // what the stream left behind
+            script = script
+                .alls_sends("{STAT}")
+                .alls_expects(Expect::Touched)
+                .alls_sends("{HALT}")
+                .alls_discards(DRAIN_QUIET * 3);
+        }
+        // The first stream waits on the proxy opening and draining the
+        // ports, so it isn't counted
+        let session = script.run();
+        session.got[1..].iter().map(|got| got.waited).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prearmed_streams_reach_the_game_within_10ms() {
+        let mut delays = stat_to_first_frame(&["--prearm"], 10);
+        delays.sort();
+        assert!(
+            delays[delays.len() / 2] < Duration::from_millis(10),
+            "{:?}",
+            delays
+        );
+    }
+
+    // An ADX that streams `frames` frames, or until it is halted, sends
+    // `then`, and fails every read after with `end`
+    struct FailingAdx {
+        frame: Vec<u8>,
+        frames: usize,
+        then: Vec<u8>,
+        end: std::io::ErrorKind,
+        halted: Arc<AtomicBool>,
+    }
+
+    impl FailingAdx {
+        fn new(frames: usize, end: std::io::ErrorKind) -> Self {
+            FailingAdx {
+                frame: maimai::ADX.wrap(&[1, 0, 0, 0, 0, 0, 0]),
+                frames,
+                then: Vec::new(),
+                end,
+                halted: Arc::default(),
+            }
+        }
+    }
+
+    impl Read for FailingAdx {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.frames == 0 || self.halted.load(Ordering::Relaxed) {
+                if !self.then.is_empty() {
+                    let n = buf.len().min(self.then.len());
+                    buf[..n].copy_from_slice(&self.then[..n]);
+                    self.then.drain(..n);
+                    return Ok(n);
+                }
+                thread::sleep(Duration::from_millis(1));
+                return Err(self.end.into());
+            }
+            self.frames -= 1;
+            thread::sleep(Duration::from_millis(1));
+            buf[..self.frame.len()].copy_from_slice(&self.frame);
+            Ok(self.frame.len())
+        }
+    }
+
+    // What the proxy sends the ADX: a {HALT} stops it streaming, and a
+    // {STAT} fails once `fail_stat` is set
+    struct AdxCommands {
+        halted: Arc<AtomicBool>,
+        fail_stat: bool,
+    }
+
+    impl Write for AdxCommands {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match buf {
+                b"{HALT}" => self.halted.store(true, Ordering::Relaxed),
+                b"{STAT}" if self.fail_stat => return Err(std::io::ErrorKind::BrokenPipe.into()),
+                _ => {}
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A game that never sends anything
+    struct QuietGame;
+
+    impl Read for QuietGame {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            Err(std::io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    // An ALLS port that keeps what it is sent, and fails every write once
+    // `fail_after` bytes have gone out
+    #[derive(Clone)]
+    struct AllsPort {
+        sent: Arc<Mutex<Vec<u8>>>,
+        fail_after: usize,
+    }
+
+    impl Write for AllsPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut sent = self.sent.lock().unwrap();
+            if sent.len() >= self.fail_after {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A game that sends {HALT} once `after` has reached its ALLS port
+    struct HaltingGame {
+        sent: Arc<Mutex<Vec<u8>>>,
+        after: Vec<u8>,
+        halted: bool,
+    }
+
+    impl Read for HaltingGame {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let seen = || {
+                let sent = self.sent.lock().unwrap();
+                sent.windows(self.after.len())
+                    .any(|window| window == self.after)
+            };
+            if self.halted || !seen() {
+                thread::sleep(Duration::from_millis(1));
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            self.halted = true;
+            buf[..6].copy_from_slice(b"{HALT}");
+            Ok(6)
+        }
+    }
+
+    // A game on a port with the usual timeout: each read waits that long
+    // for the next bytes the test sends it
+    struct ChannelGame(mpsc::Receiver<Vec<u8>>);
+
+    impl Read for ChannelGame {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.recv_timeout(ports::PORT_TIMEOUT) {
+                Ok(bytes) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => Err(std::io::ErrorKind::TimedOut.into()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => Ok(0),
+            }
+        }
+    }
+
+    // What was written, and when
+    type Written = (Instant, Vec<u8>);
+
+    // An ALLS port that notes when each write went out
+    #[derive(Clone, Default)]
+    struct TimedAlls {
+        writes: Arc<Mutex<Vec<Written>>>,
+    }
+
+    impl TimedAlls {
+        // When the last write of `frame` went out
+        fn last(&self, frame: &[u8]) -> Option<Instant> {
+            let writes = self.writes.lock().unwrap();
+            writes
+                .iter()
+                .rfind(|(_, written)| written == frame)
+                .map(|(at, _)| *at)
+        }
+    }
+
+    impl Write for TimedAlls {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut writes = self.writes.lock().unwrap();
+            writes.push((Instant::now(), buf.to_vec()));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Streams from `adx` to `alls` until the stream ends by itself
+    fn stream(
+        options: &[&str],
+        adx: FailingAdx,
+        alls: &mut AllsPort,
+    ) -> (Result<()>, Arc<SessionReport>) {
+        stream_to(options, adx, QuietGame, alls)
+    }
+
+    // As stream, with `game` on the other end of the ALLS
+    fn stream_to(
+        options: &[&str],
+        adx: FailingAdx,
+        game: impl Read + Send,
+        alls: &mut (dyn Write + Send),
+    ) -> (Result<()>, Arc<SessionReport>) {
+        stream_on(options, adx, game, alls, &MonotonicClock)
+    }
+
+    // As stream_to, timed by `clock`
+    fn stream_on(
+        options: &[&str],
+        adx: FailingAdx,
+        game: impl Read + Send,
+        alls: &mut (dyn Write + Send),
+        clock: &(dyn Clock + Sync),
+    ) -> (Result<()>, Arc<SessionReport>) {
+        let config = parse(options).unwrap();
+        let spec = WireSpec::maimai();
+        let mut pipeline = Pipeline::new(&config, &spec).unwrap();
+        let mut adx_writer = AdxCommands {
+            halted: adx.halted.clone(),
+            fail_stat: !adx.then.is_empty(),
+        };
+        let mut adx_reader = BufReader::new(adx);
+        let mut alls_reader = BufReader::new(game);
+        // The game was last heard sending the {STAT} that started the stream
+        pipeline.report.game_heard(clock.now());
+        let result = stat_mode(
+            &config,
+            &spec,
+            &mut pipeline,
+            &mut adx_reader,
+            &mut adx_writer,
+            &mut alls_reader,
+            alls,
+            clock,
+        );
+        (result, pipeline.report.clone())
+    }
+
+    fn alls(fail_after: usize) -> AllsPort {
+        AllsPort {
+            sent: Arc::default(),
+            fail_after,
+        }
+    }
+
+    #[test]
+    fn a_failed_adx_read_ends_the_stream_with_an_error() {
+        let adx = FailingAdx::new(20, std::io::ErrorKind::BrokenPipe);
+        let frame = adx.frame.clone();
+        let mut alls = alls(usize::MAX);
+        let (result, report) = stream(&[], adx, &mut alls);
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("ADX read failed while streaming"), "{}", err);
+        // The stream is still accounted for, and the game isn't left
+        // holding the last touch
+        assert_eq!(report.sessions.load(Ordering::Relaxed), 1);
+        assert_eq!(report.final_clears.load(Ordering::Relaxed), 1);
+        let sent = alls.sent.lock().unwrap();
+        assert!(sent.ends_with(&all_clear_frame(&WireSpec::maimai())));
+        assert!(sent.windows(9).any(|window| window == frame));
+    }
+
+    #[test]
+    fn a_halted_stream_ends_on_an_all_clear_frame() {
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let frame = adx.frame.clone();
+        let mut alls = alls(usize::MAX);
+        let game = HaltingGame {
+            sent: alls.sent.clone(),
+            after: frame.clone(),
+            halted: false,
+        };
+        let (result, report) = stream_to(&[], adx, game, &mut alls);
+        result.unwrap();
+        assert_eq!(report.final_clears.load(Ordering::Relaxed), 1);
+        let sent = alls.sent.lock().unwrap();
+        let all_clear = all_clear_frame(&WireSpec::maimai());
+        // The last touch the game saw is let go of
+        assert!(sent.ends_with(&all_clear), "{:?}", sent);
+        let touched = sent
+            .windows(frame.len())
+            .rposition(|window| window == frame);
+        assert!(touched.unwrap() < sent.len() - all_clear.len());
+    }
+
+    #[test]
+    fn the_stream_is_timed_by_its_clock() {
+        let clock = MockClock::new();
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let frame = adx.frame.clone();
+        let mut alls = alls(usize::MAX);
+        let game = HaltingGame {
+            sent: alls.sent.clone(),
+            after: frame.clone(),
+            halted: false,
+        };
+        let all_clear = all_clear_frame(&WireSpec::maimai());
+        let sent = alls.sent.clone();
+        let (result, report) = thread::scope(|scope| {
+            scope.spawn(|| {
+                // However long the stream really runs, the warmup doesn't
+                // end until the clock says a second has gone by
+                thread::sleep(Duration::from_millis(50));
+                let written = sent.lock().unwrap().clone();
+                assert!(!written.is_empty());
+                assert!(written.chunks(9).all(|chunk| chunk == all_clear));
+                clock.advance(Duration::from_secs(1));
+            });
+            let options = ["--stream-warmup-ms", "1000"];
+            stream_on(&options, adx, game, &mut alls, &clock)
+        });
+        result.unwrap();
+        // The clock stood still once the warmup was over, so every gap
+        // between accepted frames was nothing at all
+        let (bound, gaps) = report.frame_gaps.buckets().next().unwrap();
+        assert_eq!(bound, Some(Duration::from_micros(250)));
+        assert!(gaps > 0);
+        let total: u64 = report.frame_gaps.buckets().map(|(_, count)| count).sum();
+        assert_eq!(gaps, total);
+    }
+
+    #[test]
+    fn a_stream_ends_once_the_game_has_gone_quiet_for_long_enough() {
+        let clock = MockClock::new();
+        // Ahead of when the report starts counting, as a clock that has
+        // been running a while would be
+        clock.advance(Duration::from_secs(1));
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let halted = adx.halted.clone();
+        let mut alls = alls(usize::MAX);
+        let sent = alls.sent.clone();
+        let (result, report) = thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                // From the first write on, the game has been heard from
+                while sent.lock().unwrap().is_empty() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                clock.advance(Duration::from_millis(4999));
+                thread::sleep(Duration::from_millis(50));
+                // Just short of the limit, the stream carries on
+                let streaming = !halted.load(Ordering::Relaxed);
+                clock.advance(Duration::from_millis(1));
+                streaming
+            });
+            let options = ["--halt-on-game-loss-secs", "5"];
+            let ended = stream_on(&options, adx, QuietGame, &mut alls, &clock);
+            assert!(watcher.join().unwrap());
+            ended
+        });
+        result.unwrap();
+        assert_eq!(report.game_losses.load(Ordering::Relaxed), 1);
+        assert!(report.game_silence(clock.now()) >= Duration::from_secs(5));
+        // The game isn't left holding a touch for whenever it comes back
+        let sent = alls.sent.lock().unwrap();
+        assert!(sent.ends_with(&all_clear_frame(&WireSpec::maimai())));
+    }
+
+    #[test]
+    fn a_stream_over_the_cap_is_ended_and_reported() {
+        let events = std::env::temp_dir().join(format!("maitouch-capped-{}", std::process::id()));
+        let hook = format!(
+            "echo \"$MAITOUCH_EVENT $MAITOUCH_DETAIL\" >> {}",
+            events.display()
+        );
+        let clock = MockClock::new();
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let mut alls = alls(usize::MAX);
+        let sent = alls.sent.clone();
+        let (result, report) = thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                while sent.lock().unwrap().is_empty() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                clock.advance(Duration::from_millis(59_999));
+                thread::sleep(Duration::from_millis(50));
+                // However long it has really been, the cap is a minute by
+                // the clock
+                let capped = events.exists();
+                clock.advance(Duration::from_millis(1));
+                capped
+            });
+            let options = ["--max-stream-minutes", "1", "--on-event", &hook];
+            let ended = stream_on(&options, adx, QuietGame, &mut alls, &clock);
+            assert!(!watcher.join().unwrap());
+            ended
+        });
+        result.unwrap();
+        assert_eq!(report.stream_caps.load(Ordering::Relaxed), 1);
+        let sent = alls.sent.lock().unwrap();
+        assert!(sent.ends_with(&all_clear_frame(&WireSpec::maimai())));
+        // The hook runs in the background
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut fired = String::new();
+        while fired.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            fired = std::fs::read_to_string(&events).unwrap_or_default();
+        }
+        let _ = std::fs::remove_file(&events);
+        assert_eq!(
+            fired,
+            "stream-capped streaming for over 60s without a HALT\n"
+        );
+    }
+
+    #[test]
+    fn a_halt_after_a_quiet_spell_stops_the_frames_within_50ms() {
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let frame = adx.frame.clone();
+        let mut alls = TimedAlls::default();
+        let timed = alls.clone();
+        let (send, game) = mpsc::channel();
+        let (halt_at, (result, _)) = thread::scope(|scope| {
+            let streaming = scope.spawn(|| stream_to(&[], adx, ChannelGame(game), &mut alls));
+            // Long enough for the halt watcher's read to have timed out
+            // and started over
+            thread::sleep(ports::PORT_TIMEOUT + Duration::from_millis(300));
+            let halt_at = Instant::now();
+            send.send(b"{HALT}".to_vec()).unwrap();
+            (halt_at, streaming.join().unwrap())
+        });
+        result.unwrap();
+        let last = timed.last(&frame).unwrap();
+        let latency = last.saturating_duration_since(halt_at);
+        assert!(latency <= Duration::from_millis(50), "{:?}", latency);
+        // The frame stops there, and only the all-clear goes out after it
+        let all_clear = all_clear_frame(&WireSpec::maimai());
+        assert!(timed.last(&all_clear).unwrap() > last);
+    }
+
+    #[test]
+    fn a_halted_stream_is_torn_down_within_50ms() {
+        // The board keeps streaming until it gets the HALT itself
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let mut alls = alls(usize::MAX);
+        let (send, game) = mpsc::channel();
+        let teardown = thread::scope(|scope| {
+            let streaming = scope.spawn(|| {
+                let ended = stream_to(&[], adx, ChannelGame(game), &mut alls);
+                (Instant::now(), ended)
+            });
+            thread::sleep(Duration::from_millis(100));
+            let halt_at = Instant::now();
+            send.send(b"{HALT}".to_vec()).unwrap();
+            let (ended_at, (result, _)) = streaming.join().unwrap();
+            result.unwrap();
+            ended_at - halt_at
+        });
+        // Writer and reader stopped, and the ADX drained down to the quiet
+        // period it has to keep
+        assert!(teardown >= DRAIN_QUIET, "{:?}", teardown);
+        assert!(teardown <= Duration::from_millis(50), "{:?}", teardown);
+    }
+
+    #[test]
+    fn a_stalled_board_gets_the_alls_no_frames_under_strict_passthrough() {
+        let clock = MockClock::new();
+        let adx = FailingAdx::new(5, std::io::ErrorKind::TimedOut);
+        let frame = adx.frame.clone();
+        let mut alls = alls(usize::MAX);
+        let sent = alls.sent.clone();
+        let (send, game) = mpsc::channel();
+        let (streaming, withheld, result, report) = thread::scope(|scope| {
+            let stream = scope.spawn(|| {
+                let options = ["--strict-passthrough", "--adx-timeout-ms", "50"];
+                stream_on(&options, adx, ChannelGame(game), &mut alls, &clock)
+            });
+            let touched = || {
+                let sent = sent.lock().unwrap();
+                sent.windows(frame.len()).any(|window| window == frame)
+            };
+            while !touched() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Until the clock says the board has been quiet too long, the
+            // last frame keeps going out
+            thread::sleep(Duration::from_millis(20));
+            let before = sent.lock().unwrap().len();
+            thread::sleep(Duration::from_millis(20));
+            let streaming = sent.lock().unwrap().len() > before;
+            clock.advance(Duration::from_millis(51));
+            thread::sleep(Duration::from_millis(20));
+            let stalled_at = sent.lock().unwrap().len();
+            thread::sleep(Duration::from_millis(30));
+            let withheld = sent.lock().unwrap().len() == stalled_at;
+            send.send(b"{HALT}".to_vec()).unwrap();
+            let (result, report) = stream.join().unwrap();
+            (streaming, withheld.then_some(stalled_at), result, report)
+        });
+        result.unwrap();
+        assert!(streaming);
+        assert_eq!(report.stalls.load(Ordering::Relaxed), 1);
+        // Nothing more went out once the board stalled, bar the all-clear
+        // as the stream ended
+        let stalled_at = withheld.unwrap();
+        let sent = alls.sent.lock().unwrap();
+        assert_eq!(sent[stalled_at..], all_clear_frame(&WireSpec::maimai()));
+    }
+
+    #[test]
+    fn a_failed_alls_write_ends_the_stream_with_an_error() {
+        let adx = FailingAdx::new(usize::MAX, std::io::ErrorKind::TimedOut);
+        let mut alls = alls(9 * 5);
+        let (result, report) = stream(&[], adx, &mut alls);
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("ALLS write failed while streaming"), "{}", err);
+        assert!(err.ends_with("broken pipe"), "{}", err);
+        assert_eq!(report.sessions.load(Ordering::Relaxed), 1);
+        assert_eq!(report.final_clears.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn a_failed_restart_ends_the_stream_with_an_error() {
+        // The board power-cycles after a few frames, and won't take the
+        // {STAT} that would restart it
+        let mut adx = FailingAdx::new(5, std::io::ErrorKind::TimedOut);
+        adx.then = b"BOOT".to_vec();
+        let mut alls = alls(usize::MAX);
+        let (result, report) = stream(&["--adx-banner", "BOOT"], adx, &mut alls);
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(
+            err.starts_with("couldn't restart the ADX after it power-cycled"),
+            "{}",
+            err
+        );
+        assert_eq!(report.adx_power_cycles.load(Ordering::Relaxed), 1);
+        assert_eq!(report.final_clears.load(Ordering::Relaxed), 1);
+    }
+
+    // An ADX with its first frame ready as soon as it is read from, and a
+    // frame a millisecond after that until it is halted
+    struct EagerAdx(Arc<AtomicBool>, bool);
+
+    impl Read for EagerAdx {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if std::mem::replace(&mut self.1, true) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            if self.0.load(Ordering::Relaxed) {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            let frame = maimai::ADX.wrap(&[1, 0, 0, 0, 0, 0, 0]);
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(frame.len())
+        }
+    }
+
+    // An ALLS port that notes when the first ADX frame went out on it
+    struct FirstFrame(Arc<Mutex<Option<Instant>>>);
+
+    impl Write for FirstFrame {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf != all_clear_frame(&WireSpec::maimai()) {
+                self.0.lock().unwrap().get_or_insert_with(Instant::now);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A game that halts the stream once it has had a frame
+    struct HaltOnFrame(Arc<Mutex<Option<Instant>>>, bool);
+
+    impl Read for HaltOnFrame {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.1 || self.0.lock().unwrap().is_none() {
+                thread::sleep(Duration::from_micros(100));
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            self.1 = true;
+            buf[..6].copy_from_slice(b"{HALT}");
+            Ok(6)
+        }
+    }
+
+    // How long after a {STAT} the first ADX frame reaches the ALLS, from a
+    // stream armed a while before it or only once it came in
+    fn first_frame_after_stat(prearmed: bool) -> Duration {
+        let config = parse(&[]).unwrap();
+        let spec = WireSpec::maimai();
+        let mut pipeline = Pipeline::new(&config, &spec).unwrap();
+        let halted = Arc::new(AtomicBool::new(false));
+        let mut adx_reader = BufReader::new(EagerAdx(halted.clone(), false));
+        let mut adx_writer = AdxCommands {
+            halted,
+            fail_stat: false,
+        };
+        let first_frame = Arc::new(Mutex::new(None));
+        let mut alls_reader = BufReader::new(HaltOnFrame(first_frame.clone(), false));
+        let mut alls_writer = FirstFrame(first_frame.clone());
+        thread::scope(|scope| {
+            let prearmed = prearmed.then(|| Armed::new(scope, &config, &spec, &MonotonicClock));
+            // Config mode goes on for a while before the game sends {STAT}
+            thread::sleep(Duration::from_millis(5));
+            pipeline.stat_at = Instant::now();
+            let stat_at = pipeline.stat_at;
+            let lent = Lent {
+                pipeline: &mut pipeline,
+                adx_reader: &mut adx_reader,
+                adx_writer: &mut adx_writer,
+                alls_writer: &mut alls_writer,
+            };
+            prearmed
+                .unwrap_or_else(|| Armed::new(scope, &config, &spec, &MonotonicClock))
+                .stream(&config, &spec, lent, &mut alls_reader)
+                .unwrap();
+            first_frame.lock().unwrap().unwrap() - stat_at
+        })
+    }
+
+    #[test]
+    fn prearming_cuts_the_wait_for_the_first_frame() {
+        // Interleaved, so both see the machine as loaded as the other
+        let (mut cold, mut prearmed): (Vec<_>, Vec<_>) = (0..25)
+            .map(|_| (first_frame_after_stat(false), first_frame_after_stat(true)))
+            .unzip();
+        cold.sort();
+        prearmed.sort();
+        let (cold, prearmed) = (cold[cold.len() / 2], prearmed[prearmed.len() / 2]);
+        assert!(
+            prearmed < cold,
+            "{:?} prearmed, {:?} without",
+            prearmed,
+            cold
+        );
+    }
 }
@@ -0,0 +1,233 @@
+use crate::ports;
+use crate::report::SessionReport;
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+// Writes held for the mirror at most, about 64ms of frames while
+// streaming. Past that they are dropped rather than held against the
+// primary.
+const QUEUE_LEN: usize = 64;
+
+// A second ALLS port that gets a copy of everything written to the
+// primary. Its own thread does the writing, so a slow or failing mirror
+// only ever loses its copy; nothing is read from it.
+pub struct Mirror {
+    queue: SyncSender<Vec<u8>>,
+    // With --alls-mirror-stream-only, config responses stay off the mirror
+    stream_only: bool,
+    streaming: AtomicBool,
+    full_reported: AtomicBool,
+    report: Arc<SessionReport>,
+}
+
+impl Mirror {
+    pub fn open(name: &str, stream_only: bool, report: Arc<SessionReport>) -> Result<Arc<Self>> {
+        let endpoint =
+            ports::open_endpoint(name).with_context(|| format!("opening ALLS mirror {}", name))?;
+        let (queue, pending) = mpsc::sync_channel(QUEUE_LEN);
+        let name = name.to_string();
+        tracing::info!("Mirroring the ALLS output to {}", name);
+        thread::Builder::new()
+            .name("alls mirror".into())
+            .spawn(move || forward(endpoint, pending, &name))
+            .context("spawning the ALLS mirror thread")?;
+        Ok(Arc::new(Mirror {
+            queue,
+            stream_only,
+            streaming: AtomicBool::new(false),
+            full_reported: AtomicBool::new(false),
+            report,
+        }))
+    }
+
+    // A new stream gets its own warning if the mirror falls behind
+    pub fn set_streaming(&self, streaming: bool) {
+        if streaming {
+            self.full_reported.store(false, Ordering::Relaxed);
+        }
+        self.streaming.store(streaming, Ordering::Relaxed);
+    }
+
+    // `primary`, copying whatever it takes to the mirror
+    pub fn wrap(self: &Arc<Self>, primary: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        Box::new(MirroredWriter {
+            primary,
+            mirror: self.clone(),
+        })
+    }
+
+    fn copy(&self, bytes: &[u8]) {
+        if bytes.is_empty() || (self.stream_only && !self.streaming.load(Ordering::Relaxed)) {
+            return;
+        }
+        match self.queue.try_send(bytes.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.report.mirror_dropped.fetch_add(1, Ordering::Relaxed);
+                if !self.full_reported.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("ALLS mirror is falling behind, dropping its copy");
+                }
+            }
+            // Only if the mirror thread died
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+struct MirroredWriter {
+    primary: Box<dyn Write + Send>,
+    mirror: Arc<Mirror>,
+}
+
+impl Write for MirroredWriter {
+    // The mirror gets exactly the bytes the primary took, so a frame cut
+    // short by a write timeout is cut short on both
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.primary.write(buf)?;
+        self.mirror.copy(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.primary.flush()
+    }
+}
+
+// Holds the whole endpoint, so a PTY mirror stays open
+fn forward(mut endpoint: ports::Endpoint, pending: Receiver<Vec<u8>>, name: &str) {
+    let mut failing = false;
+    for bytes in pending {
+        match endpoint
+            .writer
+            .write_all(&bytes)
+            .and_then(|()| endpoint.writer.flush())
+        {
+            Ok(()) if failing => {
+                failing = false;
+                tracing::info!("ALLS mirror {} is taking writes again", name);
+            }
+            Ok(()) => {}
+            Err(err) if !failing => {
+                failing = true;
+                tracing::warn!("Couldn't write to ALLS mirror {}: {}", name, err);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // The primary, kept where the test can still see it
+    #[derive(Clone, Default)]
+    struct Primary(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Primary {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Opens a mirror on a PTY and runs `test` with it and a mirrored
+    // primary, then gives back what each got
+    #[cfg(unix)]
+    fn mirrored(
+        stream_only: bool,
+        test: impl FnOnce(&Mirror, &mut dyn Write),
+    ) -> (Vec<u8>, Vec<u8>) {
+        use serialport::{SerialPort, TTYPort};
+
+        let (mut far, near) = TTYPort::pair().unwrap();
+        far.set_timeout(Duration::from_millis(200)).unwrap();
+        let report = Arc::new(SessionReport::new());
+        let mirror = Mirror::open(&near.name().unwrap(), stream_only, report).unwrap();
+        let primary = Primary::default();
+        let mut writer = mirror.wrap(Box::new(primary.clone()));
+        test(&mirror, &mut writer);
+        let mut copy = Vec::new();
+        let mut buf = [0u8; 256];
+        // Until the mirror thread has nothing more for it
+        while let Ok(n @ 1..) = far.read(&mut buf) {
+            copy.extend_from_slice(&buf[..n]);
+        }
+        let primary = primary.0.lock().unwrap().clone();
+        (primary, copy)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_mirror_gets_the_same_bytes_as_the_primary() {
+        let (primary, copy) = mirrored(false, |mirror, writer| {
+            writer.write_all(b"(LAr2)").unwrap();
+            mirror.set_streaming(true);
+            for n in 0..50u8 {
+                writer
+                    .write_all(&[b'(', n, 1, 2, 3, 4, 5, 6, b')'])
+                    .unwrap();
+            }
+            mirror.set_streaming(false);
+            writer.write_all(b"(RAr2)").unwrap();
+        });
+        assert_eq!(primary.len(), 6 + 50 * 9 + 6);
+        assert_eq!(copy, primary);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stream_only_keeps_config_responses_off_the_mirror() {
+        let frame = b"(\x01\x00\x00\x00\x00\x00\x00)";
+        let (primary, copy) = mirrored(true, |mirror, writer| {
+            writer.write_all(b"(LAr2)").unwrap();
+            mirror.set_streaming(true);
+            writer.write_all(frame).unwrap();
+            writer.write_all(frame).unwrap();
+            mirror.set_streaming(false);
+            writer.write_all(b"(RAr2)").unwrap();
+        });
+        assert_eq!(primary, [&b"(LAr2)"[..], frame, frame, b"(RAr2)"].concat());
+        assert_eq!(copy, frame.repeat(2));
+    }
+
+    #[test]
+    fn a_mirror_behind_drops_its_copy_and_warns_once_a_stream() {
+        // Nothing drains the queue, as with a mirror stuck on a write
+        let (queue, _pending) = mpsc::sync_channel(QUEUE_LEN);
+        let report = Arc::new(SessionReport::new());
+        let mirror = Arc::new(Mirror {
+            queue,
+            stream_only: false,
+            streaming: AtomicBool::new(false),
+            full_reported: AtomicBool::new(false),
+            report: report.clone(),
+        });
+        let primary = Primary::default();
+        let mut writer = mirror.wrap(Box::new(primary.clone()));
+        crate::logcapture::capturing(|log| {
+            mirror.set_streaming(true);
+            for _ in 0..QUEUE_LEN + 10 {
+                writer.write_all(b"(frame)").unwrap();
+            }
+            mirror.set_streaming(true);
+            writer.write_all(b"(frame)").unwrap();
+            let warning = "ALLS mirror is falling behind, dropping its copy";
+            assert_eq!(log.take(), [warning, warning]);
+        });
+        // The primary lost nothing to it
+        assert_eq!(primary.0.lock().unwrap().len(), (QUEUE_LEN + 11) * 7);
+        assert_eq!(report.mirror_dropped.load(Ordering::Relaxed), 11);
+    }
+}
@@ -0,0 +1,498 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+// Name the proxy logs under in the OS log
+const IDENTIFIER: &str = "maitouch_rs";
+
+// Where --log-target sends the logs. stdout, stderr and file get everything
+// the verbosity lets through; journald and eventlog only get warnings and
+// errors, for monitoring that watches the OS log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    Stderr,
+    File(PathBuf),
+    Journald,
+    EventLog,
+}
+
+impl LogTarget {
+    pub fn is_os_log(&self) -> bool {
+        matches!(self, LogTarget::Journald | LogTarget::EventLog)
+    }
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "stdout" => LogTarget::Stdout,
+            "stderr" => LogTarget::Stderr,
+            "journald" => LogTarget::Journald,
+            "eventlog" => LogTarget::EventLog,
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => LogTarget::File(path.into()),
+                _ => bail!(
+                    "unknown log target {}, expected stdout, stderr, file:<path>, journald or \
+                     eventlog",
+                    s
+                ),
+            },
+        })
+    }
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogTarget::Stdout => write!(f, "stdout"),
+            LogTarget::Stderr => write!(f, "stderr"),
+            LogTarget::File(path) => write!(f, "file:{}", path.display()),
+            LogTarget::Journald => write!(f, "journald"),
+            LogTarget::EventLog => write!(f, "eventlog"),
+        }
+    }
+}
+
+// One warning or error on its way to the OS log
+pub struct Record<'a> {
+    pub level: Level,
+    pub target: &'a str,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+    pub message: String,
+    // The event's other fields, by name
+    pub fields: Vec<(&'static str, String)>,
+}
+
+pub trait OsLog: Send + Sync {
+    fn write(&self, record: &Record);
+}
+
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.fields.push((name, value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => self.fields.push((name, format!("{:?}", value))),
+        }
+    }
+}
+
+// Hands warnings and errors to an OS log, next to the fmt layers that keep
+// writing everything to the console or a file
+pub struct OsLogLayer {
+    log: Box<dyn OsLog>,
+}
+
+impl OsLogLayer {
+    pub fn new(log: Box<dyn OsLog>) -> Self {
+        OsLogLayer { log }
+    }
+
+    // The OS log `target` stands for, which must be journald or eventlog
+    pub fn open(target: &LogTarget) -> Result<Self> {
+        let log: Box<dyn OsLog> = match target {
+            #[cfg(target_os = "linux")]
+            LogTarget::Journald => Box::new(journald::Journald::connect()?),
+            #[cfg(windows)]
+            LogTarget::EventLog => Box::new(eventlog::EventLog::register()?),
+            target => bail!("log target {} isn't available on this platform", target),
+        };
+        Ok(Self::new(log))
+    }
+}
+
+impl<S: Subscriber> Layer<S> for OsLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        // More verbose levels compare greater
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.log.write(&Record {
+            level: *metadata.level(),
+            target: metadata.target(),
+            file: metadata.file(),
+            line: metadata.line(),
+            message: fields.message,
+            fields: fields.fields,
+        });
+    }
+}
+
+// The record as one block of text, for logs without fields of their own
+#[cfg(windows)]
+fn flatten(record: &Record) -> String {
+    use std::fmt::Write as _;
+    let mut text = record.message.clone();
+    for (name, value) in &record.fields {
+        let _ = write!(text, "\n{}: {}", name, value);
+    }
+    let _ = write!(text, "\n\nsource: {}", record.target);
+    if let Some((file, line)) = record.file.zip(record.line) {
+        let _ = write!(text, " ({}:{})", file, line);
+    }
+    text
+}
+
+// journald's native protocol: one datagram per entry of KEY=value lines
+// on its socket, without going through syslog
+#[cfg(target_os = "linux")]
+mod journald {
+    use super::{OsLog, Record, IDENTIFIER};
+    use anyhow::{Context, Result};
+    use std::os::unix::net::UnixDatagram;
+    use tracing::Level;
+
+    const SOCKET: &str = "/run/systemd/journal/socket";
+
+    pub struct Journald {
+        socket: UnixDatagram,
+    }
+
+    impl Journald {
+        pub fn connect() -> Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(SOCKET)
+                .with_context(|| format!("connecting to journald at {}", SOCKET))?;
+            Ok(Journald { socket })
+        }
+    }
+
+    // journald field names are upper case letters, digits and _, and
+    // can't start with _ (those are the journal's own)
+    fn field_name(name: &str) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+                _ => '_',
+            })
+            .collect();
+        format!("F_{}", name.trim_start_matches('_'))
+    }
+
+    // A value with a newline in it goes as its length and bytes instead
+    fn push_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+
+    impl OsLog for Journald {
+        fn write(&self, record: &Record) {
+            let priority = if record.level == Level::ERROR {
+                "3"
+            } else {
+                "4"
+            };
+            let mut entry = Vec::new();
+            push_field(&mut entry, "MESSAGE", &record.message);
+            push_field(&mut entry, "PRIORITY", priority);
+            push_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+            push_field(&mut entry, "TARGET", record.target);
+            if let Some(file) = record.file {
+                push_field(&mut entry, "CODE_FILE", file);
+            }
+            if let Some(line) = record.line {
+                push_field(&mut entry, "CODE_LINE", &line.to_string());
+            }
+            for (name, value) in &record.fields {
+                push_field(&mut entry, &field_name(name), value);
+            }
+            // Logging the failure would only come back here
+            let _ = self.socket.send(&entry);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn sends_an_entry_of_fields_in_the_native_protocol() {
+            let (socket, journal) = UnixDatagram::pair().unwrap();
+            let journald = Journald { socket };
+            journald.write(&Record {
+                level: Level::ERROR,
+                target: "maitouch_rs::ports",
+                file: Some("src/ports.rs"),
+                line: Some(42),
+                message: "ADX gone\nfor good".to_string(),
+                fields: vec![
+                    ("port", "/dev/ttyUSB0".to_string()),
+                    ("_retries", "3".to_string()),
+                ],
+            });
+            let mut buf = [0u8; 512];
+            let n = journal.recv(&mut buf).unwrap();
+            let mut expected = b"MESSAGE\n".to_vec();
+            expected.extend_from_slice(&17u64.to_le_bytes());
+            expected.extend_from_slice(
+                b"ADX gone\nfor good\n\
+                  PRIORITY=3\n\
+                  SYSLOG_IDENTIFIER=maitouch_rs\n\
+                  TARGET=maitouch_rs::ports\n\
+                  CODE_FILE=src/ports.rs\n\
+                  CODE_LINE=42\n\
+                  F_PORT=/dev/ttyUSB0\n\
+                  F_RETRIES=3\n",
+            );
+            assert_eq!(
+                String::from_utf8_lossy(&buf[..n]),
+                String::from_utf8_lossy(&expected)
+            );
+        }
+
+        #[test]
+        fn field_names_are_made_fit_for_the_journal() {
+            assert_eq!(field_name("port"), "F_PORT");
+            assert_eq!(field_name("adx.baud"), "F_ADX_BAUD");
+            assert_eq!(field_name("__own"), "F_OWN");
+        }
+    }
+}
+
+// The Application event log, under a source named after the proxy. Without
+// a message file registered for it, Event Viewer notes that the
+// description is missing, then shows the text anyway.
+#[cfg(windows)]
+mod eventlog {
+    use super::{flatten, OsLog, Record, IDENTIFIER};
+    use anyhow::{bail, Result};
+    use std::ffi::c_void;
+    use std::io::Error;
+    use tracing::Level;
+
+    const EVENTLOG_ERROR_TYPE: u16 = 0x0001;
+    const EVENTLOG_WARNING_TYPE: u16 = 0x0002;
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn RegisterEventSourceW(server: *const u16, source: *const u16) -> *mut c_void;
+        fn ReportEventW(
+            log: *mut c_void,
+            kind: u16,
+            category: u16,
+            event_id: u32,
+            user_sid: *mut c_void,
+            strings: u16,
+            data_size: u32,
+            text: *const *const u16,
+            data: *const c_void,
+        ) -> i32;
+    }
+
+    fn wide(text: &str) -> Vec<u16> {
+        text.encode_utf16().chain(Some(0)).collect()
+    }
+
+    pub struct EventLog {
+        handle: *mut c_void,
+    }
+
+    // SAFETY: event log handles may be used from any thread
+    unsafe impl Send for EventLog {}
+    unsafe impl Sync for EventLog {}
+
+    impl EventLog {
+        pub fn register() -> Result<Self> {
+            let source = wide(IDENTIFIER);
+            // SAFETY: source is a NUL-terminated UTF-16 string
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+            if handle.is_null() {
+                bail!(
+                    "registering event source {}: {}",
+                    IDENTIFIER,
+                    Error::last_os_error()
+                );
+            }
+            Ok(EventLog { handle })
+        }
+    }
+
+    impl OsLog for EventLog {
+        fn write(&self, record: &Record) {
+            let kind = if record.level == Level::ERROR {
+                EVENTLOG_ERROR_TYPE
+            } else {
+                EVENTLOG_WARNING_TYPE
+            };
+            let text = wide(&flatten(record));
+            let strings = [text.as_ptr()];
+            // SAFETY: one NUL-terminated string, no SID and no binary data
+            unsafe {
+                ReportEventW(
+                    self.handle,
+                    kind,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logcapture::Capture;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // What an OsLog was handed, kept for the test
+    #[derive(Debug, PartialEq)]
+    struct Logged {
+        level: Level,
+        message: String,
+        fields: Vec<(&'static str, String)>,
+        here: bool,
+    }
+
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<Logged>>>);
+
+    impl OsLog for Collector {
+        fn write(&self, record: &Record) {
+            self.0.lock().unwrap().push(Logged {
+                level: record.level,
+                message: record.message.clone(),
+                fields: record.fields.clone(),
+                here: record.target == module_path!()
+                    && record.file == Some(file!())
+                    && record.line.is_some(),
+            });
+        }
+    }
+
+    // Runs `test` under layers put together as init_logging does, with a
+    // console layer writing to a capture and the collector standing in
+    // for the OS log, at `level`
+    fn layered(level: LevelFilter, test: impl FnOnce()) -> (Vec<String>, Vec<Logged>) {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let collector = Collector::default();
+        let layers: Vec<Box<dyn Layer<_> + Send + Sync>> = vec![
+            Box::new(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(move || writer.clone())
+                    .without_time()
+                    .with_target(false)
+                    .with_ansi(false),
+            ),
+            Box::new(OsLogLayer::new(Box::new(collector.clone()))),
+        ];
+        let subscriber = tracing_subscriber::registry().with(level).with(layers);
+        tracing::subscriber::with_default(subscriber, test);
+        let logged = std::mem::take(&mut *collector.0.lock().unwrap());
+        (capture.take(), logged)
+    }
+
+    #[test]
+    fn only_warnings_and_errors_reach_the_os_log() {
+        let (console, logged) = layered(LevelFilter::INFO, || {
+            tracing::debug!("not at this verbosity");
+            tracing::info!("ALLS /dev/ttyS0 ADX /dev/ttyUSB0");
+            tracing::warn!("ADX stalled");
+            tracing::error!(port = "/dev/ttyUSB0", retries = 3, "ADX gone");
+        });
+        assert_eq!(
+            console,
+            [
+                "INFO ALLS /dev/ttyS0 ADX /dev/ttyUSB0",
+                "WARN ADX stalled",
+                "ERROR ADX gone port=\"/dev/ttyUSB0\" retries=3",
+            ]
+        );
+        assert_eq!(
+            logged,
+            [
+                Logged {
+                    level: Level::WARN,
+                    message: "ADX stalled".to_string(),
+                    fields: Vec::new(),
+                    here: true,
+                },
+                Logged {
+                    level: Level::ERROR,
+                    message: "ADX gone".to_string(),
+                    fields: vec![
+                        ("port", "/dev/ttyUSB0".to_string()),
+                        ("retries", "3".to_string())
+                    ],
+                    here: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_noisier_console_leaves_the_os_log_as_it_was() {
+        let (console, logged) = layered(LevelFilter::TRACE, || {
+            tracing::debug!("From ALLS: {{STAT}}");
+            tracing::trace!("read 9 bytes");
+        });
+        assert_eq!(console, ["DEBUG From ALLS: {STAT}", "TRACE read 9 bytes"]);
+        assert!(logged.is_empty());
+    }
+
+    #[test]
+    fn log_targets_parse_and_print_back() {
+        for target in [
+            "stdout",
+            "stderr",
+            "file:/var/log/maitouch.log",
+            "journald",
+            "eventlog",
+        ] {
+            let parsed: LogTarget = target.parse().unwrap();
+            assert_eq!(parsed.to_string(), target);
+        }
+        assert!(LogTarget::Journald.is_os_log());
+        assert!(!"file:x".parse::<LogTarget>().unwrap().is_os_log());
+        for target in ["syslog", "file:"] {
+            assert_eq!(
+                target.parse::<LogTarget>().unwrap_err().to_string(),
+                format!(
+                    "unknown log target {}, expected stdout, stderr, file:<path>, journald or \
+                     eventlog",
+                    target
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,682 @@
+use crate::clock::Clock;
+use std::io::{ErrorKind, Result, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Weight of the newest sample in the write cost average
+const EWMA_ALPHA: f64 = 0.1;
+// Consecutive frames with the average above the interval before we complain
+const OVERRUN_WARN_FRAMES: u32 = 100;
+// Longest --coalesce-us window; past it, holding frames costs more latency
+// than the writes it saves are worth
+pub const MAX_COALESCE_US: u64 = 1000;
+// Timed out attempts at finishing a torn frame before starting a fresh one
+const COMPLETION_ATTEMPTS: u32 = 3;
+
+// Writes frames at a fixed cadence, waking early by the measured write cost
+// so the frame is on the wire at the target time rather than after it.
+pub struct PacedWriter<C: Clock> {
+    clock: C,
+    interval: Duration,
+    next_frame: Option<Instant>,
+    write_cost_us: f64,
+    overrun_frames: u32,
+}
+
+impl<C: Clock> PacedWriter<C> {
+    pub fn new(clock: C, interval: Duration) -> Self {
+        PacedWriter {
+            clock,
+            interval,
+            next_frame: None,
+            write_cost_us: 0.0,
+            overrun_frames: 0,
+        }
+    }
+
+    pub fn write_cost(&self) -> Duration {
+        Duration::from_secs_f64(self.write_cost_us / 1_000_000.0)
+    }
+
+    // Runs `write` when the next frame is due
+    pub fn write_frame(&mut self, write: impl FnOnce() -> Result<()>) -> Result<()> {
+        let target = *self.next_frame.get_or_insert_with(|| self.clock.now());
+        let wake = target.checked_sub(self.write_cost()).unwrap_or(target);
+        self.clock.sleep_until(wake);
+
+        let start = self.clock.now();
+        write()?;
+        let end = self.clock.now();
+
+        let cost_us = (end - start).as_secs_f64() * 1_000_000.0;
+        self.write_cost_us += EWMA_ALPHA * (cost_us - self.write_cost_us);
+
+        if self.write_cost() > self.interval {
+            self.overrun_frames += 1;
+            if self.overrun_frames == OVERRUN_WARN_FRAMES {
+                tracing::warn!(
+                    "ALLS write cost {:?} exceeds frame interval {:?}, adapter can't keep up",
+                    self.write_cost(),
+                    self.interval
+                );
+            }
+        } else {
+            self.overrun_frames = 0;
+        }
+
+        // If we fell behind, restart the cadence from now instead of bursting to catch up
+        let next = target + self.interval;
+        self.next_frame = Some(if next < end { end } else { next });
+        Ok(())
+    }
+}
+
+// Forwards only every nth frame to a slow consumer, except that a frame
+// that differs from the last one forwarded always goes straight out so
+// presses and releases are never held back.
+pub struct Decimator {
+    every: u32,
+    since_forwarded: u32,
+    last: Vec<u8>,
+    pub forwarded: u64,
+    pub skipped: u64,
+}
+
+impl Decimator {
+    pub fn new(every: u32) -> Self {
+        Decimator {
+            every,
+            since_forwarded: 0,
+            last: Vec::new(),
+            forwarded: 0,
+            skipped: 0,
+        }
+    }
+
+    // Whether this frame should be written
+    pub fn admit(&mut self, frame: &[u8]) -> bool {
+        self.since_forwarded += 1;
+        if self.since_forwarded < self.every && frame == self.last.as_slice() {
+            self.skipped += 1;
+            return false;
+        }
+        self.since_forwarded = 0;
+        self.last.clear();
+        self.last.extend_from_slice(frame);
+        self.forwarded += 1;
+        true
+    }
+}
+
+// Joins the frames that arrive within `window` of the first one held into
+// one write, for ALLS transports where every write is a packet or a
+// syscall of its own. Nothing is held past the window by more than it
+// takes the writer to wake up, as long as poll() is called that often.
+pub struct Coalescer<C: Clock> {
+    clock: C,
+    window: Duration,
+    held: Vec<u8>,
+    // When the first frame in `held` came in
+    since: Option<Instant>,
+    pub frames: u64,
+    pub writes: u64,
+    pub longest_hold: Duration,
+}
+
+impl<C: Clock> Coalescer<C> {
+    pub fn new(clock: C, window: Duration) -> Self {
+        Coalescer {
+            clock,
+            window,
+            held: Vec::new(),
+            since: None,
+            frames: 0,
+            writes: 0,
+            longest_hold: Duration::ZERO,
+        }
+    }
+
+    // Holds `frame`, and writes everything held if the window is up
+    pub fn push(&mut self, frame: &[u8], write: impl FnOnce(&[u8]) -> Result<()>) -> Result<()> {
+        self.since.get_or_insert_with(|| self.clock.now());
+        self.held.extend_from_slice(frame);
+        self.frames += 1;
+        self.poll(write)
+    }
+
+    // Writes what is held once the first of it has waited out the window
+    pub fn poll(&mut self, write: impl FnOnce(&[u8]) -> Result<()>) -> Result<()> {
+        match self.since {
+            Some(since) if self.clock.now().saturating_duration_since(since) >= self.window => {
+                self.flush(write)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // How long until what is held is due, the whole window if nothing is
+    pub fn remaining(&self) -> Duration {
+        self.since.map_or(self.window, |since| {
+            self.window
+                .saturating_sub(self.clock.now().saturating_duration_since(since))
+        })
+    }
+
+    // Writes what is held now, for the end of a stream
+    pub fn flush(&mut self, write: impl FnOnce(&[u8]) -> Result<()>) -> Result<()> {
+        let Some(since) = self.since.take() else {
+            return Ok(());
+        };
+        self.longest_hold = self
+            .longest_hold
+            .max(self.clock.now().saturating_duration_since(since));
+        self.writes += 1;
+        let written = write(&self.held);
+        self.held.clear();
+        written
+    }
+}
+
+// Writes whole frames to a port whose writes can time out partway. A frame
+// cut short is finished on the next call before any new frame starts, so
+// the ALLS never sees the start of one frame run into another. If it still
+// won't go out after a few tries, the rest is abandoned and a fresh frame
+// written; its open delimiter lets the ALLS parser resynchronize.
+#[derive(Default)]
+pub struct FrameCursor {
+    pending: Vec<u8>,
+    written: usize,
+    attempts: u32,
+    pub completed: u64,
+    pub resynced: u64,
+}
+
+impl FrameCursor {
+    pub fn write(&mut self, writer: &mut dyn Write, frame: &[u8]) -> Result<()> {
+        if self.written < self.pending.len() {
+            self.attempts += 1;
+            if self.attempts <= COMPLETION_ATTEMPTS {
+                if self.write_pending(writer)? {
+                    self.completed += 1;
+                }
+                return Ok(());
+            }
+            tracing::warn!(
+                "Gave up finishing a torn frame after {} bytes, resynchronizing the ALLS",
+                self.written
+            );
+            self.resynced += 1;
+        }
+        self.pending.clear();
+        self.pending.extend_from_slice(frame);
+        self.written = 0;
+        self.attempts = 0;
+        if !self.write_pending(writer)? && self.written == 0 {
+            // Nothing went out, so nothing is torn; the next frame will be newer
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    // Finishes a torn frame, if there is one, within the usual attempts
+    pub fn finish(&mut self, writer: &mut dyn Write) -> Result<()> {
+        while self.written < self.pending.len() && self.attempts < COMPLETION_ATTEMPTS {
+            self.attempts += 1;
+            if self.write_pending(writer)? {
+                self.completed += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the frame last started went out whole
+    fn sent(&self) -> bool {
+        !self.pending.is_empty() && self.written == self.pending.len()
+    }
+
+    // Whether the pending frame is now fully written. A timeout keeps the
+    // progress made so far for the next call.
+    fn write_pending(&mut self, writer: &mut dyn Write) -> Result<bool> {
+        while self.written < self.pending.len() {
+            match writer.write(&self.pending[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::TimedOut => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        match writer.flush() {
+            Err(err) if err.kind() == ErrorKind::TimedOut => {}
+            result => result?,
+        }
+        Ok(true)
+    }
+}
+
+// The ALLS side of one stream. Dropping it writes an all-clear frame, so
+// however the stream ends, on a HALT, an error or a panic, the game isn't
+// left holding whatever was last touched. The ALLS may be what
+// failed, so the frame is only attempted, within the cursor's usual
+// attempts, and errors are ignored.
+pub struct Finalizer<'a> {
+    writer: &'a mut (dyn Write + Send),
+    cursor: FrameCursor,
+    all_clear: Vec<u8>,
+    // Counts the all-clear frames that went out
+    cleared: &'a AtomicU64,
+}
+
+impl<'a> Finalizer<'a> {
+    pub fn new(
+        writer: &'a mut (dyn Write + Send),
+        all_clear: Vec<u8>,
+        cleared: &'a AtomicU64,
+    ) -> Self {
+        Finalizer {
+            writer,
+            cursor: FrameCursor::default(),
+            all_clear,
+            cleared,
+        }
+    }
+
+    pub fn write(&mut self, frame: &[u8]) -> Result<()> {
+        self.cursor.write(self.writer, frame)
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.cursor.finish(self.writer)
+    }
+
+    pub fn cursor(&self) -> &FrameCursor {
+        &self.cursor
+    }
+}
+
+impl Drop for Finalizer<'_> {
+    fn drop(&mut self) {
+        let all_clear = std::mem::take(&mut self.all_clear);
+        let written = self
+            .finish()
+            .and_then(|()| self.write(&all_clear))
+            .and_then(|()| self.finish());
+        // A frame nothing of went out is dropped by the cursor, which
+        // leaves nothing pending either
+        match written {
+            Ok(()) if self.cursor.sent() => {
+                self.cleared.fetch_add(1, Ordering::Relaxed);
+                tracing::info!("Sent the ALLS an all-clear frame on the way out");
+            }
+            Ok(()) => tracing::debug!("All-clear frame to the ALLS timed out"),
+            Err(err) => tracing::debug!("Couldn't send the ALLS an all-clear frame: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const INTERVAL: Duration = Duration::from_millis(1);
+
+    // Writes `count` frames that each take `cost` on the clock, returning
+    // when each one finished
+    fn write_frames(
+        paced: &mut PacedWriter<&MockClock>,
+        clock: &MockClock,
+        cost: Duration,
+        count: usize,
+    ) -> Vec<Instant> {
+        (0..count)
+            .map(|_| {
+                paced
+                    .write_frame(|| {
+                        clock.advance(cost);
+                        Ok(())
+                    })
+                    .unwrap();
+                clock.now()
+            })
+            .collect()
+    }
+
+    fn gaps(ends: &[Instant]) -> Vec<Duration> {
+        ends.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+
+    fn near(a: Duration, b: Duration) -> bool {
+        a.abs_diff(b) <= Duration::from_micros(5)
+    }
+
+    #[test]
+    fn wakes_early_by_the_write_cost() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut paced = PacedWriter::new(&clock, INTERVAL);
+        let cost = Duration::from_micros(400);
+        let ends = write_frames(&mut paced, &clock, cost, 300);
+        // The first frame lands a whole write late, later ones on time
+        assert_eq!(ends[0] - start, cost);
+        assert!(near(paced.write_cost(), cost));
+        for (n, end) in ends.iter().enumerate().skip(200) {
+            assert!(near(*end - start, INTERVAL * n as u32), "frame {}", n);
+        }
+        assert!(gaps(&ends).iter().all(|gap| *gap <= INTERVAL));
+    }
+
+    #[test]
+    fn write_cost_is_a_moving_average() {
+        let clock = MockClock::new();
+        let mut paced = PacedWriter::new(&clock, INTERVAL);
+        write_frames(&mut paced, &clock, Duration::from_micros(500), 1);
+        assert!(near(paced.write_cost(), Duration::from_micros(50)));
+        write_frames(&mut paced, &clock, Duration::from_micros(500), 1);
+        assert!(near(paced.write_cost(), Duration::from_micros(95)));
+        write_frames(&mut paced, &clock, Duration::ZERO, 1);
+        assert!(near(paced.write_cost(), Duration::from_micros(86)));
+    }
+
+    #[test]
+    fn a_slow_writer_widens_the_interval_and_a_fast_one_narrows_it_back() {
+        let clock = MockClock::new();
+        let mut paced = PacedWriter::new(&clock, INTERVAL);
+        let slow = write_frames(&mut paced, &clock, Duration::from_millis(3), 50);
+        // Behind every frame, the cadence restarts from the last write
+        // rather than bursting to catch up
+        for gap in gaps(&slow) {
+            assert_eq!(gap, Duration::from_millis(3));
+        }
+        assert!(paced.write_cost() > INTERVAL);
+        let fast = write_frames(&mut paced, &clock, Duration::from_micros(100), 300);
+        assert!(gaps(&fast)
+            .iter()
+            .all(|gap| *gap <= Duration::from_millis(3)));
+        for gap in &gaps(&fast)[200..] {
+            assert!(near(*gap, INTERVAL), "{:?}", gap);
+        }
+        assert!(near(paced.write_cost(), Duration::from_micros(100)));
+    }
+
+    #[test]
+    fn a_failed_write_gives_its_slot_to_the_next_frame() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut paced = PacedWriter::new(&clock, INTERVAL);
+        paced.write_frame(|| Ok(())).unwrap();
+        let failed = paced.write_frame(|| Err(ErrorKind::TimedOut.into()));
+        assert_eq!(failed.unwrap_err().kind(), ErrorKind::TimedOut);
+        // The failed frame slept to its slot, and the next one takes it over
+        paced.write_frame(|| Ok(())).unwrap();
+        assert_eq!(clock.since(start), INTERVAL);
+        paced.write_frame(|| Ok(())).unwrap();
+        assert_eq!(clock.since(start), INTERVAL * 2);
+    }
+
+    // An ALLS port that takes writes as scripted, then as `rest` does
+    #[derive(Clone, Copy)]
+    enum Step {
+        Take(usize),
+        Fail(ErrorKind),
+    }
+
+    struct Port {
+        wire: Vec<u8>,
+        script: std::collections::VecDeque<Step>,
+        rest: Step,
+        writes: usize,
+    }
+
+    impl Port {
+        fn new(script: &[Step], rest: Step) -> Self {
+            Port {
+                wire: Vec::new(),
+                script: script.iter().copied().collect(),
+                rest,
+                writes: 0,
+            }
+        }
+
+        fn accepting() -> Self {
+            Port::new(&[], Step::Take(usize::MAX))
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.writes += 1;
+            match self.script.pop_front().unwrap_or(self.rest) {
+                Step::Take(n) => {
+                    let n = n.min(buf.len());
+                    self.wire.extend_from_slice(&buf[..n]);
+                    Ok(n)
+                }
+                Step::Fail(kind) => Err(kind.into()),
+            }
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    const ALL_CLEAR: &[u8] = b"(\x00\x00\x00\x00\x00\x00\x00)";
+
+    // Drops a finalizer over `port` after `stream` has used it, returning
+    // how many all-clear frames it counted
+    fn finalize(port: &mut Port, stream: impl FnOnce(&mut Finalizer)) -> u64 {
+        let cleared = AtomicU64::new(0);
+        let mut finalizer = Finalizer::new(port, ALL_CLEAR.to_vec(), &cleared);
+        stream(&mut finalizer);
+        drop(finalizer);
+        cleared.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn an_armed_finalizer_clears_the_alls() {
+        let mut port = Port::accepting();
+        let cleared = finalize(&mut port, |finalizer| {
+            finalizer.write(TOUCH).unwrap();
+        });
+        assert_eq!(cleared, 1);
+        assert_eq!(port.wire, [TOUCH, ALL_CLEAR].concat());
+    }
+
+    #[test]
+    fn a_timed_out_all_clear_isnt_counted() {
+        let mut port = Port::new(&[], Step::Fail(ErrorKind::TimedOut));
+        assert_eq!(finalize(&mut port, |_| {}), 0);
+        assert!(port.wire.is_empty());
+        assert!(port.writes > 0);
+    }
+
+    #[test]
+    fn a_failed_all_clear_isnt_counted() {
+        let mut port = Port::new(&[], Step::Fail(ErrorKind::BrokenPipe));
+        assert_eq!(finalize(&mut port, |_| {}), 0);
+    }
+
+    #[test]
+    fn a_torn_frame_is_finished_before_the_all_clear() {
+        let mut port = Port::new(&[Step::Take(3)], Step::Take(usize::MAX));
+        let cleared = finalize(&mut port, |finalizer| {
+            finalizer.write(TOUCH).unwrap();
+        });
+        assert_eq!(cleared, 1);
+        assert_eq!(port.wire, [TOUCH, ALL_CLEAR].concat());
+    }
+
+    #[test]
+    fn a_panicking_stream_still_clears_the_alls() {
+        let mut port = Port::accepting();
+        let cleared = AtomicU64::new(0);
+        let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut finalizer = Finalizer::new(&mut port, ALL_CLEAR.to_vec(), &cleared);
+            finalizer.write(TOUCH).unwrap();
+            panic!("the stream died");
+        }));
+        assert!(unwound.is_err());
+        assert_eq!(cleared.load(Ordering::Relaxed), 1);
+        assert_eq!(port.wire, [TOUCH, ALL_CLEAR].concat());
+    }
+
+    const FRAME: &[u8] = b"(\x02\x00\x00\x00\x00\x00\x00)";
+
+    #[test]
+    fn whole_frames_go_straight_out() {
+        let mut port = Port::accepting();
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        cursor.write(&mut port, FRAME).unwrap();
+        assert_eq!(port.wire, [TOUCH, FRAME].concat());
+        assert_eq!((cursor.completed, cursor.resynced), (0, 0));
+    }
+
+    #[test]
+    fn short_writes_are_retried_within_a_call() {
+        let mut port = Port::new(
+            &[
+                Step::Take(2),
+                Step::Fail(ErrorKind::Interrupted),
+                Step::Take(3),
+            ],
+            Step::Take(usize::MAX),
+        );
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        assert_eq!(port.wire, TOUCH);
+        assert_eq!(port.writes, 4);
+    }
+
+    #[test]
+    fn a_torn_frame_is_finished_before_the_next_one_starts() {
+        let mut port = Port::new(
+            &[Step::Take(3), Step::Fail(ErrorKind::TimedOut)],
+            Step::Take(usize::MAX),
+        );
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        assert_eq!(port.wire, &TOUCH[..3]);
+        // The call that finishes the torn frame doesn't start FRAME, which
+        // is stale by the time the next one comes in
+        cursor.write(&mut port, FRAME).unwrap();
+        assert_eq!(port.wire, TOUCH);
+        assert_eq!(cursor.completed, 1);
+        cursor.write(&mut port, IDLE).unwrap();
+        assert_eq!(port.wire, [TOUCH, IDLE].concat());
+    }
+
+    #[test]
+    fn a_frame_that_times_out_untouched_is_dropped() {
+        let mut port = Port::new(&[Step::Fail(ErrorKind::TimedOut)], Step::Take(usize::MAX));
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        assert!(port.wire.is_empty());
+        // Nothing is torn, so the next frame goes out in full
+        cursor.write(&mut port, FRAME).unwrap();
+        assert_eq!(port.wire, FRAME);
+        assert_eq!((cursor.completed, cursor.resynced), (0, 0));
+    }
+
+    #[test]
+    fn a_frame_that_wont_finish_is_abandoned() {
+        let timeouts = [Step::Fail(ErrorKind::TimedOut); COMPLETION_ATTEMPTS as usize];
+        let script = [
+            &[Step::Take(3), Step::Fail(ErrorKind::TimedOut)],
+            &timeouts[..],
+        ]
+        .concat();
+        let mut port = Port::new(&script, Step::Take(usize::MAX));
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        for _ in 0..COMPLETION_ATTEMPTS {
+            cursor.write(&mut port, FRAME).unwrap();
+        }
+        assert_eq!(port.wire, &TOUCH[..3]);
+        // Past the attempts, a fresh frame starts; the ALLS resynchronizes
+        // on its open delimiter
+        cursor.write(&mut port, IDLE).unwrap();
+        assert_eq!(port.wire, [&TOUCH[..3], IDLE].concat());
+        assert_eq!((cursor.completed, cursor.resynced), (0, 1));
+    }
+
+    #[test]
+    fn finish_completes_a_torn_frame_within_the_attempts() {
+        let mut port = Port::new(
+            &[
+                Step::Take(3),
+                Step::Fail(ErrorKind::TimedOut),
+                Step::Fail(ErrorKind::TimedOut),
+            ],
+            Step::Take(usize::MAX),
+        );
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        cursor.finish(&mut port).unwrap();
+        assert_eq!(port.wire, TOUCH);
+        assert_eq!(cursor.completed, 1);
+
+        let mut port = Port::new(&[Step::Take(3)], Step::Fail(ErrorKind::TimedOut));
+        let mut cursor = FrameCursor::default();
+        cursor.write(&mut port, TOUCH).unwrap();
+        cursor.finish(&mut port).unwrap();
+        assert_eq!(port.wire, &TOUCH[..3]);
+        assert_eq!(port.writes, 2 + COMPLETION_ATTEMPTS as usize);
+        assert_eq!(cursor.completed, 0);
+    }
+
+    #[test]
+    fn write_errors_are_passed_on() {
+        let mut cursor = FrameCursor::default();
+        let mut port = Port::new(&[], Step::Fail(ErrorKind::BrokenPipe));
+        let err = cursor.write(&mut port, TOUCH).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        let mut port = Port::new(&[], Step::Take(0));
+        let err = cursor.write(&mut port, TOUCH).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+    }
+
+    const IDLE: &[u8] = b"(\x00\x00\x00\x00\x00\x00\x00)";
+    const TOUCH: &[u8] = b"(\x01\x00\x00\x00\x00\x00\x00)";
+
+    fn admitted(decimator: &mut Decimator, frames: &[&[u8]]) -> Vec<bool> {
+        frames.iter().map(|frame| decimator.admit(frame)).collect()
+    }
+
+    #[test]
+    fn unchanged_frames_are_thinned() {
+        let mut decimator = Decimator::new(3);
+        let got = admitted(&mut decimator, &[IDLE; 10]);
+        let expected = [true, false, false].iter().cycle().take(10);
+        assert!(got.iter().eq(expected), "{:?}", got);
+        assert_eq!((decimator.forwarded, decimator.skipped), (4, 6));
+    }
+
+    #[test]
+    fn a_changed_frame_bypasses_decimation() {
+        let mut decimator = Decimator::new(4);
+        let got = admitted(
+            &mut decimator,
+            &[IDLE, IDLE, TOUCH, TOUCH, IDLE, IDLE, IDLE, IDLE, IDLE],
+        );
+        // The press and the release go out as soon as they come in, and
+        // the count toward the next forwarded frame starts over from them
+        assert_eq!(
+            got,
+            [true, false, true, false, true, false, false, false, true]
+        );
+        assert_eq!((decimator.forwarded, decimator.skipped), (4, 5));
+    }
+
+    #[test]
+    fn a_change_back_within_the_window_still_goes_out() {
+        // A tap shorter than the decimation window: the release matches
+        // the frame before the press, but not the last one forwarded
+        let mut decimator = Decimator::new(10);
+        assert_eq!(
+            admitted(&mut decimator, &[IDLE, TOUCH, IDLE, IDLE]),
+            [true, true, true, false]
+        );
+    }
+}
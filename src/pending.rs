@@ -0,0 +1,191 @@
+use maitouch_protocol::command::{self, CommandKind};
+use maitouch_protocol::framing::PacketDelimiter;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+
+// Most commands held back at once; past that they wait in the port
+const MAX_PENDING: usize = 8;
+
+// Commands the game sent before it got the answer to an earlier one. They
+// are forwarded one at a time in the order they came, so each response
+// still goes back for the command it answers.
+pub struct PendingCommands {
+    queue: VecDeque<Vec<u8>>,
+    full_reported: bool,
+}
+
+impl PendingCommands {
+    pub fn new() -> Self {
+        PendingCommands {
+            queue: VecDeque::new(),
+            full_reported: false,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.queue.pop_front()
+    }
+
+    // Whether the game sent an RSET behind the command in flight, as one
+    // that restarted while it waited for an answer does. What was queued
+    // ahead of the RSET is dropped, returning how much, since the game that
+    // sent it is gone; the RSET and what follows stay.
+    pub fn restart(&mut self, packet: &PacketDelimiter) -> Option<usize> {
+        let reset = self
+            .queue
+            .iter()
+            .position(|command| command::classify(packet, command) == CommandKind::Reset)?;
+        self.queue.drain(..reset);
+        Some(reset)
+    }
+
+    // Moves the commands already read in behind `current` into the queue,
+    // without waiting on the port. Stops at anything that isn't a whole
+    // packet, so stray bytes are still found by the next read, and after a
+    // STAT, HALT or RSET, as what follows one of those is read in the mode
+    // it switches to.
+    pub fn take_buffered<R: Read>(
+        &mut self,
+        reader: &mut BufReader<R>,
+        packet: &PacketDelimiter,
+        current: &[u8],
+    ) {
+        if self
+            .queue
+            .back()
+            .is_some_and(|last| ends_config(packet, last))
+        {
+            return;
+        }
+        while let Some(len) = whole_packet(reader.buffer(), packet) {
+            if self.queue.len() >= MAX_PENDING {
+                if !std::mem::replace(&mut self.full_reported, true) {
+                    tracing::warn!(
+                        "{} commands from the game are already waiting, leaving the rest in the port",
+                        MAX_PENDING
+                    );
+                }
+                return;
+            }
+            let command = reader.buffer()[..len].to_vec();
+            reader.consume(len);
+            tracing::info!(
+                "Game sent {} before the answer to {}, queued ({} waiting)",
+                String::from_utf8_lossy(&command),
+                String::from_utf8_lossy(current),
+                self.queue.len() + 1
+            );
+            let last = ends_config(packet, &command);
+            self.queue.push_back(command);
+            if last {
+                return;
+            }
+        }
+        self.full_reported = false;
+    }
+}
+
+// Length of the packet at the start of `buffer`, if all of it is there
+fn whole_packet(buffer: &[u8], packet: &PacketDelimiter) -> Option<usize> {
+    if buffer.first() != Some(&(packet.open as u8)) {
+        return None;
+    }
+    let close = memchr::memchr(packet.close as u8, buffer)?;
+    Some(close + 1)
+}
+
+fn ends_config(packet: &PacketDelimiter, command: &[u8]) -> bool {
+    command::classify(packet, command) != CommandKind::Config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::WireSpec;
+
+    // What a BufReader over `bytes` has read in and left after `pending`
+    // took what it could
+    fn take(pending: &mut PendingCommands, bytes: &[u8]) -> Vec<u8> {
+        let mut reader = BufReader::new(bytes);
+        reader.fill_buf().unwrap();
+        pending.take_buffered(&mut reader, &WireSpec::maimai().alls, b"{LAr2}");
+        reader.buffer().to_vec()
+    }
+
+    fn queued(pending: &mut PendingCommands) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| pending.pop()).collect()
+    }
+
+    #[test]
+    fn whole_commands_are_queued_in_the_order_they_came() {
+        let mut pending = PendingCommands::new();
+        crate::logcapture::capturing(|log| {
+            // The rest of {RB is read in with whatever follows it
+            assert_eq!(take(&mut pending, b"{RAr2}{LBr2}{RB"), b"{RB");
+            assert_eq!(
+                log.take(),
+                [
+                    "Game sent {RAr2} before the answer to {LAr2}, queued (1 waiting)",
+                    "Game sent {LBr2} before the answer to {LAr2}, queued (2 waiting)",
+                ]
+            );
+        });
+        assert_eq!(queued(&mut pending), [b"{RAr2}", b"{LBr2}"]);
+        assert_eq!(pending.pop(), None);
+    }
+
+    #[test]
+    fn stray_bytes_stop_the_queue() {
+        let mut pending = PendingCommands::new();
+        assert_eq!(take(&mut pending, b"{RAr2}x{LBr2}"), b"x{LBr2}");
+        assert_eq!(queued(&mut pending), [b"{RAr2}"]);
+    }
+
+    #[test]
+    fn nothing_is_queued_past_a_mode_switch() {
+        let mut pending = PendingCommands::new();
+        assert_eq!(take(&mut pending, b"{RAr2}{STAT}{LBr2}"), b"{LBr2}");
+        // And not on a later call either, while the STAT is still queued
+        assert_eq!(take(&mut pending, b"{LBr2}"), b"{LBr2}");
+        assert_eq!(queued(&mut pending), [b"{RAr2}", b"{STAT}"]);
+        assert_eq!(take(&mut pending, b"{LBr2}"), b"");
+        assert_eq!(queued(&mut pending), [b"{LBr2}"]);
+    }
+
+    #[test]
+    fn the_queue_is_bounded_and_says_so_once() {
+        let mut pending = PendingCommands::new();
+        let commands = b"{RAr2}".repeat(MAX_PENDING + 2);
+        crate::logcapture::capturing(|log| {
+            let left = take(&mut pending, &commands);
+            assert_eq!(left, b"{RAr2}{RAr2}");
+            assert_eq!(take(&mut pending, &left), left);
+            let warnings: Vec<String> = log
+                .take()
+                .into_iter()
+                .filter(|line| !line.starts_with("Game sent"))
+                .collect();
+            assert_eq!(
+                warnings,
+                ["8 commands from the game are already waiting, leaving the rest in the port"]
+            );
+        });
+        assert_eq!(queued(&mut pending).len(), MAX_PENDING);
+        // Room again, and the warning is back once the queue has drained
+        assert_eq!(take(&mut pending, b"{RAr2}"), b"");
+        assert!(!pending.full_reported);
+    }
+
+    #[test]
+    fn a_restart_drops_what_came_before_the_rset() {
+        let alls = WireSpec::maimai().alls;
+        let mut pending = PendingCommands::new();
+        take(&mut pending, b"{RAr2}{LBr2}{RSET}");
+        assert_eq!(pending.restart(&alls), Some(2));
+        assert_eq!(queued(&mut pending), [b"{RSET}"]);
+
+        take(&mut pending, b"{RAr2}{LBr2}");
+        assert_eq!(pending.restart(&alls), None);
+        assert_eq!(queued(&mut pending).len(), 2);
+    }
+}
@@ -0,0 +1,633 @@
+use anyhow::Result;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use crate::pty::Pty;
+#[cfg(unix)]
+use std::path::Path;
+
+pub const PORT_TIMEOUT: Duration = Duration::from_secs(1);
+// ADX read timeout while streaming, which bounds how long HALT waits on the reader
+pub const STREAM_TIMEOUT: Duration = Duration::from_millis(10);
+// Both sides talk at this rate unless --upgrade-baud switches the ADX
+pub const DEFAULT_BAUD: u32 = 9600;
+// How often a read on a shared handle checks for input, letting writes in between
+const SHARED_POLL: Duration = Duration::from_millis(1);
+
+// ALLS name that talks over the proxy's own stdin/stdout instead of a port
+pub const STDIO: &str = "stdio";
+// Port name prefix that has the proxy create a PTY pair
+pub const PTY_PREFIX: &str = "pty:";
+
+// The kinds of port the proxy can be pointed at, told apart by the name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Serial,
+    Pty,
+    Stdio,
+    TcpListen,
+}
+
+impl Transport {
+    pub const ALL: &'static [Transport] = &[
+        Transport::Serial,
+        Transport::Pty,
+        Transport::Stdio,
+        Transport::TcpListen,
+    ];
+
+    pub fn of(name: &str) -> Self {
+        if name == STDIO {
+            Transport::Stdio
+        } else if name.starts_with(PTY_PREFIX) {
+            Transport::Pty
+        } else if name.starts_with(crate::tcp::LISTEN_PREFIX) {
+            Transport::TcpListen
+        } else {
+            Transport::Serial
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Serial => "serial",
+            Transport::Pty => "pty",
+            Transport::Stdio => "stdio",
+            Transport::TcpListen => "tcp-listen",
+        }
+    }
+
+    // How a port name of this kind is written
+    pub fn form(self) -> &'static str {
+        match self {
+            Transport::Serial => "<port>",
+            Transport::Pty => "pty:[link]",
+            Transport::Stdio => STDIO,
+            Transport::TcpListen => "tcp-listen://<addr:port>",
+        }
+    }
+
+    // Whether the ADX can be reached this way, rather than only the ALLS
+    pub fn reaches_adx(self) -> bool {
+        matches!(self, Transport::Serial | Transport::Pty)
+    }
+
+    // Whether this build can open it
+    pub fn available(self) -> bool {
+        self != Transport::Pty || cfg!(unix)
+    }
+
+    // Whether it's a port the OS already has, rather than one the proxy
+    // makes for itself
+    pub fn is_device(self) -> bool {
+        self == Transport::Serial
+    }
+}
+
+pub struct OpenPort {
+    pub port: Box<dyn SerialPort>,
+    #[cfg(unix)]
+    _pty: Option<Pty>,
+}
+
+// The ALLS side only needs a byte stream each way, so it can also be stdio
+pub struct Endpoint {
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+    // A third handle to a serial port, for reading its modem lines. Only
+    // opened where something reads them.
+    pub modem: Option<Box<dyn SerialPort>>,
+    #[cfg(unix)]
+    _pty: Option<Pty>,
+}
+
+impl Endpoint {
+    pub fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
+        Endpoint {
+            reader,
+            writer,
+            modem: None,
+            #[cfg(unix)]
+            _pty: None,
+        }
+    }
+}
+
+impl Read for Endpoint {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+pub fn open_endpoint(name: &str) -> Result<Endpoint> {
+    if Transport::of(name) == Transport::Stdio {
+        return Ok(Endpoint::new(
+            Box::new(StdinReader::spawn()),
+            Box::new(io::stdout()),
+        ));
+    }
+    let mut port = open(name)?;
+    // The reader probe only has the DSR line to go on on Windows
+    let modem = (cfg!(windows) && Transport::of(name).is_device())
+        .then(|| port.port.try_clone().ok())
+        .flatten();
+    let writer;
+    (port.port, writer) = duplex(name, port.port);
+    Ok(Endpoint {
+        reader: Box::new(port.port),
+        writer: Box::new(writer),
+        modem,
+        #[cfg(unix)]
+        _pty: port._pty,
+    })
+}
+
+// Stdin has no read timeout, so a thread does the blocking reads and this
+// end times out like a serial port would
+struct StdinReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+}
+
+impl StdinReader {
+    fn spawn() -> Self {
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buf = [0u8; 256];
+            loop {
+                let chunk = match stdin.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                if sender.send(chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        StdinReader {
+            chunks,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Read for StdinReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.pending = match self.chunks.recv_timeout(PORT_TIMEOUT) {
+                Ok(chunk) => chunk?,
+                Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Err(ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+// Opens a serial port by name. `pty:` or `pty:<link>` creates a PTY pair
+// owned by the proxy instead and hands the slave path to whoever needs it.
+pub fn open(name: &str) -> Result<OpenPort> {
+    if let Some(link) = name.strip_prefix(PTY_PREFIX) {
+        #[cfg(unix)]
+        {
+            let (mut master, pty) = Pty::create((!link.is_empty()).then(|| Path::new(link)))?;
+            master.set_timeout(PORT_TIMEOUT)?;
+            tracing::info!("Created PTY {}", pty.display_path());
+            return Ok(OpenPort {
+                port: Box::new(master),
+                _pty: Some(pty),
+            });
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = link;
+            anyhow::bail!("pty: ports are only supported on Unix");
+        }
+    }
+    let port = serialport::new(name, DEFAULT_BAUD)
+        .timeout(PORT_TIMEOUT)
+        .open()?;
+    Ok(OpenPort {
+        port,
+        #[cfg(unix)]
+        _pty: None,
+    })
+}
+
+// Splits off a second handle to `port`, so one thread can read while
+// another writes. Some drivers can't clone a handle; then both halves
+// share the one handle and take turns (half duplex).
+pub fn duplex(name: &str, port: Box<dyn SerialPort>) -> (Box<dyn SerialPort>, Box<dyn SerialPort>) {
+    match port.try_clone() {
+        Ok(clone) => (port, clone),
+        Err(err) => {
+            tracing::warn!(
+                "!!! Couldn't clone the handle to {} ({}), running it half duplex on one shared handle",
+                name,
+                err
+            );
+            let shared = SharedPort(Arc::new(Mutex::new(port)));
+            (Box::new(shared.clone()), Box::new(shared))
+        }
+    }
+}
+
+// One port handle used from several threads. A read only takes the lock
+// once input is waiting, so a writer is never held up for a whole read
+// timeout; waiting reads poll instead, honouring the port's timeout.
+#[derive(Clone)]
+struct SharedPort(Arc<Mutex<Box<dyn SerialPort>>>);
+
+impl SharedPort {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Box<dyn SerialPort>> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Read for SharedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout();
+        loop {
+            {
+                let mut port = self.lock();
+                if port.bytes_to_read()? > 0 {
+                    return port.read(buf);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            thread::sleep(SHARED_POLL);
+        }
+    }
+}
+
+impl Write for SharedPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl SerialPort for SharedPort {
+    fn name(&self) -> Option<String> {
+        self.lock().name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.lock().baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        self.lock().data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        self.lock().flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        self.lock().parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        self.lock().stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.lock().timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.lock().set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.lock().set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.lock().set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.lock().set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.lock().set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.lock().set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.lock().write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.lock().write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.lock().read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.lock().read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.lock().read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.lock().read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.lock().bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.lock().bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        self.lock().clear(buffer_to_clear)
+    }
+
+    // Another turn-taking handle, since the driver can't make a real one
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.lock().set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.lock().clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // A port in memory whose handle can't be cloned, like the drivers
+    // duplex falls back for
+    struct Unclonable {
+        line: Arc<Mutex<Line>>,
+        timeout: Duration,
+    }
+
+    #[derive(Default)]
+    struct Line {
+        incoming: VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    fn unclonable() -> (Box<dyn SerialPort>, Arc<Mutex<Line>>) {
+        let line = Arc::new(Mutex::new(Line::default()));
+        let port = Unclonable {
+            line: line.clone(),
+            timeout: PORT_TIMEOUT,
+        };
+        (Box::new(port), line)
+    }
+
+    impl Read for Unclonable {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut line = self.line.lock().unwrap();
+            if line.incoming.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let read = buf.len().min(line.incoming.len());
+            for (slot, byte) in buf.iter_mut().zip(line.incoming.drain(..read)) {
+                *slot = byte;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for Unclonable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.line.lock().unwrap().written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialPort for Unclonable {
+        fn name(&self) -> Option<String> {
+            Some("COM3".into())
+        }
+
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(DEFAULT_BAUD)
+        }
+
+        fn data_bits(&self) -> serialport::Result<DataBits> {
+            Ok(DataBits::Eight)
+        }
+
+        fn flow_control(&self) -> serialport::Result<FlowControl> {
+            Ok(FlowControl::None)
+        }
+
+        fn parity(&self) -> serialport::Result<Parity> {
+            Ok(Parity::None)
+        }
+
+        fn stop_bits(&self) -> serialport::Result<StopBits> {
+            Ok(StopBits::One)
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            Ok(self.line.lock().unwrap().incoming.len() as u32)
+        }
+
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+
+        fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+            Err(serialport::Error::new(
+                serialport::ErrorKind::Io(ErrorKind::Unsupported),
+                "cloning not supported",
+            ))
+        }
+
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn an_unclonable_port_is_shared_half_duplex() {
+        let (port, line) = unclonable();
+        crate::logcapture::capturing(|log| {
+            let (mut reader, mut writer) = duplex("COM3", port);
+            assert_eq!(
+                log.take(),
+                [
+                    "!!! Couldn't clone the handle to COM3 (cloning not supported), running it \
+                  half duplex on one shared handle"
+                ]
+            );
+            writer.write_all(b"(frame)").unwrap();
+            line.lock().unwrap().incoming.extend(b"{HALT}");
+            let mut buf = [0u8; 16];
+            let read = reader.read(&mut buf).unwrap();
+            assert_eq!(&buf[..read], b"{HALT}");
+            // Settings made through either half are the one port's
+            writer.set_timeout(STREAM_TIMEOUT).unwrap();
+            assert_eq!(reader.timeout(), STREAM_TIMEOUT);
+        });
+        assert_eq!(line.lock().unwrap().written, b"(frame)");
+    }
+
+    #[test]
+    fn a_waiting_read_doesnt_hold_up_writes() {
+        let (port, line) = unclonable();
+        let (mut reader, mut writer) = duplex("COM3", port);
+        let timeout = Duration::from_millis(300);
+        reader.set_timeout(timeout).unwrap();
+        thread::scope(|scope| {
+            let read = scope.spawn(move || {
+                let started = Instant::now();
+                let mut buf = [0u8; 16];
+                let err = reader.read(&mut buf).unwrap_err();
+                (err.kind(), started.elapsed())
+            });
+            let started = Instant::now();
+            for _ in 0..50 {
+                writer.write_all(b"(frame)").unwrap();
+            }
+            // Each write gets in between the read's polls
+            assert!(started.elapsed() < timeout / 2, "{:?}", started.elapsed());
+            let (kind, waited) = read.join().unwrap();
+            assert_eq!(kind, ErrorKind::TimedOut);
+            assert!(waited >= timeout, "{:?}", waited);
+        });
+        assert_eq!(line.lock().unwrap().written.len(), 50 * b"(frame)".len());
+    }
+
+    #[test]
+    fn halt_is_noticed_promptly_while_frames_stream_on_a_shared_handle() {
+        let (port, line) = unclonable();
+        let (mut reader, mut writer) = duplex("COM3", port);
+        reader.set_timeout(STREAM_TIMEOUT).unwrap();
+        let streaming = AtomicBool::new(true);
+        let noticed = thread::scope(|scope| {
+            scope.spawn(|| {
+                while streaming.load(Ordering::Relaxed) {
+                    writer.write_all(b"(frame)").unwrap();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let watcher = scope.spawn(move || {
+                let mut buf = [0u8; 16];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(read) => {
+                            assert_eq!(&buf[..read], b"{HALT}");
+                            return Instant::now();
+                        }
+                        Err(err) => assert_eq!(err.kind(), ErrorKind::TimedOut),
+                    }
+                }
+            });
+            thread::sleep(Duration::from_millis(100));
+            let sent = Instant::now();
+            line.lock().unwrap().incoming.extend(b"{HALT}");
+            let noticed = watcher.join().unwrap() - sent;
+            streaming.store(false, Ordering::Relaxed);
+            noticed
+        });
+        // A poll and a write at most, with plenty of slack for a busy machine
+        assert!(noticed < Duration::from_millis(50), "{:?}", noticed);
+    }
+}
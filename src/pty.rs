@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use serialport::{SerialPort, TTYPort};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// Keeps the slave end of a PTY pair open (so the master doesn't see EIO
+// while nobody else has it open) and removes the symlink on drop.
+pub struct Pty {
+    slave: TTYPort,
+    link: Option<PathBuf>,
+}
+
+impl Pty {
+    pub fn create(link: Option<&Path>) -> Result<(TTYPort, Pty)> {
+        let (master, slave) = TTYPort::pair().context("creating PTY pair")?;
+        let pty = Pty { slave, link: None };
+        let pty = match link {
+            Some(link) => pty.link_to(link)?,
+            None => pty,
+        };
+        Ok((master, pty))
+    }
+
+    pub fn path(&self) -> String {
+        self.slave.name().unwrap_or_default()
+    }
+
+    // The stable name if one was requested, otherwise the /dev/pts path
+    pub fn display_path(&self) -> String {
+        match &self.link {
+            Some(link) => format!("{} -> {}", link.display(), self.path()),
+            None => self.path(),
+        }
+    }
+
+    fn link_to(mut self, link: &Path) -> Result<Self> {
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(link)
+                .with_context(|| format!("removing old link {}", link.display()))?;
+        }
+        symlink(self.path(), link).with_context(|| format!("linking {}", link.display()))?;
+        self.link = Some(link.to_path_buf());
+        Ok(self)
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
+
+// Two PTY pairs with their masters cross-connected, the Linux stand-in for
+// a com0com pair. Runs until one side fails.
+pub fn bridge(game_link: Option<&Path>, proxy_link: Option<&Path>) -> Result<()> {
+    let (game_master, game) = Pty::create(game_link)?;
+    let (proxy_master, proxy) = Pty::create(proxy_link)?;
+    println!("Game side:  {}", game.display_path());
+    println!("Proxy side: {}", proxy.display_path());
+    println!("Pass the game side to the game and the proxy side to --alls. Ctrl+C to stop.");
+
+    let game_reader = game_master.try_clone_native()?;
+    let proxy_reader = proxy_master.try_clone_native()?;
+    thread::scope(|scope| {
+        let to_proxy = scope.spawn(move || relay(game_reader, proxy_master));
+        let to_game = scope.spawn(move || relay(proxy_reader, game_master));
+        to_proxy.join().unwrap().and(to_game.join().unwrap())
+    })
+}
+
+fn relay(mut from: TTYPort, mut to: TTYPort) -> Result<()> {
+    let mut buf = [0u8; 256];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                to.write_all(&buf[..n])?;
+                to.flush()?;
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn link_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("maitouch-pty-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn a_link_replaces_a_stale_one_and_goes_with_the_pty() {
+        let link = link_path("link");
+        std::fs::write(&link, "stale").unwrap();
+        let (_master, pty) = Pty::create(Some(&link)).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new(&pty.path()));
+        assert_eq!(
+            pty.display_path(),
+            format!("{} -> {}", link.display(), pty.path())
+        );
+        drop(pty);
+        assert!(link.symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn a_relay_carries_one_side_to_the_other_until_it_hangs_up() {
+        let (from_master, from) = Pty::create(None).unwrap();
+        let (to_master, to) = Pty::create(None).unwrap();
+        assert_eq!(from.display_path(), from.path());
+        let relay = thread::spawn(move || relay(from_master, to_master));
+
+        let mut game = serialport::new(from.path(), 115_200).open_native().unwrap();
+        let mut proxy = serialport::new(to.path(), 115_200).open_native().unwrap();
+        proxy.set_timeout(Duration::from_secs(5)).unwrap();
+        game.write_all(b"{STAT}").unwrap();
+        let mut got = [0u8; 6];
+        proxy.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"{STAT}");
+
+        // With every slave handle gone, the master reads EIO
+        drop((game, from));
+        assert!(relay.join().unwrap().is_err());
+    }
+}
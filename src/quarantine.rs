@@ -0,0 +1,253 @@
+use crate::clock::{Clock, MonotonicClock};
+use crate::filter::Filter;
+use crate::report::SessionReport;
+use maitouch_protocol::touch::{Region, TouchState};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Regions that changed between two states
+pub fn transitions(before: TouchState, after: TouchState) -> u32 {
+    Region::all()
+        .filter(|&region| before.is_active(region) != after.is_active(region))
+        .count() as u32
+}
+
+// --max-transitions-per-sec: how many presses and releases a second a touch
+// source may make before it's taken for broken, and for how long it's then
+// shut out
+#[derive(Clone, Copy, Debug)]
+pub struct TransitionLimit {
+    pub per_sec: u32,
+    pub cooldown: Duration,
+}
+
+// Keeps a touch source to its TransitionLimit with a token bucket that
+// holds up to a second's worth of transitions, so a source may burst but
+// not keep it up. One that runs the bucket dry is quarantined: it is told
+// to release everything and stays shut out until the cooldown has passed,
+// when it starts again with a full bucket.
+pub struct TransitionGuard<C: Clock> {
+    clock: C,
+    source: &'static str,
+    limit: TransitionLimit,
+    report: Arc<SessionReport>,
+    tokens: f64,
+    refilled: Instant,
+    quarantined_until: Option<Instant>,
+}
+
+impl<C: Clock> TransitionGuard<C> {
+    pub fn new(
+        clock: C,
+        source: &'static str,
+        limit: TransitionLimit,
+        report: Arc<SessionReport>,
+    ) -> Self {
+        let refilled = clock.now();
+        TransitionGuard {
+            clock,
+            source,
+            limit,
+            report,
+            tokens: limit.per_sec as f64,
+            refilled,
+            quarantined_until: None,
+        }
+    }
+
+    // Whether a change of `count` transitions may go through. False while
+    // the source is quarantined, including for the change that trips it.
+    pub fn admit(&mut self, count: u32) -> bool {
+        let now = self.clock.now();
+        if let Some(until) = self.quarantined_until {
+            if now < until {
+                return false;
+            }
+            self.quarantined_until = None;
+            self.tokens = self.limit.per_sec as f64;
+            self.refilled = now;
+            tracing::info!("Letting {} back in after its quarantine", self.source);
+        }
+        let capacity = self.limit.per_sec as f64;
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity).min(capacity);
+        self.refilled = now;
+        if self.tokens >= count as f64 {
+            self.tokens -= count as f64;
+            return true;
+        }
+        self.quarantined_until = Some(now + self.limit.cooldown);
+        self.report.quarantines.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "!!! Quarantining {}: more than {} touch transitions a second, so its touches are \
+             released and it's ignored for {:?}",
+            self.source,
+            self.limit.per_sec,
+            self.limit.cooldown
+        );
+        false
+    }
+}
+
+// Shuts out the board itself when it floods, for --limit-adx-transitions:
+// while it's quarantined, the game sees nothing touched
+pub struct AdxGuard {
+    guard: TransitionGuard<MonotonicClock>,
+    last: TouchState,
+}
+
+impl AdxGuard {
+    pub fn new(limit: TransitionLimit, report: Arc<SessionReport>) -> Self {
+        AdxGuard {
+            guard: TransitionGuard::new(MonotonicClock, "the ADX", limit, report),
+            last: TouchState::default(),
+        }
+    }
+}
+
+impl Filter for AdxGuard {
+    fn apply(&mut self, state: TouchState) -> TouchState {
+        let count = transitions(self.last, state);
+        self.last = state;
+        if self.guard.admit(count) {
+            state
+        } else {
+            TouchState::default()
+        }
+    }
+
+    // The fresh stream starts from nothing touched; a quarantine in force
+    // still runs its course
+    fn reset(&mut self) {
+        self.last = TouchState::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn touched(names: &[&str]) -> TouchState {
+        let mut state = TouchState::default();
+        for name in names {
+            state.set(name.parse().unwrap(), true);
+        }
+        state
+    }
+
+    fn guard<'a>(
+        clock: &'a MockClock,
+        report: &Arc<SessionReport>,
+    ) -> TransitionGuard<&'a MockClock> {
+        let limit = TransitionLimit {
+            per_sec: 10,
+            cooldown: Duration::from_secs(5),
+        };
+        TransitionGuard::new(clock, "the test source", limit, report.clone())
+    }
+
+    #[test]
+    fn counts_the_regions_that_changed() {
+        assert_eq!(transitions(TouchState::default(), TouchState::default()), 0);
+        assert_eq!(
+            transitions(touched(&["A1", "B2"]), touched(&["B2", "C1"])),
+            2
+        );
+        assert_eq!(
+            transitions(TouchState::default(), touched(&["A1", "E8", "C1"])),
+            3
+        );
+    }
+
+    #[test]
+    fn a_burst_up_to_the_limit_goes_through() {
+        let clock = MockClock::new();
+        let report = Arc::new(SessionReport::new());
+        let mut guard = guard(&clock, &report);
+        assert!(guard.admit(6));
+        assert!(guard.admit(4));
+        assert_eq!(report.quarantines.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn a_flood_trips_the_quarantine() {
+        let clock = MockClock::new();
+        let report = Arc::new(SessionReport::new());
+        let mut guard = guard(&clock, &report);
+        crate::logcapture::capturing(|log| {
+            assert!(guard.admit(10));
+            assert!(!guard.admit(1));
+            assert_eq!(
+                log.take(),
+                [
+                    "!!! Quarantining the test source: more than 10 touch transitions a second, \
+                  so its touches are released and it's ignored for 5s"
+                ]
+            );
+        });
+        assert_eq!(report.quarantines.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_quarantined_source_is_shut_out_until_the_cooldown_is_over() {
+        let clock = MockClock::new();
+        let report = Arc::new(SessionReport::new());
+        let mut guard = guard(&clock, &report);
+        assert!(!guard.admit(11));
+        // Even a change that would fit, and long enough to refill the bucket
+        clock.advance(Duration::from_millis(4999));
+        assert!(!guard.admit(0));
+        assert!(!guard.admit(1));
+        crate::logcapture::capturing(|log| {
+            clock.advance(Duration::from_millis(1));
+            // Back with a full bucket
+            assert!(guard.admit(10));
+            assert_eq!(
+                log.take(),
+                ["Letting the test source back in after its quarantine"]
+            );
+        });
+        assert!(!guard.admit(1));
+        assert_eq!(report.quarantines.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn the_bucket_refills_at_the_limit_and_no_further() {
+        let clock = MockClock::new();
+        let report = Arc::new(SessionReport::new());
+        let mut guard = guard(&clock, &report);
+        assert!(guard.admit(10));
+        clock.advance(Duration::from_millis(500));
+        assert!(guard.admit(5));
+        assert!(!guard.admit(1));
+
+        let mut guard = self::guard(&clock, &report);
+        // A quiet minute still only holds a second's worth
+        clock.advance(Duration::from_secs(60));
+        assert!(!guard.admit(11));
+    }
+
+    #[test]
+    fn a_flooding_board_reads_as_nothing_touched_while_quarantined() {
+        let limit = TransitionLimit {
+            per_sec: 4,
+            cooldown: Duration::from_secs(3600),
+        };
+        let report = Arc::new(SessionReport::new());
+        let mut guard = AdxGuard::new(limit, report.clone());
+        assert_eq!(guard.apply(touched(&["A1", "A2"])), touched(&["A1", "A2"]));
+        assert_eq!(
+            guard.apply(touched(&["A1", "A2", "B1"])),
+            touched(&["A1", "A2", "B1"])
+        );
+        // Two more than the bucket has left
+        assert_eq!(guard.apply(touched(&["B2", "B3"])), TouchState::default());
+        // A frame with nothing changed gets no further, nor does a new stream
+        assert_eq!(guard.apply(touched(&["B2", "B3"])), TouchState::default());
+        guard.reset();
+        assert_eq!(guard.apply(touched(&["C1"])), TouchState::default());
+        assert_eq!(report.quarantines.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Rates ADX firmwares stream at, besides as fast as the line allows
+const KNOWN_RATES: &[f64] = &[250.0, 500.0, 1000.0];
+// How far off the nearest expected rate still counts as on rate
+const TOLERANCE: f64 = 0.2;
+// Frames the rate is measured over
+const WINDOW: Duration = Duration::from_secs(2);
+// When the rate is first judged, once the stream has settled
+const FIRST_CHECK: Duration = Duration::from_secs(3);
+const RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Start, data and stop bits per byte on the wire
+const BITS_PER_BYTE: f64 = 10.0;
+
+// Frames per second over a sliding window of arrival times
+pub struct RateEstimator {
+    window: Duration,
+    arrivals: VecDeque<Instant>,
+}
+
+impl RateEstimator {
+    pub fn new(window: Duration) -> Self {
+        RateEstimator {
+            window,
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.arrivals.push_back(now);
+        while self
+            .arrivals
+            .front()
+            .is_some_and(|&first| now - first > self.window)
+        {
+            self.arrivals.pop_front();
+        }
+    }
+
+    // None until there are two frames to measure between
+    pub fn rate(&self) -> Option<f64> {
+        let span = *self.arrivals.back()? - *self.arrivals.front()?;
+        if span.is_zero() {
+            return None;
+        }
+        Some((self.arrivals.len() - 1) as f64 / span.as_secs_f64())
+    }
+}
+
+// Warns when the ADX streams well off the rate its firmware should, as a
+// debug build or a struggling USB adapter would. Checked a few seconds in,
+// then periodically for the rest of the session.
+pub struct RateMonitor {
+    estimator: RateEstimator,
+    expected: Vec<f64>,
+    next_check: Option<Instant>,
+    off_rate: bool,
+    // Times the rate was found off during the session
+    pub deviations: u64,
+}
+
+impl RateMonitor {
+    // With no expected rate, any known firmware rate or the most the line
+    // can carry at `baud` is accepted
+    pub fn new(expected: Option<u32>, baud: u32, frame_len: usize) -> Self {
+        let expected = match expected {
+            Some(rate) => vec![rate as f64],
+            None => {
+                let line_max = baud as f64 / (BITS_PER_BYTE * frame_len as f64);
+                KNOWN_RATES.iter().copied().chain([line_max]).collect()
+            }
+        };
+        RateMonitor {
+            estimator: RateEstimator::new(WINDOW),
+            expected,
+            next_check: None,
+            off_rate: false,
+            deviations: 0,
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.estimator.record(now);
+        let next_check = *self.next_check.get_or_insert(now + FIRST_CHECK);
+        if now < next_check {
+            return;
+        }
+        self.next_check = Some(now + RECHECK_INTERVAL);
+        let Some(rate) = self.estimator.rate() else {
+            return;
+        };
+        // Nothing to hold the rate to
+        let Some((nearest, deviation)) = self
+            .expected
+            .iter()
+            .map(|&expected| (expected, (rate - expected).abs() / expected))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+        else {
+            return;
+        };
+        let off_rate = deviation > TOLERANCE;
+        if off_rate && !self.off_rate {
+            self.deviations += 1;
+            tracing::warn!(
+                "!!! ADX is streaming at {:.0}Hz, {:.0}% off the expected {:.0}Hz; check the \
+                 firmware build and USB adapter",
+                rate,
+                deviation * 100.0,
+                nearest
+            );
+        } else if !off_rate && self.off_rate {
+            tracing::info!("ADX frame rate back to {:.0}Hz", rate);
+        } else if !off_rate {
+            tracing::debug!("ADX streaming at {:.0}Hz", rate);
+        }
+        self.off_rate = off_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records a frame every `every` for `until`, from `start`, returning
+    // when the next one would have come
+    fn frames(
+        monitor: &mut RateMonitor,
+        start: Instant,
+        every: Duration,
+        until: Duration,
+    ) -> Instant {
+        let mut now = start;
+        while now < start + until {
+            monitor.record(now);
+            now += every;
+        }
+        now
+    }
+
+    #[test]
+    fn no_rate_until_there_is_a_span_to_measure() {
+        let mut estimator = RateEstimator::new(WINDOW);
+        assert_eq!(estimator.rate(), None);
+        let start = Instant::now();
+        estimator.record(start);
+        assert_eq!(estimator.rate(), None);
+        // Two frames read at the same instant span nothing either
+        estimator.record(start);
+        assert_eq!(estimator.rate(), None);
+    }
+
+    #[test]
+    fn measures_frames_per_second() {
+        let mut estimator = RateEstimator::new(WINDOW);
+        let start = Instant::now();
+        for i in 0..=100 {
+            estimator.record(start + Duration::from_millis(2) * i);
+        }
+        let rate = estimator.rate().unwrap();
+        assert!((rate - 500.0).abs() < 1e-6, "{}", rate);
+    }
+
+    #[test]
+    fn only_the_window_is_measured() {
+        let mut estimator = RateEstimator::new(Duration::from_secs(1));
+        let start = Instant::now();
+        // A fast burst, then a slower rate for longer than the window
+        for i in 0..1000 {
+            estimator.record(start + Duration::from_micros(100) * i);
+        }
+        let slow = start + Duration::from_millis(100);
+        for i in 0..=2000 {
+            estimator.record(slow + Duration::from_millis(1) * i);
+        }
+        let rate = estimator.rate().unwrap();
+        assert!((rate - 1000.0).abs() < 1e-6, "{}", rate);
+    }
+
+    #[test]
+    fn the_rate_is_first_judged_once_the_stream_settles() {
+        let mut monitor = RateMonitor::new(Some(1000), 115_200, 9);
+        let start = Instant::now();
+        let now = frames(&mut monitor, start, Duration::from_millis(2), FIRST_CHECK);
+        assert_eq!(monitor.deviations, 0);
+        // The frame that lands on it is the first to be judged
+        assert_eq!(now, start + FIRST_CHECK);
+        monitor.record(now);
+        assert_eq!(monitor.deviations, 1);
+    }
+
+    #[test]
+    fn an_off_rate_spell_is_counted_once() {
+        let mut monitor = RateMonitor::new(Some(1000), 115_200, 9);
+        let slow = Duration::from_millis(2);
+        let on_rate = Duration::from_millis(1);
+        let now = frames(&mut monitor, Instant::now(), slow, FIRST_CHECK * 10);
+        assert_eq!(monitor.deviations, 1);
+        // Back on rate, and off again
+        let now = frames(&mut monitor, now, on_rate, RECHECK_INTERVAL * 2);
+        assert_eq!(monitor.deviations, 1);
+        assert!(!monitor.off_rate);
+        frames(&mut monitor, now, slow, RECHECK_INTERVAL * 2);
+        assert_eq!(monitor.deviations, 2);
+    }
+
+    #[test]
+    fn within_tolerance_is_on_rate() {
+        let mut monitor = RateMonitor::new(Some(1000), 115_200, 9);
+        // 833Hz, not quite 17% under, is on rate; 1250Hz, 25% over, isn't
+        let now = frames(
+            &mut monitor,
+            Instant::now(),
+            Duration::from_micros(1200),
+            FIRST_CHECK * 2,
+        );
+        assert_eq!(monitor.deviations, 0);
+        frames(
+            &mut monitor,
+            now,
+            Duration::from_micros(800),
+            RECHECK_INTERVAL * 2,
+        );
+        assert_eq!(monitor.deviations, 1);
+    }
+
+    #[test]
+    fn without_an_expected_rate_any_firmware_rate_will_do() {
+        // 115200 baud carries 1280 9-byte frames a second
+        for every in [4000, 2000, 1000, 781] {
+            let mut monitor = RateMonitor::new(None, 115_200, 9);
+            let every = Duration::from_micros(every);
+            frames(&mut monitor, Instant::now(), every, FIRST_CHECK * 2);
+            assert_eq!(monitor.deviations, 0, "{:?}", every);
+        }
+        // 700Hz is nowhere near any of them
+        let mut monitor = RateMonitor::new(None, 115_200, 9);
+        frames(
+            &mut monitor,
+            Instant::now(),
+            Duration::from_micros(1429),
+            FIRST_CHECK * 2,
+        );
+        assert_eq!(monitor.deviations, 1);
+    }
+}
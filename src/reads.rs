@@ -0,0 +1,358 @@
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Reads it takes before the share of single bytes says anything
+const MIN_READS: u64 = 100;
+
+// How the driver hands bytes over: what each read() on the port returned.
+// A touch frame at 9600 baud takes about 9ms on the wire, so a port that
+// delivers it a byte at a time is waking the reader nine times a frame.
+#[derive(Default)]
+pub struct ReadStats {
+    reads: AtomicU64,
+    bytes: AtomicU64,
+    single_bytes: AtomicU64,
+    timeouts: AtomicU64,
+    empty: AtomicU64,
+}
+
+impl ReadStats {
+    fn record(&self, result: &io::Result<usize>) {
+        let count = |counter: &AtomicU64| counter.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(0) => count(&self.empty),
+            Ok(n) => {
+                self.bytes.fetch_add(*n as u64, Ordering::Relaxed);
+                if *n == 1 {
+                    count(&self.single_bytes);
+                }
+                count(&self.reads)
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => count(&self.timeouts),
+            Err(_) => 0,
+        };
+    }
+
+    pub fn snapshot(&self) -> ReadTotals {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ReadTotals {
+            reads: load(&self.reads),
+            bytes: load(&self.bytes),
+            single_bytes: load(&self.single_bytes),
+            timeouts: load(&self.timeouts),
+            empty: load(&self.empty),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ReadTotals {
+    pub reads: u64,
+    pub bytes: u64,
+    pub single_bytes: u64,
+    pub timeouts: u64,
+    pub empty: u64,
+}
+
+impl ReadTotals {
+    // Most reads brought a single byte, the pattern of a USB adapter
+    // flushing on every byte (an FTDI latency timer problem) or a driver
+    // without any buffering
+    pub fn trickling(&self) -> bool {
+        self.reads >= MIN_READS && self.single_bytes * 2 > self.reads
+    }
+
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"reads\":{},\"bytes\":{},\"single_bytes\":{},\"timeouts\":{},\"empty\":{}}}",
+            self.reads, self.bytes, self.single_bytes, self.timeouts, self.empty
+        )
+    }
+}
+
+impl fmt::Display for ReadTotals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let average = self.bytes as f64 / self.reads.max(1) as f64;
+        write!(
+            f,
+            "{} reads of {:.1} bytes on average, {} of a single byte, {} timeouts, {} empty",
+            self.reads, average, self.single_bytes, self.timeouts, self.empty
+        )
+    }
+}
+
+// A port that counts its reads into `stats`. Its clones count into the
+// same stats.
+pub struct CountingPort {
+    port: Box<dyn SerialPort>,
+    stats: Arc<ReadStats>,
+}
+
+impl CountingPort {
+    pub fn new(port: Box<dyn SerialPort>, stats: Arc<ReadStats>) -> Self {
+        CountingPort { port, stats }
+    }
+}
+
+impl Read for CountingPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.port.read(buf);
+        self.stats.record(&result);
+        result
+    }
+}
+
+impl Write for CountingPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl SerialPort for CountingPort {
+    fn name(&self) -> Option<String> {
+        self.port.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.port.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        self.port.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        self.port.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        self.port.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        self.port.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.port.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.port.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.port.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.port.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.port.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.port.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.port.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.port.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.port.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.port.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.port.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.port.bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.port.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        self.port.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(CountingPort::new(
+            self.port.try_clone()?,
+            self.stats.clone(),
+        )))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.port.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.port.clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counted(results: impl IntoIterator<Item = io::Result<usize>>) -> ReadTotals {
+        let stats = ReadStats::default();
+        for result in results {
+            stats.record(&result);
+        }
+        stats.snapshot()
+    }
+
+    fn timed_out() -> io::Result<usize> {
+        Err(ErrorKind::TimedOut.into())
+    }
+
+    #[test]
+    fn each_read_is_counted_by_what_it_brought() {
+        let totals = counted([
+            Ok(9),
+            Ok(1),
+            Ok(1),
+            timed_out(),
+            Ok(0),
+            Ok(4),
+            timed_out(),
+            // Other errors end the session, and say nothing about delivery
+            Err(ErrorKind::BrokenPipe.into()),
+        ]);
+        assert_eq!(
+            (
+                totals.reads,
+                totals.bytes,
+                totals.single_bytes,
+                totals.timeouts,
+                totals.empty
+            ),
+            (4, 15, 2, 2, 1)
+        );
+    }
+
+    #[test]
+    fn trickling_takes_enough_reads_mostly_of_one_byte() {
+        let ones = |count| std::iter::repeat_with(|| Ok(1)).take(count);
+        let frames = |count| std::iter::repeat_with(|| Ok(9)).take(count);
+        // Too few reads to tell
+        assert!(!counted(ones(99)).trickling());
+        assert!(counted(ones(100)).trickling());
+        assert!(counted(ones(51).chain(frames(49))).trickling());
+        assert!(!counted(ones(50).chain(frames(50))).trickling());
+        // Timeouts and empty reads aren't reads of a byte
+        let quiet = std::iter::repeat_with(timed_out).take(200);
+        assert!(!counted(ones(40).chain(quiet).chain(frames(60))).trickling());
+    }
+
+    #[test]
+    fn totals_are_summed_up_for_the_log_and_the_summary() {
+        let totals = counted([Ok(9), Ok(1), Ok(2), timed_out(), Ok(0)]);
+        assert_eq!(
+            totals.to_string(),
+            "3 reads of 4.0 bytes on average, 1 of a single byte, 1 timeouts, 1 empty"
+        );
+        assert_eq!(
+            totals.json(),
+            "{\"reads\":3,\"bytes\":12,\"single_bytes\":1,\"timeouts\":1,\"empty\":1}"
+        );
+        assert_eq!(
+            counted([]).to_string(),
+            "0 reads of 0.0 bytes on average, 0 of a single byte, 0 timeouts, 0 empty"
+        );
+    }
+
+    // A board on the far end of a PTY that sends `bytes` one at a time,
+    // pausing after each, then goes quiet. The test reads the near end
+    // through a CountingPort.
+    #[cfg(unix)]
+    fn trickled(bytes: &[u8], test: impl FnOnce(CountingPort, &ReadStats)) {
+        use serialport::TTYPort;
+
+        let (mut board, near) = TTYPort::pair().unwrap();
+        let stats = Arc::new(ReadStats::default());
+        let mut port = CountingPort::new(Box::new(near), stats.clone());
+        port.set_timeout(Duration::from_millis(20)).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for &byte in bytes {
+                    board.write_all(&[byte]).unwrap();
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                // Stays open until the test has read everything
+                std::thread::sleep(Duration::from_millis(200));
+            });
+            test(port, &stats);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_frame_trickled_in_a_byte_at_a_time_is_counted_read_by_read() {
+        let frames = b"(\x01\x00\x00\x00\x00\x00\x00)".repeat(3);
+        trickled(&frames, |port, stats| {
+            let mut reader = io::BufReader::new(port);
+            let mut kept = Vec::new();
+            for _ in 0..3 {
+                crate::io::skip_until(&mut reader, b')', Some(&mut kept)).unwrap();
+            }
+            assert_eq!(kept.len(), 3 * 8);
+            // Then nothing, until the read times out
+            assert_eq!(
+                crate::io::skip_until(&mut reader, b')', None)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::TimedOut
+            );
+            let totals = stats.snapshot();
+            assert_eq!(totals.bytes, 27);
+            assert!(totals.timeouts >= 1);
+            // The odd pair of bytes may still come in together
+            assert!(totals.single_bytes * 2 > totals.reads, "{}", totals);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_clone_counts_into_the_same_stats() {
+        trickled(b"ab", |mut port, stats| {
+            let mut clone = port.try_clone().unwrap();
+            let mut buf = [0u8; 1];
+            assert_eq!(port.read(&mut buf).unwrap(), 1);
+            assert_eq!(clone.read(&mut buf).unwrap(), 1);
+            let totals = stats.snapshot();
+            assert_eq!((totals.reads, totals.bytes, totals.single_bytes), (2, 2, 2));
+        });
+    }
+}
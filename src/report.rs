@@ -0,0 +1,546 @@
+use crate::histogram::Histogram;
+use crate::reads::{ReadStats, ReadTotals};
+use anyhow::{Context, Result};
+use maitouch_protocol::touch::{Region, REGION_COUNT};
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+// Totals for the whole run, logged and optionally written as JSON on exit.
+// Counters are atomic so a signal handler can finish the report while the
+// streaming threads are still updating it.
+pub struct SessionReport {
+    started: Instant,
+    finished: AtomicBool,
+    pub streaming_us: AtomicU64,
+    pub sessions: AtomicU64,
+    pub frames: AtomicU64,
+    pub malformed: AtomicU64,
+    pub skipped: AtomicU64,
+    pub stalls: AtomicU64,
+    // Times the ADX frame rate was found well off what its firmware should send
+    pub rate_deviations: AtomicU64,
+    // ALLS frames cut short by a write timeout: finished later, or given up on
+    pub torn_frames: AtomicU64,
+    pub resyncs: AtomicU64,
+    // All-clear frames sent to the ALLS as a stream ended
+    pub final_clears: AtomicU64,
+    // Microseconds into the run the game last sent a packet, in either mode,
+    // and the longest it has gone without one
+    last_game_us: AtomicU64,
+    longest_game_silence_us: AtomicU64,
+    // Times the game went quiet long enough to be given up on
+    pub game_losses: AtomicU64,
+    // Streams ended for running past --max-stream-minutes
+    pub stream_caps: AtomicU64,
+    // Whether the ALLS port looks like nothing has it open right now, and
+    // for how long it has looked that way while streaming
+    pub no_reader: AtomicBool,
+    pub no_reader_us: AtomicU64,
+    // --adx-keepalive writes sent, and ones that failed
+    pub keepalives: AtomicU64,
+    pub keepalive_failures: AtomicU64,
+    // Writes the --alls-mirror copy was too far behind to take
+    pub mirror_dropped: AtomicU64,
+    // Commands taken from --inject-listen, and datagram lines that didn't parse
+    pub injected: AtomicU64,
+    pub malformed_injections: AtomicU64,
+    // Times a touch source was shut out for --max-transitions-per-sec
+    pub quarantines: AtomicU64,
+    // Times the ADX was seen power-cycling by its --adx-banner
+    pub adx_power_cycles: AtomicU64,
+    // The bitmap the ADX answered --feature-probe with
+    pub adx_features: OnceLock<u32>,
+    pub presses: [AtomicU64; REGION_COUNT],
+    // Intervals between consecutive forwarded frames
+    pub frame_gaps: Histogram,
+    // What each read on the ADX port brought in
+    pub adx_reads: Arc<ReadStats>,
+}
+
+impl SessionReport {
+    pub fn new() -> Self {
+        SessionReport {
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+            streaming_us: AtomicU64::new(0),
+            sessions: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            rate_deviations: AtomicU64::new(0),
+            torn_frames: AtomicU64::new(0),
+            resyncs: AtomicU64::new(0),
+            final_clears: AtomicU64::new(0),
+            last_game_us: AtomicU64::new(0),
+            longest_game_silence_us: AtomicU64::new(0),
+            game_losses: AtomicU64::new(0),
+            stream_caps: AtomicU64::new(0),
+            no_reader: AtomicBool::new(false),
+            no_reader_us: AtomicU64::new(0),
+            keepalives: AtomicU64::new(0),
+            keepalive_failures: AtomicU64::new(0),
+            mirror_dropped: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+            malformed_injections: AtomicU64::new(0),
+            quarantines: AtomicU64::new(0),
+            adx_power_cycles: AtomicU64::new(0),
+            adx_features: OnceLock::new(),
+            presses: std::array::from_fn(|_| AtomicU64::new(0)),
+            frame_gaps: Histogram::new(),
+            adx_reads: Arc::new(ReadStats::default()),
+        }
+    }
+
+    // Called for every packet from the game, with when it came in
+    pub fn game_heard(&self, now: Instant) {
+        let silence = self.game_silence(now);
+        self.longest_game_silence_us
+            .fetch_max(silence.as_micros() as u64, Ordering::Relaxed);
+        self.last_game_us.store(
+            now.saturating_duration_since(self.started).as_micros() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn game_ever_heard(&self) -> bool {
+        self.last_game_us.load(Ordering::Relaxed) != 0
+    }
+
+    // How long the game has gone without sending anything by `now`, or
+    // since startup
+    pub fn game_silence(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.started)
+            .saturating_sub(Duration::from_micros(
+                self.last_game_us.load(Ordering::Relaxed),
+            ))
+    }
+
+    // Logs the summary and writes the JSON copy if asked to, once. Errors
+    // writing the file are only logged, this runs while the proxy goes down.
+    pub fn finish(&self, path: Option<&str>) {
+        if self.finished.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let totals = Totals::from(self);
+        tracing::info!("Session summary:");
+        tracing::info!("  Uptime            {:.1?}", totals.uptime);
+        tracing::info!("  Config mode       {:.1?}", totals.config());
+        tracing::info!(
+            "  Streaming         {:.1?} over {} sessions",
+            totals.streaming,
+            totals.sessions
+        );
+        tracing::info!("  Frames forwarded  {}", totals.frames);
+        tracing::info!("  Malformed         {}", totals.malformed);
+        tracing::info!("  Skipped backlog   {}", totals.skipped);
+        tracing::info!("  ADX stalls        {}", totals.stalls);
+        if totals.rate_deviations > 0 {
+            tracing::info!("  Off-rate ADX      {} times", totals.rate_deviations);
+        }
+        if totals.torn_frames > 0 || totals.resyncs > 0 {
+            tracing::info!(
+                "  Torn ALLS frames  {} finished, {} abandoned",
+                totals.torn_frames,
+                totals.resyncs
+            );
+        }
+        if totals.final_clears > 0 {
+            tracing::info!("  Final clears      {}", totals.final_clears);
+        }
+        tracing::info!(
+            "  Game silent       {:.1?} at most, {:.1?} at exit",
+            totals.longest_game_silence,
+            totals.game_silence
+        );
+        if totals.game_losses > 0 {
+            tracing::info!("  Game losses       {}", totals.game_losses);
+        }
+        if totals.stream_caps > 0 {
+            tracing::info!("  Streams capped    {}", totals.stream_caps);
+        }
+        if totals.no_reader > Duration::ZERO {
+            tracing::info!("  No ALLS reader    {:.1?} of streaming", totals.no_reader);
+        }
+        if totals.keepalives > 0 || totals.keepalive_failures > 0 {
+            tracing::info!(
+                "  ADX keep-alives   {} sent, {} failed",
+                totals.keepalives,
+                totals.keepalive_failures
+            );
+        }
+        if totals.mirror_dropped > 0 {
+            tracing::info!("  Mirror dropped    {} writes", totals.mirror_dropped);
+        }
+        if totals.injected > 0 || totals.malformed_injections > 0 {
+            tracing::info!(
+                "  Injected          {} commands, {} malformed",
+                totals.injected,
+                totals.malformed_injections
+            );
+        }
+        if totals.quarantines > 0 {
+            tracing::info!("  Quarantined       {} times", totals.quarantines);
+        }
+        if totals.adx_power_cycles > 0 {
+            tracing::info!("  ADX power cycles  {}", totals.adx_power_cycles);
+        }
+        if let Some(features) = totals.adx_features {
+            tracing::info!("  ADX features      {:#x}", features);
+        }
+        let presses: Vec<String> = Region::all()
+            .filter(|region| totals.presses[region.index()] > 0)
+            .map(|region| format!("{}={}", region, totals.presses[region.index()]))
+            .collect();
+        if !presses.is_empty() {
+            tracing::info!("  Presses           {}", presses.join(" "));
+        }
+        if totals.gaps.iter().any(|(_, count)| *count > 0) {
+            tracing::info!("  Frame gaps        {}", self.frame_gaps);
+        }
+        tracing::info!("  ADX port          {}", totals.adx_reads);
+        if totals.adx_reads.trickling() {
+            tracing::warn!(
+                "!!! Most ADX reads brought a single byte; run doctor on the port, a USB \
+                 adapter's latency timer or driver may be to blame"
+            );
+        }
+
+        if let Some(path) = path {
+            if let Err(err) = totals.write_json(path) {
+                tracing::warn!("Couldn't write session summary: {:#}", err);
+            }
+        }
+    }
+}
+
+// Snapshot of the counters at the moment the report is finished
+struct Totals {
+    uptime: Duration,
+    streaming: Duration,
+    sessions: u64,
+    frames: u64,
+    malformed: u64,
+    skipped: u64,
+    stalls: u64,
+    rate_deviations: u64,
+    torn_frames: u64,
+    resyncs: u64,
+    final_clears: u64,
+    game_silence: Duration,
+    longest_game_silence: Duration,
+    game_losses: u64,
+    stream_caps: u64,
+    no_reader: Duration,
+    keepalives: u64,
+    keepalive_failures: u64,
+    mirror_dropped: u64,
+    injected: u64,
+    malformed_injections: u64,
+    quarantines: u64,
+    adx_power_cycles: u64,
+    adx_features: Option<u32>,
+    presses: [u64; REGION_COUNT],
+    gaps: Vec<(Option<Duration>, u64)>,
+    adx_reads: ReadTotals,
+}
+
+impl Totals {
+    fn from(report: &SessionReport) -> Self {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let game_silence = report.game_silence(Instant::now());
+        Totals {
+            uptime: report.started.elapsed(),
+            streaming: Duration::from_micros(load(&report.streaming_us)),
+            sessions: load(&report.sessions),
+            frames: load(&report.frames),
+            malformed: load(&report.malformed),
+            skipped: load(&report.skipped),
+            stalls: load(&report.stalls),
+            rate_deviations: load(&report.rate_deviations),
+            torn_frames: load(&report.torn_frames),
+            resyncs: load(&report.resyncs),
+            final_clears: load(&report.final_clears),
+            game_silence,
+            // The silence still going on counts too
+            longest_game_silence: Duration::from_micros(load(&report.longest_game_silence_us))
+                .max(game_silence),
+            game_losses: load(&report.game_losses),
+            stream_caps: load(&report.stream_caps),
+            no_reader: Duration::from_micros(load(&report.no_reader_us)),
+            keepalives: load(&report.keepalives),
+            keepalive_failures: load(&report.keepalive_failures),
+            mirror_dropped: load(&report.mirror_dropped),
+            injected: load(&report.injected),
+            malformed_injections: load(&report.malformed_injections),
+            quarantines: load(&report.quarantines),
+            adx_power_cycles: load(&report.adx_power_cycles),
+            adx_features: report.adx_features.get().copied(),
+            presses: std::array::from_fn(|i| load(&report.presses[i])),
+            gaps: report.frame_gaps.buckets().collect(),
+            adx_reads: report.adx_reads.snapshot(),
+        }
+    }
+
+    fn config(&self) -> Duration {
+        self.uptime.saturating_sub(self.streaming)
+    }
+
+    fn write_json(&self, path: &str) -> Result<()> {
+        let presses: Vec<String> = Region::all()
+            .map(|region| format!("\"{}\":{}", region, self.presses[region.index()]))
+            .collect();
+        let gaps: Vec<String> = self
+            .gaps
+            .iter()
+            .map(|(bound, count)| {
+                let bound = bound.map_or("null".to_string(), |bound| bound.as_micros().to_string());
+                format!("{{\"le_us\":{},\"count\":{}}}", bound, count)
+            })
+            .collect();
+        let json = format!(
+            "{{\"uptime_ms\":{},\"config_ms\":{},\"streaming_ms\":{},\"streaming_sessions\":{},\
+             \"frames_forwarded\":{},\"malformed\":{},\"skipped\":{},\"stalls\":{},\
+             \"rate_deviations\":{},\"torn_frames\":{},\"resyncs\":{},\"final_clears\":{},\
+             \"game_silence_ms\":{},\"longest_game_silence_ms\":{},\"game_losses\":{},\"stream_caps\":{},\
+             \"no_reader_ms\":{},\"keepalives\":{},\"keepalive_failures\":{},\
+             \"mirror_dropped\":{},\"injected\":{},\"malformed_injections\":{},\"quarantines\":{},\
+             \"adx_power_cycles\":{},\"adx_features\":{},\"presses\":{{{}}},\"frame_gaps\":[{}],\"adx_reads\":{}}}\n",
+            self.uptime.as_millis(),
+            self.config().as_millis(),
+            self.streaming.as_millis(),
+            self.sessions,
+            self.frames,
+            self.malformed,
+            self.skipped,
+            self.stalls,
+            self.rate_deviations,
+            self.torn_frames,
+            self.resyncs,
+            self.final_clears,
+            self.game_silence.as_millis(),
+            self.longest_game_silence.as_millis(),
+            self.game_losses,
+            self.stream_caps,
+            self.no_reader.as_millis(),
+            self.keepalives,
+            self.keepalive_failures,
+            self.mirror_dropped,
+            self.injected,
+            self.malformed_injections,
+            self.quarantines,
+            self.adx_power_cycles,
+            self.adx_features
+                .map_or("null".to_string(), |features| features.to_string()),
+            presses.join(","),
+            gaps.join(","),
+            self.adx_reads.json()
+        );
+        fs::write(path, json).with_context(|| format!("writing {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Just enough JSON to read the summary back
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Number(u64),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn parse(text: &str) -> Json {
+            let mut chars = text.trim().chars().peekable();
+            let json = Json::value(&mut chars);
+            assert_eq!(chars.next(), None, "trailing text in {}", text);
+            json
+        }
+
+        fn value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+            match chars.next() {
+                Some('n') => {
+                    let rest: String = chars.take(3).collect();
+                    assert_eq!(rest, "ull");
+                    Json::Null
+                }
+                Some('[') => {
+                    let mut items = Vec::new();
+                    while chars.next_if_eq(&']').is_none() {
+                        items.push(Json::value(chars));
+                        chars.next_if_eq(&',');
+                    }
+                    Json::Array(items)
+                }
+                Some('{') => {
+                    let mut fields = Vec::new();
+                    while chars.next_if_eq(&'}').is_none() {
+                        assert_eq!(chars.next(), Some('"'));
+                        let key: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                        assert_eq!(chars.next(), Some(':'));
+                        fields.push((key, Json::value(chars)));
+                        chars.next_if_eq(&',');
+                    }
+                    Json::Object(fields)
+                }
+                Some(digit @ '0'..='9') => {
+                    let mut number = digit.to_string();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        number.push(digit);
+                    }
+                    Json::Number(number.parse().unwrap())
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        fn kind(&self) -> &'static str {
+            match self {
+                Json::Null => "null",
+                Json::Number(_) => "number",
+                Json::Array(_) => "array",
+                Json::Object(_) => "object",
+            }
+        }
+
+        fn fields(&self) -> &[(String, Json)] {
+            match self {
+                Json::Object(fields) => fields,
+                other => panic!("{:?} isn't an object", other),
+            }
+        }
+
+        fn get(&self, key: &str) -> &Json {
+            let field = self.fields().iter().find(|(name, _)| name == key);
+            &field.unwrap_or_else(|| panic!("no {}", key)).1
+        }
+
+        // Each field with the kind of its value
+        fn schema(&self) -> Vec<(&str, &'static str)> {
+            self.fields()
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.kind()))
+                .collect()
+        }
+    }
+
+    fn summary(report: &SessionReport, name: &str) -> Json {
+        let path = std::env::temp_dir().join(format!(
+            "maitouch-report-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        Totals::from(report).write_json(path).unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        Json::parse(&text)
+    }
+
+    #[test]
+    fn the_summary_has_every_field_with_its_type() {
+        let json = summary(&SessionReport::new(), "schema");
+        let counters = [
+            "uptime_ms",
+            "config_ms",
+            "streaming_ms",
+            "streaming_sessions",
+            "frames_forwarded",
+            "malformed",
+            "skipped",
+            "stalls",
+            "rate_deviations",
+            "torn_frames",
+            "resyncs",
+            "final_clears",
+            "game_silence_ms",
+            "longest_game_silence_ms",
+            "game_losses",
+            "stream_caps",
+            "no_reader_ms",
+            "keepalives",
+            "keepalive_failures",
+            "mirror_dropped",
+            "injected",
+            "malformed_injections",
+            "quarantines",
+            "adx_power_cycles",
+        ];
+        let mut expected: Vec<(&str, &str)> =
+            counters.iter().map(|&name| (name, "number")).collect();
+        // Only known once the board has answered --feature-probe
+        expected.push(("adx_features", "null"));
+        expected.extend([
+            ("presses", "object"),
+            ("frame_gaps", "array"),
+            ("adx_reads", "object"),
+        ]);
+        assert_eq!(json.schema(), expected);
+
+        let regions: Vec<String> = Region::all().map(|region| region.to_string()).collect();
+        let presses = json.get("presses").schema();
+        assert_eq!(presses.len(), REGION_COUNT);
+        for ((name, kind), region) in presses.iter().zip(&regions) {
+            assert_eq!((*name, *kind), (region.as_str(), "number"));
+        }
+        assert_eq!(
+            json.get("adx_reads").schema(),
+            [
+                ("reads", "number"),
+                ("bytes", "number"),
+                ("single_bytes", "number"),
+                ("timeouts", "number"),
+                ("empty", "number"),
+            ]
+        );
+        let Json::Array(gaps) = json.get("frame_gaps") else {
+            unreachable!();
+        };
+        let (last, bounded) = gaps.split_last().unwrap();
+        for gap in bounded {
+            assert_eq!(gap.schema(), [("le_us", "number"), ("count", "number")]);
+        }
+        // The last bucket takes everything above the others
+        assert_eq!(last.schema(), [("le_us", "null"), ("count", "number")]);
+    }
+
+    #[test]
+    fn the_summary_matches_its_schema() {
+        let path = std::env::temp_dir().join(format!(
+            "maitouch-report-checked-{}.json",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let report = SessionReport::new();
+        let schema = crate::schema::document("summary").unwrap();
+        Totals::from(&report).write_json(path).unwrap();
+        let fresh = fs::read_to_string(path).unwrap();
+        // And once the nulls have values
+        report.adx_features.set(0x5).unwrap();
+        Totals::from(&report).write_json(path).unwrap();
+        let probed = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        crate::schema::check(&schema, &fresh).unwrap();
+        crate::schema::check(&schema, &probed).unwrap();
+    }
+
+    #[test]
+    fn the_summary_carries_the_counters() {
+        let report = SessionReport::new();
+        report.stream_caps.store(2, Ordering::Relaxed);
+        report.frames.store(1234, Ordering::Relaxed);
+        report.presses[0].store(7, Ordering::Relaxed);
+        report.adx_features.set(0x5).unwrap();
+        let json = summary(&report, "counters");
+        assert_eq!(json.get("stream_caps"), &Json::Number(2));
+        assert_eq!(json.get("frames_forwarded"), &Json::Number(1234));
+        assert_eq!(json.get("presses").get("A1"), &Json::Number(7));
+        assert_eq!(json.get("adx_features"), &Json::Number(5));
+    }
+}
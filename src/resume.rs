@@ -0,0 +1,222 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// How often the file is rewritten while streaming, so its age says how
+// long ago the proxy went away rather than when the stream started
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// A file older than this is left over from some earlier run
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+// Remembers across restarts that the game had the ADX streaming, since it
+// won't send {STAT} again to a proxy that restarted under it
+#[derive(Clone)]
+pub struct ResumeState {
+    path: PathBuf,
+    player: String,
+}
+
+impl ResumeState {
+    pub fn new(path: &str, player: Option<&str>) -> Self {
+        ResumeState {
+            path: path.into(),
+            player: player.unwrap_or_default().to_string(),
+        }
+    }
+
+    // `no_reader` notes that nothing seems to have the ALLS port open, for
+    // whatever watches the file
+    pub fn mark_streaming(&self, no_reader: bool) {
+        let mut contents = format!("mode=streaming\nplayer={}\n", self.player);
+        if no_reader {
+            contents.push_str("reader=none\n");
+        }
+        if let Err(err) = fs::write(&self.path, contents) {
+            tracing::warn!(
+                "Couldn't write resume state {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    pub fn clear(&self) {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => tracing::warn!(
+                "Couldn't remove resume state {}: {}",
+                self.path.display(),
+                err
+            ),
+            _ => {}
+        }
+    }
+
+    // Whether the last run was interrupted while streaming. A stale file,
+    // or one written for another player, is removed.
+    pub fn interrupted(&self) -> bool {
+        let (contents, modified) = match read(&self.path) {
+            Ok(Some(file)) => file,
+            Ok(None) => return false,
+            Err(err) => {
+                tracing::warn!(
+                    "Couldn't read resume state {}: {}",
+                    self.path.display(),
+                    err
+                );
+                return false;
+            }
+        };
+        let field = |key: &str| {
+            contents.lines().find_map(|line| {
+                line.split_once('=')
+                    .filter(|(name, _)| name.trim() == key)
+                    .map(|(_, value)| value.trim().to_string())
+            })
+        };
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        let resume = if field("mode").as_deref() != Some("streaming") {
+            false
+        } else if age > STALE_AFTER {
+            tracing::info!(
+                "Ignoring resume state {} from {:.0?} ago",
+                self.path.display(),
+                age
+            );
+            false
+        } else if field("player").unwrap_or_default() != self.player {
+            tracing::warn!(
+                "Ignoring resume state {}, it was written for another player",
+                self.path.display()
+            );
+            false
+        } else {
+            true
+        };
+        if !resume {
+            self.clear();
+        }
+        resume
+    }
+}
+
+fn read(path: &Path) -> io::Result<Option<(String, SystemTime)>> {
+    let contents = match fs::read_to_string(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(Some((contents, modified)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn state_file(name: &str, player: Option<&str>) -> ResumeState {
+        let path =
+            std::env::temp_dir().join(format!("maitouch-resume-{}-{}", name, std::process::id()));
+        ResumeState::new(path.to_str().unwrap(), player)
+    }
+
+    fn written_ago(state: &ResumeState, age: Duration) {
+        File::options()
+            .write(true)
+            .open(&state.path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[test]
+    fn a_fresh_file_from_the_same_player_resumes() {
+        let state = state_file("fresh", Some("1p"));
+        state.mark_streaming(false);
+        assert_eq!(
+            fs::read_to_string(&state.path).unwrap(),
+            "mode=streaming\nplayer=1p\n"
+        );
+        assert!(state.interrupted());
+        // It's kept, in case the proxy restarts again before streaming
+        assert!(state.path.exists());
+        state.clear();
+        assert!(!state.path.exists());
+    }
+
+    #[test]
+    fn no_reader_is_noted_and_doesnt_stop_a_resume() {
+        let state = state_file("no-reader", None);
+        state.mark_streaming(true);
+        assert_eq!(
+            fs::read_to_string(&state.path).unwrap(),
+            "mode=streaming\nplayer=\nreader=none\n"
+        );
+        assert!(state.interrupted());
+        state.clear();
+    }
+
+    #[test]
+    fn no_file_means_no_resume() {
+        let state = state_file("missing", None);
+        assert!(!state.interrupted());
+        // Clearing what isn't there is fine too
+        crate::logcapture::capturing(|log| {
+            state.clear();
+            assert!(log.take().is_empty());
+        });
+    }
+
+    #[test]
+    fn a_stale_file_is_ignored_and_removed() {
+        let state = state_file("stale", None);
+        state.mark_streaming(false);
+        written_ago(&state, STALE_AFTER + Duration::from_secs(5));
+        crate::logcapture::capturing(|log| {
+            assert!(!state.interrupted());
+            let logged = log.take();
+            let ignored = format!(
+                "Ignoring resume state {} from 35s ago",
+                state.path.display()
+            );
+            assert_eq!(logged, [ignored]);
+        });
+        assert!(!state.path.exists());
+    }
+
+    #[test]
+    fn a_file_kept_fresh_while_streaming_is_never_stale() {
+        let state = state_file("refreshed", None);
+        state.mark_streaming(false);
+        written_ago(&state, STALE_AFTER - REFRESH_INTERVAL);
+        assert!(state.interrupted());
+        state.clear();
+    }
+
+    #[test]
+    fn a_file_for_another_player_is_ignored_and_removed() {
+        let state = state_file("other-player", Some("1p"));
+        state.mark_streaming(false);
+        crate::logcapture::capturing(|log| {
+            assert!(!state_file("other-player", Some("2p")).interrupted());
+            assert_eq!(
+                log.take(),
+                [format!(
+                    "Ignoring resume state {}, it was written for another player",
+                    state.path.display()
+                )]
+            );
+        });
+        assert!(!state.path.exists());
+    }
+
+    #[test]
+    fn a_file_not_written_while_streaming_is_removed() {
+        let state = state_file("not-streaming", None);
+        fs::write(&state.path, "mode=config\nplayer=\n").unwrap();
+        assert!(!state.interrupted());
+        assert!(!state.path.exists());
+    }
+}
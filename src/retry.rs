@@ -0,0 +1,194 @@
+use crate::clock::Clock;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+// The backoff doubles on every consecutive timeout up to this multiple
+const MAX_BACKOFF_FACTOR: u32 = 8;
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub backoff: Duration,
+}
+
+// Tracks the timeouts of one read site. The consecutive count can be
+// watched from other threads while the owner is blocked retrying.
+pub struct Retry<'a> {
+    policy: RetryPolicy,
+    clock: &'a (dyn Clock + Sync),
+    deadline: Option<Instant>,
+    consecutive: AtomicU32,
+    cancelled: AtomicBool,
+}
+
+impl<'a> Retry<'a> {
+    pub fn new(policy: RetryPolicy, clock: &'a (dyn Clock + Sync)) -> Self {
+        Self::until(policy, clock, None)
+    }
+
+    pub fn until(
+        policy: RetryPolicy,
+        clock: &'a (dyn Clock + Sync),
+        deadline: Option<Instant>,
+    ) -> Self {
+        Retry {
+            policy,
+            clock,
+            deadline,
+            consecutive: AtomicU32::new(0),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn consecutive(&self) -> u32 {
+        self.consecutive.load(Ordering::Relaxed)
+    }
+
+    pub fn succeeded(&self) {
+        self.consecutive.store(0, Ordering::Relaxed);
+    }
+
+    // Makes the owner give up at its next timeout, from any thread
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // Call after a timeout. Backs off before the next attempt, or returns
+    // false if the deadline has passed or the retry was cancelled and the
+    // caller should give up.
+    pub fn timed_out(&self) -> bool {
+        let count = self.consecutive.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = self.clock.now();
+        if self.cancelled.load(Ordering::Relaxed)
+            || self.deadline.is_some_and(|deadline| now >= deadline)
+        {
+            return false;
+        }
+        let factor = (1u32 << (count - 1).min(31)).min(MAX_BACKOFF_FACTOR);
+        let mut wake = now + self.policy.backoff * factor;
+        if let Some(deadline) = self.deadline {
+            wake = wake.min(deadline);
+        }
+        if wake > now {
+            self.clock.sleep_until(wake);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::thread;
+
+    const BACKOFF: Duration = Duration::from_millis(2);
+    const POLICY: RetryPolicy = RetryPolicy { backoff: BACKOFF };
+
+    // How long each of `count` timeouts in a row backs off for
+    fn backoffs(retry: &Retry, clock: &MockClock, count: usize) -> Vec<Duration> {
+        (0..count)
+            .map(|_| {
+                let before = clock.now();
+                assert!(retry.timed_out());
+                clock.since(before)
+            })
+            .collect()
+    }
+
+    fn ms(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|&ms| Duration::from_millis(ms)).collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        assert_eq!(backoffs(&retry, &clock, 7), ms(&[2, 4, 8, 16, 16, 16, 16]));
+        assert_eq!(retry.consecutive(), 7);
+    }
+
+    #[test]
+    fn success_starts_the_backoff_over() {
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        assert_eq!(backoffs(&retry, &clock, 3), ms(&[2, 4, 8]));
+        retry.succeeded();
+        assert_eq!(retry.consecutive(), 0);
+        assert_eq!(backoffs(&retry, &clock, 2), ms(&[2, 4]));
+    }
+
+    #[test]
+    fn a_long_run_of_timeouts_stays_capped() {
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        let all = backoffs(&retry, &clock, 100);
+        assert!(all[3..]
+            .iter()
+            .all(|backoff| *backoff == BACKOFF * MAX_BACKOFF_FACTOR));
+    }
+
+    #[test]
+    fn no_backoff_never_sleeps() {
+        let clock = MockClock::new();
+        let retry = Retry::new(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &clock,
+        );
+        assert_eq!(backoffs(&retry, &clock, 5), vec![Duration::ZERO; 5]);
+    }
+
+    #[test]
+    fn gives_up_at_the_deadline() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let retry = Retry::until(POLICY, &clock, Some(start + Duration::from_millis(10)));
+        // 2 and 4, then the third backoff is cut short at the deadline
+        assert_eq!(backoffs(&retry, &clock, 3), ms(&[2, 4, 4]));
+        assert_eq!(clock.since(start), Duration::from_millis(10));
+        assert!(!retry.timed_out());
+        assert_eq!(clock.since(start), Duration::from_millis(10));
+        assert_eq!(retry.consecutive(), 4);
+    }
+
+    #[test]
+    fn a_passed_deadline_gives_up_at_once() {
+        let clock = MockClock::new();
+        let retry = Retry::until(POLICY, &clock, Some(clock.now()));
+        let before = clock.now();
+        assert!(!retry.timed_out());
+        assert_eq!(clock.since(before), Duration::ZERO);
+    }
+
+    #[test]
+    fn cancel_gives_up_without_backing_off() {
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        assert!(retry.timed_out());
+        retry.cancel();
+        let before = clock.now();
+        assert!(!retry.timed_out());
+        assert_eq!(clock.since(before), Duration::ZERO);
+    }
+
+    #[test]
+    fn cancel_ends_a_retry_on_another_thread() {
+        let clock = MockClock::new();
+        let retry = Retry::new(POLICY, &clock);
+        thread::scope(|scope| {
+            let spinning = scope.spawn(|| {
+                let mut timeouts = 0u32;
+                while retry.timed_out() {
+                    timeouts += 1;
+                }
+                timeouts
+            });
+            while retry.consecutive() < 3 {
+                thread::yield_now();
+            }
+            retry.cancel();
+            assert!(spinning.join().unwrap() >= 2);
+        });
+    }
+}
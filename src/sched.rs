@@ -0,0 +1,208 @@
+use std::io::{Error, ErrorKind, Result};
+
+// SCHED_FIFO priority for --realtime on Unix: above every normal thread but
+// well below the kernel's own realtime threads
+#[cfg(unix)]
+const FIFO_PRIORITY: libc::c_int = 10;
+
+// Scheduling tweaks for the streaming threads. They're best effort: when
+// the OS refuses, the thread carries on with default scheduling.
+#[derive(Clone, Copy)]
+pub struct ThreadTuning {
+    pub realtime: bool,
+    pub pin_cpu: Option<usize>,
+}
+
+impl ThreadTuning {
+    // Applies to the calling thread, so call it first thing after spawning
+    pub fn apply(self, thread: &str) {
+        if self.realtime {
+            match set_realtime() {
+                Ok(()) => tracing::debug!("{} thread running at realtime priority", thread),
+                Err(err) => tracing::warn!("Couldn't raise {} thread priority: {}", thread, err),
+            }
+        }
+        if let Some(cpu) = self.pin_cpu {
+            match pin_to(cpu) {
+                Ok(()) => tracing::debug!("{} thread pinned to CPU {}", thread, cpu),
+                Err(err) => {
+                    tracing::warn!("Couldn't pin {} thread to CPU {}: {}", thread, cpu, err)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_realtime() -> Result<()> {
+    let param = libc::sched_param {
+        sched_priority: FIFO_PRIORITY,
+    };
+    // SAFETY: only changes the scheduling of the calling thread
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        errno => Err(Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to(cpu: usize) -> Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "CPU index out of range",
+        ));
+    }
+    // SAFETY: cpu_set_t is plain data and the index was checked above
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn pin_to(_cpu: usize) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "not supported on this OS",
+    ))
+}
+
+#[cfg(windows)]
+mod kernel32 {
+    pub type Handle = *mut std::ffi::c_void;
+    pub const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn GetCurrentThread() -> Handle;
+        pub fn SetThreadPriority(thread: Handle, priority: i32) -> i32;
+        pub fn SetThreadAffinityMask(thread: Handle, mask: usize) -> usize;
+    }
+}
+
+#[cfg(windows)]
+fn set_realtime() -> Result<()> {
+    // SAFETY: the pseudo handle always refers to the calling thread
+    let ok = unsafe {
+        kernel32::SetThreadPriority(
+            kernel32::GetCurrentThread(),
+            kernel32::THREAD_PRIORITY_TIME_CRITICAL,
+        )
+    };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn pin_to(cpu: usize) -> Result<()> {
+    if cpu >= usize::BITS as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "CPU index out of range",
+        ));
+    }
+    // SAFETY: the pseudo handle always refers to the calling thread
+    let previous =
+        unsafe { kernel32::SetThreadAffinityMask(kernel32::GetCurrentThread(), 1 << cpu) };
+    if previous == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::logcapture::capturing;
+    use std::thread;
+
+    // The CPUs the calling thread may run on
+    fn affinity() -> Vec<usize> {
+        // SAFETY: cpu_set_t is plain data, filled in for the calling thread
+        unsafe {
+            let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+            assert_eq!(
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set),
+                0
+            );
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                .collect()
+        }
+    }
+
+    // Runs `test` on a fresh thread, as the streaming threads are, so
+    // its tuning doesn't stick to the test harness's own
+    fn on_a_thread(test: impl FnOnce() + Send + 'static) {
+        thread::spawn(test).join().unwrap();
+    }
+
+    #[test]
+    fn a_pinned_thread_runs_on_its_cpu_only() {
+        on_a_thread(|| {
+            capturing(|log| {
+                let tuning = ThreadTuning {
+                    realtime: false,
+                    pin_cpu: Some(0),
+                };
+                tuning.apply("test");
+                assert_eq!(affinity(), [0]);
+                assert!(log.take().is_empty());
+            })
+        });
+    }
+
+    #[test]
+    fn a_refused_tweak_is_warned_about_and_the_thread_carries_on() {
+        on_a_thread(|| {
+            capturing(|log| {
+                let before = affinity();
+                let tuning = ThreadTuning {
+                    realtime: false,
+                    pin_cpu: Some(libc::CPU_SETSIZE as usize),
+                };
+                tuning.apply("test");
+                assert_eq!(affinity(), before);
+                assert_eq!(
+                    log.take(),
+                    ["Couldn't pin test thread to CPU 1024: CPU index out of range"]
+                );
+            })
+        });
+    }
+
+    #[test]
+    fn realtime_is_best_effort() {
+        // Whether it's allowed depends on who runs the test, but either way
+        // the thread is left running and says which it was
+        on_a_thread(|| {
+            capturing(|log| {
+                let tuning = ThreadTuning {
+                    realtime: true,
+                    pin_cpu: None,
+                };
+                tuning.apply("test");
+                // SAFETY: reads the scheduling policy of the calling thread
+                let policy = unsafe { libc::sched_getscheduler(0) };
+                let log = log.take();
+                if policy == libc::SCHED_FIFO {
+                    assert!(log.is_empty(), "{:?}", log);
+                } else {
+                    assert_eq!(log.len(), 1);
+                    assert!(
+                        log[0].starts_with("Couldn't raise test thread priority: "),
+                        "{:?}",
+                        log
+                    );
+                }
+            })
+        });
+    }
+}
@@ -0,0 +1,455 @@
+use maitouch_protocol::touch::Region;
+
+// The shape of each JSON document the proxy writes, for tools that read
+// them. The documents themselves are still built with format!, so each
+// one's tests check what it writes against its schema here.
+pub enum Schema {
+    // Counts, times and sizes, none of them negative
+    Integer,
+    Boolean,
+    String,
+    // The value, or null while it isn't known
+    Nullable(Box<Schema>),
+    Array(Box<Schema>),
+    // Exactly these keys, in this order
+    Object(Vec<(String, Schema)>),
+}
+
+pub const DOCUMENTS: &[&str] = &["summary", "capabilities", "bench"];
+
+pub fn document(name: &str) -> Option<Schema> {
+    match name {
+        "summary" => Some(summary()),
+        "capabilities" => Some(capabilities()),
+        "bench" => Some(bench()),
+        _ => None,
+    }
+}
+
+// A document's schema as one line of JSON Schema, for generating clients
+pub fn json_schema(name: &str) -> Option<String> {
+    let body = document(name)?.render();
+    Some(format!(
+        "{{\"$schema\":\"https://json-schema.org/draft/2020-12/schema\",\
+         \"title\":\"maitouch_rs {}\",{}",
+        name,
+        &body[1..]
+    ))
+}
+
+impl Schema {
+    fn render(&self) -> String {
+        match self {
+            Schema::Integer => "{\"type\":\"integer\",\"minimum\":0}".to_string(),
+            Schema::Boolean => "{\"type\":\"boolean\"}".to_string(),
+            Schema::String => "{\"type\":\"string\"}".to_string(),
+            Schema::Nullable(schema) => {
+                format!("{{\"anyOf\":[{},{{\"type\":\"null\"}}]}}", schema.render())
+            }
+            Schema::Array(items) => {
+                format!("{{\"type\":\"array\",\"items\":{}}}", items.render())
+            }
+            Schema::Object(fields) => {
+                let properties: Vec<String> = fields
+                    .iter()
+                    .map(|(key, schema)| format!("\"{}\":{}", key, schema.render()))
+                    .collect();
+                let required: Vec<String> = fields
+                    .iter()
+                    .map(|(key, _)| format!("\"{}\"", key))
+                    .collect();
+                format!(
+                    "{{\"type\":\"object\",\"properties\":{{{}}},\"required\":[{}],\
+                     \"additionalProperties\":false}}",
+                    properties.join(","),
+                    required.join(",")
+                )
+            }
+        }
+    }
+}
+
+fn object<K: Into<String>>(fields: impl IntoIterator<Item = (K, Schema)>) -> Schema {
+    Schema::Object(
+        fields
+            .into_iter()
+            .map(|(key, schema)| (key.into(), schema))
+            .collect(),
+    )
+}
+
+fn integers(keys: &[&str]) -> Vec<(String, Schema)> {
+    keys.iter()
+        .map(|&key| (key.to_string(), Schema::Integer))
+        .collect()
+}
+
+fn array(items: Schema) -> Schema {
+    Schema::Array(Box::new(items))
+}
+
+fn nullable(schema: Schema) -> Schema {
+    Schema::Nullable(Box::new(schema))
+}
+
+// --summary-file
+fn summary() -> Schema {
+    let mut fields = integers(&[
+        "uptime_ms",
+        "config_ms",
+        "streaming_ms",
+        "streaming_sessions",
+        "frames_forwarded",
+        "malformed",
+        "skipped",
+        "stalls",
+        "rate_deviations",
+        "torn_frames",
+        "resyncs",
+        "final_clears",
+        "game_silence_ms",
+        "longest_game_silence_ms",
+        "game_losses",
+        "stream_caps",
+        "no_reader_ms",
+        "keepalives",
+        "keepalive_failures",
+        "mirror_dropped",
+        "injected",
+        "malformed_injections",
+        "quarantines",
+        "adx_power_cycles",
+    ]);
+    let presses = Region::all().map(|region| (region.to_string(), Schema::Integer));
+    fields.extend([
+        ("adx_features".to_string(), nullable(Schema::Integer)),
+        ("presses".to_string(), object(presses)),
+        (
+            "frame_gaps".to_string(),
+            // The last bucket has no upper bound
+            array(object([
+                ("le_us", nullable(Schema::Integer)),
+                ("count", Schema::Integer),
+            ])),
+        ),
+        (
+            "adx_reads".to_string(),
+            object(integers(&[
+                "reads",
+                "bytes",
+                "single_bytes",
+                "timeouts",
+                "empty",
+            ])),
+        ),
+    ]);
+    Schema::Object(fields)
+}
+
+// The capabilities tool
+fn capabilities() -> Schema {
+    let names = || array(Schema::String);
+    let protocol = object([
+        ("alls", Schema::String),
+        ("adx", Schema::String),
+        ("touch_frame_len", Schema::Integer),
+        ("payload_len", Schema::Integer),
+        ("command_max_len", Schema::Integer),
+        ("commands", names()),
+        (
+            "sized_responses",
+            array(object([
+                ("prefix", Schema::String),
+                ("len", Schema::Integer),
+            ])),
+        ),
+        ("region_count", Schema::Integer),
+        ("regions", names()),
+        (
+            "adx_features",
+            array(object([
+                ("bit", Schema::Integer),
+                ("name", Schema::String),
+                ("option", Schema::String),
+            ])),
+        ),
+    ]);
+    object([
+        ("format", Schema::Integer),
+        ("version", Schema::String),
+        ("os", Schema::String),
+        ("features", object([("shm", Schema::Boolean)])),
+        ("tools", names()),
+        (
+            "transports",
+            array(object([
+                ("name", Schema::String),
+                ("form", Schema::String),
+                ("adx", Schema::Boolean),
+                ("available", Schema::Boolean),
+            ])),
+        ),
+        ("wire_specs", names()),
+        ("alls_protocols", names()),
+        ("bit_orders", names()),
+        ("byte_orders", names()),
+        ("silence_actions", names()),
+        ("protocol", protocol),
+    ])
+}
+
+// bench-loopback --json
+fn bench() -> Schema {
+    let mut fields = integers(&["duration_us", "frames_in", "frames_out", "writes"]);
+    fields.extend([
+        (
+            "latency".to_string(),
+            object(integers(&["p50_us", "p90_us", "p99_us", "max_us"])),
+        ),
+        // Where the OS doesn't report it
+        ("cpu_us".to_string(), nullable(Schema::Integer)),
+    ]);
+    Schema::Object(fields)
+}
+
+// Whether `text` is one JSON value matching `schema`, naming where it
+// doesn't
+#[cfg(test)]
+pub fn check(schema: &Schema, text: &str) -> Result<(), String> {
+    let mut chars = text.trim_end_matches('\n').chars().peekable();
+    let value = json::value(&mut chars)?;
+    if let Some(c) = chars.next() {
+        return Err(format!("trailing {:?}", c));
+    }
+    matches(schema, &value, "document")
+}
+
+#[cfg(test)]
+fn matches(schema: &Schema, value: &json::Value, at: &str) -> Result<(), String> {
+    use json::Value;
+    match (schema, value) {
+        (Schema::Nullable(_), Value::Null) => Ok(()),
+        (Schema::Nullable(schema), value) => matches(schema, value, at),
+        (Schema::Integer, Value::Number(number)) if number.bytes().all(|b| b.is_ascii_digit()) => {
+            Ok(())
+        }
+        (Schema::Boolean, Value::Bool) | (Schema::String, Value::Str) => Ok(()),
+        (Schema::Array(items), Value::Array(values)) => values
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, value)| matches(items, value, &format!("{}[{}]", at, index))),
+        (Schema::Object(fields), Value::Object(values)) => {
+            let keys: Vec<&str> = fields.iter().map(|(key, _)| key.as_str()).collect();
+            let got: Vec<&str> = values.iter().map(|(key, _)| key.as_str()).collect();
+            if keys != got {
+                return Err(format!("{} has keys {:?}, not {:?}", at, got, keys));
+            }
+            fields
+                .iter()
+                .zip(values)
+                .try_for_each(|((key, schema), (_, value))| {
+                    matches(schema, value, &format!("{}.{}", at, key))
+                })
+        }
+        (schema, value) => Err(format!(
+            "{} should match {}, got {:?}",
+            at,
+            schema.render(),
+            value
+        )),
+    }
+}
+
+// Just enough of a JSON reader to check documents with
+#[cfg(test)]
+mod json {
+    use std::iter::Peekable;
+    use std::str::Chars;
+
+    // Only what a schema checks is kept
+    #[derive(Debug)]
+    pub enum Value {
+        Null,
+        Bool,
+        // As written, so the schema decides what kind of number it is
+        Number(String),
+        Str,
+        Array(Vec<Value>),
+        Object(Vec<(String, Value)>),
+    }
+
+    pub fn value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+        match chars.peek().copied() {
+            Some('n') => word(chars, "null", Value::Null),
+            Some('t') => word(chars, "true", Value::Bool),
+            Some('f') => word(chars, "false", Value::Bool),
+            Some('"') => string(chars).map(|_| Value::Str),
+            Some('[') => {
+                chars.next();
+                let mut items = Vec::new();
+                if chars.next_if_eq(&']').is_none() {
+                    loop {
+                        items.push(value(chars)?);
+                        match chars.next() {
+                            Some(',') => {}
+                            Some(']') => break,
+                            other => return Err(format!("expected , or ], got {:?}", other)),
+                        }
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            Some('{') => {
+                chars.next();
+                let mut fields = Vec::new();
+                if chars.next_if_eq(&'}').is_none() {
+                    loop {
+                        let key = string(chars)?;
+                        if chars.next() != Some(':') {
+                            return Err(format!("expected : after {:?}", key));
+                        }
+                        fields.push((key, value(chars)?));
+                        match chars.next() {
+                            Some(',') => {}
+                            Some('}') => break,
+                            other => return Err(format!("expected , or }}, got {:?}", other)),
+                        }
+                    }
+                }
+                Ok(Value::Object(fields))
+            }
+            Some('-' | '0'..='9') => {
+                let mut number = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e'))
+                {
+                    number.push(c);
+                }
+                Ok(Value::Number(number))
+            }
+            other => Err(format!("unexpected {:?}", other)),
+        }
+    }
+
+    fn word(chars: &mut Peekable<Chars>, word: &str, value: Value) -> Result<Value, String> {
+        let got: String = chars.take(word.len()).collect();
+        if got != word {
+            return Err(format!("expected {}, got {:?}", word, got));
+        }
+        Ok(value)
+    }
+
+    fn string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+        if chars.next() != Some('"') {
+            return Err("expected a string".to_string());
+        }
+        let mut text = String::new();
+        loop {
+            match chars.next().ok_or("unterminated string")? {
+                '"' => return Ok(text),
+                '\\' => match chars.next() {
+                    Some(c @ ('"' | '\\' | '/')) => text.push(c),
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or(format!("bad escape \\u{}", hex))?;
+                        text.push(code);
+                    }
+                    other => return Err(format!("bad escape {:?}", other)),
+                },
+                c if c.is_control() => return Err(format!("unescaped {:?} in a string", c)),
+                c => text.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_capabilities_match_their_schema() {
+        check(&capabilities(), &crate::capabilities::json()).unwrap();
+    }
+
+    #[test]
+    fn every_schema_is_itself_a_json_document() {
+        // A schema is an object whose values are schemas, or lists of
+        // them, which is all `check` needs to read it
+        for name in DOCUMENTS {
+            let schema = json_schema(name).unwrap();
+            let mut chars = schema.chars().peekable();
+            let json::Value::Object(fields) = json::value(&mut chars).unwrap() else {
+                panic!("{} isn't an object", schema);
+            };
+            assert_eq!(chars.next(), None);
+            let keys: Vec<&str> = fields.iter().map(|(key, _)| key.as_str()).collect();
+            assert_eq!(
+                &keys[..4],
+                ["$schema", "title", "type", "properties"],
+                "{}",
+                name
+            );
+        }
+        assert!(json_schema("status").is_none());
+    }
+
+    #[test]
+    fn a_schema_renders_its_keys_in_order_and_allows_no_others() {
+        let schema = object([
+            ("count", Schema::Integer),
+            ("names", array(Schema::String)),
+            ("cpu", nullable(Schema::Boolean)),
+        ]);
+        assert_eq!(
+            schema.render(),
+            "{\"type\":\"object\",\"properties\":{\
+             \"count\":{\"type\":\"integer\",\"minimum\":0},\
+             \"names\":{\"type\":\"array\",\"items\":{\"type\":\"string\"}},\
+             \"cpu\":{\"anyOf\":[{\"type\":\"boolean\"},{\"type\":\"null\"}]}},\
+             \"required\":[\"count\",\"names\",\"cpu\"],\"additionalProperties\":false}"
+        );
+    }
+
+    #[test]
+    fn a_document_off_its_schema_is_caught_where_it_goes_wrong() {
+        let schema = object([
+            ("count", Schema::Integer),
+            ("names", array(Schema::String)),
+            ("cpu", nullable(Schema::Integer)),
+        ]);
+        check(
+            &schema,
+            "{\"count\":3,\"names\":[\"a\",\"b\\\"\"],\"cpu\":null}\n",
+        )
+        .unwrap();
+        check(&schema, "{\"count\":0,\"names\":[],\"cpu\":12}").unwrap();
+
+        let err = |text: &str| check(&schema, text).unwrap_err();
+        assert_eq!(
+            err("{\"count\":3,\"names\":[]}"),
+            "document has keys [\"count\", \"names\"], not [\"count\", \"names\", \"cpu\"]"
+        );
+        assert!(err("{\"count\":3,\"names\":[],\"cpu\":null,\"extra\":1}")
+            .starts_with("document has keys"));
+        assert!(err("{\"count\":-3,\"names\":[],\"cpu\":null}").starts_with("document.count "));
+        assert!(err("{\"count\":1.5,\"names\":[],\"cpu\":null}").starts_with("document.count "));
+        assert!(err("{\"count\":3,\"names\":[\"a\",4],\"cpu\":null}")
+            .starts_with("document.names[1] should match {\"type\":\"string\"}"));
+        assert!(err("{\"count\":3,\"names\":[],\"cpu\":\"1\"}")
+            .starts_with("document.cpu should match"));
+        assert_eq!(
+            err("{\"count\":3,\"names\":[],\"cpu\":null}}"),
+            "trailing '}'"
+        );
+        assert_eq!(
+            err("{\"count\":3,\"names\":[\"a\nb\"],\"cpu\":null}"),
+            "unescaped '\\n' in a string"
+        );
+    }
+}
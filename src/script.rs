@@ -0,0 +1,604 @@
+// Proxy sessions for tests, written as what the game and the board do in
+// order rather than as threads and byte vectors:
+//
+//     let session = SessionScript::new()
+//         .adx_answers("{LAr2}", "(LAr2)")
+//         .adx_streams(frames(20))
+//         .alls_sends("{LAr2}")
+//         .alls_expects(Expect::Reply("(LAr2)".into()))
+//         .alls_sends("{STAT}")
+//         .alls_expects(Expect::Stream)
+//         .alls_sends("{HALT}")
+//         .run();
+//
+// `run` puts proxy_loop between a scripted ADX and the game, both on PTYs,
+// and plays the game's steps. It hangs up on the proxy once the steps are
+// done and only then checks what each expectation got, so a failed one
+// never leaves the proxy running.
+
+use crate::clock::{Clock, MockClock, MonotonicClock};
+use crate::report::SessionReport;
+use crate::{all_clear_frame, proxy_loop, Config, Pipeline, WireSpec};
+use anyhow::Result;
+use maitouch_protocol::command::{self, CommandKind};
+use serialport::{SerialPort, TTYPort};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+// How long the game waits for what it expects before giving up on it
+const EXPECT_WAIT: Duration = Duration::from_secs(10);
+// Read timeout on both scripted ends
+const POLL: Duration = Duration::from_millis(20);
+// Between the frames of a stream, as a board at 500Hz
+const FRAME_GAP: Duration = Duration::from_millis(2);
+
+enum Frame {
+    // Told apart by the number in its first two payload bytes
+    Numbered(u8),
+    // A numbered frame that lost its close delimiter on the line
+    Torn(u8),
+}
+
+impl Frame {
+    fn bytes(&self, spec: &WireSpec) -> Vec<u8> {
+        let mut payload = vec![0; spec.touch_frame_len - 2];
+        let (Frame::Numbered(n) | Frame::Torn(n)) = *self;
+        payload[0] = n & 0x1f;
+        payload[1] = n >> 5;
+        let mut frame = spec.adx.wrap(&payload);
+        if let Frame::Torn(_) = self {
+            frame.pop();
+        }
+        frame
+    }
+}
+
+// What the ADX streams on each {STAT}
+pub struct Frames {
+    frames: Vec<Frame>,
+    hangup: bool,
+}
+
+// `count` frames, each different from the last
+pub fn frames(count: u8) -> Frames {
+    Frames {
+        frames: (1..=count).map(Frame::Numbered).collect(),
+        hangup: false,
+    }
+}
+
+impl Frames {
+    // The `index`th frame, counted from 0, arrives without its close
+    // delimiter and runs into the one after it
+    pub fn torn_at(mut self, index: usize) -> Frames {
+        let Frame::Numbered(n) = self.frames[index] else {
+            panic!("only a numbered frame can be torn");
+        };
+        self.frames[index] = Frame::Torn(n);
+        self
+    }
+
+    // The board drops off the line once the last frame is out
+    pub fn then_hangup(mut self) -> Frames {
+        self.hangup = true;
+        self
+    }
+}
+
+// What the ADX does with a config command
+enum Reply {
+    Answer(Vec<u8>),
+    // Answers, but only once this long has gone by
+    Late(Duration, Vec<u8>),
+    // Drops off the line instead
+    Hangup,
+}
+
+// What the game reads for
+pub enum Expect {
+    // Exactly these bytes, e.g. a config answer
+    Reply(Vec<u8>),
+    // All-clear frames, at least one, and then these bytes
+    ClearThen(Vec<u8>),
+    // The stream's frames, torn ones aside, in order and ending on the last.
+    // All-clear frames can come before them, and the writer only sends the
+    // latest, so some may be skipped.
+    Stream,
+    // The stream's first frame, somewhere
+    Touched,
+    // Whatever is waiting after this long, which has to end on the
+    // all-clear frame
+    Cleared(Duration),
+    // Nothing at all for this long
+    Nothing(Duration),
+}
+
+enum Step {
+    Send(Vec<u8>),
+    Expect(Expect),
+    // The game goes quiet for a while
+    Sleep(Duration),
+    // Throws away whatever is waiting after this long
+    Discard(Duration),
+    // Moves the mock clock on
+    Advance(Duration),
+    // Until the report says so, or EXPECT_WAIT passes
+    Until(Box<dyn Fn(&SessionReport) -> bool + Send + Sync>),
+    // What the report has to say at this point, checked with the
+    // expectations
+    Check(
+        &'static str,
+        Box<dyn Fn(&SessionReport) -> bool + Send + Sync>,
+    ),
+}
+
+// What one expectation got, and how long the game waited for it
+pub struct Got {
+    pub bytes: Vec<u8>,
+    pub waited: Duration,
+}
+
+pub struct Session {
+    // One per expectation, in the order they were written
+    pub got: Vec<Got>,
+    // What proxy_loop returned
+    pub result: Result<()>,
+    pub report: Arc<SessionReport>,
+}
+
+pub struct SessionScript {
+    spec: WireSpec,
+    options: Vec<String>,
+    mock_clock: bool,
+    replies: Vec<(Vec<u8>, Reply)>,
+    stream: Frames,
+    lead: Duration,
+    watchdog: Option<(Vec<u8>, Duration)>,
+    power_cycle: Option<(usize, Vec<u8>)>,
+    steps: Vec<Step>,
+}
+
+impl SessionScript {
+    pub fn new() -> Self {
+        SessionScript {
+            spec: WireSpec::maimai(),
+            options: Vec::new(),
+            mock_clock: false,
+            replies: Vec::new(),
+            stream: frames(0),
+            lead: Duration::ZERO,
+            watchdog: None,
+            power_cycle: None,
+            steps: Vec::new(),
+        }
+    }
+
+    // The spec both the script and the proxy use, the proxy's given as a
+    // --wire-spec value
+    pub fn wire_spec(mut self, option: &str, spec: WireSpec) -> Self {
+        self.options
+            .extend(["--wire-spec".to_string(), option.to_string()]);
+        self.spec = spec;
+        self
+    }
+
+    pub fn options(mut self, options: &[&str]) -> Self {
+        self.options
+            .extend(options.iter().map(|option| option.to_string()));
+        self
+    }
+
+    // Runs the proxy on a MockClock that only the script moves on
+    pub fn on_mock_clock(mut self) -> Self {
+        self.mock_clock = true;
+        self
+    }
+
+    // The board answers `command` with `answer`. It ignores commands
+    // without an answer, so the game times out on them.
+    pub fn adx_answers(mut self, command: &str, answer: &str) -> Self {
+        let reply = Reply::Answer(answer.as_bytes().to_vec());
+        self.replies.push((command.as_bytes().to_vec(), reply));
+        self
+    }
+
+    pub fn adx_answers_late(mut self, command: &str, answer: &str, after: Duration) -> Self {
+        let reply = Reply::Late(after, answer.as_bytes().to_vec());
+        self.replies.push((command.as_bytes().to_vec(), reply));
+        self
+    }
+
+    pub fn adx_hangs_up_on(mut self, command: &str) -> Self {
+        self.replies
+            .push((command.as_bytes().to_vec(), Reply::Hangup));
+        self
+    }
+
+    pub fn adx_streams(mut self, frames: Frames) -> Self {
+        self.stream = frames;
+        self
+    }
+
+    // How long the board takes to start streaming after {STAT}
+    pub fn adx_lead(mut self, lead: Duration) -> Self {
+        self.lead = lead;
+        self
+    }
+
+    // The board stops streaming once `keepalive` hasn't come in for
+    // `within`, as firmware with a watchdog does
+    pub fn adx_needs_keepalive(mut self, keepalive: &[u8], within: Duration) -> Self {
+        self.watchdog = Some((keepalive.to_vec(), within));
+        self
+    }
+
+    // The first stream stops after `after` frames with the board printing
+    // `banner`, as it does when it power-cycles. It then waits for another
+    // {STAT}, and streams the rest.
+    pub fn adx_power_cycles(mut self, after: usize, banner: &[u8]) -> Self {
+        self.power_cycle = Some((after, banner.to_vec()));
+        self
+    }
+
+    pub fn alls_sends(mut self, bytes: &str) -> Self {
+        self.steps.push(Step::Send(bytes.as_bytes().to_vec()));
+        self
+    }
+
+    // A command by name, wrapped in the spec's delimiters
+    pub fn alls_sends_command(mut self, name: &str) -> Self {
+        self.steps.push(Step::Send(self.spec.command(name)));
+        self
+    }
+
+    pub fn alls_expects(mut self, expect: Expect) -> Self {
+        self.steps.push(Step::Expect(expect));
+        self
+    }
+
+    pub fn alls_goes_quiet(mut self, period: Duration) -> Self {
+        self.steps.push(Step::Sleep(period));
+        self
+    }
+
+    pub fn alls_discards(mut self, after: Duration) -> Self {
+        self.steps.push(Step::Discard(after));
+        self
+    }
+
+    pub fn advance_clock(mut self, by: Duration) -> Self {
+        self.steps.push(Step::Advance(by));
+        self
+    }
+
+    pub fn until(mut self, done: impl Fn(&SessionReport) -> bool + Send + Sync + 'static) -> Self {
+        self.steps.push(Step::Until(Box::new(done)));
+        self
+    }
+
+    pub fn report_shows(
+        mut self,
+        what: &'static str,
+        shows: impl Fn(&SessionReport) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.steps.push(Step::Check(what, Box::new(shows)));
+        self
+    }
+
+    pub fn run(self) -> Session {
+        let spec = &self.spec;
+        let (adx, adx_slave) = TTYPort::pair().unwrap();
+        let (game, game_slave) = TTYPort::pair().unwrap();
+        let mut ends = [adx, game];
+        for end in &mut ends {
+            end.set_timeout(POLL).unwrap();
+        }
+        let [adx, game] = ends;
+        let names = [game_slave.name().unwrap(), adx_slave.name().unwrap()];
+        let args = ["maitouch_rs", &names[0], &names[1]]
+            .into_iter()
+            .map(String::from)
+            .chain(self.options.iter().cloned());
+        let config = Config::from_iter_safe(args).unwrap();
+        config.validate().unwrap();
+        let mut pipeline = Pipeline::new(&config, spec).unwrap();
+        let report = pipeline.report.clone();
+        let mock = MockClock::new();
+        // Ahead of when the report starts counting, as a clock that has
+        // been running a while would be
+        mock.advance(Duration::from_secs(1));
+        let clock: &(dyn Clock + Sync) = if self.mock_clock {
+            &mock
+        } else {
+            &MonotonicClock
+        };
+        let stream: Vec<Vec<u8>> = self.stream.frames.iter().map(|f| f.bytes(spec)).collect();
+        let done = AtomicBool::new(false);
+
+        let (got, checks, result) = thread::scope(|scope| {
+            let proxy = scope.spawn(|| proxy_loop(&config, spec, &mut pipeline, clock));
+            scope.spawn(|| self.board(adx, &stream, &done));
+            let (got, checks) = self.play(game, &mock, &report, &stream);
+            done.store(true, Ordering::Relaxed);
+            let result = proxy.join().unwrap();
+            (got, checks, result)
+        });
+        drop((adx_slave, game_slave));
+
+        let streamed: Vec<&[u8]> = self
+            .stream
+            .frames
+            .iter()
+            .zip(&stream)
+            .filter(|(frame, _)| !matches!(frame, Frame::Torn(_)))
+            .map(|(_, bytes)| bytes.as_slice())
+            .collect();
+        let expects = self.steps.iter().filter_map(|step| match step {
+            Step::Expect(expect) => Some(expect),
+            _ => None,
+        });
+        for (index, (expect, got)) in expects.zip(&got).enumerate() {
+            expect.check(spec, &streamed, &got.bytes, index);
+        }
+        for (what, shown) in checks {
+            assert!(shown, "the report doesn't show {}", what);
+        }
+        Session {
+            got,
+            result,
+            report,
+        }
+    }
+
+    // The game's steps, up to hanging up on the proxy, and what the report
+    // showed at each check
+    fn play(
+        &self,
+        mut game: TTYPort,
+        clock: &MockClock,
+        report: &SessionReport,
+        stream: &[Vec<u8>],
+    ) -> (Vec<Got>, Vec<(&'static str, bool)>) {
+        let mut got = Vec::new();
+        let mut checks = Vec::new();
+        for step in &self.steps {
+            match step {
+                Step::Send(bytes) => {
+                    game.write_all(bytes).unwrap();
+                }
+                Step::Expect(expect) => {
+                    let started = Instant::now();
+                    let bytes = match expect {
+                        Expect::Reply(reply) => {
+                            read_until(&mut game, |got| got.len() >= reply.len())
+                        }
+                        Expect::ClearThen(reply) => {
+                            read_until(&mut game, |got| got.ends_with(reply))
+                        }
+                        Expect::Stream => {
+                            let last = stream.last().expect("nothing to stream");
+                            read_until(&mut game, |got| got.ends_with(last))
+                        }
+                        Expect::Touched => {
+                            let first = stream.first().expect("nothing to stream");
+                            read_until(&mut game, |got| {
+                                got.windows(first.len()).any(|window| window == first)
+                            })
+                        }
+                        Expect::Cleared(after) | Expect::Nothing(after) => {
+                            thread::sleep(*after);
+                            read_waiting(&mut game)
+                        }
+                    };
+                    let waited = started.elapsed();
+                    got.push(Got { bytes, waited });
+                }
+                Step::Sleep(period) => thread::sleep(*period),
+                Step::Discard(after) => {
+                    thread::sleep(*after);
+                    read_waiting(&mut game);
+                }
+                Step::Advance(by) => clock.advance(*by),
+                Step::Until(done) => {
+                    let deadline = Instant::now() + EXPECT_WAIT;
+                    while !done(report) && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                Step::Check(what, shows) => checks.push((*what, shows(report))),
+            }
+        }
+        (got, checks)
+    }
+
+    // Takes in what the proxy sent while the board streams, saying whether
+    // `keepalive` was in it. The rest waits in `pending` for the stream to
+    // end.
+    fn heard(&self, port: &mut TTYPort, pending: &mut Vec<u8>, keepalive: &[u8]) -> bool {
+        let waiting = port.bytes_to_read().unwrap_or(0) as usize;
+        if waiting == 0 {
+            return false;
+        }
+        let mut buf = vec![0u8; waiting];
+        let n = port.read(&mut buf).unwrap_or(0);
+        let mut heard = false;
+        pending.extend_from_slice(&buf[..n]);
+        while let Some(at) = pending
+            .windows(keepalive.len())
+            .position(|w| w == keepalive)
+        {
+            pending.drain(at..at + keepalive.len());
+            heard = true;
+        }
+        heard
+    }
+
+    // The board on the far end of the ADX PTY
+    fn board(&self, mut port: TTYPort, stream: &[Vec<u8>], done: &AtomicBool) {
+        let spec = &self.spec;
+        let mut pending = Vec::new();
+        let mut buf = [0u8; 64];
+        // Where the stream picks up after a power cycle
+        let (mut power_cycled, mut resume_at) = (false, 0);
+        while !done.load(Ordering::Relaxed) {
+            let n = match port.read(&mut buf) {
+                Ok(n) => n,
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+                Err(_) => return,
+            };
+            pending.extend_from_slice(&buf[..n]);
+            while let Some(end) = pending.iter().position(|&b| b == spec.alls.close as u8) {
+                let packet: Vec<u8> = pending.drain(..=end).collect();
+                let Some(start) = packet.iter().rposition(|&b| b == spec.alls.open as u8) else {
+                    continue;
+                };
+                let packet = &packet[start..];
+                if command::classify(&spec.alls, packet) == CommandKind::Stat {
+                    thread::sleep(self.lead);
+                    let mut heard = Instant::now();
+                    let skip = std::mem::take(&mut resume_at);
+                    for (index, frame) in stream.iter().enumerate().skip(skip) {
+                        if let Some((after, banner)) = &self.power_cycle {
+                            if index == *after && !power_cycled {
+                                port.write_all(banner).unwrap();
+                                power_cycled = true;
+                                resume_at = index;
+                                break;
+                            }
+                        }
+                        if let Some((keepalive, within)) = &self.watchdog {
+                            if self.heard(&mut port, &mut pending, keepalive) {
+                                heard = Instant::now();
+                            } else if heard.elapsed() > *within {
+                                break;
+                            }
+                        }
+                        port.write_all(frame).unwrap();
+                        thread::sleep(FRAME_GAP);
+                    }
+                    if self.stream.hangup && resume_at == 0 {
+                        return;
+                    }
+                    continue;
+                }
+                match self.replies.iter().find(|(command, _)| command == packet) {
+                    Some((_, Reply::Answer(answer))) => port.write_all(answer).unwrap(),
+                    Some((_, Reply::Late(after, answer))) => {
+                        thread::sleep(*after);
+                        port.write_all(answer).unwrap();
+                    }
+                    Some((_, Reply::Hangup)) => return,
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+impl Expect {
+    // Panics with what was wrong about what the `index`th expectation got
+    fn check(&self, spec: &WireSpec, stream: &[&[u8]], got: &[u8], index: usize) {
+        let all_clear = all_clear_frame(spec);
+        let clear = |bytes: &[u8]| {
+            bytes.len().is_multiple_of(all_clear.len())
+                && bytes
+                    .chunks(all_clear.len())
+                    .all(|frame| frame == all_clear)
+        };
+        let shown = String::from_utf8_lossy(got);
+        match self {
+            Expect::Reply(reply) => {
+                assert_eq!(
+                    shown,
+                    String::from_utf8_lossy(reply),
+                    "expectation {}: the reply",
+                    index
+                );
+            }
+            Expect::ClearThen(reply) => {
+                assert!(got.ends_with(reply), "expectation {}: {:?}", index, got);
+                let before = &got[..got.len() - reply.len()];
+                assert!(
+                    !before.is_empty() && clear(before),
+                    "expectation {}: {:?} before the reply",
+                    index,
+                    before
+                );
+            }
+            Expect::Stream => {
+                let mut seen: Vec<&[u8]> = got
+                    .chunks(spec.touch_frame_len)
+                    .filter(|frame| *frame != all_clear)
+                    .collect();
+                seen.dedup();
+                assert_eq!(
+                    seen.last(),
+                    stream.last(),
+                    "expectation {}: {:?}",
+                    index,
+                    seen
+                );
+                let mut expected = stream.iter();
+                for frame in &seen {
+                    assert!(
+                        expected.any(|sent| sent == frame),
+                        "expectation {}: {:?} out of order in {:?}",
+                        index,
+                        frame,
+                        seen
+                    );
+                }
+            }
+            Expect::Touched => {
+                let first = stream[0];
+                assert!(
+                    got.windows(first.len()).any(|window| window == first),
+                    "expectation {}: {:?}",
+                    index,
+                    got
+                );
+            }
+            Expect::Cleared(_) => {
+                assert!(
+                    got.ends_with(&all_clear),
+                    "expectation {}: {:?}",
+                    index,
+                    got
+                );
+            }
+            Expect::Nothing(_) => {
+                assert!(got.is_empty(), "expectation {}: {:?}", index, got);
+            }
+        }
+    }
+}
+
+// Reads from the game's end of the ALLS until `wanted` says it has all it
+// needs, or EXPECT_WAIT goes by
+fn read_until(game: &mut TTYPort, wanted: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let mut got = Vec::new();
+    let deadline = Instant::now() + EXPECT_WAIT;
+    let mut buf = [0u8; 256];
+    while !wanted(&got) && Instant::now() < deadline {
+        match game.read(&mut buf) {
+            Ok(n) => got.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+    }
+    got
+}
+
+// Whatever is waiting at the game's end, up to the first read that times out
+fn read_waiting(game: &mut TTYPort) -> Vec<u8> {
+    let mut got = Vec::new();
+    let mut buf = [0u8; 256];
+    while let Ok(n) = game.read(&mut buf) {
+        got.extend_from_slice(&buf[..n]);
+    }
+    got
+}
@@ -0,0 +1,623 @@
+use crate::clock::MonotonicClock;
+use crate::ports::{self, OpenPort};
+use crate::retry::{Retry, RetryPolicy};
+use crate::strict::{hex, printable};
+use crate::wire::WireSpec;
+use crate::{read_packet, read_response};
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::command::{self, CommandKind};
+use maitouch_protocol::touch::{Region, TouchState};
+use serialport::SerialPort;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+// How long to wait for the board to answer a command
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+// How long the board gets to stop after HALT or RSET before its leftover
+// output is thrown away
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+// Longest stream accepted, so a typo doesn't leave the board streaming
+const MAX_STREAM: Duration = Duration::from_secs(600);
+
+const HELP: &str = "\
+Commands:
+  <COMMAND>          send {<COMMAND>} and show the answer, e.g. HALT, RSET, LAr2
+  raw <hex bytes>    send bytes as is and show the next packet, e.g. raw 7b 48 41 4c 54 7d
+  stream <secs>      stream touch frames for a while, printing the regions as they change
+  help               show this list
+  quit               leave (so does end of input)";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShellCommand {
+    Help,
+    Quit,
+    // Payload to send between the ALLS delimiters
+    Send(String),
+    Raw(Vec<u8>),
+    Stream(Duration),
+}
+
+impl ShellCommand {
+    // None for a blank line
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let mut words = line.split_whitespace();
+        let Some(first) = words.next() else {
+            return Ok(None);
+        };
+        let rest: Vec<&str> = words.collect();
+        let command = match first.to_ascii_lowercase().as_str() {
+            "help" | "?" => ShellCommand::Help,
+            "quit" | "exit" => ShellCommand::Quit,
+            "raw" => {
+                if rest.is_empty() {
+                    bail!("raw needs the bytes to send");
+                }
+                let bytes = rest
+                    .iter()
+                    .map(|byte| u8::from_str_radix(byte, 16).with_context(|| byte.to_string()))
+                    .collect::<Result<_>>()
+                    .context("bad hex byte")?;
+                ShellCommand::Raw(bytes)
+            }
+            "stream" => {
+                let [secs] = rest[..] else {
+                    bail!("stream needs a duration in seconds");
+                };
+                let secs: f64 = secs.parse().context("bad stream duration")?;
+                let duration = Duration::try_from_secs_f64(secs)
+                    .ok()
+                    .filter(|duration| *duration <= MAX_STREAM)
+                    .ok_or_else(|| anyhow!("stream for at most {:?}", MAX_STREAM))?;
+                ShellCommand::Stream(duration)
+            }
+            _ if rest.is_empty() => ShellCommand::Send(first.to_string()),
+            _ => bail!("unknown command {}, try help", first),
+        };
+        Ok(Some(command))
+    }
+}
+
+// A session with the ADX on its own, without an ALLS. Everything is
+// reported to `out`, so the dispatch can run against any port.
+pub struct Shell {
+    spec: WireSpec,
+    port: OpenPort,
+    reader: BufReader<Box<dyn SerialPort>>,
+}
+
+impl Shell {
+    pub fn new(adx: &str, mut port: OpenPort) -> Result<Self> {
+        let reader;
+        (port.port, reader) = ports::duplex(adx, port.port);
+        let reader = BufReader::new(reader);
+        Ok(Shell {
+            spec: WireSpec::maimai(),
+            port,
+            reader,
+        })
+    }
+
+    // Runs one command, returning false once the session should end
+    pub fn dispatch(&mut self, command: &ShellCommand, out: &mut dyn Write) -> Result<bool> {
+        match command {
+            ShellCommand::Help => writeln!(out, "{}", HELP)?,
+            ShellCommand::Quit => return Ok(false),
+            ShellCommand::Send(payload) => self.send(payload, out)?,
+            ShellCommand::Raw(bytes) => {
+                self.port.port.write_all(bytes)?;
+                writeln!(out, "> {}", hex(bytes))?;
+                let mut response = Vec::new();
+                match read_packet(
+                    &mut response,
+                    &mut self.reader,
+                    &self.spec.adx,
+                    &Self::deadline(RESPONSE_TIMEOUT),
+                ) {
+                    Ok(_) => show(out, &response)?,
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                        writeln!(out, "no answer")?
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            ShellCommand::Stream(duration) => self.stream(*duration, out)?,
+        }
+        Ok(true)
+    }
+
+    fn send(&mut self, payload: &str, out: &mut dyn Write) -> Result<()> {
+        let packet = self.spec.command(payload);
+        match command::classify(&self.spec.alls, &packet) {
+            CommandKind::Stat => bail!("use stream <secs>, so the board is halted again after"),
+            CommandKind::Halt | CommandKind::Reset => {
+                self.port.port.write_all(&packet)?;
+                self.settle()?;
+                writeln!(out, "sent {}", String::from_utf8_lossy(&packet))?;
+            }
+            CommandKind::Config => match self.exchange(&packet)? {
+                Some(response) => show(out, &response)?,
+                None => writeln!(out, "no answer to {}", String::from_utf8_lossy(&packet))?,
+            },
+        }
+        Ok(())
+    }
+
+    // Sends a config command and reads the answer, None if there is none
+    // in time
+    pub fn exchange(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.port.port.write_all(packet)?;
+        let mut response = Vec::new();
+        match read_response(
+            &mut response,
+            &mut self.reader,
+            &self.spec,
+            packet,
+            &Self::deadline(RESPONSE_TIMEOUT),
+        ) {
+            Ok(_) => Ok(Some(response)),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn stream(&mut self, duration: Duration, out: &mut dyn Write) -> Result<()> {
+        let start = Instant::now();
+        let mut last = None;
+        let (frames, malformed) = self.streaming(duration, |state| {
+            if last != Some(state) {
+                last = Some(state);
+                writeln!(out, "{:>9.3?}  {}", start.elapsed(), regions(state))?;
+            }
+            Ok(true)
+        })?;
+        writeln!(
+            out,
+            "{} frames in {:.1?} ({:.0}/s), {} malformed",
+            frames,
+            duration,
+            frames as f64 / duration.as_secs_f64().max(f64::EPSILON),
+            malformed
+        )?;
+        Ok(())
+    }
+
+    // Streams until one of `regions` (any region, if none are given) goes
+    // from released to touched, and returns it; None if `timeout` passes
+    // first. Regions already held when streaming starts only count once
+    // they're let go and touched again, so a stuck sensor can't fire it.
+    // The board is left halted either way.
+    pub fn wait_for_touch(
+        &mut self,
+        regions: &[Region],
+        timeout: Duration,
+    ) -> Result<Option<Region>> {
+        // Stop anything a previous session left streaming
+        self.port
+            .port
+            .write_all(&self.spec.command(command::HALT))?;
+        self.settle()?;
+        let watched = |region: &Region| regions.is_empty() || regions.contains(region);
+        let mut last: Option<TouchState> = None;
+        let mut fired = None;
+        self.streaming(timeout, |state| {
+            if let Some(last) = last {
+                fired = Region::all()
+                    .filter(watched)
+                    .find(|&region| state.is_active(region) && !last.is_active(region));
+            }
+            last = Some(state);
+            Ok(fired.is_none())
+        })?;
+        Ok(fired)
+    }
+
+    // Sends STAT and hands each touch frame to `on_frame` until it returns
+    // false or `duration` is up, then halts the board, even if reading
+    // failed, so it isn't left streaming. Returns the frames and malformed
+    // packets seen.
+    pub fn streaming(
+        &mut self,
+        duration: Duration,
+        mut on_frame: impl FnMut(TouchState) -> Result<bool>,
+    ) -> Result<(u64, u64)> {
+        let start = Instant::now();
+        self.port
+            .port
+            .write_all(&self.spec.command(command::STAT))?;
+        let retry = Retry::until(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &MonotonicClock,
+            Some(start + duration),
+        );
+        let mut frame = Vec::new();
+        let (mut frames, mut malformed) = (0u64, 0u64);
+        let result = loop {
+            if start.elapsed() >= duration {
+                break Ok(());
+            }
+            match read_packet(&mut frame, &mut self.reader, &self.spec.adx, &retry) {
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => break Ok(()),
+                Err(err) => break Err(err.into()),
+            }
+            if frame.len() != self.spec.touch_frame_len {
+                malformed += 1;
+                continue;
+            }
+            frames += 1;
+            match on_frame(TouchState::decode(&frame[1..frame.len() - 1])) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.port
+            .port
+            .write_all(&self.spec.command(command::HALT))?;
+        self.settle()?;
+        result.map(|()| (frames, malformed))
+    }
+
+    // Gives the board time to act on a command, then drops whatever it
+    // sent in the meantime
+    fn settle(&mut self) -> Result<()> {
+        std::thread::sleep(SETTLE_TIME);
+        self.port.port.clear(serialport::ClearBuffer::Input)?;
+        let buffered = self.reader.buffer().len();
+        self.reader.consume(buffered);
+        Ok(())
+    }
+
+    fn deadline(timeout: Duration) -> Retry<'static> {
+        Retry::until(
+            RetryPolicy {
+                backoff: Duration::ZERO,
+            },
+            &MonotonicClock,
+            Some(Instant::now() + timeout),
+        )
+    }
+}
+
+fn show(out: &mut dyn Write, packet: &[u8]) -> std::io::Result<()> {
+    writeln!(out, "< {:<16} {}", printable(packet), hex(packet))
+}
+
+fn regions(state: TouchState) -> String {
+    let active: Vec<String> = Region::all()
+        .filter(|&region| state.is_active(region))
+        .map(|region| region.to_string())
+        .collect();
+    if active.is_empty() {
+        "-".to_string()
+    } else {
+        active.join(" ")
+    }
+}
+
+// Waits for a touch on one of `regions` and prints it. Returns whether
+// one came before the timeout.
+pub fn wait_touch(adx: &str, regions: &[Region], timeout: Duration) -> Result<bool> {
+    let mut shell = Shell::new(adx, ports::open(adx)?)?;
+    match shell.wait_for_touch(regions, timeout)? {
+        Some(region) => {
+            println!("{}", region);
+            Ok(true)
+        }
+        None => {
+            tracing::info!("No touch within {:?}", timeout);
+            Ok(false)
+        }
+    }
+}
+
+// Reads commands from stdin until the user quits or input ends
+pub fn run(adx: &str) -> Result<()> {
+    let mut shell = Shell::new(adx, ports::open(adx)?)?;
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut stdout = std::io::stdout();
+    writeln!(stdout, "Connected to {}, type help for commands", adx)?;
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            write!(stdout, "adx> ")?;
+            stdout.flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        let command = match ShellCommand::parse(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                writeln!(stdout, "error: {:#}", err)?;
+                continue;
+            }
+        };
+        match shell.dispatch(&command, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => writeln!(stdout, "error: {:#}", err)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(line: &str) -> ShellCommand {
+        ShellCommand::parse(line).unwrap().unwrap()
+    }
+
+    fn touching(regions: &[&str]) -> TouchState {
+        let mut state = TouchState::default();
+        for region in regions {
+            state.set(region.parse().unwrap(), true);
+        }
+        state
+    }
+
+    #[test]
+    fn parses_each_command() {
+        assert_eq!(parsed("help"), ShellCommand::Help);
+        assert_eq!(parsed("?"), ShellCommand::Help);
+        assert_eq!(parsed("QUIT"), ShellCommand::Quit);
+        assert_eq!(parsed("exit"), ShellCommand::Quit);
+        assert_eq!(parsed("  LAr2 "), ShellCommand::Send("LAr2".into()));
+        assert_eq!(
+            parsed("raw 7b 48 41 4C 54 7d"),
+            ShellCommand::Raw(b"{HALT}".to_vec())
+        );
+        assert_eq!(
+            parsed("stream 0.5"),
+            ShellCommand::Stream(Duration::from_millis(500))
+        );
+        assert_eq!(ShellCommand::parse("   ").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let error = |line| format!("{:#}", ShellCommand::parse(line).unwrap_err());
+        assert_eq!(error("raw"), "raw needs the bytes to send");
+        assert_eq!(
+            error("raw 7b zz"),
+            "bad hex byte: zz: invalid digit found in string"
+        );
+        assert_eq!(
+            error("raw 17b"),
+            "bad hex byte: 17b: number too large to fit in target type"
+        );
+        assert_eq!(error("stream"), "stream needs a duration in seconds");
+        assert_eq!(error("stream 5 6"), "stream needs a duration in seconds");
+        assert_eq!(
+            error("stream soon"),
+            "bad stream duration: invalid float literal"
+        );
+        assert_eq!(error("stream 601"), "stream for at most 600s");
+        assert_eq!(error("stream -1"), "stream for at most 600s");
+        assert_eq!(error("LAr2 now"), "unknown command LAr2, try help");
+    }
+
+    // A board on the far end of a PTY. It answers {LAr2}, and after {STAT}
+    // streams `stream` a frame every 2ms, then repeats the last frame until
+    // {HALT}, or drops off the line with `hangup`. Returns the commands it
+    // got.
+    #[cfg(unix)]
+    fn with_board(stream: &[TouchState], hangup: bool, test: impl FnOnce(Shell)) -> Vec<Vec<u8>> {
+        use serialport::TTYPort;
+        use std::io::Read;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let spec = WireSpec::maimai();
+        let frames: Vec<Vec<u8>> = stream
+            .iter()
+            .map(|state| {
+                let mut payload = vec![0; spec.touch_frame_len - 2];
+                state.encode_into(&mut payload);
+                spec.adx.wrap(&payload)
+            })
+            .collect();
+        let (mut board, slave) = TTYPort::pair().unwrap();
+        board.set_timeout(Duration::from_millis(2)).unwrap();
+        let done = AtomicBool::new(false);
+        let (done, frames) = (&done, &frames);
+        std::thread::scope(|scope| {
+            // Owns its end, so hanging up closes it
+            let board = scope.spawn(move || {
+                let mut commands = Vec::new();
+                let mut streamed = None;
+                let mut got = Vec::new();
+                let mut buf = [0u8; 64];
+                while !done.load(Ordering::Relaxed) {
+                    if let Ok(n) = board.read(&mut buf) {
+                        got.extend_from_slice(&buf[..n]);
+                    }
+                    while let Some(end) = got.iter().position(|&byte| byte == b'}') {
+                        let command: Vec<u8> = got.drain(..=end).collect();
+                        match command.as_slice() {
+                            b"{LAr2}" => board.write_all(b"(LAr2)").unwrap(),
+                            b"{STAT}" => streamed = Some(0),
+                            b"{HALT}" => streamed = None,
+                            _ => {}
+                        }
+                        commands.push(command);
+                    }
+                    if let Some(sent) = streamed.as_mut() {
+                        if *sent == frames.len() && hangup {
+                            return commands;
+                        }
+                        let _ = board.write_all(&frames[(*sent).min(frames.len() - 1)]);
+                        *sent += 1;
+                    }
+                }
+                commands
+            });
+            {
+                // Lets the board go even if the test fails
+                let _done = Done(done);
+                let name = slave.name().unwrap();
+                test(Shell::new(&name, ports::open(&name).unwrap()).unwrap());
+            }
+            board.join().unwrap()
+        })
+    }
+
+    #[cfg(unix)]
+    struct Done<'a>(&'a std::sync::atomic::AtomicBool);
+
+    #[cfg(unix)]
+    impl Drop for Done<'_> {
+        fn drop(&mut self) {
+            self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(unix)]
+    fn dispatched(shell: &mut Shell, command: ShellCommand) -> (bool, String) {
+        let mut out = Vec::new();
+        let more = shell.dispatch(&command, &mut out).unwrap();
+        (more, String::from_utf8(out).unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn config_commands_show_the_answer() {
+        let commands = with_board(&[], false, |mut shell| {
+            assert_eq!(
+                dispatched(&mut shell, parsed("LAr2")),
+                (true, "< (LAr2)           28 4c 41 72 32 29\n".into())
+            );
+            assert_eq!(
+                dispatched(&mut shell, parsed("LBr2")),
+                (true, "no answer to {LBr2}\n".into())
+            );
+            assert_eq!(
+                dispatched(&mut shell, parsed("raw 7b 4c 41 72 32 7d")),
+                (
+                    true,
+                    "> 7b 4c 41 72 32 7d\n< (LAr2)           28 4c 41 72 32 29\n".into()
+                )
+            );
+            assert_eq!(
+                dispatched(&mut shell, parsed("HALT")),
+                (true, "sent {HALT}\n".into())
+            );
+        });
+        assert_eq!(commands, [&b"{LAr2}"[..], b"{LBr2}", b"{LAr2}", b"{HALT}"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn help_and_quit_send_nothing() {
+        let commands = with_board(&[], false, |mut shell| {
+            assert_eq!(
+                dispatched(&mut shell, ShellCommand::Help),
+                (true, format!("{}\n", HELP))
+            );
+            assert_eq!(
+                dispatched(&mut shell, ShellCommand::Quit),
+                (false, String::new())
+            );
+            // STAT only goes out through stream, which halts the board after
+            let mut out = Vec::new();
+            let err = shell.dispatch(&parsed("STAT"), &mut out).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "use stream <secs>, so the board is halted again after"
+            );
+        });
+        assert!(commands.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stream_prints_each_change_and_halts_after() {
+        let stream = [
+            touching(&["A1"]),
+            touching(&["A1"]),
+            touching(&["A1", "B2"]),
+            touching(&[]),
+        ];
+        let commands = with_board(&stream, false, |mut shell| {
+            let (more, out) = dispatched(&mut shell, parsed("stream 0.2"));
+            assert!(more);
+            let lines: Vec<&str> = out.lines().collect();
+            let changes: Vec<&str> = lines[..lines.len() - 1]
+                .iter()
+                .map(|line| line.trim_start().split_once("  ").unwrap().1)
+                .collect();
+            assert_eq!(changes, ["A1", "A1 B2", "-"]);
+            let summary = lines.last().unwrap();
+            assert!(summary.contains(" frames in 200.0ms ("), "{}", summary);
+            assert!(summary.ends_with("/s), 0 malformed"), "{}", summary);
+        });
+        assert_eq!(commands, [&b"{STAT}"[..], b"{HALT}"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_for_touch_returns_the_first_watched_region_touched() {
+        let stream = [
+            touching(&[]),
+            // Not watched
+            touching(&["A1"]),
+            touching(&["A1", "E4"]),
+            touching(&["A1", "E4", "B2"]),
+        ];
+        let b2 = "B2".parse().unwrap();
+        let e4 = "E4".parse().unwrap();
+        let commands = with_board(&stream, false, |mut shell| {
+            let touched = shell.wait_for_touch(&[b2, e4], Duration::from_secs(5));
+            assert_eq!(touched.unwrap(), Some(e4));
+            // Back in config mode, the board answers again
+            assert_eq!(shell.exchange(b"{LAr2}").unwrap(), Some(b"(LAr2)".to_vec()));
+        });
+        assert_eq!(commands, [&b"{HALT}"[..], b"{STAT}", b"{HALT}", b"{LAr2}"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_region_held_from_the_start_only_fires_once_touched_again() {
+        let stream = [
+            touching(&["C1"]),
+            touching(&["C1"]),
+            touching(&[]),
+            touching(&["C1"]),
+        ];
+        let c1 = "C1".parse().unwrap();
+        with_board(&stream, false, |mut shell| {
+            let touched = shell.wait_for_touch(&[], Duration::from_secs(5));
+            assert_eq!(touched.unwrap(), Some(c1));
+        });
+        with_board(&[touching(&["C1"])], false, |mut shell| {
+            let touched = shell.wait_for_touch(&[c1], Duration::from_millis(200));
+            assert_eq!(touched.unwrap(), None);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_for_touch_gives_up_after_the_timeout() {
+        let commands = with_board(&[touching(&[])], false, |mut shell| {
+            let started = Instant::now();
+            let touched = shell.wait_for_touch(&[], Duration::from_millis(300));
+            assert_eq!(touched.unwrap(), None);
+            assert!(started.elapsed() >= Duration::from_millis(300));
+            assert_eq!(shell.exchange(b"{LAr2}").unwrap(), Some(b"(LAr2)".to_vec()));
+        });
+        assert_eq!(commands, [&b"{HALT}"[..], b"{STAT}", b"{HALT}", b"{LAr2}"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_board_that_drops_off_while_waiting_is_an_error() {
+        with_board(&[touching(&[]), touching(&[])], true, |mut shell| {
+            assert!(shell.wait_for_touch(&[], Duration::from_secs(5)).is_err());
+        });
+    }
+}
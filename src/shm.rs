@@ -0,0 +1,514 @@
+use crate::events::{self, Diff};
+use anyhow::{bail, Result};
+use maitouch_protocol::touch::TouchState;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+// The segment's contents, all little-endian u64s at these byte offsets:
+//
+//      0  version       odd while an update is being written
+//      8  timestamp_us  OS monotonic clock (CLOCK_MONOTONIC, or the
+//                       performance counter on Windows) at the update
+//     16  regions       bit n set while region n is touched, A1 = bit 0
+//                       through E8 = bit 33
+//     24  frame         frames published since the segment was created
+//     32  layout        2, this layout; a segment only 32 bytes long is
+//                       layout 1, which ends at frame
+//     40  pressed       regions pressed since the previous frame
+//     48  released      regions released since the previous frame
+//
+// Layouts only ever add fields at the end, so a reader of an earlier one
+// keeps working on a later segment. To read it, load the version until
+// it's even, copy the other fields, and load the version again; if it
+// changed, the copy may be torn and has to be retried.
+// examples/shm_reader.py does exactly that.
+#[repr(C)]
+#[cfg_attr(test, derive(Default))]
+struct Layout {
+    version: AtomicU64,
+    timestamp_us: AtomicU64,
+    regions: AtomicU64,
+    frame: AtomicU64,
+    layout: AtomicU64,
+    pressed: AtomicU64,
+    released: AtomicU64,
+}
+
+const LAYOUT: u64 = 2;
+
+#[cfg(feature = "shm")]
+const SIZE: usize = std::mem::size_of::<Layout>();
+
+// The latest decoded touch state, published to a named shared-memory
+// segment for local consumers that can't afford even a UDP hop. Only the
+// streaming reader writes it.
+pub struct ShmState {
+    segment: Segment,
+    frames: u64,
+}
+
+impl ShmState {
+    pub fn create(name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains(['/', '\\']) {
+            bail!(
+                "shared memory name {:?} must be non-empty, without slashes",
+                name
+            );
+        }
+        let segment = Segment::create(name)?;
+        let layout = segment.layout();
+        for field in [
+            &layout.version,
+            &layout.timestamp_us,
+            &layout.regions,
+            &layout.frame,
+            &layout.pressed,
+            &layout.released,
+        ] {
+            field.store(0, Ordering::Relaxed);
+        }
+        layout.layout.store(LAYOUT, Ordering::Release);
+        tracing::info!("Publishing touch state to shared memory {}", name);
+        Ok(ShmState { segment, frames: 0 })
+    }
+
+    // `diff` is what changed since the previous call
+    pub fn publish(&mut self, state: TouchState, diff: Diff) {
+        self.frames += 1;
+        self.segment
+            .layout()
+            .write(self.frames, events::mask(state), diff);
+    }
+}
+
+impl Layout {
+    // Only ever called from one thread at a time
+    fn write(&self, frame: u64, regions: u64, diff: Diff) {
+        // Same protocol as SharedTouchState: odd while the fields change
+        let version = self.version.load(Ordering::Relaxed);
+        self.version.store(version + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.timestamp_us.store(monotonic_us(), Ordering::Relaxed);
+        self.regions.store(regions, Ordering::Relaxed);
+        self.frame.store(frame, Ordering::Relaxed);
+        self.pressed.store(diff.pressed, Ordering::Relaxed);
+        self.released.store(diff.released, Ordering::Relaxed);
+        self.version.store(version + 2, Ordering::Release);
+    }
+}
+
+#[cfg(all(feature = "shm", unix))]
+struct Segment {
+    name: std::ffi::CString,
+    base: *mut libc::c_void,
+}
+
+#[cfg(all(feature = "shm", windows))]
+struct Segment {
+    mapping: isize,
+    base: *mut std::ffi::c_void,
+}
+
+#[cfg(not(feature = "shm"))]
+struct Segment {
+    layout: Layout,
+}
+
+// SAFETY: the mapping is only reached through the atomics in Layout
+#[cfg(feature = "shm")]
+unsafe impl Send for Segment {}
+
+#[cfg(feature = "shm")]
+impl Segment {
+    fn layout(&self) -> &Layout {
+        // SAFETY: the mapping is SIZE bytes, page aligned and lives as long as self
+        unsafe { &*(self.base as *const Layout) }
+    }
+}
+
+#[cfg(all(feature = "shm", unix))]
+impl Segment {
+    fn create(name: &str) -> Result<Self> {
+        use anyhow::Context;
+        use std::io::Error;
+        let name = std::ffi::CString::new(format!("/{}", name))?;
+        // SAFETY: the name is a valid C string, and the descriptor is
+        // closed once mapped, which keeps the mapping
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o644);
+            if fd < 0 {
+                return Err(Error::last_os_error())
+                    .with_context(|| format!("creating shared memory {:?}", name));
+            }
+            let base = if libc::ftruncate(fd, SIZE as libc::off_t) == 0 {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    SIZE,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            } else {
+                libc::MAP_FAILED
+            };
+            let err = Error::last_os_error();
+            libc::close(fd);
+            if base == libc::MAP_FAILED {
+                libc::shm_unlink(name.as_ptr());
+                return Err(err).with_context(|| format!("mapping shared memory {:?}", name));
+            }
+            Ok(Segment { name, base })
+        }
+    }
+}
+
+#[cfg(all(feature = "shm", unix))]
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: base came from mmap, and nothing refers to it past self
+        unsafe {
+            libc::munmap(self.base, SIZE);
+            libc::shm_unlink(self.name.as_ptr());
+        }
+    }
+}
+
+#[cfg(all(feature = "shm", windows))]
+mod kernel32 {
+    use std::ffi::c_void;
+
+    pub const INVALID_HANDLE_VALUE: isize = -1;
+    pub const PAGE_READWRITE: u32 = 0x04;
+    pub const FILE_MAP_WRITE: u32 = 0x02;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn CreateFileMappingW(
+            file: isize,
+            attributes: *const c_void,
+            protect: u32,
+            size_high: u32,
+            size_low: u32,
+            name: *const u16,
+        ) -> isize;
+        pub fn MapViewOfFile(
+            mapping: isize,
+            access: u32,
+            offset_high: u32,
+            offset_low: u32,
+            size: usize,
+        ) -> *mut c_void;
+        pub fn UnmapViewOfFile(base: *const c_void) -> i32;
+        pub fn CloseHandle(handle: isize) -> i32;
+        pub fn QueryPerformanceCounter(count: *mut i64) -> i32;
+        pub fn QueryPerformanceFrequency(frequency: *mut i64) -> i32;
+    }
+}
+
+#[cfg(all(feature = "shm", windows))]
+impl Segment {
+    fn create(name: &str) -> Result<Self> {
+        use anyhow::Context;
+        use std::io::Error;
+        let wide: Vec<u16> = format!("Local\\{}", name)
+            .encode_utf16()
+            .chain([0])
+            .collect();
+        // SAFETY: a pagefile-backed mapping of SIZE bytes under a valid name
+        unsafe {
+            let mapping = kernel32::CreateFileMappingW(
+                kernel32::INVALID_HANDLE_VALUE,
+                std::ptr::null(),
+                kernel32::PAGE_READWRITE,
+                0,
+                SIZE as u32,
+                wide.as_ptr(),
+            );
+            if mapping == 0 {
+                return Err(Error::last_os_error())
+                    .with_context(|| format!("creating shared memory {}", name));
+            }
+            let base = kernel32::MapViewOfFile(mapping, kernel32::FILE_MAP_WRITE, 0, 0, SIZE);
+            if base.is_null() {
+                let err = Error::last_os_error();
+                kernel32::CloseHandle(mapping);
+                return Err(err).with_context(|| format!("mapping shared memory {}", name));
+            }
+            Ok(Segment { mapping, base })
+        }
+    }
+}
+
+#[cfg(all(feature = "shm", windows))]
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: both came from create, and nothing refers to them past self
+        unsafe {
+            kernel32::UnmapViewOfFile(self.base);
+            kernel32::CloseHandle(self.mapping);
+        }
+    }
+}
+
+#[cfg(not(feature = "shm"))]
+impl Segment {
+    fn create(_name: &str) -> Result<Self> {
+        bail!("--shm needs a build with the shm feature")
+    }
+
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+}
+
+#[cfg(unix)]
+fn monotonic_us() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: fills in a local
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1000
+}
+
+#[cfg(all(feature = "shm", windows))]
+fn monotonic_us() -> u64 {
+    let (mut count, mut frequency) = (0i64, 0i64);
+    // SAFETY: both fill in locals, and can't fail on XP and later
+    unsafe {
+        kernel32::QueryPerformanceCounter(&mut count);
+        kernel32::QueryPerformanceFrequency(&mut frequency);
+    }
+    (count as i128 * 1_000_000 / frequency.max(1) as i128) as u64
+}
+
+#[cfg(all(not(feature = "shm"), windows))]
+fn monotonic_us() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+
+    // The fields after version, copied as a reader of the segment would
+    #[derive(Debug, PartialEq, Eq)]
+    struct Snapshot {
+        timestamp_us: u64,
+        regions: u64,
+        frame: u64,
+        layout: u64,
+        pressed: u64,
+        released: u64,
+    }
+
+    fn read(layout: &Layout) -> Snapshot {
+        loop {
+            let before = layout.version.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let snapshot = Snapshot {
+                timestamp_us: layout.timestamp_us.load(Ordering::Relaxed),
+                regions: layout.regions.load(Ordering::Relaxed),
+                frame: layout.frame.load(Ordering::Relaxed),
+                layout: layout.layout.load(Ordering::Relaxed),
+                pressed: layout.pressed.load(Ordering::Relaxed),
+                released: layout.released.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if layout.version.load(Ordering::Relaxed) == before {
+                return snapshot;
+            }
+        }
+    }
+
+    // Every frame's regions differ from the last in a way that's easy to
+    // check: frame n touches the regions in n's low 34 bits, scrambled
+    fn regions_of(frame: u64) -> u64 {
+        frame.wrapping_mul(0x9e37_79b9_7f4a_7c15) & ((1 << 34) - 1)
+    }
+
+    #[test]
+    fn a_reader_never_sees_a_torn_update() {
+        // Long enough for the writer to be preempted mid-update many times,
+        // even with only one CPU to go round
+        const RUN: Duration = Duration::from_millis(300);
+        let layout = Layout::default();
+        let done = AtomicBool::new(false);
+        let (reads, frames) = std::thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let (mut reads, mut last_frame) = (0u64, 0);
+                while !done.load(Ordering::Relaxed) {
+                    let got = read(&layout);
+                    reads += 1;
+                    if got.frame == 0 {
+                        continue;
+                    }
+                    // All of one update, and never an older one than before
+                    let (now, before) = (regions_of(got.frame), regions_of(got.frame - 1));
+                    assert_eq!(got.regions, now, "{:?}", got);
+                    assert_eq!(got.pressed, now & !before, "{:?}", got);
+                    assert_eq!(got.released, before & !now, "{:?}", got);
+                    assert!(got.frame >= last_frame, "{:?} after {}", got, last_frame);
+                    last_frame = got.frame;
+                }
+                reads
+            });
+            let started = Instant::now();
+            let mut frame = 0;
+            while started.elapsed() < RUN {
+                frame += 1;
+                let (now, before) = (regions_of(frame), regions_of(frame - 1));
+                let diff = Diff {
+                    pressed: now & !before,
+                    released: before & !now,
+                };
+                layout.write(frame, now, diff);
+            }
+            done.store(true, Ordering::Relaxed);
+            (reader.join().unwrap(), frame)
+        });
+        assert!(reads > 0);
+        assert_eq!(read(&layout).frame, frames);
+        assert_eq!(layout.version.load(Ordering::Relaxed), 2 * frames);
+    }
+
+    #[test]
+    fn the_layout_is_where_the_comment_says() {
+        use std::mem::offset_of;
+        assert_eq!(offset_of!(Layout, version), 0);
+        assert_eq!(offset_of!(Layout, timestamp_us), 8);
+        assert_eq!(offset_of!(Layout, regions), 16);
+        assert_eq!(offset_of!(Layout, frame), 24);
+        // Layout 1 ended here, so layout 2 only adds to it
+        assert_eq!(offset_of!(Layout, layout), 32);
+        assert_eq!(offset_of!(Layout, pressed), 40);
+        assert_eq!(offset_of!(Layout, released), 48);
+        assert_eq!(std::mem::size_of::<Layout>(), 56);
+    }
+
+    #[test]
+    fn the_diff_spans_the_frames_the_reader_skipped() {
+        let touching = |regions: &[&str]| {
+            let mut state = TouchState::default();
+            for region in regions {
+                state.set(region.parse().unwrap(), true);
+            }
+            state
+        };
+        let (a1, b1, c1) = (1 << 0, 1 << 8, 1 << 16);
+        let layout = Layout::default();
+        let mut detector = events::TransitionDetector::default();
+        let mut publish = |frame, state| {
+            layout.write(frame, events::mask(state), detector.update(state));
+            read(&layout)
+        };
+
+        let got = publish(1, touching(&["A1"]));
+        assert_eq!((got.regions, got.pressed, got.released), (a1, a1, 0));
+        // B1 pressed, A1 released, C1 pressed and let go: all in frames
+        // skipped on the way to this one
+        let got = publish(4, touching(&["B1"]));
+        assert_eq!((got.regions, got.pressed, got.released), (b1, b1, a1));
+        let got = publish(5, touching(&["B1", "C1"]));
+        assert_eq!((got.regions, got.pressed, got.released), (b1 | c1, c1, 0));
+        let got = publish(6, touching(&["B1", "C1"]));
+        assert_eq!((got.pressed, got.released), (0, 0));
+        let got = publish(7, TouchState::default());
+        assert_eq!((got.regions, got.pressed, got.released), (0, 0, b1 | c1));
+    }
+
+    #[test]
+    fn names_with_slashes_are_refused() {
+        for name in ["", "maitouch/1p", "maitouch\\1p"] {
+            let err = ShmState::create(name).err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "shared memory name {:?} must be non-empty, without slashes",
+                    name
+                )
+            );
+        }
+    }
+
+    #[cfg(not(feature = "shm"))]
+    #[test]
+    fn without_the_feature_there_is_no_segment() {
+        let err = ShmState::create("maitouch-test").err().unwrap();
+        assert_eq!(err.to_string(), "--shm needs a build with the shm feature");
+    }
+
+    // The segment as another process sees it, mapped read-only by name
+    #[cfg(all(feature = "shm", unix))]
+    fn map_by_name(name: &str, size: usize) -> &'static [AtomicU64] {
+        let name = std::ffi::CString::new(format!("/{}", name)).unwrap();
+        // SAFETY: a valid C string, and the mapping is leaked for the
+        // rest of the test
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_RDONLY, 0);
+            assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+            let base = libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            libc::close(fd);
+            assert!(base != libc::MAP_FAILED);
+            std::slice::from_raw_parts(base as *const AtomicU64, size / 8)
+        }
+    }
+
+    #[cfg(all(feature = "shm", unix))]
+    #[test]
+    fn layout_1_and_layout_2_readers_both_read_the_segment() {
+        let name = format!("maitouch-layouts-{}", std::process::id());
+        let mut shm = ShmState::create(&name).unwrap();
+        let mut state = TouchState::default();
+        state.set("A2".parse().unwrap(), true);
+        let diff = Diff {
+            pressed: 1 << 1,
+            released: 1 << 0,
+        };
+        shm.publish(state, diff);
+
+        // A layout 1 reader maps only the 32 bytes it knows of
+        let v1 = map_by_name(&name, 32);
+        let v2 = map_by_name(&name, SIZE);
+        assert_eq!(v1.len(), 4);
+        for index in 0..4 {
+            assert_eq!(
+                v1[index].load(Ordering::Acquire),
+                v2[index].load(Ordering::Acquire)
+            );
+        }
+        assert_eq!(v1[2].load(Ordering::Acquire), 1 << 1);
+        assert_eq!(v1[3].load(Ordering::Acquire), 1);
+        let field = |index: usize| v2[index].load(Ordering::Acquire);
+        assert_eq!((field(4), field(5), field(6)), (LAYOUT, 1 << 1, 1 << 0));
+    }
+
+    #[cfg(all(feature = "shm", unix))]
+    #[test]
+    fn another_process_sees_what_is_published() {
+        let name = format!("maitouch-test-{}", std::process::id());
+        let mut shm = ShmState::create(&name).unwrap();
+        let mut state = TouchState::default();
+        state.set("B3".parse().unwrap(), true);
+        shm.publish(state, Diff::default());
+        let fields = map_by_name(&name, SIZE);
+        let field = |index: usize| fields[index].load(Ordering::Acquire);
+        assert_eq!(field(0), 2);
+        assert_ne!(field(1), 0);
+        assert_eq!(field(2), 1 << 10);
+        assert_eq!(field(3), 1);
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{Error, Result};
+use std::process;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Write end of the pipe each signal is reported through, by signal number
+#[cfg(unix)]
+static SIGNAL_PIPES: [AtomicI32; 32] = [const { AtomicI32::new(-1) }; 32];
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    let fd = SIGNAL_PIPES[signal as usize].load(Ordering::Relaxed);
+    // SAFETY: write is async-signal-safe and the pipe is never closed
+    unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+}
+
+// Has `signals` write their number to a new pipe and returns its read end.
+// The handler only wakes whoever reads the pipe; blocking the signals and
+// sigwait()ing instead doesn't work because serialport's reads unblock
+// every signal while they wait.
+#[cfg(unix)]
+pub fn forward(signals: &[libc::c_int]) -> Result<libc::c_int> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe fills in both descriptors
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    for &signal in signals {
+        SIGNAL_PIPES[signal as usize].store(write_fd, Ordering::Relaxed);
+        // SAFETY: sigaction is plain data and forward_signal only does signal-safe work
+        unsafe {
+            let mut action = std::mem::zeroed::<libc::sigaction>();
+            action.sa_sigaction = forward_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+    Ok(read_fd)
+}
+
+// Blocks until one of the signals forwarded to `read_fd` arrives
+#[cfg(unix)]
+pub fn next_signal(read_fd: libc::c_int) -> libc::c_int {
+    let mut signal = 0u8;
+    loop {
+        // SAFETY: reads one byte into a local
+        let n = unsafe { libc::read(read_fd, &mut signal as *mut u8 as *mut libc::c_void, 1) };
+        if n == 1 {
+            return signal as libc::c_int;
+        }
+    }
+}
+
+// Runs `handler` and exits when the proxy is told to stop (Ctrl+C, SIGTERM,
+// closing the console)
+#[cfg(unix)]
+pub fn on_terminate(handler: impl FnOnce() + Send + 'static) -> Result<()> {
+    let read_fd = forward(&[libc::SIGINT, libc::SIGTERM, libc::SIGHUP])?;
+    std::thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || {
+            let signal = next_signal(read_fd);
+            tracing::info!("Caught signal {}, shutting down", signal);
+            handler();
+            process::exit(128 + signal);
+        })?;
+    Ok(())
+}
+
+#[cfg(windows)]
+mod kernel32 {
+    pub type HandlerRoutine = unsafe extern "system" fn(u32) -> i32;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+    }
+}
+
+#[cfg(windows)]
+type Handler = Box<dyn FnOnce() + Send>;
+
+#[cfg(windows)]
+static HANDLER: std::sync::Mutex<Option<Handler>> = std::sync::Mutex::new(None);
+
+#[cfg(windows)]
+unsafe extern "system" fn console_event(event: u32) -> i32 {
+    tracing::info!("Caught console event {}, shutting down", event);
+    if let Some(handler) = HANDLER.lock().unwrap().take() {
+        handler();
+    }
+    process::exit(130);
+}
+
+#[cfg(windows)]
+pub fn on_terminate(handler: impl FnOnce() + Send + 'static) -> Result<()> {
+    *HANDLER.lock().unwrap() = Some(Box::new(handler));
+    // SAFETY: console_event stays valid for the life of the process
+    if unsafe { kernel32::SetConsoleCtrlHandler(Some(console_event), 1) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
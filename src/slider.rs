@@ -0,0 +1,486 @@
+use crate::conf;
+use crate::ports::Endpoint;
+use crate::wire::WireSpec;
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::command;
+use maitouch_protocol::touch::{Packing, Region, TouchState};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// Chunithm slider framing: SYNC, command, length, payload, checksum, with
+// SYNC and ESCAPE inside a frame sent as ESCAPE followed by the byte - 1.
+// The checksum makes all unescaped bytes including SYNC sum to zero.
+const SYNC: u8 = 0xff;
+const ESCAPE: u8 = 0xfd;
+
+const CMD_REPORT: u8 = 0x01;
+const CMD_SET_LED: u8 = 0x02;
+const CMD_REPORT_START: u8 = 0x03;
+const CMD_REPORT_STOP: u8 = 0x04;
+const CMD_RESET: u8 = 0x10;
+const CMD_BOARD_INFO: u8 = 0xf0;
+
+// Model, device class, chip number and firmware version of a stock slider
+const BOARD_INFO: &[u8] = b"15330   \xa006712\xff\x90";
+const BOARD_INFO_LEN: usize = 32;
+
+pub const CELLS: usize = 32;
+// Pressure reported for a cell with any of its regions active
+const PRESSED: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllsProtocol {
+    Maimai,
+    ChuniSlider,
+}
+
+impl AllsProtocol {
+    pub const NAMES: &'static [&'static str] = &["maimai", "chuni-slider"];
+}
+
+impl FromStr for AllsProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "maimai" => Ok(AllsProtocol::Maimai),
+            "chuni-slider" => Ok(AllsProtocol::ChuniSlider),
+            _ => bail!(
+                "unknown ALLS protocol {}, expected maimai or chuni-slider",
+                s
+            ),
+        }
+    }
+}
+
+// Which touch regions press each slider cell. Cells are numbered as the
+// slider reports them: pairs of top/bottom cells from right to left.
+pub struct SliderMap {
+    cells: Vec<Vec<Region>>,
+}
+
+impl Default for SliderMap {
+    // Unrolls the A ring onto the top row and the B ring onto the bottom
+    // row, two columns per region, A1/B1 on the right
+    fn default() -> Self {
+        let cells = (0..CELLS)
+            .map(|cell| {
+                let ring = if cell % 2 == 0 { 'A' } else { 'B' };
+                Region::from_ring(ring, (cell / 4) as u8 + 1)
+                    .into_iter()
+                    .collect()
+            })
+            .collect();
+        SliderMap { cells }
+    }
+}
+
+impl SliderMap {
+    pub fn load(path: &str) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading slider map {}", path))?;
+        Self::from_toml(&text).with_context(|| format!("invalid slider map {}", path))
+    }
+
+    // `cells = ["A1", "A1 E1", "", ...]`, one entry per cell
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut cells = None;
+        for entry in conf::parse(text)? {
+            match entry.key.as_str() {
+                "cells" => {
+                    let line = entry.line;
+                    let list = entry.strings()?;
+                    if list.len() != CELLS {
+                        bail!(
+                            "line {}: expected {} cells, got {}",
+                            line,
+                            CELLS,
+                            list.len()
+                        );
+                    }
+                    cells = Some(
+                        list.iter()
+                            .map(|regions| regions.split_whitespace().map(str::parse).collect())
+                            .collect::<Result<_, _>>()
+                            .with_context(|| format!("line {}", line))?,
+                    );
+                }
+                _ => return Err(entry.unknown()),
+            }
+        }
+        Ok(SliderMap {
+            cells: cells.ok_or_else(|| anyhow!("missing cells"))?,
+        })
+    }
+
+    pub fn pressures(&self, state: TouchState) -> [u8; CELLS] {
+        let mut pressures = [0u8; CELLS];
+        for (pressure, regions) in pressures.iter_mut().zip(&self.cells) {
+            if regions.iter().any(|&region| state.is_active(region)) {
+                *pressure = PRESSED;
+            }
+        }
+        pressures
+    }
+}
+
+pub fn encode(command: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![SYNC];
+    let mut sum = SYNC.wrapping_add(command).wrapping_add(payload.len() as u8);
+    let push = |frame: &mut Vec<u8>, byte: u8| {
+        if byte == SYNC || byte == ESCAPE {
+            frame.push(ESCAPE);
+            frame.push(byte - 1);
+        } else {
+            frame.push(byte);
+        }
+    };
+    push(&mut frame, command);
+    push(&mut frame, payload.len() as u8);
+    for &byte in payload {
+        sum = sum.wrapping_add(byte);
+        push(&mut frame, byte);
+    }
+    push(&mut frame, sum.wrapping_neg());
+    frame
+}
+
+// Reassembles host frames from a byte stream. Frames with a bad checksum
+// are dropped, and a SYNC always starts over.
+#[derive(Default)]
+struct Decoder {
+    body: Vec<u8>,
+    in_frame: bool,
+    escaped: bool,
+}
+
+impl Decoder {
+    // Returns (command, payload) once a whole frame has arrived
+    fn push(&mut self, byte: u8) -> Option<(u8, Vec<u8>)> {
+        if byte == SYNC {
+            self.body.clear();
+            self.in_frame = true;
+            self.escaped = false;
+            return None;
+        }
+        if !self.in_frame {
+            return None;
+        }
+        if byte == ESCAPE {
+            self.escaped = true;
+            return None;
+        }
+        let byte = if mem::take(&mut self.escaped) {
+            byte.wrapping_add(1)
+        } else {
+            byte
+        };
+        self.body.push(byte);
+        // command, length, payload, checksum
+        let len = *self.body.get(1)? as usize;
+        if self.body.len() < len + 3 {
+            return None;
+        }
+        self.in_frame = false;
+        let sum = self
+            .body
+            .iter()
+            .fold(SYNC, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0 {
+            tracing::debug!("Dropping slider frame with bad checksum");
+            return None;
+        }
+        Some((self.body[0], self.body[2..len + 2].to_vec()))
+    }
+}
+
+type SharedWriter = Arc<Mutex<Box<dyn Write + Send>>>;
+
+fn send(writer: &SharedWriter, frame: &[u8]) -> io::Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+// Turns slider host commands into the maimai commands the rest of the proxy
+// understands, answering the ones that need a reply itself
+struct SliderReader {
+    inner: Box<dyn Read + Send>,
+    writer: SharedWriter,
+    decoder: Decoder,
+    rset: Vec<u8>,
+    stat: Vec<u8>,
+    halt: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl SliderReader {
+    fn handle(&mut self, command: u8, payload: &[u8]) -> io::Result<()> {
+        match command {
+            CMD_RESET => {
+                tracing::info!("Slider reset");
+                self.pending.extend_from_slice(&self.rset);
+                send(&self.writer, &encode(CMD_RESET, &[]))
+            }
+            CMD_BOARD_INFO => {
+                let mut info = BOARD_INFO.to_vec();
+                info.resize(BOARD_INFO_LEN, 0);
+                send(&self.writer, &encode(CMD_BOARD_INFO, &info))
+            }
+            CMD_REPORT_START => {
+                self.pending.extend_from_slice(&self.stat);
+                Ok(())
+            }
+            CMD_REPORT_STOP => {
+                self.pending.extend_from_slice(&self.halt);
+                send(&self.writer, &encode(CMD_REPORT_STOP, &[]))
+            }
+            CMD_SET_LED => {
+                tracing::trace!("Dropping slider LED update ({} bytes)", payload.len());
+                Ok(())
+            }
+            other => {
+                tracing::debug!("Ignoring slider command {:#04x}", other);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for SliderReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0u8; 256];
+        while self.pending.is_empty() {
+            let n = self.inner.read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            for &byte in &raw[..n] {
+                if let Some((command, payload)) = self.decoder.push(byte) {
+                    self.handle(command, &payload)?;
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+// Takes the maimai touch frames the proxy forwards and sends slider reports
+struct SliderWriter {
+    writer: SharedWriter,
+    map: SliderMap,
+    // Packing of the frames the proxy forwards
+    packing: Packing,
+    open: u8,
+    frame_len: usize,
+    partial: Vec<u8>,
+}
+
+impl Write for SliderWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == self.open {
+                self.partial.clear();
+            }
+            self.partial.push(byte);
+            if self.partial.len() == self.frame_len && self.partial[0] == self.open {
+                let state = self.packing.decode(&self.partial[1..self.frame_len - 1]);
+                send(
+                    &self.writer,
+                    &encode(CMD_REPORT, &self.map.pressures(state)),
+                )?;
+                self.partial.clear();
+            } else if self.partial.len() >= self.frame_len {
+                self.partial.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+// Puts the slider protocol between the proxy and the ALLS endpoint
+pub fn translate(alls: &mut Endpoint, spec: &WireSpec, map: SliderMap, packing: Packing) {
+    let writer: SharedWriter = Arc::new(Mutex::new(mem::replace(
+        &mut alls.writer,
+        Box::new(io::sink()),
+    )));
+    let inner = mem::replace(&mut alls.reader, Box::new(io::empty()));
+    alls.reader = Box::new(SliderReader {
+        inner,
+        writer: writer.clone(),
+        decoder: Decoder::default(),
+        rset: spec.command(command::RSET),
+        stat: spec.command(command::STAT),
+        halt: spec.command(command::HALT),
+        pending: Vec::new(),
+    });
+    alls.writer = Box::new(SliderWriter {
+        writer,
+        map,
+        packing,
+        open: spec.adx.open as u8,
+        frame_len: spec.touch_frame_len,
+        partial: Vec::with_capacity(spec.touch_frame_len),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maitouch_protocol::touch::PAYLOAD_LEN;
+
+    fn decode_all(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut decoder = Decoder::default();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.push(byte))
+            .collect()
+    }
+
+    fn touching(names: &[&str]) -> TouchState {
+        let mut state = TouchState::default();
+        for name in names {
+            state.set(name.parse().unwrap(), true);
+        }
+        state
+    }
+
+    // What the slider side of a translated endpoint writes
+    #[derive(Clone, Default)]
+    struct Host(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Host {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Host {
+        fn frames(&self) -> Vec<(u8, Vec<u8>)> {
+            decode_all(&mem::take(&mut *self.0.lock().unwrap()))
+        }
+    }
+
+    fn translated(host_sends: Vec<u8>) -> (Endpoint, Host) {
+        let host = Host::default();
+        let mut alls = Endpoint::new(
+            Box::new(io::Cursor::new(host_sends)),
+            Box::new(host.clone()),
+        );
+        translate(
+            &mut alls,
+            &WireSpec::maimai(),
+            SliderMap::default(),
+            Packing::default(),
+        );
+        (alls, host)
+    }
+
+    #[test]
+    fn frames_sum_to_zero_and_escape_sync_and_escape() {
+        let payload = [0x01, SYNC, ESCAPE, 0x00];
+        let frame = encode(CMD_REPORT, &payload);
+        // The checksum comes out as a SYNC too, so it is escaped as well
+        assert_eq!(
+            frame,
+            [SYNC, CMD_REPORT, 0x04, 0x01, ESCAPE, 0xfe, ESCAPE, 0xfc, 0x00, ESCAPE, 0xfe]
+        );
+        let sum = [SYNC, CMD_REPORT, 0x04, 0x01, SYNC, ESCAPE, 0x00, SYNC]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        assert_eq!(sum, 0);
+        assert_eq!(decode_all(&frame), [(CMD_REPORT, payload.to_vec())]);
+    }
+
+    #[test]
+    fn a_bad_checksum_is_dropped_and_a_sync_starts_over() {
+        let mut bad = encode(CMD_RESET, &[]);
+        *bad.last_mut().unwrap() ^= 1;
+        let mut line = bad;
+        // Cut off by a SYNC, then a good one
+        line.extend([SYNC, CMD_SET_LED]);
+        line.extend(encode(CMD_REPORT_START, &[]));
+        assert_eq!(decode_all(&line), [(CMD_REPORT_START, vec![])]);
+    }
+
+    #[test]
+    fn the_default_map_unrolls_a_onto_the_top_row_and_b_onto_the_bottom() {
+        let map = SliderMap::default();
+        let pressures = map.pressures(touching(&["A1", "B8"]));
+        let pressed: Vec<usize> = (0..CELLS)
+            .filter(|&cell| pressures[cell] == PRESSED)
+            .collect();
+        assert_eq!(pressed, [0, 2, 29, 31]);
+        assert_eq!(map.pressures(touching(&["C1", "E1"])), [0; CELLS]);
+    }
+
+    #[test]
+    fn a_map_file_needs_every_cell() {
+        let mut cells = vec!["\"\""; CELLS];
+        cells[0] = "\"A1 E1\"";
+        let text = format!("cells = [{}]\n", cells.join(", "));
+        let map = SliderMap::from_toml(&text).unwrap();
+        assert_eq!(map.pressures(touching(&["E1"]))[0], PRESSED);
+        assert_eq!(map.pressures(touching(&["E1"]))[1], 0);
+        let short = format!("cells = [{}]\n", cells[..CELLS - 1].join(", "));
+        let err = SliderMap::from_toml(&short).err().unwrap();
+        assert_eq!(err.to_string(), "line 1: expected 32 cells, got 31");
+        assert!(SliderMap::from_toml("").is_err());
+        assert!(SliderMap::from_toml(&text.replace("A1 E1", "A1 Z9")).is_err());
+    }
+
+    #[test]
+    fn host_commands_come_out_as_maimai_commands() {
+        let mut sent = encode(CMD_RESET, &[]);
+        sent.extend(encode(CMD_BOARD_INFO, &[]));
+        sent.extend(encode(CMD_SET_LED, &[0; 96]));
+        sent.extend(encode(CMD_REPORT_START, &[]));
+        sent.extend(encode(CMD_REPORT_STOP, &[]));
+        let (mut alls, host) = translated(sent);
+        let mut commands = Vec::new();
+        alls.reader.read_to_end(&mut commands).unwrap();
+        assert_eq!(commands, b"{RSET}{STAT}{HALT}");
+        // Reset, board info and stop are answered right away, the LEDs not
+        let answers = host.frames();
+        let kinds: Vec<u8> = answers.iter().map(|(command, _)| *command).collect();
+        assert_eq!(kinds, [CMD_RESET, CMD_BOARD_INFO, CMD_REPORT_STOP]);
+        assert_eq!(answers[1].1.len(), BOARD_INFO_LEN);
+        assert!(answers[1].1.starts_with(BOARD_INFO));
+    }
+
+    #[test]
+    fn touch_frames_go_out_as_slider_reports() {
+        let (mut alls, host) = translated(Vec::new());
+        let spec = WireSpec::maimai();
+        let mut payload = [0u8; PAYLOAD_LEN];
+        Packing::default().encode_into(touching(&["A2"]), &mut payload);
+        let frame = spec.adx.wrap(&payload);
+        // Split across writes, after a stray byte
+        alls.writer.write_all(b"x").unwrap();
+        alls.writer.write_all(&frame[..4]).unwrap();
+        alls.writer.write_all(&frame[4..]).unwrap();
+        let reports = host.frames();
+        assert_eq!(reports.len(), 1);
+        let (command, pressures) = &reports[0];
+        assert_eq!(*command, CMD_REPORT);
+        let pressed: Vec<usize> = (0..CELLS)
+            .filter(|&cell| pressures[cell] == PRESSED)
+            .collect();
+        assert_eq!(pressed, [4, 6]);
+    }
+}
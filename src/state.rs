@@ -0,0 +1,128 @@
+use std::sync::atomic::{fence, AtomicU64, AtomicU8, Ordering};
+
+// The latest touch frame, written by one thread and polled by any number
+// of others. A seqlock: the sequence is odd while a store is in progress,
+// and readers retry if it moved under them, so they never block the
+// writer or each other and never see half of one frame and half of the
+// next. The version is the number of stores so far.
+pub struct SharedTouchState {
+    sequence: AtomicU64,
+    frame: Vec<AtomicU8>,
+}
+
+impl SharedTouchState {
+    pub fn new(initial: &[u8]) -> Self {
+        SharedTouchState {
+            sequence: AtomicU64::new(0),
+            frame: initial.iter().map(|&byte| AtomicU8::new(byte)).collect(),
+        }
+    }
+
+    // Only one thread may store at a time
+    pub fn store(&self, frame: &[u8]) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (slot, &byte) in self.frame.iter().zip(frame) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    // Copies the frame out if it was stored since `last_seen`, updating it
+    pub fn load_if_newer(&self, last_seen: &mut u64, out: &mut [u8]) -> bool {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before / 2 == *last_seen {
+                return false;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            for (byte, slot) in out.iter_mut().zip(&self.frame) {
+                *byte = slot.load(Ordering::Relaxed);
+            }
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                *last_seen = before / 2;
+                return true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const LEN: usize = 9;
+
+    #[test]
+    fn loads_what_was_stored() {
+        let state = SharedTouchState::new(&[0; LEN]);
+        let mut seen = 0;
+        let mut out = [0xff; LEN];
+        assert!(!state.load_if_newer(&mut seen, &mut out));
+        assert_eq!(out, [0xff; LEN]);
+
+        let frame = *b"(\x01\x02\x03\x04\x05\x06\x07)";
+        state.store(&frame);
+        assert!(state.load_if_newer(&mut seen, &mut out));
+        assert_eq!((out, seen), (frame, 1));
+        // Nothing new until the next store
+        assert!(!state.load_if_newer(&mut seen, &mut out));
+
+        state.store(&[0; LEN]);
+        state.store(&frame);
+        assert!(state.load_if_newer(&mut seen, &mut out));
+        assert_eq!((out, seen), (frame, 3));
+    }
+
+    #[test]
+    fn a_new_reader_sees_the_initial_frame_once_stored() {
+        let state = SharedTouchState::new(b"(\x00\x00\x00\x00\x00\x00\x00)");
+        let mut out = [0; LEN];
+        // A reader that has never seen a version starts from one that
+        // can't match
+        let mut seen = u64::MAX;
+        assert!(state.load_if_newer(&mut seen, &mut out));
+        assert_eq!((&out, seen), (b"(\x00\x00\x00\x00\x00\x00\x00)", 0));
+    }
+
+    #[test]
+    fn readers_never_see_a_torn_frame() {
+        const STORES: u64 = 200_000;
+        let state = Arc::new(SharedTouchState::new(&[0; LEN]));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    let mut seen = 0;
+                    let mut out = [0; LEN];
+                    let mut loads = 0u64;
+                    while seen < STORES {
+                        let last = seen;
+                        if !state.load_if_newer(&mut seen, &mut out) {
+                            continue;
+                        }
+                        loads += 1;
+                        // Every byte of store n is n, so a frame mixing two
+                        // stores shows up as differing bytes
+                        assert!(out.iter().all(|&byte| byte == out[0]), "{:?}", out);
+                        assert_eq!(out[0], seen as u8, "version {}", seen);
+                        assert!(seen > last);
+                    }
+                    loads
+                })
+            })
+            .collect();
+        for n in 1..=STORES {
+            state.store(&[n as u8; LEN]);
+        }
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+    }
+}
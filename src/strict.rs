@@ -0,0 +1,207 @@
+use anyhow::anyhow;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Payload bytes a config response must echo from its command
+const ECHOED_PREFIX: usize = 2;
+
+// Conditions the proxy normally tolerates and --strict makes fatal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    // A touch packet that isn't one frame long
+    MalformedFrame,
+    // Bytes outside any packet, or a packet where none belongs
+    UnexpectedPacket,
+    // A response that ended before its close delimiter
+    ShortRead,
+    // A command longer than the wire spec allows
+    CommandTooLong,
+    // A response that doesn't answer the command it follows
+    MismatchedResponse,
+    // The ALLS kept getting the same frame because the ADX went quiet
+    StaleFrame,
+}
+
+impl Violation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Violation::MalformedFrame => "malformed frame",
+            Violation::UnexpectedPacket => "unexpected packet",
+            Violation::ShortRead => "short read",
+            Violation::CommandTooLong => "command too long",
+            Violation::MismatchedResponse => "mismatched response",
+            Violation::StaleFrame => "stale frame",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    FromAlls,
+    FromAdx,
+}
+
+struct Packet {
+    at: Duration,
+    direction: Direction,
+    bytes: Vec<u8>,
+}
+
+// The last packets seen in both directions, kept so a violation can be
+// reported along with the traffic that led up to it. Shared by the
+// streaming threads, so recording takes a lock; it is only created
+// under --strict.
+pub struct Strict {
+    start: Instant,
+    capacity: usize,
+    packets: Mutex<VecDeque<Packet>>,
+}
+
+impl Strict {
+    pub fn new(capacity: usize) -> Self {
+        Strict {
+            start: Instant::now(),
+            capacity,
+            packets: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        let mut packets = self.packets.lock().unwrap();
+        if packets.len() == self.capacity {
+            packets.pop_front();
+        }
+        if self.capacity > 0 {
+            packets.push_back(Packet {
+                at: self.start.elapsed(),
+                direction,
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+
+    // Logs the violation with the transcript and returns the error to abort with
+    pub fn violation(&self, violation: Violation, detail: impl AsRef<str>) -> anyhow::Error {
+        let detail = detail.as_ref();
+        tracing::error!(
+            "!!! Strict mode: {}: {}\n{}",
+            violation.name(),
+            detail,
+            self.transcript()
+        );
+        anyhow!("strict mode: {}: {}", violation.name(), detail)
+    }
+
+    fn transcript(&self) -> String {
+        let packets = self.packets.lock().unwrap();
+        let mut out = format!("Last {} packets:", packets.len());
+        for packet in packets.iter() {
+            let direction = match packet.direction {
+                Direction::FromAlls => "ALLS>",
+                Direction::FromAdx => "ADX> ",
+            };
+            let _ = write!(
+                out,
+                "\n{:>12.3?} {} {:<48} {}",
+                packet.at,
+                direction,
+                hex(&packet.bytes),
+                printable(&packet.bytes)
+            );
+        }
+        out
+    }
+}
+
+// Whether a config response echoes the command it should answer
+pub fn answers(command: &[u8], response: &[u8]) -> bool {
+    let payload = |packet: &[u8]| -> Vec<u8> {
+        packet
+            .iter()
+            .skip(1)
+            .take(packet.len().saturating_sub(2))
+            .take(ECHOED_PREFIX)
+            .copied()
+            .collect()
+    };
+    payload(command) == payload(response)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex.join(" ")
+}
+
+pub fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logcapture::capturing;
+
+    #[test]
+    fn a_response_answers_the_command_it_echoes() {
+        assert!(answers(b"{LAr2}", b"(LAr2)"));
+        // Only the first two payload bytes are echoed back
+        assert!(answers(b"{LAk5}", b"(LAk9)"));
+        assert!(!answers(b"{LAr2}", b"(RAr2)"));
+        assert!(!answers(b"{LAr2}", b"(L)"));
+        assert!(answers(b"{}", b"()"));
+    }
+
+    #[test]
+    fn bytes_are_shown_as_hex_and_printable_text() {
+        assert_eq!(hex(b"(A\x00\xff)"), "28 41 00 ff 29");
+        assert_eq!(printable(b"(A\x00 \xff)"), "(A...)");
+        assert_eq!(hex(b""), "");
+    }
+
+    #[test]
+    fn the_transcript_keeps_the_last_packets_only() {
+        let strict = Strict::new(2);
+        strict.record(Direction::FromAlls, b"{STAT}");
+        strict.record(Direction::FromAdx, b"(A)");
+        strict.record(Direction::FromAlls, b"{HALT}");
+        let transcript = strict.transcript();
+        let lines: Vec<&str> = transcript.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "Last 2 packets:");
+        assert!(lines[1].contains("ADX>  28 41 29"), "{}", lines[1]);
+        assert!(lines[1].ends_with(" (A)"), "{}", lines[1]);
+        assert!(lines[2].contains("ALLS> 7b 48 41 4c 54 7d"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn nothing_is_kept_without_room() {
+        let strict = Strict::new(0);
+        strict.record(Direction::FromAdx, b"(A)");
+        assert_eq!(strict.transcript(), "Last 0 packets:");
+    }
+
+    #[test]
+    fn a_violation_logs_the_transcript_and_names_itself() {
+        capturing(|log| {
+            let strict = Strict::new(4);
+            strict.record(Direction::FromAdx, b"(A)");
+            let err = strict.violation(Violation::ShortRead, "(LA");
+            assert_eq!(err.to_string(), "strict mode: short read: (LA");
+            let logged = log.take();
+            assert_eq!(logged[0], "!!! Strict mode: short read: (LA");
+            assert_eq!(logged[1], "Last 1 packets:");
+            assert_eq!(logged.len(), 3);
+        });
+    }
+}
@@ -0,0 +1,220 @@
+use crate::conf;
+use crate::handshake::parse_pattern;
+use anyhow::{bail, Context, Result};
+use maitouch_protocol::command::{self, CommandKind};
+use maitouch_protocol::framing::PacketDelimiter;
+use maitouch_protocol::pattern;
+use std::fs;
+
+// Answers to config commands the board doesn't implement, from a file of
+//
+//     respond = ["{LBv?}", "(LBv?10)"]
+//     respond_and_forward = ["{XVER}", "(XVER0100)"]
+//
+// `?` in a command matches any byte, and each `?` in the answer is filled
+// with the next byte the command matched that way. `\xNN`, `\?` and `\\`
+// work as in handshake files. A `respond` command never reaches the ADX;
+// a `respond_and_forward` one does, and whatever the ADX makes of it is
+// thrown away.
+pub struct Synthesizer {
+    entries: Vec<Canned>,
+}
+
+struct Canned {
+    command: Vec<Option<u8>>,
+    response: Vec<Option<u8>>,
+    forward: bool,
+}
+
+pub struct Answer {
+    pub response: Vec<u8>,
+    pub forward: bool,
+}
+
+impl Synthesizer {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("reading synthesized responses {}", path))?;
+        let synthesizer = Self::from_toml(&text)
+            .with_context(|| format!("invalid synthesized responses {}", path))?;
+        tracing::info!(
+            "Synthesizing responses to {} command patterns from {}",
+            synthesizer.entries.len(),
+            path
+        );
+        Ok(synthesizer)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for entry in conf::parse(text)? {
+            let forward = match entry.key.as_str() {
+                "respond" => false,
+                "respond_and_forward" => true,
+                _ => return Err(entry.unknown()),
+            };
+            let line = entry.line;
+            let [command, response] = &entry.strings()?[..] else {
+                bail!("line {}: expected [<command>, <response>]", line);
+            };
+            let command = parse_pattern(command, true)
+                .with_context(|| format!("line {}: bad command", line))?;
+            let response = parse_pattern(response, true)
+                .with_context(|| format!("line {}: bad response", line))?;
+            if pattern::open_count(&response) > pattern::open_count(&command) {
+                bail!(
+                    "line {}: the response has more ? than the command has to fill them",
+                    line
+                );
+            }
+            entries.push(Canned {
+                command,
+                response,
+                forward,
+            });
+        }
+        Ok(Synthesizer { entries })
+    }
+
+    // The answer for a config command, if any entry covers it. HALT, STAT
+    // and RSET are always left to the board.
+    pub fn answer(&self, alls: &PacketDelimiter, packet: &[u8]) -> Option<Answer> {
+        if command::classify(alls, packet) != CommandKind::Config {
+            return None;
+        }
+        self.entries.iter().find_map(|canned| {
+            Some(Answer {
+                response: pattern::fill(&canned.response, &canned.command, packet)?,
+                forward: canned.forward,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::WireSpec;
+
+    fn answer(synthesizer: &Synthesizer, packet: &[u8]) -> Option<(Vec<u8>, bool)> {
+        let answer = synthesizer.answer(&WireSpec::maimai().alls, packet)?;
+        Some((answer.response, answer.forward))
+    }
+
+    #[test]
+    fn a_covered_command_is_answered_with_its_wildcards_echoed() {
+        let synthesizer = Synthesizer::from_toml(
+            "respond = [\"{LBv?}\", \"(LBv?10)\"]\n\
+             respond_and_forward = [\"{X??R}\", \"(X?-?)\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            answer(&synthesizer, b"{LBvA}"),
+            Some((b"(LBvA10)".to_vec(), false))
+        );
+        assert_eq!(
+            answer(&synthesizer, b"{LBv7}"),
+            Some((b"(LBv710)".to_vec(), false))
+        );
+        assert_eq!(
+            answer(&synthesizer, b"{XabR}"),
+            Some((b"(Xa-b)".to_vec(), true))
+        );
+        // Other config commands go to the board as usual
+        assert_eq!(answer(&synthesizer, b"{LAr2}"), None);
+        assert_eq!(answer(&synthesizer, b"{LBvAB}"), None);
+    }
+
+    #[test]
+    fn the_first_entry_that_covers_a_command_answers_it() {
+        let synthesizer = Synthesizer::from_toml(
+            "respond = [\"{LBv1}\", \"(first)\"]\n\
+             respond_and_forward = [\"{LBv?}\", \"(second)\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            answer(&synthesizer, b"{LBv1}"),
+            Some((b"(first)".to_vec(), false))
+        );
+        assert_eq!(
+            answer(&synthesizer, b"{LBv2}"),
+            Some((b"(second)".to_vec(), true))
+        );
+    }
+
+    #[test]
+    fn halt_stat_and_rset_are_left_to_the_board() {
+        let synthesizer = Synthesizer::from_toml(
+            "respond = [\"{HALT}\", \"(HALT)\"]\n\
+             respond = [\"{STAT}\", \"(STAT)\"]\n\
+             respond = [\"{RSET}\", \"(RSET)\"]\n\
+             respond = [\"{????}\", \"(any)\"]\n",
+        )
+        .unwrap();
+        for command in [b"{HALT}", b"{STAT}", b"{RSET}"] {
+            assert_eq!(answer(&synthesizer, command), None);
+        }
+        assert_eq!(
+            answer(&synthesizer, b"{LAr2}"),
+            Some((b"(any)".to_vec(), false))
+        );
+    }
+
+    #[test]
+    fn escapes_match_literal_bytes() {
+        let synthesizer =
+            Synthesizer::from_toml("respond = [\"{\\x01\\?}\", \"(\\x02\\\\)\"]\n").unwrap();
+        assert_eq!(
+            answer(&synthesizer, b"{\x01?}"),
+            Some((b"(\x02\\)".to_vec(), false))
+        );
+        assert_eq!(answer(&synthesizer, b"{\x01x}"), None);
+    }
+
+    #[test]
+    fn a_bad_entry_is_reported_by_line() {
+        let error = |text: &str| format!("{:#}", Synthesizer::from_toml(text).err().unwrap());
+        assert_eq!(
+            error("respond = [\"{LBv?}\", \"(LBv)\"]\nanswer = [\"{A}\", \"(A)\"]\n"),
+            "line 2: unknown key answer"
+        );
+        assert_eq!(
+            error("respond = [\"{LBv?}\"]\n"),
+            "line 1: expected [<command>, <response>]"
+        );
+        assert_eq!(
+            error("respond = [\"{LBv?}\", \"(LBv??)\"]\n"),
+            "line 1: the response has more ? than the command has to fill them"
+        );
+        assert_eq!(
+            error("respond = [\"{LBv\\z}\", \"(LBv)\"]\n"),
+            "line 1: bad command: bad escape \\z"
+        );
+        assert_eq!(
+            error("respond = [\"{LBv}\", \"(LBv\\x0)\"]\n"),
+            "line 1: bad response: bad escape \\x0)"
+        );
+    }
+
+    #[test]
+    fn a_file_is_loaded_or_its_problem_named() {
+        let path = std::env::temp_dir().join(format!("maitouch-synth-{}.toml", std::process::id()));
+        let name = path.to_str().unwrap();
+        std::fs::write(&path, "respond = [\"{LBv?}\", \"(LBv?10)\"]\n").unwrap();
+        let loaded = Synthesizer::load(name);
+        std::fs::write(&path, "respond = \"{LBv?}\"\n").unwrap();
+        let invalid = Synthesizer::load(name);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            answer(&loaded.unwrap(), b"{LBv3}"),
+            Some((b"(LBv310)".to_vec(), false))
+        );
+        assert_eq!(
+            format!("{:#}", invalid.err().unwrap()),
+            format!("invalid synthesized responses {}: line 1: bad value for respond: expected an array", name)
+        );
+        assert!(format!("{:#}", Synthesizer::load(name).err().unwrap())
+            .starts_with(&format!("reading synthesized responses {}: ", name)));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::ports::{self, Endpoint};
+use anyhow::{Context, Result};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// ALLS name prefix that takes the game over TCP, one client at a time
+pub const LISTEN_PREFIX: &str = "tcp-listen://";
+
+// Accepts game clients for the ALLS side. Each connection gets a fresh
+// handshake; the proxy only goes back to accept() once the last client
+// is gone, so others wait in the backlog meanwhile.
+pub struct AllsListener {
+    listener: TcpListener,
+    idle: Option<Duration>,
+}
+
+impl AllsListener {
+    pub fn bind(name: &str, idle: Option<Duration>) -> Result<Self> {
+        let addr = name.strip_prefix(LISTEN_PREFIX).unwrap_or(name);
+        let listener = TcpListener::bind(addr).with_context(|| format!("listening on {}", addr))?;
+        Ok(AllsListener { listener, idle })
+    }
+
+    pub fn accept(&self) -> Result<Endpoint> {
+        tracing::info!("Waiting for the game on {}", self.listener.local_addr()?);
+        let (stream, peer) = self.listener.accept()?;
+        // Frames are small and latency bound, so never let them be batched
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(ports::PORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(ports::PORT_TIMEOUT))?;
+        tracing::info!("Game connected from {}", peer);
+        let activity = Arc::new(Activity {
+            since: Instant::now(),
+            last_us: AtomicU64::new(0),
+        });
+        let reader = ClientReader {
+            stream: stream.try_clone()?,
+            activity: activity.clone(),
+            idle: self.idle,
+        };
+        let writer = ClientWriter { stream, activity };
+        Ok(Endpoint::new(Box::new(reader), Box::new(writer)))
+    }
+}
+
+// When either half last moved data. The game is silent while streaming,
+// but the frames going to it keep the connection from counting as idle.
+struct Activity {
+    since: Instant,
+    last_us: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last_us
+            .store(self.since.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.since
+            .elapsed()
+            .saturating_sub(Duration::from_micros(self.last_us.load(Ordering::Relaxed)))
+    }
+}
+
+// Reads like a serial port: timeouts come back as TimedOut, and the client
+// going away (or idling past the limit) as UnexpectedEof
+struct ClientReader {
+    stream: TcpStream,
+    activity: Arc<Activity>,
+    idle: Option<Duration>,
+}
+
+impl Read for ClientReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                self.activity.touch();
+                Ok(n)
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                let idle_for = self.activity.idle_for();
+                if self.idle.is_some_and(|limit| idle_for >= limit) {
+                    tracing::warn!("Game client idle for {:.0?}, dropping it", idle_for);
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Err(ErrorKind::TimedOut.into())
+            }
+            Err(err) if is_disconnect(&err) => Err(ErrorKind::UnexpectedEof.into()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Frames written after the client has gone are dropped; the reader is
+// the one that reports the disconnect, so it ends the session cleanly
+struct ClientWriter {
+    stream: TcpStream,
+    activity: Arc<Activity>,
+}
+
+impl Write for ClientWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stream.write(buf) {
+            Ok(n) => {
+                self.activity.touch();
+                Ok(n)
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ErrorKind::TimedOut.into()),
+            Err(err) if is_disconnect(&err) => Ok(buf.len()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn listener(idle: Option<Duration>) -> (AllsListener, String) {
+        let listener = AllsListener::bind("tcp-listen://127.0.0.1:0", idle).unwrap();
+        let addr = listener.listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
+    fn read_some(endpoint: &mut Endpoint) -> io::Result<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        let read = endpoint.reader.read(&mut buf)?;
+        Ok(buf[..read].to_vec())
+    }
+
+    #[test]
+    fn clients_are_taken_one_after_another_each_on_its_own_connection() {
+        let (listener, addr) = listener(None);
+        let mut first = TcpStream::connect(&addr).unwrap();
+        // Waits in the backlog until the first one is done with
+        let mut second = TcpStream::connect(&addr).unwrap();
+
+        let mut alls = listener.accept().unwrap();
+        first.write_all(b"{LAr2}").unwrap();
+        assert_eq!(read_some(&mut alls).unwrap(), b"{LAr2}");
+        alls.writer.write_all(b"(LAr2)").unwrap();
+        let mut answer = [0u8; 6];
+        first.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer, b"(LAr2)");
+        drop(first);
+        assert_eq!(
+            read_some(&mut alls).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        // Frames still on their way to the client that left are dropped
+        alls.writer.write_all(b"(frame)").unwrap();
+        drop(alls);
+
+        let mut alls = listener.accept().unwrap();
+        second.write_all(b"{RSET}").unwrap();
+        assert_eq!(read_some(&mut alls).unwrap(), b"{RSET}");
+    }
+
+    #[test]
+    fn a_quiet_read_times_out_like_a_serial_port() {
+        let (listener, addr) = listener(None);
+        let _game = TcpStream::connect(&addr).unwrap();
+        let mut alls = listener.accept().unwrap();
+        let started = Instant::now();
+        assert_eq!(
+            read_some(&mut alls).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        assert!(started.elapsed() >= ports::PORT_TIMEOUT);
+    }
+
+    #[test]
+    fn an_idle_client_is_dropped() {
+        let (listener, addr) = listener(Some(Duration::from_millis(500)));
+        let mut game = TcpStream::connect(&addr).unwrap();
+        let mut alls = listener.accept().unwrap();
+        crate::logcapture::capturing(|log| {
+            let err = read_some(&mut alls).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+            let logged = log.take();
+            assert_eq!(logged.len(), 1);
+            assert!(
+                logged[0].starts_with("Game client idle for 1s"),
+                "{:?}",
+                logged
+            );
+        });
+        // And it sees the connection closed
+        let mut buf = [0u8; 1];
+        assert_eq!(game.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn frames_going_out_keep_a_client_from_counting_as_idle() {
+        let idle = Duration::from_millis(1500);
+        let (listener, addr) = listener(Some(idle));
+        let _game = TcpStream::connect(&addr).unwrap();
+        let mut alls = listener.accept().unwrap();
+        let mut writer = std::mem::replace(&mut alls.writer, Box::new(io::sink()));
+        thread::scope(|scope| {
+            let streaming = scope.spawn(move || {
+                let started = Instant::now();
+                while started.elapsed() < idle * 2 {
+                    writer.write_all(b"(frame)").unwrap();
+                    thread::sleep(Duration::from_millis(2));
+                }
+            });
+            let started = Instant::now();
+            while started.elapsed() < idle * 2 {
+                assert_eq!(
+                    read_some(&mut alls).unwrap_err().kind(),
+                    ErrorKind::TimedOut
+                );
+            }
+            streaming.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,288 @@
+use crate::conf::{self, Value};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::time::Duration;
+
+// A command's deadline is its slowest answers times this much
+const MARGIN: u32 = 2;
+// Nothing gets less than this, so scheduling noise can't miss a deadline
+const MIN_TIMEOUT: Duration = Duration::from_millis(20);
+// Misses in a row after which a command's profile entry is given up on
+const MAX_MISSES: u32 = 3;
+
+// How long the ADX took to answer each config command, as written by
+// --learn-timeouts:
+//
+//     latency = ["{LAr2}", 4210, 37]
+//
+// is the 99th percentile in microseconds over that many answers.
+pub struct LatencyLearner {
+    path: String,
+    samples: BTreeMap<String, Vec<Duration>>,
+    write_failed: bool,
+}
+
+impl LatencyLearner {
+    pub fn new(path: &str) -> Self {
+        tracing::info!("Learning ADX response times into {}", path);
+        LatencyLearner {
+            path: path.to_string(),
+            samples: BTreeMap::new(),
+            write_failed: false,
+        }
+    }
+
+    // Rewrites the file on every answer, so it's complete however the
+    // proxy goes down. Config exchanges are far too rare for that to cost.
+    pub fn record(&mut self, command: &[u8], latency: Duration) {
+        // The file's strings have no escapes
+        if command.contains(&b'"') {
+            return;
+        }
+        self.samples
+            .entry(String::from_utf8_lossy(command).into_owned())
+            .or_default()
+            .push(latency);
+        let mut text =
+            String::from("# ADX response times: [command, 99th percentile in us, answers]\n");
+        for (command, samples) in &self.samples {
+            let _ = writeln!(
+                text,
+                "latency = [\"{}\", {}, {}]",
+                command,
+                p99(samples).as_micros(),
+                samples.len()
+            );
+        }
+        match fs::write(&self.path, text) {
+            Ok(()) => self.write_failed = false,
+            Err(err) if !std::mem::replace(&mut self.write_failed, true) => {
+                tracing::warn!("Couldn't write response times to {}: {}", self.path, err)
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn p99(samples: &[Duration]) -> Duration {
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    sorted[(sorted.len() * 99).div_ceil(100) - 1]
+}
+
+struct Learned {
+    timeout: Duration,
+    p99: Duration,
+    answers: usize,
+    misses: u32,
+}
+
+// Per-command response deadlines from a --learn-timeouts file. Commands it
+// doesn't cover are left to the usual rule.
+pub struct TimeoutProfile {
+    commands: BTreeMap<Vec<u8>, Learned>,
+}
+
+impl TimeoutProfile {
+    pub fn load(path: &str) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading response times {}", path))?;
+        let profile =
+            Self::from_toml(&text).with_context(|| format!("invalid response times {}", path))?;
+        tracing::info!(
+            "Response deadlines for {} commands from {}",
+            profile.commands.len(),
+            path
+        );
+        Ok(profile)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut commands = BTreeMap::new();
+        for entry in conf::parse(text)? {
+            if entry.key != "latency" {
+                return Err(entry.unknown());
+            }
+            let line = entry.line;
+            let fields = entry.list()?;
+            let [Value::Str(command), Value::Int(us), Value::Int(answers)] = &fields[..] else {
+                bail!(
+                    "line {}: expected [<command>, <microseconds>, <answers>]",
+                    line
+                );
+            };
+            let (Ok(us), Ok(answers)) = (u64::try_from(*us), usize::try_from(*answers)) else {
+                bail!("line {}: negative time or count", line);
+            };
+            let p99 = Duration::from_micros(us);
+            commands.insert(
+                command.as_bytes().to_vec(),
+                Learned {
+                    timeout: (p99 * MARGIN).max(MIN_TIMEOUT),
+                    p99,
+                    answers,
+                    misses: 0,
+                },
+            );
+        }
+        Ok(TimeoutProfile { commands })
+    }
+
+    pub fn timeout(&self, command: &[u8]) -> Option<Duration> {
+        self.commands.get(command).map(|learned| learned.timeout)
+    }
+
+    pub fn answered(&mut self, command: &[u8]) {
+        if let Some(learned) = self.commands.get_mut(command) {
+            learned.misses = 0;
+        }
+    }
+
+    // After a few misses in a row the board clearly isn't the one the
+    // profile was learned on, so the command goes back to the usual rule
+    pub fn missed(&mut self, command: &[u8]) {
+        let name = String::from_utf8_lossy(command);
+        let Some(learned) = self.commands.get_mut(command) else {
+            return;
+        };
+        learned.misses += 1;
+        tracing::warn!(
+            "No answer to {} within {:.0?}; the profile had it at {:.1?} over {} answers",
+            name,
+            learned.timeout,
+            learned.p99,
+            learned.answers
+        );
+        if learned.misses >= MAX_MISSES {
+            tracing::warn!(
+                "!!! {} missed its learned deadline {} times in a row, dropping it from the \
+                 profile; relearn with --learn-timeouts on this board",
+                name,
+                learned.misses
+            );
+            self.commands.remove(command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn learning(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("maitouch-timeouts-{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn p99_is_the_slowest_answer_but_one_in_a_hundred() {
+        let hundred: Vec<Duration> = (1..=100).rev().map(ms).collect();
+        assert_eq!(p99(&hundred), ms(99));
+        assert_eq!(p99(&[ms(3)]), ms(3));
+        // Under a hundred answers there's nothing to leave out
+        assert_eq!(p99(&[ms(5), ms(300), ms(4)]), ms(300));
+    }
+
+    #[test]
+    fn a_learned_file_loads_back_as_deadlines() {
+        let path = learning("roundtrip");
+        let mut learner = LatencyLearner::new(&path);
+        for latency in [4, 6, 5] {
+            learner.record(b"{LAr2}", ms(latency));
+        }
+        learner.record(b"{RAr2}", Duration::from_micros(300_500));
+        // Left out, as the file has no way to write it
+        learner.record(b"{L\"r2}", ms(1));
+        let text = fs::read_to_string(&path).unwrap();
+        let profile = TimeoutProfile::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            text,
+            "# ADX response times: [command, 99th percentile in us, answers]\n\
+             latency = [\"{LAr2}\", 6000, 3]\n\
+             latency = [\"{RAr2}\", 300500, 1]\n"
+        );
+        // 12ms, raised to the floor
+        assert_eq!(profile.timeout(b"{LAr2}"), Some(MIN_TIMEOUT));
+        assert_eq!(profile.timeout(b"{RAr2}"), Some(ms(601)));
+        assert_eq!(profile.timeout(b"{LBr2}"), None);
+    }
+
+    #[test]
+    fn a_file_that_cant_be_written_is_warned_about_once() {
+        let path = learning("missing/dir/file");
+        crate::logcapture::capturing(|log| {
+            let mut learner = LatencyLearner::new(&path);
+            learner.record(b"{LAr2}", ms(4));
+            learner.record(b"{LAr2}", ms(4));
+            let log = log.take();
+            assert_eq!(log.len(), 2, "{:?}", log);
+            assert!(log[1].starts_with(&format!("Couldn't write response times to {}: ", path)));
+        });
+    }
+
+    #[test]
+    fn deadlines_are_twice_the_p99_but_never_too_short() {
+        let profile = TimeoutProfile::from_toml(
+            "latency = [\"{LAr2}\", 150000, 40]\n\
+             latency = [\"{RAr2}\", 2000, 40]\n",
+        )
+        .unwrap();
+        assert_eq!(profile.timeout(b"{LAr2}"), Some(ms(300)));
+        assert_eq!(profile.timeout(b"{RAr2}"), Some(MIN_TIMEOUT));
+    }
+
+    #[test]
+    fn a_bad_profile_line_is_reported_by_number() {
+        let err = |text: &str| format!("{:#}", TimeoutProfile::from_toml(text).err().unwrap());
+        assert_eq!(
+            err("latency = [\"{LAr2}\", 4000, 3]\ntimeout = 5\n"),
+            "line 2: unknown key timeout"
+        );
+        assert_eq!(
+            err("latency = [\"{LAr2}\", 4000]\n"),
+            "line 1: expected [<command>, <microseconds>, <answers>]"
+        );
+        assert_eq!(
+            err("latency = [\"{LAr2}\", -4000, 3]\n"),
+            "line 1: negative time or count"
+        );
+    }
+
+    #[test]
+    fn a_board_far_slower_than_the_profile_loses_its_deadline() {
+        let mut profile = TimeoutProfile::from_toml("latency = [\"{LAr2}\", 1000, 50]\n").unwrap();
+        crate::logcapture::capturing(|log| {
+            profile.missed(b"{LAr2}");
+            profile.missed(b"{LAr2}");
+            // An answer in between starts the count again
+            profile.answered(b"{LAr2}");
+            profile.missed(b"{LAr2}");
+            profile.missed(b"{LAr2}");
+            assert_eq!(profile.timeout(b"{LAr2}"), Some(MIN_TIMEOUT));
+            assert_eq!(
+                log.take()[0],
+                "No answer to {LAr2} within 20ms; the profile had it at 1.0ms over 50 answers"
+            );
+
+            profile.missed(b"{LAr2}");
+            assert_eq!(
+                log.take().last().unwrap(),
+                "!!! {LAr2} missed its learned deadline 3 times in a row, dropping it from the \
+                 profile; relearn with --learn-timeouts on this board"
+            );
+            assert_eq!(profile.timeout(b"{LAr2}"), None);
+            // Commands it never covered are left alone
+            profile.missed(b"{RAr2}");
+            assert!(log.take().is_empty());
+        });
+    }
+}
@@ -0,0 +1,326 @@
+use crate::clock::MonotonicClock;
+use crate::conf::{self, Value};
+use crate::handshake;
+use crate::ports;
+use crate::retry::{Retry, RetryPolicy};
+use crate::shell::Shell;
+use crate::strict;
+use crate::wire::WireSpec;
+use crate::{read_packet, read_response};
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::command::{self, CommandKind};
+use maitouch_protocol::pattern;
+use maitouch_protocol::touch::{Region, TouchState};
+use std::fs;
+use std::io::Cursor;
+use std::time::Duration;
+
+// The vectors for the stock link, shipped in the binary so check-vectors
+// needs nothing else
+pub const BUILTIN: &str = include_str!("../tests/vectors/maimai.toml");
+// How long check-vectors streams from the board
+const STREAM_TIME: Duration = Duration::from_secs(2);
+
+// What a vector's bytes should come out as
+#[derive(Debug)]
+pub enum Expected {
+    // From command::classify, for an ALLS packet
+    Command(CommandKind),
+    // Whether the bytes read as exactly one touch frame
+    Frame(bool),
+    // The regions a touch frame decodes to
+    Touch(TouchState),
+    // The ADX's answer to a config command
+    Response(Vec<Option<u8>>),
+}
+
+#[derive(Debug)]
+pub struct Vector {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub expected: Expected,
+}
+
+// A manifest is a flat TOML file of `name = ["<kind>", "<bytes>",
+// "<expected>"]` lines; tests/vectors/maimai.toml describes the kinds
+pub fn parse(text: &str) -> Result<Vec<Vector>> {
+    let mut vectors: Vec<Vector> = Vec::new();
+    for entry in conf::parse(text)? {
+        let line = entry.line;
+        let name = entry.key.clone();
+        if vectors.iter().any(|vector| vector.name == name) {
+            bail!("line {}: vector {} is defined twice", line, name);
+        }
+        let fields = entry
+            .list()?
+            .into_iter()
+            .map(Value::string)
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("line {}: bad vector {}", line, name))?;
+        let [kind, bytes, expected] = &fields[..] else {
+            bail!(
+                "line {}: vector {} should be [kind, bytes, expected]",
+                line,
+                name
+            );
+        };
+        let vector = Vector {
+            bytes: handshake::parse_bytes(bytes)?,
+            expected: parse_expected(kind, expected)?,
+            name,
+        };
+        vectors.push(vector);
+    }
+    Ok(vectors)
+}
+
+fn parse_expected(kind: &str, text: &str) -> Result<Expected> {
+    let bad = || anyhow!("unexpected {} for a {} vector", text, kind);
+    Ok(match kind {
+        "command" => Expected::Command(match text {
+            "halt" => CommandKind::Halt,
+            "stat" => CommandKind::Stat,
+            "reset" => CommandKind::Reset,
+            "config" => CommandKind::Config,
+            _ => return Err(bad()),
+        }),
+        "frame" => Expected::Frame(match text {
+            "valid" => true,
+            "invalid" => false,
+            _ => return Err(bad()),
+        }),
+        "touch" => {
+            let mut state = TouchState::default();
+            match text {
+                "none" => {}
+                "all" => Region::all().for_each(|region| state.set(region, true)),
+                _ => {
+                    for name in text.split_whitespace() {
+                        let region: Region = name.parse().map_err(|_| bad())?;
+                        state.set(region, true);
+                    }
+                }
+            }
+            Expected::Touch(state)
+        }
+        "response" => Expected::Response(handshake::parse_pattern(text, true)?),
+        _ => bail!(
+            "unknown vector kind {}, expected command, frame, touch or response",
+            kind
+        ),
+    })
+}
+
+// The manifest at `path`, or the built-in one
+pub fn load(path: Option<&str>) -> Result<Vec<Vector>> {
+    let text = match path {
+        Some(path) => {
+            fs::read_to_string(path).with_context(|| format!("reading vectors {}", path))?
+        }
+        None => BUILTIN.to_string(),
+    };
+    parse(&text).with_context(|| format!("invalid vectors {}", path.unwrap_or("(built-in)")))
+}
+
+// Runs a vector through what the proxy does with such bytes
+pub fn check(spec: &WireSpec, vector: &Vector) -> Result<()> {
+    let bytes = &vector.bytes;
+    match &vector.expected {
+        Expected::Command(kind) => {
+            let got = command::classify(&spec.alls, bytes);
+            if got != *kind {
+                bail!("classified as {:?}, expected {:?}", got, kind);
+            }
+        }
+        Expected::Frame(valid) => {
+            let got = read_frame(spec, bytes).is_ok();
+            if got != *valid {
+                bail!(
+                    "read as {} frame",
+                    if got { "a valid" } else { "an invalid" }
+                );
+            }
+        }
+        Expected::Touch(state) => {
+            let frame = read_frame(spec, bytes)?;
+            let payload = &frame[1..frame.len() - 1];
+            let got = TouchState::decode(payload);
+            if got != *state {
+                bail!("decoded as {:?}, expected {:?}", got, state);
+            }
+            let mut encoded = payload.to_vec();
+            got.encode_into(&mut encoded);
+            if encoded != payload {
+                bail!("re-encoded as {}", strict::hex(&encoded));
+            }
+        }
+        Expected::Response(response) => {
+            if command::classify(&spec.alls, bytes) != CommandKind::Config {
+                bail!("isn't a config command");
+            }
+            if response.len() < 2
+                || response[0] != Some(spec.adx.open as u8)
+                || response[response.len() - 1] != Some(spec.adx.close as u8)
+            {
+                bail!("expected response isn't between the ADX delimiters");
+            }
+            if let Some(len) = spec.response_len(bytes) {
+                if response.len() != len {
+                    bail!(
+                        "expected response isn't the {} bytes the wire spec says",
+                        len
+                    );
+                }
+            }
+            // The reader has to take exactly the answer, whatever the
+            // open positions hold
+            let answer: Vec<u8> = response.iter().map(|byte| byte.unwrap_or(b'0')).collect();
+            let mut reader = Cursor::new(&answer);
+            let mut read = Vec::new();
+            read_response(&mut read, &mut reader, spec, bytes, &no_retry())?;
+            if read != answer || !strict::answers(bytes, &read) {
+                bail!("{} doesn't read as the answer", strict::printable(&answer));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reads `bytes` as the ADX's stream, which must hold exactly one frame
+fn read_frame(spec: &WireSpec, bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Cursor::new(bytes);
+    let mut frame = Vec::new();
+    let stray = read_packet(&mut frame, &mut reader, &spec.adx, &no_retry())?;
+    if stray > 0 || (reader.position() as usize) < bytes.len() {
+        bail!("bytes outside the frame");
+    }
+    if frame.len() != spec.touch_frame_len || frame.last() != Some(&(spec.adx.close as u8)) {
+        bail!("packet of {} bytes", frame.len());
+    }
+    Ok(frame)
+}
+
+fn no_retry() -> Retry<'static> {
+    Retry::until(
+        RetryPolicy {
+            backoff: Duration::ZERO,
+        },
+        &MonotonicClock,
+        None,
+    )
+}
+
+// check-vectors: runs every vector, then the board's side of them against
+// the ADX on `adx` — the config exchanges, and a short stream whose frames
+// must all be well-formed. Returns whether everything passed.
+pub fn run(adx: &str, manifest: Option<&str>) -> Result<bool> {
+    let spec = WireSpec::maimai();
+    let vectors = load(manifest)?;
+    let mut failed = 0;
+    let mut report = |name: &str, result: Result<()>| match result {
+        Ok(()) => println!("ok    {}", name),
+        Err(err) => {
+            failed += 1;
+            println!("FAIL  {}: {:#}", name, err);
+        }
+    };
+    for vector in &vectors {
+        report(&vector.name, check(&spec, vector));
+    }
+    let mut shell = Shell::new(adx, ports::open(adx)?)?;
+    for vector in &vectors {
+        let Expected::Response(response) = &vector.expected else {
+            continue;
+        };
+        let answered = shell
+            .exchange(&vector.bytes)
+            .and_then(|answer| match answer {
+                Some(answer) if pattern::matches(response, &answer) => Ok(()),
+                Some(answer) => Err(anyhow!("the board answered {}", strict::printable(&answer))),
+                None => Err(anyhow!("the board didn't answer")),
+            });
+        report(&format!("live {}", vector.name), answered);
+    }
+    let streamed =
+        shell
+            .streaming(STREAM_TIME, |_| Ok(true))
+            .and_then(|(frames, malformed)| match (frames, malformed) {
+                (0, _) => Err(anyhow!("no frames in {:?}", STREAM_TIME)),
+                (_, 0) => Ok(()),
+                _ => Err(anyhow!(
+                    "{} of {} frames malformed",
+                    malformed,
+                    frames + malformed
+                )),
+            });
+    report("live stream", streamed);
+    println!("{} vectors, {} failed", vectors.len(), failed);
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_vectors_pass() {
+        let spec = WireSpec::maimai();
+        let vectors = parse(BUILTIN).unwrap();
+        assert!(vectors.len() > 30);
+        for vector in &vectors {
+            if let Err(err) = check(&spec, vector) {
+                panic!("vector {} failed: {:#}", vector.name, err);
+            }
+        }
+    }
+
+    #[test]
+    fn builtin_vectors_cover_every_kind() {
+        let vectors = parse(BUILTIN).unwrap();
+        let has = |wanted: fn(&Expected) -> bool| vectors.iter().any(|v| wanted(&v.expected));
+        assert!(has(|e| matches!(e, Expected::Command(CommandKind::Halt))));
+        assert!(has(|e| matches!(e, Expected::Command(CommandKind::Stat))));
+        assert!(has(|e| matches!(e, Expected::Command(CommandKind::Reset))));
+        assert!(has(|e| matches!(e, Expected::Command(CommandKind::Config))));
+        assert!(has(|e| matches!(e, Expected::Frame(true))));
+        assert!(has(|e| matches!(e, Expected::Frame(false))));
+        assert!(has(|e| matches!(e, Expected::Touch(_))));
+        assert!(has(|e| matches!(e, Expected::Response(_))));
+    }
+
+    #[test]
+    fn a_wrong_expectation_fails() {
+        let spec = WireSpec::maimai();
+        for line in [
+            r#"v = ["command", "{HALT}", "stat"]"#,
+            r#"v = ["frame", "(\x00\x00)", "valid"]"#,
+            r#"v = ["frame", "(\x00\x00\x00\x00\x00\x00\x00)", "invalid"]"#,
+            r#"v = ["touch", "(\x01\x00\x00\x00\x00\x00\x00)", "A2"]"#,
+            r#"v = ["touch", "(\x01\x00)", "A1"]"#,
+            r#"v = ["response", "{LAr2}", "(LAr2"]"#,
+            r#"v = ["response", "{LAr2}", "(LAr22)"]"#,
+            r#"v = ["response", "{LAr2}", "(RAr2)"]"#,
+            r#"v = ["response", "{STAT}", "(STAT)"]"#,
+        ] {
+            let vectors = parse(line).unwrap();
+            assert!(check(&spec, &vectors[0]).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_manifests() {
+        for text in [
+            r#"v = "command""#,
+            r#"v = ["command", "{HALT}"]"#,
+            r#"v = ["command", "{HALT}", "halt", "extra"]"#,
+            r#"v = ["command", "{HALT}", "stop"]"#,
+            r#"v = ["frame", "()", "maybe"]"#,
+            r#"v = ["touch", "()", "A9"]"#,
+            r#"v = ["packet", "()", "valid"]"#,
+            r#"v = ["command", "\q", "halt"]"#,
+            "v = [\"command\", \"{HALT}\", \"halt\"]\nv = [\"command\", \"{STAT}\", \"stat\"]",
+        ] {
+            assert!(parse(text).is_err(), "{}", text);
+        }
+    }
+}
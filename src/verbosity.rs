@@ -0,0 +1,176 @@
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+// From quietest to noisiest
+const LEVELS: &[LevelFilter] = &[
+    LevelFilter::ERROR,
+    LevelFilter::WARN,
+    LevelFilter::INFO,
+    LevelFilter::DEBUG,
+    LevelFilter::TRACE,
+];
+
+// The level logs are filtered at, which can be changed while running
+#[derive(Clone)]
+pub struct Verbosity {
+    handle: reload::Handle<LevelFilter, Registry>,
+}
+
+impl Verbosity {
+    pub fn new(handle: reload::Handle<LevelFilter, Registry>) -> Self {
+        Verbosity { handle }
+    }
+
+    pub fn current(&self) -> LevelFilter {
+        self.handle.clone_current().unwrap_or(LevelFilter::INFO)
+    }
+
+    // Moves one level noisier or quieter, staying within ERROR..TRACE
+    pub fn step(&self, noisier: bool) {
+        let current = self.current();
+        let index = LEVELS
+            .iter()
+            .position(|&level| level == current)
+            .unwrap_or(2);
+        let index = if noisier {
+            (index + 1).min(LEVELS.len() - 1)
+        } else {
+            index.saturating_sub(1)
+        };
+        self.set(LEVELS[index]);
+    }
+
+    pub fn set(&self, level: LevelFilter) {
+        // Logged at WARN from whichever side of the change can show it
+        let quieter = level < self.current();
+        if quieter {
+            tracing::warn!("Log level {}", level);
+        }
+        if let Err(err) = self.handle.reload(level) {
+            tracing::warn!("Couldn't change the log level: {}", err);
+        } else if !quieter {
+            tracing::warn!("Log level {}", level);
+        }
+    }
+}
+
+// SIGUSR1 makes the logs noisier and SIGUSR2 quieter, one level at a time
+#[cfg(unix)]
+pub fn on_signals(verbosity: Verbosity) -> std::io::Result<()> {
+    let read_fd = crate::shutdown::forward(&[libc::SIGUSR1, libc::SIGUSR2])?;
+    std::thread::Builder::new()
+        .name("verbosity".into())
+        .spawn(move || loop {
+            let signal = crate::shutdown::next_signal(read_fd);
+            verbosity.step(signal == libc::SIGUSR1);
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logcapture::Capture;
+    use std::time::{Duration, Instant};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Runs `test` with its logs filtered as init_logging does, starting at
+    // INFO, and kept in the capture it is handed
+    fn reloadable(test: impl FnOnce(&Verbosity, &Capture)) {
+        let (filter, handle) = reload::Layer::new(LevelFilter::INFO);
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(move || writer.clone())
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            .with_ansi(false);
+        let subscriber = tracing_subscriber::registry().with(filter).with(layer);
+        tracing::subscriber::with_default(subscriber, || test(&Verbosity::new(handle), &capture));
+    }
+
+    #[test]
+    fn steps_one_level_at_a_time_and_stops_at_either_end() {
+        reloadable(|verbosity, _| {
+            let mut noisier = Vec::new();
+            for _ in 0..3 {
+                verbosity.step(true);
+                noisier.push(verbosity.current());
+            }
+            assert_eq!(
+                noisier,
+                [LevelFilter::DEBUG, LevelFilter::TRACE, LevelFilter::TRACE]
+            );
+            let mut quieter = Vec::new();
+            for _ in 0..5 {
+                verbosity.step(false);
+                quieter.push(verbosity.current());
+            }
+            assert_eq!(
+                quieter,
+                [
+                    LevelFilter::DEBUG,
+                    LevelFilter::INFO,
+                    LevelFilter::WARN,
+                    LevelFilter::ERROR,
+                    LevelFilter::ERROR
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn the_new_level_filters_what_is_logged() {
+        reloadable(|verbosity, log| {
+            tracing::debug!("not yet");
+            verbosity.set(LevelFilter::DEBUG);
+            tracing::debug!("now shown");
+            tracing::trace!("still not");
+            assert_eq!(log.take(), ["Log level debug", "now shown"]);
+        });
+    }
+
+    #[test]
+    fn each_change_is_logged_from_the_side_that_shows_it() {
+        reloadable(|verbosity, log| {
+            // Going quieter, only the old level shows the warning
+            verbosity.set(LevelFilter::ERROR);
+            tracing::warn!("hidden");
+            assert_eq!(log.take(), ["Log level error"]);
+            // Going noisier, only the new one does
+            verbosity.set(LevelFilter::WARN);
+            assert_eq!(log.take(), ["Log level warn"]);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sigusr1_and_sigusr2_step_the_level() {
+        fn raise(signal: libc::c_int) {
+            // SAFETY: the handler on_signals installs only writes to a pipe
+            unsafe { libc::kill(libc::getpid(), signal) };
+        }
+        fn wait_for(verbosity: &Verbosity, level: LevelFilter) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while verbosity.current() != level {
+                assert!(
+                    Instant::now() < deadline,
+                    "still at {}",
+                    verbosity.current()
+                );
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        reloadable(|verbosity, _| {
+            on_signals(verbosity.clone()).unwrap();
+            raise(libc::SIGUSR1);
+            wait_for(verbosity, LevelFilter::DEBUG);
+            raise(libc::SIGUSR2);
+            wait_for(verbosity, LevelFilter::INFO);
+            raise(libc::SIGUSR2);
+            wait_for(verbosity, LevelFilter::WARN);
+        });
+    }
+}
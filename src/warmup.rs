@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+// --stream-warmup-ms: what the ADX sends in the first stretch of a stream,
+// while its scan settles, is counted and thrown away. The cutoff is a point
+// in time, however many frames come before it. A board that power-cycles
+// mid-stream settles again, so the warmup can start over.
+pub struct Warmup {
+    length: Duration,
+    // When the warmup in progress started; None once it's over
+    started: Option<Instant>,
+    pub discarded: u64,
+    pub malformed: u64,
+}
+
+impl Warmup {
+    pub fn new(length: Duration, start: Instant) -> Self {
+        Warmup {
+            length,
+            started: (!length.is_zero()).then_some(start),
+            discarded: 0,
+            malformed: 0,
+        }
+    }
+
+    // Whether a packet read at `now` is discarded. The first one read once
+    // the warmup is over ends it.
+    pub fn discard(&mut self, now: Instant, well_formed: bool) -> bool {
+        let Some(started) = self.started else {
+            return false;
+        };
+        if now.saturating_duration_since(started) >= self.length {
+            self.finish();
+            return false;
+        }
+        if well_formed {
+            self.discarded += 1;
+        } else {
+            self.malformed += 1;
+        }
+        true
+    }
+
+    // Starts the warmup over from `now`, once the last one is logged
+    pub fn restart(&mut self, now: Instant) {
+        if self.length.is_zero() {
+            return;
+        }
+        self.finish();
+        self.started = Some(now);
+        self.discarded = 0;
+        self.malformed = 0;
+    }
+
+    // Logs what the warmup in progress discarded, and ends it
+    pub fn finish(&mut self) {
+        if self.started.take().is_none() {
+            return;
+        }
+        if self.malformed > 0 {
+            tracing::warn!(
+                "Stream warmup discarded {} frames, {} of them malformed",
+                self.discarded + self.malformed,
+                self.malformed
+            );
+        } else {
+            tracing::info!("Stream warmup discarded {} frames", self.discarded);
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.started.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+
+    const LENGTH: Duration = Duration::from_millis(50);
+
+    // Feeds well-formed frames every `every` until `until` after the
+    // clock's current time, returning how many were discarded
+    fn frames(warmup: &mut Warmup, clock: &MockClock, every: Duration, until: Duration) -> u64 {
+        let end = clock.now() + until;
+        let mut discarded = 0;
+        while clock.now() < end {
+            if warmup.discard(clock.now(), true) {
+                discarded += 1;
+            }
+            clock.advance(every);
+        }
+        discarded
+    }
+
+    #[test]
+    fn discards_until_the_cutoff_and_not_after() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut warmup = Warmup::new(LENGTH, start);
+        assert!(warmup.discard(start, true));
+        assert!(warmup.discard(start + Duration::from_millis(10), true));
+        assert!(warmup.discard(start + LENGTH - Duration::from_micros(1), false));
+        assert!(!warmup.is_over());
+        assert!(!warmup.discard(start + LENGTH, true));
+        assert!(warmup.is_over());
+        // Nothing after the cutoff is discarded, whatever its timestamp
+        assert!(!warmup.discard(start + Duration::from_millis(1), true));
+        assert_eq!((warmup.discarded, warmup.malformed), (2, 1));
+    }
+
+    #[test]
+    fn the_cutoff_is_a_time_not_a_frame_count() {
+        for every_us in [100, 1000, 7000, 30_000] {
+            let clock = MockClock::new();
+            let mut warmup = Warmup::new(LENGTH, clock.now());
+            let every = Duration::from_micros(every_us);
+            let discarded = frames(&mut warmup, &clock, every, Duration::from_millis(200));
+            // Exactly the frames read inside the first 50ms
+            let expected = LENGTH.as_micros().div_ceil(every_us as u128) as u64;
+            assert_eq!(discarded, expected, "a frame every {:?}", every);
+            assert_eq!(warmup.discarded, expected);
+            assert!(warmup.is_over());
+        }
+    }
+
+    #[test]
+    fn starts_from_the_given_instant() {
+        let clock = MockClock::new();
+        let stream_start = clock.now();
+        // The first frame only comes in 40ms into the stream
+        clock.advance(Duration::from_millis(40));
+        let mut warmup = Warmup::new(LENGTH, stream_start);
+        assert_eq!(
+            frames(&mut warmup, &clock, Duration::from_millis(1), LENGTH),
+            10
+        );
+    }
+
+    #[test]
+    fn restart_arms_a_fresh_warmup() {
+        let clock = MockClock::new();
+        let mut warmup = Warmup::new(LENGTH, clock.now());
+        assert_eq!(
+            frames(&mut warmup, &clock, Duration::from_millis(1), LENGTH * 2),
+            50
+        );
+        assert!(warmup.is_over());
+        // The board power-cycles a while into the stream
+        clock.advance(Duration::from_secs(3));
+        warmup.restart(clock.now());
+        assert_eq!((warmup.discarded, warmup.malformed), (0, 0));
+        assert_eq!(
+            frames(&mut warmup, &clock, Duration::from_millis(2), LENGTH * 2),
+            25
+        );
+        assert!(warmup.is_over());
+    }
+
+    #[test]
+    fn restart_during_a_warmup_moves_the_cutoff() {
+        let clock = MockClock::new();
+        let mut warmup = Warmup::new(LENGTH, clock.now());
+        assert_eq!(
+            frames(
+                &mut warmup,
+                &clock,
+                Duration::from_millis(1),
+                Duration::from_millis(30)
+            ),
+            30
+        );
+        warmup.restart(clock.now());
+        assert_eq!(
+            frames(&mut warmup, &clock, Duration::from_millis(1), LENGTH * 2),
+            50
+        );
+    }
+
+    #[test]
+    fn no_warmup_discards_nothing() {
+        let clock = MockClock::new();
+        let mut warmup = Warmup::new(Duration::ZERO, clock.now());
+        assert!(warmup.is_over());
+        assert!(!warmup.discard(clock.now(), true));
+        warmup.restart(clock.now());
+        assert!(!warmup.discard(clock.now(), false));
+        assert_eq!((warmup.discarded, warmup.malformed), (0, 0));
+    }
+}
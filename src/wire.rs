@@ -0,0 +1,200 @@
+use crate::conf;
+use anyhow::{anyhow, bail, Context, Result};
+use maitouch_protocol::framing::{maimai, PacketDelimiter};
+use std::fs;
+
+// Commands starting with `prefix` (after the open delimiter) are answered
+// with exactly `len` bytes, delimiters included, whatever bytes they hold.
+// Written `L:6` in spec files.
+pub struct ResponseLen {
+    pub prefix: String,
+    pub len: usize,
+}
+
+impl ResponseLen {
+    fn parse(text: &str) -> Result<Self> {
+        let (prefix, len) = text
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("response length {} should look like L:6", text))?;
+        let len = len
+            .trim()
+            .parse()
+            .with_context(|| format!("bad length in {}", text))?;
+        if len < 2 {
+            bail!("response length {} must include both delimiters", text);
+        }
+        Ok(ResponseLen {
+            prefix: prefix.to_string(),
+            len,
+        })
+    }
+}
+
+// Framing of both sides of the link. The ALLS sends commands, the ADX
+// answers them and streams touch frames.
+pub struct WireSpec {
+    pub name: String,
+    pub alls: PacketDelimiter,
+    pub adx: PacketDelimiter,
+    pub touch_frame_len: usize,
+    pub command_max_len: usize,
+    // Config responses read by length rather than up to the close delimiter
+    pub responses: Vec<ResponseLen>,
+    // Prefixes of commands the ADX takes without answering, such as button
+    // LED commands on boards that share the touch port
+    pub unanswered: Vec<String>,
+}
+
+pub const PRESETS: &[&str] = &["maimai"];
+
+impl WireSpec {
+    // The stock link. Sensitivity answers are read by length, which is what
+    // a stock ADX sends; a spec file can turn that off with `responses = []`.
+    pub fn maimai() -> Self {
+        WireSpec {
+            name: "maimai".to_string(),
+            alls: maimai::ALLS,
+            adx: maimai::ADX,
+            touch_frame_len: maimai::TOUCH_FRAME_LEN,
+            command_max_len: maimai::COMMAND_MAX_LEN,
+            responses: maimai::SIZED_RESPONSE_PREFIXES
+                .iter()
+                .map(|prefix| ResponseLen {
+                    prefix: prefix.to_string(),
+                    len: maimai::SIZED_RESPONSE_LEN,
+                })
+                .collect(),
+            unanswered: Vec::new(),
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "maimai" => Some(Self::maimai()),
+            _ => None,
+        }
+    }
+
+    // A preset name, or the path to a TOML file describing a custom spec
+    pub fn load(spec: &str) -> Result<Self> {
+        if let Some(preset) = Self::preset(spec) {
+            return Ok(preset);
+        }
+        let text = fs::read_to_string(spec).with_context(|| {
+            format!(
+                "wire spec {} is neither a preset ({}) nor a readable file",
+                spec,
+                PRESETS.join(", ")
+            )
+        })?;
+        Self::from_toml(&text).with_context(|| format!("invalid wire spec file {}", spec))
+    }
+
+    // Custom specs are flat `key = value` tables. Unset keys keep their maimai values.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut spec = Self::maimai();
+        spec.name = "custom".to_string();
+        for entry in conf::parse(text)? {
+            match entry.key.as_str() {
+                "name" => spec.name = entry.string()?,
+                "alls_open" => spec.alls.open = entry.char()?,
+                "alls_close" => spec.alls.close = entry.char()?,
+                "adx_open" => spec.adx.open = entry.char()?,
+                "adx_close" => spec.adx.close = entry.char()?,
+                "touch_frame_len" => spec.touch_frame_len = entry.usize()?,
+                "command_max_len" => spec.command_max_len = entry.usize()?,
+                "responses" => {
+                    let line = entry.line;
+                    spec.responses = entry
+                        .strings()?
+                        .iter()
+                        .map(|text| ResponseLen::parse(text))
+                        .collect::<Result<_>>()
+                        .with_context(|| format!("line {}", line))?;
+                }
+                "unanswered" => spec.unanswered = entry.strings()?,
+                _ => return Err(entry.unknown()),
+            }
+        }
+        if spec.touch_frame_len < 2 || spec.command_max_len < 2 {
+            bail!("frame and command lengths must include both delimiters");
+        }
+        Ok(spec)
+    }
+
+    // A named command wrapped in the ALLS delimiters, e.g. {HALT}
+    pub fn command(&self, name: &str) -> Vec<u8> {
+        self.alls.wrap(name.as_bytes())
+    }
+
+    // Whether the ADX answers `command`, a config command
+    pub fn expects_response(&self, command: &[u8]) -> bool {
+        let name = command.get(1..).unwrap_or_default();
+        !self
+            .unanswered
+            .iter()
+            .any(|prefix| name.starts_with(prefix.as_bytes()))
+    }
+
+    // Length of the ADX's answer to `command`, if the spec knows it
+    pub fn response_len(&self, command: &[u8]) -> Option<usize> {
+        let name = command.get(1..)?;
+        self.responses
+            .iter()
+            .find(|response| name.starts_with(response.prefix.as_bytes()))
+            .map(|response| response.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_stock_spec_sizes_sensitivity_answers_only() {
+        let spec = WireSpec::maimai();
+        assert_eq!(spec.response_len(b"{LAr2}"), Some(6));
+        assert_eq!(spec.response_len(b"{RA)2}"), Some(6));
+        assert_eq!(spec.response_len(b"{HALT}"), None);
+        assert_eq!(spec.response_len(b""), None);
+    }
+
+    #[test]
+    fn a_spec_file_sets_its_own_response_lengths() {
+        let spec = WireSpec::from_toml("responses = [\"FW:9\", \"L:6\"]\n").unwrap();
+        assert_eq!(spec.response_len(b"{FW}"), Some(9));
+        assert_eq!(spec.response_len(b"{LAr2}"), Some(6));
+        assert_eq!(spec.response_len(b"{RAr2}"), None);
+        let spec = WireSpec::from_toml("responses = []\n").unwrap();
+        assert_eq!(spec.response_len(b"{LAr2}"), None);
+    }
+
+    #[test]
+    fn only_commands_listed_as_unanswered_go_without_an_answer() {
+        assert!(WireSpec::maimai().expects_response(b"{BL01}"));
+        let spec = WireSpec::from_toml("unanswered = [\"B\", \"LED\"]\n").unwrap();
+        assert!(!spec.expects_response(b"{BL01}"));
+        assert!(!spec.expects_response(b"{LED1}"));
+        assert!(spec.expects_response(b"{LAr2}"));
+        assert!(spec.expects_response(b"{RBr2}"));
+        assert!(spec.expects_response(b""));
+    }
+
+    #[test]
+    fn a_response_length_needs_room_for_its_delimiters() {
+        let err = |text: &str| {
+            format!(
+                "{:#}",
+                WireSpec::from_toml(&format!("responses = [\"{}\"]\n", text))
+                    .err()
+                    .unwrap()
+            )
+        };
+        assert_eq!(
+            err("L:1"),
+            "line 1: response length L:1 must include both delimiters"
+        );
+        assert_eq!(err("L"), "line 1: response length L should look like L:6");
+        assert!(err("L:six").starts_with("line 1: bad length in L:six"));
+    }
+}
@@ -0,0 +1,166 @@
+// Runs the proxy binary with stdio as the ALLS port: the test is the game
+// on its stdin/stdout, and a board on a PTY is the ADX.
+#![cfg(unix)]
+
+use serialport::{SerialPort, TTYPort};
+use std::io::{ErrorKind, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WAIT: Duration = Duration::from_secs(10);
+const FRAME: &[u8] = b"(\x01\x00\x00\x00\x00\x00\x00)";
+
+// Answers {LAr2} and streams FRAME every 2ms between {STAT} and {HALT}
+fn board(mut port: TTYPort, done: &AtomicBool) {
+    port.set_timeout(Duration::from_millis(2)).unwrap();
+    let mut streaming = false;
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 64];
+    while !done.load(Ordering::Relaxed) {
+        match port.read(&mut buf) {
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == ErrorKind::TimedOut => {}
+            Err(_) => return,
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'}') {
+            let command: Vec<u8> = pending.drain(..=end).collect();
+            match command.as_slice() {
+                b"{STAT}" => streaming = true,
+                b"{HALT}" => streaming = false,
+                b"{LAr2}" => port.write_all(b"(LAr2)").unwrap(),
+                _ => {}
+            }
+        }
+        if streaming {
+            let _ = port.write_all(FRAME);
+        }
+    }
+}
+
+// The proxy's stdout, read on a thread so the test can time out on it
+fn chunks(mut stdout: ChildStdout) -> Receiver<Vec<u8>> {
+    let (sender, chunks) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        while let Ok(n) = stdout.read(&mut buf) {
+            if n == 0 || sender.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    chunks
+}
+
+// What stdout has sent by the time `wanted` is happy with it, or WAIT
+// has gone by
+fn read_until(chunks: &Receiver<Vec<u8>>, wanted: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let deadline = Instant::now() + WAIT;
+    let mut got = Vec::new();
+    while !wanted(&got) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match chunks.recv_timeout(left) {
+            Ok(chunk) => got.extend(chunk),
+            Err(_) => break,
+        }
+    }
+    got
+}
+
+fn proxy(adx: &str) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_maitouch_rs"))
+        .args(["stdio", adx])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+#[test]
+fn the_game_talks_over_stdin_and_stdout_and_logs_go_to_stderr() {
+    let (adx, adx_slave) = TTYPort::pair().unwrap();
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| board(adx, &done));
+        let mut child = proxy(&adx_slave.name().unwrap());
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = chunks(child.stdout.take().unwrap());
+
+        stdin.write_all(b"{LAr2}").unwrap();
+        let answer = read_until(&stdout, |got| got.len() >= 6);
+        stdin.write_all(b"{STAT}").unwrap();
+        let streamed = read_until(&stdout, |got| {
+            got.windows(FRAME.len()).any(|window| window == FRAME)
+        });
+        stdin.write_all(b"{HALT}").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        // Closing stdin is the game hanging up
+        drop(stdin);
+        let status = child.wait().unwrap();
+        let mut log = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        done.store(true, Ordering::Relaxed);
+
+        // Nothing but the answer on stdout; the logs are all on stderr
+        assert_eq!(String::from_utf8_lossy(&answer), "(LAr2)", "{}", log);
+        assert!(
+            streamed.windows(FRAME.len()).any(|window| window == FRAME),
+            "{:?}\n{}",
+            streamed,
+            log
+        );
+        assert!(!log.is_empty());
+        assert!(status.success(), "{}\n{}", status, log);
+    });
+    drop(adx_slave);
+}
+
+#[test]
+fn a_closed_stdin_mid_stream_stops_the_proxy_cleanly() {
+    let (adx, adx_slave) = TTYPort::pair().unwrap();
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| board(adx, &done));
+        let mut child = proxy(&adx_slave.name().unwrap());
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = chunks(child.stdout.take().unwrap());
+
+        stdin.write_all(b"{STAT}").unwrap();
+        read_until(&stdout, |got| {
+            got.windows(FRAME.len()).any(|window| window == FRAME)
+        });
+        // Never halted: the stream has to notice on its own
+        drop(stdin);
+        let deadline = Instant::now() + WAIT;
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break Some(status);
+            }
+            if Instant::now() > deadline {
+                child.kill().unwrap();
+                break None;
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        let mut log = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        done.store(true, Ordering::Relaxed);
+
+        let status = status.unwrap_or_else(|| panic!("still running\n{}", log));
+        assert!(status.success(), "{}\n{}", status, log);
+    });
+    drop(adx_slave);
+}